@@ -30,6 +30,9 @@ pub const APP_NAME: &str = env!("CARGO_PKG_NAME");
 
 pub type Result<T> = std::result::Result<T, AddressBookError>;
 
+/// The most addresses which can be looked up in one `request_pubkeys` request.
+pub const MAX_PUBKEYS_PER_REQUEST: usize = 1000;
+
 /// Command line arguments
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -135,6 +138,37 @@ pub fn init_web_server<T: Store + 'static>(
     })
     .unwrap();
 
+    // Fetch the public keys for a list of addresses. The result has an entry for each address,
+    // which is null if the address is not found.
+    api.post("request_pubkeys", |req_params, server_state| {
+        async move {
+            let addresses: Vec<UserAddress> = req_params.body_auto()?;
+            trace!("/request_pubkeys {} addresses", addresses.len());
+            if addresses.len() > MAX_PUBKEYS_PER_REQUEST {
+                return Err(AddressBookError::Other {
+                    status: StatusCode::BadRequest,
+                    msg: format!(
+                        "cannot look up more than {} addresses at once",
+                        MAX_PUBKEYS_PER_REQUEST
+                    ),
+                });
+            }
+            addresses
+                .iter()
+                .map(|address| {
+                    (*server_state.store)
+                        .load(address)
+                        .map_err(|e| AddressBookError::Other {
+                            status: StatusCode::InternalServerError,
+                            msg: e.to_string(),
+                        })
+                })
+                .collect::<Result<Vec<Option<UserPubKey>>>>()
+        }
+        .boxed()
+    })
+    .unwrap();
+
     // Fetch all the public key bundles for all peers.
     api.get("request_peers", |_req_params, server_state| {
         async move {
@@ -5,7 +5,7 @@ use address_book::{
     error::AddressBookError,
     init_web_server,
     store::{address_book_temp_dir, FileStore, Store, TransientFileStore},
-    InsertPubKey,
+    InsertPubKey, MAX_PUBKEYS_PER_REQUEST,
 };
 use async_std::task::spawn;
 use jf_cap::keys::{UserKeyPair, UserPubKey};
@@ -99,6 +99,7 @@ async fn round_trip<T: Store + 'static>(store: T) {
     }
 
     // Lookup addresses we didn't insert.
+    let mut not_found = vec![];
     for _ in 0..NOT_FOUND_COUNT {
         let user_key = UserKeyPair::generate(&mut rng2);
         let pub_key = user_key.pub_key();
@@ -110,7 +111,42 @@ async fn round_trip<T: Store + 'static>(store: T) {
             .await
             .unwrap_err();
         assert_eq!(err.status(), StatusCode::NotFound);
+        not_found.push(pub_key);
     }
+
+    // Lookup inserted and missing addresses in bulk.
+    let expected = inserted
+        .iter()
+        .cloned()
+        .map(Some)
+        .chain(not_found.iter().map(|_| None))
+        .collect::<Vec<_>>();
+    let addresses = inserted
+        .iter()
+        .chain(&not_found)
+        .map(|pub_key| pub_key.address())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        client
+            .post::<Vec<Option<UserPubKey>>>("request_pubkeys")
+            .body_json(&addresses)
+            .unwrap()
+            .send()
+            .await
+            .unwrap(),
+        expected
+    );
+
+    // Bulk lookups are bounded.
+    let too_many = vec![addresses[0].clone(); MAX_PUBKEYS_PER_REQUEST + 1];
+    let err = client
+        .post::<Vec<Option<UserPubKey>>>("request_pubkeys")
+        .body_json(&too_many)
+        .unwrap()
+        .send()
+        .await
+        .unwrap_err();
+    assert_eq!(err.status(), StatusCode::BadRequest);
     assert!(handle.cancel().await.is_none());
 }
 
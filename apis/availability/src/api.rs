@@ -3,7 +3,10 @@
 
 use crate::{
     data_source::AvailabilityDataSource,
-    query_data::{BlockQueryData, BlockSummaryQueryData, RecordQueryData, StateQueryData},
+    query_data::{
+        BlockQueryData, BlockSummaryQueryData, RecordProofQueryData, RecordQueryData,
+        StateQueryData,
+    },
 };
 use ark_serialize::CanonicalSerialize;
 use clap::Args;
@@ -80,6 +83,16 @@ pub enum Error {
         output_index: u64,
     },
 
+    #[from(ignore)]
+    #[snafu(display("this server does not have any blocks yet"))]
+    NoBlocks,
+
+    #[from(ignore)]
+    #[snafu(display("this server does not have a proof for record UID {}", uid))]
+    MissingRecordProof {
+        uid: u64,
+    },
+
     #[from(ignore)]
     #[snafu(display("this server does not have block {}", block_id))]
     MissingBlock {
@@ -91,6 +104,10 @@ pub enum Error {
     MissingState {
         block_id: u64,
     },
+
+    #[from(ignore)]
+    #[snafu(display("this server does not sign state snapshots"))]
+    SnapshotsUnavailable,
 }
 
 impl Error {
@@ -103,8 +120,11 @@ impl Error {
             Self::InvalidBlockId { .. } => StatusCode::BadRequest,
             Self::InvalidTransactionId { .. } => StatusCode::BadRequest,
             Self::InvalidRecordId { .. } => StatusCode::BadRequest,
+            Self::NoBlocks => StatusCode::NotFound,
+            Self::MissingRecordProof { .. } => StatusCode::NotFound,
             Self::MissingBlock { .. } => StatusCode::NotFound,
             Self::MissingState { .. } => StatusCode::NotFound,
+            Self::SnapshotsUnavailable => StatusCode::NotImplemented,
         }
     }
 }
@@ -159,6 +179,43 @@ where
     Ok(summaries)
 }
 
+fn get_record<State>(
+    state: State,
+    block_id: u64,
+    txn_id: u64,
+    output_index: u64,
+) -> Result<RecordQueryData, Error>
+where
+    State: AvailabilityDataSource,
+{
+    let block = get_block(state, block_id)?;
+    let txns = &block.raw_block.block.0;
+    let txn = txns
+        .get(txn_id as usize)
+        .context(InvalidTransactionIdSnafu { block_id, txn_id })?;
+    let commitment = *txn
+        .output_commitments()
+        .get(output_index as usize)
+        .context(InvalidRecordIdSnafu {
+            block_id,
+            txn_id,
+            output_index,
+        })?;
+    let uid = block.records_from
+        + txns[..txn_id as usize]
+            .iter()
+            .map(|txn| txn.output_len() as u64)
+            .sum::<u64>()
+        + output_index;
+    Ok(RecordQueryData {
+        commitment,
+        uid,
+        block_id,
+        txn_id,
+        output_index,
+    })
+}
+
 fn get_state<State>(state: State, block_id: u64) -> Result<StateQueryData, Error>
 where
     State: AvailabilityDataSource,
@@ -217,6 +274,44 @@ where
     Ok(summaries)
 }
 
+/// A proof of the record with `uid`, relative to the record Merkle tree after block `block_id`,
+/// or after the latest block if `block_id` is [None].
+pub fn get_record_proof<State>(
+    state: State,
+    uid: u64,
+    block_id: Option<u64>,
+) -> Result<RecordProofQueryData, Error>
+where
+    State: AvailabilityDataSource + Copy,
+{
+    let (record_block, txn_id, output_index) = state
+        .get_record_index_by_uid(uid)
+        .context(UnknownRecordUidSnafu { uid })?;
+    let record = get_record(state, record_block, txn_id, output_index)?;
+    let (proof, merkle_commitment, block_id) = match block_id {
+        Some(block_id) => {
+            let (proof, merkle_commitment) = state
+                .get_record_proof_at(uid, block_id)
+                .context(MissingRecordProofSnafu { uid })?;
+            (proof, merkle_commitment, block_id)
+        }
+        None => {
+            let block_id = state.get_latest_block_id().context(NoBlocksSnafu)?;
+            let (proof, merkle_commitment) = state
+                .get_record_proof(uid)
+                .context(MissingRecordProofSnafu { uid })?;
+            (proof, merkle_commitment, block_id)
+        }
+    };
+    Ok(RecordProofQueryData {
+        uid,
+        commitment: record.commitment,
+        proof,
+        merkle_commitment,
+        block_id,
+    })
+}
+
 pub fn define_api<State>(options: &Options) -> Result<Api<State, Error>, ApiError>
 where
     State: 'static + Send + Sync + ReadState,
@@ -250,17 +345,37 @@ where
         })?
         .get("getstatecomm", |req, state| {
             async move {
-                let id = block_index(&req, state)?;
+                let id = match req.opt_integer_param("block_id")? {
+                    Some(id) => id,
+                    None => state.get_latest_block_id().context(NoBlocksSnafu)?,
+                };
                 Ok(get_state(state, id)?.commitment)
             }
             .boxed()
         })?
+        .get("getsnapshot", |req, state| {
+            async move {
+                let id = match req.opt_integer_param("block_id")? {
+                    Some(id) => id,
+                    None => state.get_latest_block_id().context(NoBlocksSnafu)?,
+                };
+                state
+                    .sign_snapshot(&get_state(state, id)?)
+                    .context(SnapshotsUnavailableSnafu)
+            }
+            .boxed()
+        })?
         .get("gettransaction", |req, state| {
             async move {
                 let (block_id, txn_id) = if let Some(hash) = req.opt_blob_param("hash")? {
                     state
                         .get_txn_index_by_hash(hash)
                         .context(UnknownTransactionHashSnafu { hash })?
+                } else if let Some(uid) = req.opt_integer_param("uid")? {
+                    let (block_id, txn_id, _) = state
+                        .get_record_index_by_uid(uid)
+                        .context(UnknownRecordUidSnafu { uid })?;
+                    (block_id, txn_id)
                 } else {
                     (req.integer_param("block_id")?, req.integer_param("txn_id")?)
                 };
@@ -285,28 +400,15 @@ where
                             req.integer_param("output_index")?,
                         )
                     };
-                let block = get_block(state, block_id)?;
-                let commitment = *block
-                    .raw_block
-                    .block
-                    .0
-                    .get(txn_id as usize)
-                    .context(InvalidTransactionIdSnafu { block_id, txn_id })?
-                    .output_commitments()
-                    .get(output_index as usize)
-                    .context(InvalidRecordIdSnafu {
-                        block_id,
-                        txn_id,
-                        output_index,
-                    })?;
-                let uid = block.records_from + output_index;
-                Ok(RecordQueryData {
-                    commitment,
-                    uid,
-                    block_id,
-                    txn_id,
-                    output_index,
-                })
+                get_record(state, block_id, txn_id, output_index)
+            }
+            .boxed()
+        })?
+        .get("getrecordproof", |req, state| {
+            async move {
+                let uid = req.integer_param("uid")?;
+                let block_id = req.opt_integer_param("block_id")?;
+                get_record_proof(state, uid, block_id)
             }
             .boxed()
         })?
@@ -2,9 +2,12 @@
 // This file is part of the Espresso library.
 
 use crate::query_data::{BlockQueryData, EncodedPublicKey, StateQueryData};
-use espresso_core::state::{ElaboratedBlockCommitment, TransactionCommitment, ValidatorState};
+use espresso_core::{
+    snapshot::SignedSnapshot,
+    state::{ElaboratedBlockCommitment, TransactionCommitment, ValidatorState},
+};
 use hotshot_types::data::QuorumCertificate;
-use jf_cap::MerkleTree;
+use jf_cap::{MerkleCommitment, MerkleLeafProof, MerkleTree};
 use std::error::Error;
 use std::fmt::Debug;
 
@@ -29,6 +32,27 @@ pub trait AvailabilityDataSource {
                                                                             // leaving more compact and/or performant solutions as optional
     fn get_record_merkle_tree_at_block_index(&self, n: usize) -> Option<MerkleTree>;
     fn get_block_ids_by_proposer_id(&self, id: EncodedPublicKey) -> Vec<u64>;
+    /// The ID of the most recent block, or [None] if there are no blocks yet.
+    fn get_latest_block_id(&self) -> Option<u64>;
+    /// A proof of the record with `uid` relative to the latest record Merkle tree.
+    ///
+    /// Returns [None] if `uid` is out of range or this data source does not have the record.
+    fn get_record_proof(&self, uid: u64) -> Option<(MerkleLeafProof, MerkleCommitment)>;
+    /// A proof of the record with `uid` relative to the record Merkle tree after block `block_id`.
+    ///
+    /// Transactions may be built against any of the last
+    /// [HISTORY_SIZE](espresso_core::state::ValidatorState::HISTORY_SIZE) record Merkle roots, so
+    /// data sources only need to serve proofs for blocks that recent, and may return [None] for
+    /// older blocks.
+    fn get_record_proof_at(
+        &self,
+        uid: u64,
+        block_id: u64,
+    ) -> Option<(MerkleLeafProof, MerkleCommitment)>;
+    /// Sign `state` with this node's staking key.
+    ///
+    /// Returns [None] if this data source does not have a key to sign snapshots with.
+    fn sign_snapshot(&self, state: &StateQueryData) -> Option<SignedSnapshot>;
 }
 
 pub trait UpdateAvailabilityData {
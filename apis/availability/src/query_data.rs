@@ -6,7 +6,7 @@ use espresso_core::state::{
     state_comm::LedgerStateCommitment, ElaboratedBlock, ElaboratedBlockCommitment,
     ElaboratedTransaction, TransactionCommitment, ValidatorState,
 };
-use jf_cap::structs::RecordCommitment;
+use jf_cap::{structs::RecordCommitment, MerkleCommitment, MerkleLeafProof};
 use jf_utils::tagged_blob;
 use serde::{Deserialize, Serialize};
 
@@ -69,6 +69,18 @@ pub struct RecordQueryData {
     pub output_index: u64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RecordProofQueryData {
+    pub uid: u64,
+    pub commitment: RecordCommitment,
+    /// Membership proof for `commitment` at position `uid`.
+    pub proof: MerkleLeafProof,
+    /// The record Merkle tree `proof` is relative to.
+    pub merkle_commitment: MerkleCommitment,
+    /// The ID of the block which created the state containing `merkle_commitment`.
+    pub block_id: u64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct StateQueryData {
     pub state: ValidatorState,
@@ -1,16 +1,22 @@
 // Copyright (c) 2022 Espresso Systems (espressosys.com)
 // This file is part of the Espresso library.
 
-use crate::data_source::CatchUpDataSource;
+use crate::data_source::{CatchUpDataSource, PostMemosData, PostMemosError};
 use clap::Args;
 use derive_more::From;
-use futures::{stream::iter, FutureExt, StreamExt, TryFutureExt};
+use espresso_core::ledger::EspressoLedger;
+use futures::{
+    future::ready,
+    stream::{iter, BoxStream},
+    FutureExt, StreamExt, TryFutureExt,
+};
+use seahorse::events::LedgerEvent;
 use serde::{Deserialize, Serialize};
 use snafu::Snafu;
 use std::path::PathBuf;
 use tide_disco::{
     api::{Api, ApiError},
-    method::ReadState,
+    method::{ReadState, WriteState},
     RequestError, StatusCode,
 };
 
@@ -22,20 +28,58 @@ pub struct Options {
 
 #[derive(Clone, Debug, From, Snafu, Deserialize, Serialize)]
 pub enum Error {
-    Request { source: RequestError },
+    Request {
+        source: RequestError,
+    },
+
+    #[snafu(display("memos rejected: {}", source))]
+    InvalidMemos {
+        source: PostMemosError,
+    },
 }
 
 impl Error {
     pub fn status(&self) -> StatusCode {
         match self {
             Self::Request { .. } => StatusCode::BadRequest,
+            Self::InvalidMemos {
+                source: PostMemosError::Unavailable { .. },
+            } => StatusCode::ServiceUnavailable,
+            Self::InvalidMemos { .. } => StatusCode::BadRequest,
         }
     }
 }
 
+/// The events in `state` starting from index `first`, each tagged with its index, followed by new
+/// events as they are added.
+pub fn indexed_events_from<State>(
+    state: State,
+    first: usize,
+) -> BoxStream<'static, (usize, Option<LedgerEvent<EspressoLedger>>)>
+where
+    State: CatchUpDataSource,
+{
+    let prefix: Vec<_> = if first >= state.len() {
+        vec![]
+    } else {
+        state.get_nth_event_iter(first).collect()
+    };
+    let receiver = state.subscribe();
+    let next = first + prefix.len();
+    iter(
+        prefix
+            .into_iter()
+            .enumerate()
+            .map(move |(i, e)| (first + i, e)),
+    )
+    .chain(receiver.filter(move |(i, _)| ready(*i >= next)))
+    .boxed()
+}
+
 pub fn define_api<State>(options: &Options) -> Result<Api<State, Error>, ApiError>
 where
-    State: 'static + Send + Sync + ReadState,
+    State: 'static + Send + Sync + WriteState,
+    <State as ReadState>::State: Send + Sync + PostMemosData,
     for<'a> &'a <State as ReadState>::State: Send + Sync + CatchUpDataSource,
 {
     let mut api = match &options.api_path {
@@ -96,6 +140,26 @@ where
             }
             .try_flatten_stream()
             .boxed()
+        })?
+        .stream("subscribe_for_indexed_events", |req, state| {
+            async move {
+                let first = req.integer_param("first")?;
+                let events = state
+                    .read(|state| async move { indexed_events_from(state, first) }.boxed())
+                    .await;
+                Ok(events.map(Ok))
+            }
+            .try_flatten_stream()
+            .boxed()
+        })?
+        .post("post_memos", |req, state| {
+            async move {
+                let block_id = req.integer_param("block_id")?;
+                let txn_id = req.integer_param("txn_id")?;
+                let (memos, sig) = req.body_auto()?;
+                Ok(state.post_memos(block_id, txn_id, memos, sig).await?)
+            }
+            .boxed()
         })?;
     Ok(api)
 }
@@ -3,8 +3,11 @@
 
 use async_trait::async_trait;
 use espresso_core::ledger::EspressoLedger;
+use jf_cap::{structs::ReceiverMemo, Signature};
 use postage::broadcast::Receiver;
 use seahorse::events::LedgerEvent;
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
 use std::error::Error;
 use std::fmt::Debug;
 
@@ -28,3 +31,48 @@ pub trait UpdateCatchUpData {
         events: Vec<Option<LedgerEvent<EspressoLedger>>>,
     ) -> Result<(), Self::Error>;
 }
+
+#[derive(Clone, Debug, Snafu, Deserialize, Serialize)]
+pub enum PostMemosError {
+    #[snafu(display("block {} does not exist", block_id))]
+    UnknownBlock { block_id: u64 },
+
+    #[snafu(display("block {} does not have a transaction {}", block_id, txn_id))]
+    UnknownTransaction { block_id: u64, txn_id: u64 },
+
+    #[snafu(display(
+        "transaction {}/{} is not a CAP transaction, so it has no memos",
+        block_id,
+        txn_id
+    ))]
+    NotCapTransaction { block_id: u64, txn_id: u64 },
+
+    #[snafu(display("wrong number of memos (expected {}, got {})", expected, actual))]
+    WrongNumberOfMemos { expected: usize, actual: usize },
+
+    #[snafu(display("memos signature does not match the committed transaction"))]
+    BadSignature,
+
+    #[snafu(display("memos for transaction {}/{} were already published", block_id, txn_id))]
+    AlreadyPublished { block_id: u64, txn_id: u64 },
+
+    #[snafu(display("memos cannot be published yet: {}", reason))]
+    Unavailable { reason: String },
+}
+
+/// A bulletin board where receiver memos can be posted after their transaction is committed.
+#[async_trait]
+pub trait PostMemosData {
+    /// Post memos for the outputs of transaction `txn_id` in block `block_id`.
+    ///
+    /// The implementation must check `sig` against the committed transaction before accepting the
+    /// memos. Accepted memos are persisted and broadcast to subscribers as a
+    /// [LedgerEvent::Memos].
+    async fn post_memos(
+        &mut self,
+        block_id: u64,
+        txn_id: u64,
+        memos: Vec<ReceiverMemo>,
+        sig: Signature,
+    ) -> Result<(), PostMemosError>;
+}
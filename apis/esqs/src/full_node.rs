@@ -11,6 +11,9 @@
 //! * [metastate]
 //! * [status]
 //! * [validator]
+//!
+//! With the `grpc` feature, transaction submission, event subscriptions and record and nullifier
+//! proofs can also be served over gRPC (see `espresso_grpc_api`) by setting `Options::grpc_port`.
 
 use crate::{
     full_node_data_source::QueryData,
@@ -19,16 +22,20 @@ use crate::{
 };
 use async_std::{
     sync::{Arc, RwLock},
-    task::{spawn, JoinHandle},
+    task::{block_on, spawn, JoinHandle},
 };
 use clap::{Args, Subcommand};
 use espresso_availability_api::api as availability;
 use espresso_catchup_api::api as catchup;
+#[cfg(feature = "grpc")]
+use espresso_grpc_api::server as grpc;
 use espresso_metastate_api::api as metastate;
 use espresso_status_api::api as status;
 use espresso_validator_api::{api as validator, data_source::ValidatorDataSource};
 use std::fmt::Display;
 use std::io;
+#[cfg(feature = "grpc")]
+use std::thread;
 use tide_disco::{http::Url, App};
 
 #[derive(Args)]
@@ -36,6 +43,11 @@ pub struct Options {
     #[arg(short, long, env = "ESPRESSO_ESQS_PORT")]
     pub port: u16,
 
+    /// If set, also serve the APIs supported by the gRPC transport on this port.
+    #[cfg(feature = "grpc")]
+    #[arg(long, env = "ESPRESSO_ESQS_GRPC_PORT")]
+    pub grpc_port: Option<u16>,
+
     #[command(flatten)]
     pub availability: availability::Options,
 
@@ -56,6 +68,8 @@ impl Options {
     pub fn with_port(port: u16) -> Self {
         Self {
             port,
+            #[cfg(feature = "grpc")]
+            grpc_port: None,
             availability: Default::default(),
             catchup: Default::default(),
             metastate: Default::default(),
@@ -90,6 +104,8 @@ pub struct EsQS {
     port: u16,
     _updater: Arc<RwLock<UpdateQueryDataSource<UpdateQueryDataSourceTypesBinder>>>,
     _server: JoinHandle<io::Result<()>>,
+    #[cfg(feature = "grpc")]
+    _grpc_server: Option<thread::JoinHandle<io::Result<()>>>,
 }
 
 impl EsQS {
@@ -127,9 +143,25 @@ impl EsQS {
                 Ok(())
             }
         });
+        #[cfg(feature = "grpc")]
+        let grpc_server = opt
+            .grpc_port
+            .map(|port| {
+                grpc::serve(
+                    grpc::query_routes(data_source.clone()),
+                    ([0, 0, 0, 0], port).into(),
+                )
+            })
+            .transpose()?;
         let events = consensus.into_stream();
+        // Resume from the last state in the data source, so that a node restarted from its store
+        // appends new blocks after the ones it already has.
+        let validator_state = block_on(data_source.read())
+            .latest_state()
+            .unwrap_or_default();
         let updater = UpdateQueryDataSource::new(
             events,
+            validator_state,
             data_source.clone(),
             data_source.clone(),
             data_source.clone(),
@@ -140,6 +172,8 @@ impl EsQS {
             port,
             _updater: updater,
             _server: server,
+            #[cfg(feature = "grpc")]
+            _grpc_server: grpc_server,
         })
     }
 
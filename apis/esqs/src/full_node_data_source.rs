@@ -1,10 +1,13 @@
 // Copyright (c) 2022 Espresso Systems (espressosys.com)
 // This file is part of the Espresso library.
 
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::From;
 use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
 
+use crate::nullifier_cache::NullifierProofCache;
 use crate::ApiError;
 use async_trait::async_trait;
 use atomic_store::{
@@ -16,12 +19,21 @@ use espresso_availability_api::data_source::{
 };
 use espresso_availability_api::query_data::EncodedPublicKey;
 use espresso_availability_api::query_data::{BlockQueryData, StateQueryData};
-use espresso_catchup_api::data_source::{CatchUpDataSource, UpdateCatchUpData};
+use espresso_catchup_api::data_source::{
+    CatchUpDataSource, PostMemosData, PostMemosError, UpdateCatchUpData,
+};
 use espresso_core::ledger::EspressoLedger;
+use espresso_core::mempool::{
+    BlockMetrics, BlockPolicy, FeeSchedule, Mempool, MempoolConfig, MempoolError, MempoolStatus,
+};
+use espresso_core::reward::{insert_collected_rewards, CollectedRewardsSet};
+use espresso_core::snapshot::SignedSnapshot;
+use espresso_core::stake_table::StakingPrivKey;
 use espresso_core::state::{
-    ElaboratedBlockCommitment, ElaboratedTransaction, SetMerkleProof, SetMerkleTree,
-    TransactionCommitment, ValidatorState,
+    Block, ElaboratedBlockCommitment, ElaboratedTransaction, EspressoTransaction, SetMerkleProof,
+    SetMerkleTree, TransactionCommitment, ValidatorState,
 };
+use espresso_core::universal_params::MERKLE_HEIGHT;
 use espresso_metastate_api::{
     api as metastate,
     data_source::{MetaStateDataSource, UpdateMetaStateData},
@@ -31,8 +43,9 @@ use espresso_status_api::query_data::ValidatorStatus;
 use espresso_validator_api::data_source::{ConsensusEvent, ValidatorDataSource};
 use hotshot::{data::QuorumCertificate, HotShotError};
 use itertools::izip;
-use jf_cap::structs::Nullifier;
-use jf_cap::MerkleTree;
+use jf_cap::structs::{Nullifier, ReceiverMemo};
+use jf_cap::Signature;
+use jf_cap::{MerkleCommitment, MerkleLeafProof, MerkleTree};
 use postage::{broadcast, sink::Sink};
 use seahorse::events::LedgerEvent;
 use tracing::warn;
@@ -56,6 +69,13 @@ pub struct QueryData {
     event_sender: broadcast::Sender<(usize, Option<LedgerEvent<EspressoLedger>>)>,
     event_receiver: broadcast::Receiver<(usize, Option<LedgerEvent<EspressoLedger>>)>,
     cached_nullifier_sets: BTreeMap<u64, SetMerkleTree>,
+    // A full copy of the latest record Merkle tree, used to serve record proofs. This is [None] if
+    // we are missing a block, since we then can't reconstruct the tree.
+    record_merkle_tree: Option<MerkleTree>,
+    // Transactions whose memos have been published through the memo bulletin board.
+    posted_memos: HashSet<(u64, u64)>,
+    mempool: Mempool,
+    block_policy: BlockPolicy,
     node_status: ValidatorStatus,
     query_storage: AtomicStore,
     block_storage: AppendLog<BincodeLoadStore<Option<BlockQueryData>>>,
@@ -65,6 +85,15 @@ pub struct QueryData {
     status_storage: RollingLog<BincodeLoadStore<ValidatorStatus>>,
     consensus: Consensus,
     location: Option<String>,
+    // The key used to sign state snapshots, if this node serves them.
+    snapshot_key: Option<StakingPrivKey>,
+    // Proofs served through the metastate API. Lookups take `&self`, hence the lock.
+    nullifier_proofs: Mutex<NullifierProofCache>,
+    // The record Merkle tree after a recent block, rebuilt to serve proofs against that block.
+    historical_record_tree: Mutex<Option<(u64, MerkleTree)>>,
+    // The outcomes of checking mempool transactions before forwarding them to consensus: "valid",
+    // or the kind of validation error.
+    validation_outcomes: BTreeMap<&'static str, u64>,
 }
 
 pub trait Extract<T> {
@@ -292,6 +321,57 @@ impl<'a> AvailabilityDataSource for &'a QueryData {
         }
     }
 
+    fn get_latest_block_id(&self) -> Option<u64> {
+        (self.cached_blocks_start + self.cached_blocks.len())
+            .checked_sub(1)
+            .map(|id| id as u64)
+    }
+
+    fn get_record_proof(&self, uid: u64) -> Option<(MerkleLeafProof, MerkleCommitment)> {
+        let tree = self.record_merkle_tree.as_ref()?;
+        let (_, proof) = tree.get_leaf(uid).expect_ok().ok()?;
+        Some((proof, tree.commitment()))
+    }
+
+    fn get_record_proof_at(
+        &self,
+        uid: u64,
+        block_id: u64,
+    ) -> Option<(MerkleLeafProof, MerkleCommitment)> {
+        let latest = self.get_latest_block_id()?;
+        if block_id == latest {
+            return self.get_record_proof(uid);
+        }
+        if block_id > latest || latest - block_id > ValidatorState::HISTORY_SIZE as u64 {
+            return None;
+        }
+
+        // We only keep the full record Merkle tree for the latest state, so rebuild the tree as of
+        // `block_id` from the records created up to that block. Wallets rebuilding witnesses for a
+        // stale transaction usually ask about the same state, so keep the tree for the next
+        // request.
+        let mut historical = self.historical_record_tree.lock().unwrap();
+        if !matches!(&*historical, Some((id, _)) if *id == block_id) {
+            let mut tree = MerkleTree::new(MERKLE_HEIGHT);
+            for block in self.get_nth_block_iter(0).take(block_id as usize + 1) {
+                QueryData::append_block_records(&mut tree, block.as_ref());
+            }
+            *historical = Some((block_id, tree?));
+        }
+        let (_, tree) = historical.as_ref()?;
+        let (_, proof) = tree.get_leaf(uid).expect_ok().ok()?;
+        Some((proof, tree.commitment()))
+    }
+
+    fn sign_snapshot(&self, state: &StateQueryData) -> Option<SignedSnapshot> {
+        let key = self.snapshot_key.as_ref()?;
+        Some(SignedSnapshot::sign(
+            state.state.clone(),
+            state.continuation_event_index,
+            key,
+        ))
+    }
+
     fn get_record_merkle_tree_at_block_index(&self, n: usize) -> Option<MerkleTree> {
         let apply = |state: &StateQueryData| {
             let state = &state.state;
@@ -347,7 +427,9 @@ impl UpdateAvailabilityData for QueryData {
                     self.index_by_txn_hash
                         .insert(*txn_hash, (block.block_id, index as u64));
                 }
+                self.mempool.remove_committed(&block.raw_block.block.0);
             }
+            Self::append_block_records(&mut self.record_merkle_tree, opt_block.as_ref());
             if let Err(e) = self.block_storage.store_resource(opt_block) {
                 warn!("Failed to store block {:?}: Error: {}", opt_block, e);
             }
@@ -443,6 +525,86 @@ impl UpdateCatchUpData for QueryData {
     }
 }
 
+#[async_trait]
+impl PostMemosData for QueryData {
+    async fn post_memos(
+        &mut self,
+        block_id: u64,
+        txn_id: u64,
+        memos: Vec<ReceiverMemo>,
+        sig: Signature,
+    ) -> Result<(), PostMemosError> {
+        let block = (&*self)
+            .get_nth_block_iter(block_id as usize)
+            .next()
+            .flatten()
+            .ok_or(PostMemosError::UnknownBlock { block_id })?;
+        let txn = block
+            .raw_block
+            .block
+            .0
+            .get(txn_id as usize)
+            .ok_or(PostMemosError::UnknownTransaction { block_id, txn_id })?;
+        // Only CAP transactions bind a key for signing their memos, so memos for any other kind of
+        // transaction could not be authenticated.
+        let note = match txn {
+            EspressoTransaction::CAP(note) => note,
+            _ => return Err(PostMemosError::NotCapTransaction { block_id, txn_id }),
+        };
+        if self.posted_memos.contains(&(block_id, txn_id))
+            || block.raw_block.memos[txn_id as usize].is_some()
+        {
+            return Err(PostMemosError::AlreadyPublished { block_id, txn_id });
+        }
+        if memos.len() != txn.output_len() {
+            return Err(PostMemosError::WrongNumberOfMemos {
+                expected: txn.output_len(),
+                actual: memos.len(),
+            });
+        }
+        note.verify_receiver_memos_signature(&memos, &sig)
+            .map_err(|_| PostMemosError::BadSignature)?;
+
+        // Authenticate the records corresponding to the memos relative to the latest record tree.
+        let tree = self
+            .record_merkle_tree
+            .as_ref()
+            .ok_or_else(|| PostMemosError::Unavailable {
+                reason: "record Merkle tree is incomplete".into(),
+            })?;
+        let first_uid = block.records_from
+            + block.raw_block.block.0[..txn_id as usize]
+                .iter()
+                .map(|txn| txn.output_len() as u64)
+                .sum::<u64>();
+        let uids = (first_uid..first_uid + memos.len() as u64).collect::<Vec<_>>();
+        let merkle_paths = uids
+            .iter()
+            .map(|uid| {
+                tree.get_leaf(*uid)
+                    .expect_ok()
+                    .map(|(_, proof)| proof.path)
+                    .map_err(|_| PostMemosError::Unavailable {
+                        reason: format!("no Merkle path for record {}", uid),
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let event = LedgerEvent::Memos {
+            outputs: izip!(memos, txn.output_commitments(), uids, merkle_paths).collect(),
+            transaction: Some((block_id, txn_id, txn.hash(), txn.kind())),
+        };
+        self.append_events(vec![Some(event)])
+            .await
+            .map_err(|err| PostMemosError::Unavailable {
+                reason: err.to_string(),
+            })?;
+        self.posted_memos.insert((block_id, txn_id));
+        self.commit_all();
+        Ok(())
+    }
+}
+
 impl QueryData {
     fn with_nullifier_set_at_block<U>(
         &self,
@@ -494,11 +656,14 @@ impl QueryData {
                 .take((block_height - cached_block_height) as usize);
             iter.for_each(|block| {
                 if let Some(block) = block {
-                    for transaction in block.raw_block.block.0.iter() {
-                        for nullifier_in in transaction.input_nullifiers() {
-                            adjusted_nullifier_set.insert(nullifier_in);
-                        }
-                    }
+                    adjusted_nullifier_set.insert_batch(
+                        block
+                            .raw_block
+                            .block
+                            .0
+                            .iter()
+                            .flat_map(|txn| txn.input_nullifiers()),
+                    );
                 }
             });
             Ok(op(&adjusted_nullifier_set))
@@ -512,14 +677,53 @@ impl MetaStateDataSource for QueryData {
         block_id: u64,
         nullifier: Nullifier,
     ) -> Option<(bool, SetMerkleProof)> {
-        if let Ok(proof) = self.with_nullifier_set_at_block(block_id, |ns| {
-            tracing::info!("getting nullifier proof for {} in {}", nullifier, ns.hash());
-            ns.contains(nullifier)
-        }) {
-            proof
-        } else {
-            None
+        self.get_nullifier_proofs_for(block_id, &[nullifier])?
+            .into_iter()
+            .next()
+    }
+
+    fn get_nullifier_proofs_for(
+        &self,
+        block_id: u64,
+        nullifiers: &[Nullifier],
+    ) -> Option<Vec<(bool, SetMerkleProof)>> {
+        let mut cache = self.nullifier_proofs.lock().unwrap();
+
+        // If we have the nullifier set for this block on hand, we know its root without building
+        // anything, and can serve cached proofs straight away.
+        let mut proofs = match self.cached_nullifier_sets.get(&block_id) {
+            Some(ns) => {
+                let root = ns.hash();
+                nullifiers
+                    .iter()
+                    .map(|nullifier| cache.get(root, *nullifier))
+                    .collect()
+            }
+            None => vec![None; nullifiers.len()],
+        };
+        if proofs.iter().all(Option::is_some) {
+            return proofs.into_iter().collect();
         }
+
+        // Build the proofs we don't have, all from the same nullifier set.
+        self.with_nullifier_set_at_block(block_id, |ns| {
+            let root = ns.hash();
+            tracing::info!(
+                "getting {} nullifier proofs in {}",
+                proofs.iter().filter(|proof| proof.is_none()).count(),
+                root
+            );
+            for (nullifier, proof) in nullifiers.iter().zip(&mut proofs) {
+                if proof.is_none() {
+                    let (spent, new_proof) = ns.contains(*nullifier)?;
+                    cache.insert(root, *nullifier, spent, new_proof.clone());
+                    *proof = Some((spent, new_proof));
+                }
+            }
+            proofs.into_iter().collect()
+        })
+        .ok()
+        .flatten()
     }
 }
 
@@ -535,9 +739,7 @@ impl UpdateMetaStateData for QueryData {
         } else {
             self.with_nullifier_set_at_block(block_id - 1, |ns| ns.clone())?
         };
-        for nullifier in nullifiers {
-            nullifier_set.insert(nullifier);
-        }
+        nullifier_set.insert_batch(nullifiers);
         self.cached_nullifier_sets.insert(block_id, nullifier_set);
         Ok(())
     }
@@ -587,12 +789,32 @@ impl ValidatorDataSource for QueryData {
     type Error = HotShotError;
 
     async fn submit(&mut self, txn: ElaboratedTransaction) -> Result<(), Self::Error> {
-        self.consensus.submit(txn).await
+        if self.mempool.status(&TransactionCommitment(txn.txn.hash())) == MempoolStatus::Unknown {
+            // The transaction bypassed the mempool, so there is nothing to batch it with.
+            return self.consensus.submit(txn).await;
+        }
+        self.flush_mempool().await
     }
 
     async fn next_event(&mut self) -> Result<ConsensusEvent, Self::Error> {
         self.consensus.next_event().await
     }
+
+    fn admit(
+        &mut self,
+        txn: &ElaboratedTransaction,
+        sender: Option<String>,
+    ) -> Result<(), MempoolError> {
+        self.mempool.insert(txn.clone(), sender)
+    }
+
+    fn fee_schedule(&self) -> FeeSchedule {
+        self.mempool.config().fee_schedule
+    }
+
+    fn mempool_status(&self, hash: &TransactionCommitment) -> MempoolStatus {
+        self.mempool.status(hash)
+    }
 }
 
 const STATUS_STORAGE_COUNT: u32 = 10u32;
@@ -634,6 +856,10 @@ impl QueryData {
             event_sender,
             event_receiver,
             cached_nullifier_sets: BTreeMap::new(),
+            record_merkle_tree: MerkleTree::new(MERKLE_HEIGHT),
+            posted_memos: HashSet::new(),
+            mempool: Mempool::default(),
+            block_policy: BlockPolicy::default(),
             node_status: ValidatorStatus::default(),
             query_storage,
             block_storage,
@@ -643,6 +869,10 @@ impl QueryData {
             status_storage,
             consensus,
             location,
+            snapshot_key: None,
+            nullifier_proofs: Default::default(),
+            historical_record_tree: Default::default(),
+            validation_outcomes: Default::default(),
         })
     }
 
@@ -702,18 +932,22 @@ impl QueryData {
         let mut index_by_proposer_id = HashMap::new();
         let mut cached_nullifier_sets = BTreeMap::new();
         let mut running_nullifier_set = SetMerkleTree::default();
+        let mut record_merkle_tree = MerkleTree::new(MERKLE_HEIGHT);
         let index_by_block_hash = block_storage
             .iter()
             .filter_map(|res: Result<Option<BlockQueryData>, _>| match res {
                 Err(e) => {
                     warn!("failed to load block. Error: {}", e);
+                    Self::append_block_records(&mut record_merkle_tree, None);
                     None
                 }
                 Ok(None) => {
                     // If a block is missing, we can't add it to the index.
+                    Self::append_block_records(&mut record_merkle_tree, None);
                     None
                 }
                 Ok(Some(block)) => {
+                    Self::append_block_records(&mut record_merkle_tree, Some(&block));
                     block
                         .txn_hashes
                         .iter()
@@ -721,11 +955,14 @@ impl QueryData {
                         .for_each(|(id, txn_hash)| {
                             index_by_txn_hash.insert(*txn_hash, (block.block_id, id as u64));
                         });
-                    block.raw_block.block.0.iter().for_each(|txn| {
-                        for n in txn.input_nullifiers() {
-                            running_nullifier_set.insert(n);
-                        }
-                    });
+                    running_nullifier_set.insert_batch(
+                        block
+                            .raw_block
+                            .block
+                            .0
+                            .iter()
+                            .flat_map(|txn| txn.input_nullifiers()),
+                    );
                     if Self::calculate_sparse_cache(
                         block.block_id,
                         block_storage.iter().len() as u64,
@@ -745,6 +982,21 @@ impl QueryData {
             })
             .collect();
 
+        // Memos events for transactions whose memos were not bundled with the block come from the
+        // memo bulletin board; remember them so the same memos cannot be published twice.
+        let mut posted_memos = HashSet::new();
+        for event in event_storage.iter().filter_map(|ev| ev.ok().flatten()) {
+            if let LedgerEvent::Memos {
+                outputs,
+                transaction: Some((block_id, txn_id, ..)),
+            } = event
+            {
+                if !outputs.is_empty() {
+                    posted_memos.insert((block_id, txn_id));
+                }
+            }
+        }
+
         let (event_sender, event_receiver) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
         let events_loader = event_storage.iter();
         let events_count = events_loader.len();
@@ -781,6 +1033,10 @@ impl QueryData {
             event_sender,
             event_receiver,
             cached_nullifier_sets,
+            record_merkle_tree,
+            posted_memos,
+            mempool: Mempool::default(),
+            block_policy: BlockPolicy::default(),
             node_status,
             query_storage,
             block_storage,
@@ -790,9 +1046,162 @@ impl QueryData {
             status_storage,
             consensus,
             location,
+            snapshot_key: None,
+            nullifier_proofs: Default::default(),
+            historical_record_tree: Default::default(),
+            validation_outcomes: Default::default(),
         })
     }
 
+    /// Replace the mempool with an empty one using `config`.
+    pub fn with_mempool_config(mut self, config: MempoolConfig) -> Self {
+        self.mempool = Mempool::new(config);
+        self
+    }
+
+    pub fn with_block_policy(mut self, policy: BlockPolicy) -> Self {
+        self.block_policy = policy;
+        self
+    }
+
+    /// Serve state snapshots signed with `key`.
+    pub fn with_snapshot_key(mut self, key: StakingPrivKey) -> Self {
+        self.snapshot_key = Some(key);
+        self
+    }
+
+    /// The state after the most recent block in the store, if there is one.
+    pub fn latest_state(&self) -> Option<ValidatorState> {
+        self.cached_blocks
+            .last()
+            .and_then(|(_, state, _)| state.as_ref())
+            .map(|state| state.state.clone())
+    }
+
+    /// The set of rewards collected in the first `block_height` blocks in the store.
+    ///
+    /// Only the root hash of this set is part of the ledger state, so a validator restored from its
+    /// store rebuilds the set from the stored blocks in order to prove that the rewards it claims
+    /// have not been collected yet.
+    pub fn collected_rewards(&self, block_height: u64) -> CollectedRewardsSet {
+        let mut set = CollectedRewardsSet::EmptySubtree;
+        for (block_id, block) in self
+            .block_storage
+            .iter()
+            .take(block_height as usize)
+            .enumerate()
+        {
+            match block {
+                Ok(Some(block)) => insert_collected_rewards(&mut set, &block.raw_block.block.0),
+                _ => warn!(
+                    "block {} is missing, rewards collected in it will not be accounted for",
+                    block_id
+                ),
+            }
+        }
+        set
+    }
+
+    pub fn mempool(&self) -> &Mempool {
+        &self.mempool
+    }
+
+    pub fn block_metrics(&self) -> &BlockMetrics {
+        self.mempool.block_metrics()
+    }
+
+    /// The number of nullifier proofs served from the cache and built from scratch, respectively.
+    pub fn nullifier_cache_stats(&self) -> (u64, u64) {
+        let cache = self.nullifier_proofs.lock().unwrap();
+        (cache.hits(), cache.misses())
+    }
+
+    /// The number of mempool transactions checked before being forwarded to consensus, by outcome.
+    ///
+    /// The outcome is `"valid"` for transactions which passed, or the
+    /// [kind](espresso_core::state::ValidationError::kind) of error for those which were dropped.
+    pub fn validation_outcomes(&self) -> &BTreeMap<&'static str, u64> {
+        &self.validation_outcomes
+    }
+
+    /// Forward a batch of transactions from the mempool to consensus, if the block policy calls
+    /// for it.
+    ///
+    /// Transactions in the batch which are not valid against the latest state are dropped from the
+    /// mempool with [DropReason::Invalid](espresso_core::mempool::DropReason::Invalid) instead of
+    /// being forwarded, so that one bad transaction does not get the whole block rejected. Their
+    /// submitters can see why through the mempool status of the transaction.
+    ///
+    /// A transaction is only marked as submitted once consensus accepts it. If forwarding one
+    /// fails, the rest of the batch is still forwarded, the failed transaction stays pending to be
+    /// included in the next batch, and the first error is returned.
+    pub async fn flush_mempool(&mut self) -> Result<(), HotShotError> {
+        let mut res = Ok(());
+        if let Some((_, mut txns)) = self.mempool.flush(&self.block_policy, Instant::now()) {
+            if let Some(state) = self.latest_state() {
+                txns = self.exclude_invalid(&state, txns);
+            }
+            for txn in txns {
+                let hash = TransactionCommitment(txn.txn.hash());
+                match self.consensus.submit(txn).await {
+                    Ok(()) => self.mempool.mark_submitted(&hash, Instant::now()),
+                    Err(err) => {
+                        warn!(
+                            "failed to forward transaction {} to consensus: {}",
+                            hash, err
+                        );
+                        if res.is_ok() {
+                            res = Err(err);
+                        }
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    fn exclude_invalid(
+        &mut self,
+        state: &ValidatorState,
+        txns: Vec<ElaboratedTransaction>,
+    ) -> Vec<ElaboratedTransaction> {
+        let block = Block(txns.iter().map(|txn| txn.txn.clone()).collect());
+        let proofs = txns
+            .iter()
+            .map(|txn| txn.proofs.clone())
+            .collect::<Vec<_>>();
+        let results = match state.check_transactions(
+            &(state.prev_commit_time + 1),
+            state.commit(),
+            &block,
+            &proofs,
+        ) {
+            Ok(results) => results,
+            // Block-level failures depend on the state consensus builds on, which may be newer
+            // than ours, so leave them to consensus.
+            Err(_) => return txns,
+        };
+        txns.into_iter()
+            .zip(results)
+            .filter_map(|(txn, res)| {
+                let outcome = match &res {
+                    Ok(()) => "valid",
+                    Err(err) => err.kind(),
+                };
+                *self.validation_outcomes.entry(outcome).or_default() += 1;
+                match res {
+                    Ok(()) => Some(txn),
+                    Err(err) => {
+                        let hash = TransactionCommitment(txn.txn.hash());
+                        warn!("excluding invalid transaction {} from block: {}", hash, err);
+                        self.mempool.drop_invalid(&hash);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
     pub fn commit_all(&mut self) {
         if let Err(e) = self.block_storage.commit_version() {
             warn!("Failed to commit block storage: Error {}", e);
@@ -817,6 +1226,23 @@ impl QueryData {
         }
     }
 
+    fn append_block_records(tree: &mut Option<MerkleTree>, block: Option<&BlockQueryData>) {
+        match (tree.as_mut(), block) {
+            (Some(tree), Some(block)) => {
+                for txn in &block.raw_block.block.0 {
+                    for comm in txn.output_commitments() {
+                        tree.push(comm.to_field_element());
+                    }
+                }
+            }
+            (Some(_), None) => {
+                warn!("missing block, record proofs will not be available");
+                *tree = None;
+            }
+            (None, _) => {}
+        }
+    }
+
     fn calculate_sparse_cache(_index: u64, _total_size: u64) -> bool {
         // issue: make this an inverse geometric function, with inflection at ~10%
         true
@@ -13,6 +13,7 @@ use tide_disco::StatusCode;
 
 pub mod full_node;
 pub mod full_node_data_source;
+pub mod nullifier_cache;
 pub mod update_query_data_source;
 
 #[derive(Clone, Debug, From, Snafu, Deserialize, Serialize)]
@@ -0,0 +1,97 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! A cache of recently served nullifier proofs.
+//!
+//! Keystores submitting many transactions ask for proofs of the same nullifiers against the same
+//! few recent nullifier sets over and over. Each proof costs a traversal of the nullifier set, so
+//! the query service remembers the proofs it has served, keyed by the root hash of the set they
+//! were generated against and the nullifier. Since a root hash identifies one exact nullifier set,
+//! entries never go stale; old entries are only evicted to bound memory.
+
+use espresso_core::set_merkle_tree::{set_hash, SetMerkleProof};
+use jf_cap::structs::Nullifier;
+use std::collections::{HashMap, VecDeque};
+
+/// The default number of proofs retained by a [NullifierProofCache].
+pub const DEFAULT_CACHE_CAPACITY: usize = 100_000;
+
+#[derive(Debug)]
+pub struct NullifierProofCache {
+    proofs: HashMap<(set_hash::Hash, Nullifier), (bool, SetMerkleProof)>,
+    order: VecDeque<(set_hash::Hash, Nullifier)>,
+    capacity: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl Default for NullifierProofCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_CAPACITY)
+    }
+}
+
+impl NullifierProofCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            proofs: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Look up a proof, counting the lookup as a hit or a miss.
+    pub fn get(
+        &mut self,
+        root: set_hash::Hash,
+        nullifier: Nullifier,
+    ) -> Option<(bool, SetMerkleProof)> {
+        let res = self.proofs.get(&(root, nullifier)).cloned();
+        if res.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        res
+    }
+
+    /// Remember a proof, evicting the oldest proofs beyond the capacity of the cache.
+    pub fn insert(
+        &mut self,
+        root: set_hash::Hash,
+        nullifier: Nullifier,
+        spent: bool,
+        proof: SetMerkleProof,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = (root, nullifier);
+        if self.proofs.insert(key, (spent, proof)).is_none() {
+            self.order.push_back(key);
+            while self.order.len() > self.capacity {
+                if let Some(old) = self.order.pop_front() {
+                    self.proofs.remove(&old);
+                }
+            }
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub fn len(&self) -> usize {
+        self.proofs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.proofs.is_empty()
+    }
+}
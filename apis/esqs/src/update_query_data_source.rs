@@ -58,8 +58,13 @@ impl<TYPES> UpdateQueryDataSource<TYPES>
 where
     TYPES: UpdateQueryDataSourceTypes + 'static,
 {
+    /// Start updating the query data stores from `event_source`.
+    ///
+    /// `validator_state` is the state after the last block already in the stores: the default state
+    /// for new stores, or the latest persisted state when the stores were loaded from disk.
     pub fn new(
         event_source: impl 'static + Send + Unpin + Stream<Item = HotShotEvent>,
+        validator_state: ValidatorState,
         catchup_store: Arc<RwLock<TYPES::CU>>,
         availability_store: Arc<RwLock<TYPES::AV>>,
         meta_state_store: Arc<RwLock<TYPES::MS>>,
@@ -72,7 +77,7 @@ where
             meta_state_store,
             status_store,
             event_handler,
-            validator_state: Default::default(),
+            validator_state,
             _event_task: None,
         }));
         if let Ok(task_handle) = launch_updates(event_source, instance.clone()) {
@@ -165,10 +170,35 @@ where
                         let output_len = txn.output_len() as u64;
                         let txn_uids = (first_uid..first_uid + output_len).collect::<Vec<_>>();
                         first_uid += output_len;
-                        let merkle_paths = txn_uids
+                        let merkle_paths = match txn_uids
                             .iter()
-                            .map(|uid| record_proofs.get_leaf(*uid).expect_ok().unwrap().1.path)
-                            .collect::<Vec<_>>();
+                            .map(|uid| {
+                                record_proofs
+                                    .get_leaf(*uid)
+                                    .expect_ok()
+                                    .map(|(_, proof)| proof.path)
+                                    .map_err(|err| (*uid, err))
+                            })
+                            .collect::<Result<Vec<_>, _>>()
+                        {
+                            Ok(paths) => paths,
+                            Err((uid, err)) => {
+                                // This indicates a bug in how we maintain the records frontier,
+                                // but it only affects the memos for this transaction. Rather than
+                                // crashing the whole service, record a missing event; keystores
+                                // can still fetch the paths for their records individually.
+                                tracing::error!(
+                                    "missing Merkle path for record {} in block {}, \
+                                    omitting memos for transaction {}: {}",
+                                    uid,
+                                    block_index,
+                                    txn_id,
+                                    err
+                                );
+                                events.push(None);
+                                continue;
+                            }
+                        };
                         events.push(Some(LedgerEvent::Memos {
                             outputs: izip!(
                                 memos.clone().map(|(memos, _)| memos).unwrap_or_default(),
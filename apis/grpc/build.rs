@@ -0,0 +1,10 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Use a vendored protoc, so that building this crate does not require protobuf tools to be
+    // installed.
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    tonic_build::compile_protos("proto/espresso.proto")?;
+    Ok(())
+}
@@ -0,0 +1,45 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Serves public key lookup from an address book store over gRPC.
+//!
+//! This reads the same store as the address book web server, so the two can run side by side.
+
+use address_book::store::{address_book_store_path, FileStore};
+use clap::Parser;
+use espresso_grpc_api::server::{address_book_routes, serve};
+use std::fs;
+use std::path::PathBuf;
+use tide_disco::init_logging;
+
+#[derive(Parser)]
+struct Options {
+    /// Port for the gRPC server.
+    #[arg(
+        short,
+        long,
+        env = "ESPRESSO_ADDRESS_BOOK_GRPC_PORT",
+        default_value = "50080"
+    )]
+    port: u16,
+
+    /// Path to the address book store. Defaults to the store of the address book web server.
+    #[arg(long)]
+    store_path: Option<PathBuf>,
+
+    #[arg(long)]
+    ansi_color: bool,
+}
+
+fn main() -> std::io::Result<()> {
+    let opt = Options::parse();
+    init_logging(opt.ansi_color);
+
+    let store_path = opt.store_path.unwrap_or_else(address_book_store_path);
+    fs::create_dir_all(&store_path)?;
+    let store = FileStore::new(store_path);
+
+    serve(address_book_routes(store), ([0, 0, 0, 0], opt.port).into())?
+        .join()
+        .unwrap()
+}
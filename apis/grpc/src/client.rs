@@ -0,0 +1,131 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! A client for the gRPC services
+//!
+//! [GrpcClient] wraps the generated stubs, encoding and decoding the ledger types they carry. It
+//! must be used from within a tokio runtime.
+
+use crate::{decode_response, encode, proto};
+use espresso_availability_api::query_data::RecordProofQueryData;
+use espresso_core::{ledger::EspressoLedger, state::ElaboratedTransaction};
+use espresso_metastate_api::api::NullifierCheck;
+use futures::stream::{BoxStream, StreamExt};
+use jf_cap::{
+    keys::{UserAddress, UserPubKey},
+    structs::Nullifier,
+};
+use proto::{
+    address_book_client::AddressBookClient, availability_client::AvailabilityClient,
+    catch_up_client::CatchUpClient, meta_state_client::MetaStateClient,
+    validator_client::ValidatorClient,
+};
+use seahorse::events::LedgerEvent;
+use tonic::{
+    codegen::StdError,
+    transport::{Channel, Endpoint},
+    Status,
+};
+
+/// A connection to a gRPC server.
+///
+/// The server need not serve every service: calls to services it does not serve fail with
+/// [Code::Unimplemented](tonic::Code::Unimplemented).
+#[derive(Clone, Debug)]
+pub struct GrpcClient {
+    channel: Channel,
+}
+
+impl GrpcClient {
+    /// Connect to the server at `dst`, such as `"http://localhost:50051"`.
+    pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+    where
+        D: TryInto<Endpoint>,
+        D::Error: Into<StdError>,
+    {
+        let channel = Endpoint::new(dst)?.connect().await?;
+        Ok(Self { channel })
+    }
+
+    /// Submit a transaction for inclusion in a block.
+    pub async fn submit(&self, txn: &ElaboratedTransaction) -> Result<(), Status> {
+        ValidatorClient::new(self.channel.clone())
+            .submit(proto::SubmitRequest {
+                transaction: encode(txn)?,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Subscribe to events starting from index `first`, each tagged with its index.
+    pub async fn subscribe_for_indexed_events(
+        &self,
+        first: usize,
+    ) -> Result<
+        BoxStream<'static, Result<(usize, Option<LedgerEvent<EspressoLedger>>), Status>>,
+        Status,
+    > {
+        let events = CatchUpClient::new(self.channel.clone())
+            .subscribe_for_indexed_events(proto::SubscribeRequest {
+                first: first as u64,
+            })
+            .await?
+            .into_inner();
+        Ok(events
+            .map(|event| -> Result<_, Status> {
+                let event = event?;
+                Ok((
+                    event.index as usize,
+                    event
+                        .event
+                        .map(|bytes| decode_response("event", &bytes))
+                        .transpose()?,
+                ))
+            })
+            .boxed())
+    }
+
+    /// A proof of the record with `uid`, relative to the record Merkle tree after block
+    /// `block_id`, or after the latest block if `block_id` is [None].
+    pub async fn get_record_proof(
+        &self,
+        uid: u64,
+        block_id: Option<u64>,
+    ) -> Result<RecordProofQueryData, Status> {
+        let res = AvailabilityClient::new(self.channel.clone())
+            .get_record_proof(proto::GetRecordProofRequest { uid, block_id })
+            .await?
+            .into_inner();
+        decode_response("proof", &res.proof)
+    }
+
+    /// Whether `nullifier` is in the nullifier set after block `block_id`, with a proof.
+    pub async fn check_nullifier(
+        &self,
+        block_id: u64,
+        nullifier: Nullifier,
+    ) -> Result<NullifierCheck, Status> {
+        let res = MetaStateClient::new(self.channel.clone())
+            .check_nullifier(proto::CheckNullifierRequest {
+                block_id,
+                nullifier: encode(&nullifier)?,
+            })
+            .await?
+            .into_inner();
+        Ok(NullifierCheck {
+            spent: res.spent,
+            proof: decode_response("proof", &res.proof)?,
+        })
+    }
+
+    /// Look up the public key registered for `address`.
+    pub async fn request_pubkey(&self, address: &UserAddress) -> Result<UserPubKey, Status> {
+        let res = AddressBookClient::new(self.channel.clone())
+            .request_pubkey(proto::RequestPubkeyRequest {
+                address: encode(address)?,
+            })
+            .await?
+            .into_inner();
+        decode_response("pub_key", &res.pub_key)
+    }
+}
@@ -0,0 +1,363 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! gRPC transport for the Espresso APIs
+//!
+//! An alternative to the HTTP/WebSocket APIs for integrators with existing gRPC infrastructure. The
+//! protocol, including how ledger types are encoded, is defined in `proto/espresso.proto`. The
+//! services share their implementation with the HTTP routes they mirror, so the two transports
+//! always agree.
+//!
+//! gRPC runs on [tokio], whereas the rest of Espresso runs on async-std. [server::serve] runs the
+//! services on a tokio runtime of their own, and [client::GrpcClient] must be used from within a
+//! tokio runtime.
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Display;
+use tide_disco::StatusCode;
+use tonic::{Code, Status};
+
+pub mod client;
+pub mod server;
+
+/// Types and stubs generated from `proto/espresso.proto`.
+pub mod proto {
+    tonic::include_proto!("espresso.v1");
+}
+
+/// The gRPC status for an error which the HTTP APIs report with HTTP status `code`.
+pub fn status(code: StatusCode, err: impl Display) -> Status {
+    let code = match code {
+        StatusCode::BadRequest => Code::InvalidArgument,
+        StatusCode::Unauthorized => Code::Unauthenticated,
+        StatusCode::Forbidden => Code::PermissionDenied,
+        StatusCode::NotFound => Code::NotFound,
+        StatusCode::RequestTimeout => Code::DeadlineExceeded,
+        StatusCode::Conflict => Code::AlreadyExists,
+        StatusCode::TooManyRequests => Code::ResourceExhausted,
+        StatusCode::NotImplemented => Code::Unimplemented,
+        StatusCode::ServiceUnavailable => Code::Unavailable,
+        code if code.is_client_error() => Code::FailedPrecondition,
+        _ => Code::Internal,
+    };
+    Status::new(code, err.to_string())
+}
+
+pub(crate) fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, Status> {
+    bincode::serialize(value)
+        .map_err(|err| Status::internal(format!("failed to encode response: {}", err)))
+}
+
+/// Decode a bincode `bytes` field of a request.
+pub(crate) fn decode_request<T: DeserializeOwned>(field: &str, bytes: &[u8]) -> Result<T, Status> {
+    bincode::deserialize(bytes)
+        .map_err(|err| Status::invalid_argument(format!("malformed {}: {}", field, err)))
+}
+
+/// Decode a bincode `bytes` field of a response.
+pub(crate) fn decode_response<T: DeserializeOwned>(field: &str, bytes: &[u8]) -> Result<T, Status> {
+    bincode::deserialize(bytes)
+        .map_err(|err| Status::data_loss(format!("malformed {} in response: {}", field, err)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        client::GrpcClient,
+        server::{serve, AddressBookService, QueryServices},
+    };
+    use address_book::store::{address_book_temp_dir, FileStore, Store};
+    use async_std::sync::{Arc, RwLock};
+    use async_trait::async_trait;
+    use espresso_catchup_api::data_source::CatchUpDataSource;
+    use espresso_core::{
+        ledger::EspressoLedger,
+        mempool::MempoolError,
+        set_merkle_tree::{SetMerkleProof, SetMerkleTree},
+        state::{
+            ConsensusTime, ElaboratedBlock, ElaboratedTransaction, TransactionCommitment,
+            ValidatorState,
+        },
+        testing::genesis_txn,
+    };
+    use espresso_metastate_api::data_source::MetaStateDataSource;
+    use espresso_validator_api::data_source::{ConsensusEvent, ValidatorDataSource};
+    use futures::{future::pending, StreamExt};
+    use jf_cap::{keys::UserKeyPair, structs::Nullifier};
+    use portpicker::pick_unused_port;
+    use postage::{broadcast, sink::Sink};
+    use proto::{
+        address_book_server::AddressBookServer, catch_up_server::CatchUpServer,
+        meta_state_server::MetaStateServer, validator_server::ValidatorServer,
+    };
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+    use seahorse::events::LedgerEvent;
+    use std::io;
+    use std::net::TcpListener;
+    use tonic::transport::{server::Router, Server};
+
+    // A data source for the services whose data source traits are cheap to implement. The
+    // availability service is a thin wrapper around the same function as the HTTP route, which is
+    // tested with the EsQS.
+    struct MockData {
+        events: Vec<Option<LedgerEvent<EspressoLedger>>>,
+        event_sender: broadcast::Sender<(usize, Option<LedgerEvent<EspressoLedger>>)>,
+        event_receiver: broadcast::Receiver<(usize, Option<LedgerEvent<EspressoLedger>>)>,
+        pending: Vec<ElaboratedTransaction>,
+        submitted: Vec<ElaboratedTransaction>,
+        // The nullifier set after block 0, the only block.
+        nullifiers: SetMerkleTree,
+    }
+
+    impl MockData {
+        fn new(events: Vec<Option<LedgerEvent<EspressoLedger>>>) -> Self {
+            let (event_sender, event_receiver) = broadcast::channel(10);
+            Self {
+                events,
+                event_sender,
+                event_receiver,
+                pending: vec![],
+                submitted: vec![],
+                nullifiers: SetMerkleTree::default(),
+            }
+        }
+
+        async fn append_event(&mut self, event: Option<LedgerEvent<EspressoLedger>>) {
+            let index = self.events.len();
+            self.events.push(event.clone());
+            self.event_sender.send((index, event)).await.unwrap();
+        }
+    }
+
+    impl<'a> CatchUpDataSource for &'a MockData {
+        type EventIterType = std::vec::IntoIter<Option<LedgerEvent<EspressoLedger>>>;
+
+        fn len(&self) -> usize {
+            self.events.len()
+        }
+
+        fn is_empty(&self) -> bool {
+            self.events.is_empty()
+        }
+
+        fn get_nth_event_iter(&self, n: usize) -> Self::EventIterType {
+            self.events[n..].to_vec().into_iter()
+        }
+
+        fn subscribe(&self) -> broadcast::Receiver<(usize, Option<LedgerEvent<EspressoLedger>>)> {
+            self.event_receiver.clone()
+        }
+    }
+
+    impl MetaStateDataSource for MockData {
+        fn get_nullifier_proof_for(
+            &self,
+            block_id: u64,
+            nullifier: Nullifier,
+        ) -> Option<(bool, SetMerkleProof)> {
+            if block_id == 0 {
+                self.nullifiers.contains(nullifier)
+            } else {
+                None
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ValidatorDataSource for MockData {
+        type Error = io::Error;
+
+        async fn submit(&mut self, txn: ElaboratedTransaction) -> Result<(), Self::Error> {
+            self.submitted.push(txn);
+            Ok(())
+        }
+
+        async fn next_event(&mut self) -> Result<ConsensusEvent, Self::Error> {
+            pending().await
+        }
+
+        fn admit(
+            &mut self,
+            txn: &ElaboratedTransaction,
+            _sender: Option<String>,
+        ) -> Result<(), MempoolError> {
+            if self.pending.iter().any(|p| p.txn.hash() == txn.txn.hash()) {
+                return Err(MempoolError::Duplicate);
+            }
+            self.pending.push(txn.clone());
+            Ok(())
+        }
+    }
+
+    fn query_routes(data: Arc<RwLock<MockData>>) -> Router {
+        let services = QueryServices::new(data);
+        Server::builder()
+            .add_service(ValidatorServer::new(services.clone()))
+            .add_service(CatchUpServer::new(services.clone()))
+            .add_service(MetaStateServer::new(services))
+    }
+
+    async fn start(router: Router) -> GrpcClient {
+        let port = pick_unused_port().unwrap();
+        serve(router, ([127, 0, 0, 1], port).into()).unwrap();
+        GrpcClient::connect(format!("http://localhost:{}", port))
+            .await
+            .unwrap()
+    }
+
+    fn commit_event(block_id: u64) -> LedgerEvent<EspressoLedger> {
+        LedgerEvent::Commit {
+            block: ElaboratedBlock::new(ValidatorState::default().commit()),
+            block_id,
+            state_comm: ValidatorState::default().commit(),
+            proof: ConsensusTime::genesis(),
+        }
+    }
+
+    fn block_id(event: Option<LedgerEvent<EspressoLedger>>) -> Option<u64> {
+        event.map(|event| match event {
+            LedgerEvent::Commit { block_id, .. } => block_id,
+            event => panic!("expected commit event, got {:?}", event),
+        })
+    }
+
+    #[test]
+    fn test_status() {
+        assert_eq!(
+            status(StatusCode::BadRequest, "bad").code(),
+            Code::InvalidArgument
+        );
+        assert_eq!(
+            status(StatusCode::NotFound, "missing").code(),
+            Code::NotFound
+        );
+        assert_eq!(
+            status(StatusCode::Conflict, "pending").code(),
+            Code::AlreadyExists
+        );
+        assert_eq!(
+            status(StatusCode::ServiceUnavailable, "busy").code(),
+            Code::Unavailable
+        );
+        assert_eq!(
+            status(StatusCode::UnprocessableEntity, "invalid").code(),
+            Code::FailedPrecondition
+        );
+        assert_eq!(
+            status(StatusCode::InternalServerError, "oops").code(),
+            Code::Internal
+        );
+        assert_eq!(status(StatusCode::NotFound, "missing").message(), "missing");
+    }
+
+    #[test]
+    fn test_serve_port_in_use() {
+        let port = pick_unused_port().unwrap();
+        let _listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+        let data = Arc::new(RwLock::new(MockData::new(vec![])));
+        let err = serve(query_routes(data), ([127, 0, 0, 1], port).into()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let data = Arc::new(RwLock::new(MockData::new(vec![
+            Some(commit_event(0)),
+            None,
+            Some(commit_event(1)),
+        ])));
+        let client = start(query_routes(data.clone())).await;
+
+        // Events the server already has, including the one it is missing.
+        let mut events = client.subscribe_for_indexed_events(1).await.unwrap();
+        let (index, event) = events.next().await.unwrap().unwrap();
+        assert_eq!((index, block_id(event)), (1, None));
+        let (index, event) = events.next().await.unwrap().unwrap();
+        assert_eq!((index, block_id(event)), (2, Some(1)));
+
+        // A new event.
+        data.write().await.append_event(Some(commit_event(2))).await;
+        let (index, event) = events.next().await.unwrap().unwrap();
+        assert_eq!((index, block_id(event)), (3, Some(2)));
+
+        // A subscription starting past the end waits for new events.
+        let mut events = client.subscribe_for_indexed_events(4).await.unwrap();
+        data.write().await.append_event(Some(commit_event(3))).await;
+        let (index, event) = events.next().await.unwrap().unwrap();
+        assert_eq!((index, block_id(event)), (4, Some(3)));
+    }
+
+    #[tokio::test]
+    async fn test_submit() {
+        let data = Arc::new(RwLock::new(MockData::new(vec![])));
+        let client = start(query_routes(data.clone())).await;
+
+        client.submit(&genesis_txn(0)).await.unwrap();
+        client.submit(&genesis_txn(1)).await.unwrap();
+        assert_eq!(data.read().await.submitted.len(), 2);
+
+        // Resubmitting a pending transaction fails with a status naming it, and does not submit it
+        // again.
+        let err = client.submit(&genesis_txn(0)).await.unwrap_err();
+        assert_eq!(err.code(), Code::AlreadyExists);
+        assert!(
+            err.message()
+                .contains(&TransactionCommitment(genesis_txn(0).txn.hash()).to_string()),
+            "{}",
+            err.message()
+        );
+        assert_eq!(data.read().await.submitted.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_check_nullifier() {
+        let mut rng = ChaChaRng::from_seed([0; 32]);
+        let spent = Nullifier::random_for_test(&mut rng);
+        let unspent = Nullifier::random_for_test(&mut rng);
+        let mut data = MockData::new(vec![]);
+        data.nullifiers.insert(spent).unwrap();
+        let root = data.nullifiers.hash();
+        let client = start(query_routes(Arc::new(RwLock::new(data)))).await;
+
+        let check = client.check_nullifier(0, spent).await.unwrap();
+        assert!(check.spent);
+        assert_eq!(check.proof.check(spent, &root), Ok(true));
+        let check = client.check_nullifier(0, unspent).await.unwrap();
+        assert!(!check.spent);
+        assert_eq!(check.proof.check(unspent, &root), Ok(false));
+
+        let err = client.check_nullifier(1, spent).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_request_pubkey() {
+        let mut rng = ChaChaRng::from_seed([0; 32]);
+        let registered = UserKeyPair::generate(&mut rng).pub_key();
+        let unregistered = UserKeyPair::generate(&mut rng).pub_key();
+        let dir = address_book_temp_dir();
+        let store = FileStore::new(dir.path().to_path_buf());
+        store.save(&registered.address(), &registered).unwrap();
+        let client = start(
+            Server::builder().add_service(AddressBookServer::new(AddressBookService::new(store))),
+        )
+        .await;
+
+        assert_eq!(
+            client.request_pubkey(&registered.address()).await.unwrap(),
+            registered
+        );
+        let err = client
+            .request_pubkey(&unregistered.address())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::NotFound);
+
+        // The server only serves the address book, so the query services are unimplemented.
+        let err = client
+            .check_nullifier(0, Nullifier::random_for_test(&mut rng))
+            .await;
+        assert_eq!(err.unwrap_err().code(), Code::Unimplemented);
+    }
+}
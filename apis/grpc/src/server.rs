@@ -0,0 +1,221 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! gRPC services
+//!
+//! [QueryServices] serves the query service APIs from the same data source as the EsQS, and
+//! [AddressBookService] serves key lookup from an address book [Store]. Use [query_routes] and
+//! [address_book_routes] to collect them into a router, and [serve] to run it.
+
+use crate::{decode_request, encode, proto, status};
+use address_book::store::Store;
+use async_std::sync::{Arc, RwLock};
+use espresso_availability_api::{api as availability, data_source::AvailabilityDataSource};
+use espresso_catchup_api::{api as catchup, data_source::CatchUpDataSource};
+use espresso_metastate_api::{api as metastate, data_source::MetaStateDataSource};
+use espresso_validator_api::{api as validator, data_source::ValidatorDataSource};
+use futures::stream::{BoxStream, StreamExt};
+use jf_cap::keys::UserAddress;
+use proto::{
+    address_book_server::{AddressBook, AddressBookServer},
+    availability_server::{Availability, AvailabilityServer},
+    catch_up_server::{CatchUp, CatchUpServer},
+    meta_state_server::{MetaState, MetaStateServer},
+    validator_server::{Validator, ValidatorServer},
+};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{
+    transport::{server::Router, Server},
+    Request, Response, Status,
+};
+use tracing::trace;
+
+/// The query service APIs, served from a shared data source.
+pub struct QueryServices<D> {
+    data_source: Arc<RwLock<D>>,
+}
+
+impl<D> QueryServices<D> {
+    pub fn new(data_source: Arc<RwLock<D>>) -> Self {
+        Self { data_source }
+    }
+}
+
+// Not derived, since `D` need not be [Clone].
+impl<D> Clone for QueryServices<D> {
+    fn clone(&self) -> Self {
+        Self {
+            data_source: self.data_source.clone(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<D> Validator for QueryServices<D>
+where
+    D: ValidatorDataSource + Send + Sync + 'static,
+{
+    async fn submit(
+        &self,
+        req: Request<proto::SubmitRequest>,
+    ) -> Result<Response<proto::SubmitResponse>, Status> {
+        let sender = req.remote_addr().map(|addr| addr.ip().to_string());
+        let txn = decode_request("transaction", &req.into_inner().transaction)?;
+        let mut data_source = self.data_source.write().await;
+        validator::submit(&mut *data_source, txn, sender)
+            .await
+            .map_err(|err| status(err.status(), err))?;
+        Ok(Response::new(proto::SubmitResponse {}))
+    }
+}
+
+#[tonic::async_trait]
+impl<D> CatchUp for QueryServices<D>
+where
+    D: Send + Sync + 'static,
+    for<'a> &'a D: CatchUpDataSource,
+{
+    type SubscribeForIndexedEventsStream = BoxStream<'static, Result<proto::IndexedEvent, Status>>;
+
+    async fn subscribe_for_indexed_events(
+        &self,
+        req: Request<proto::SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeForIndexedEventsStream>, Status> {
+        let first = req.into_inner().first as usize;
+        let events = catchup::indexed_events_from(&*self.data_source.read().await, first);
+        Ok(Response::new(
+            events
+                .map(|(index, event)| -> Result<_, Status> {
+                    Ok(proto::IndexedEvent {
+                        index: index as u64,
+                        event: event.as_ref().map(encode).transpose()?,
+                    })
+                })
+                .boxed(),
+        ))
+    }
+}
+
+#[tonic::async_trait]
+impl<D> Availability for QueryServices<D>
+where
+    D: Send + Sync + 'static,
+    for<'a> &'a D: AvailabilityDataSource,
+{
+    async fn get_record_proof(
+        &self,
+        req: Request<proto::GetRecordProofRequest>,
+    ) -> Result<Response<proto::GetRecordProofResponse>, Status> {
+        let req = req.into_inner();
+        let proof =
+            availability::get_record_proof(&*self.data_source.read().await, req.uid, req.block_id)
+                .map_err(|err| status(err.status(), err))?;
+        Ok(Response::new(proto::GetRecordProofResponse {
+            proof: encode(&proof)?,
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl<D> MetaState for QueryServices<D>
+where
+    D: MetaStateDataSource + Send + Sync + 'static,
+{
+    async fn check_nullifier(
+        &self,
+        req: Request<proto::CheckNullifierRequest>,
+    ) -> Result<Response<proto::CheckNullifierResponse>, Status> {
+        let req = req.into_inner();
+        let nullifier = decode_request("nullifier", &req.nullifier)?;
+        let check =
+            metastate::check_nullifier(&*self.data_source.read().await, req.block_id, nullifier)
+                .map_err(|err| status(err.status(), err))?;
+        Ok(Response::new(proto::CheckNullifierResponse {
+            spent: check.spent,
+            proof: encode(&check.proof)?,
+        }))
+    }
+}
+
+/// Public key lookup, served from an address book store.
+#[derive(Clone)]
+pub struct AddressBookService<S> {
+    store: S,
+}
+
+impl<S: Store> AddressBookService<S> {
+    pub fn new(store: S) -> Self {
+        Self { store }
+    }
+}
+
+#[tonic::async_trait]
+impl<S: Store + 'static> AddressBook for AddressBookService<S> {
+    async fn request_pubkey(
+        &self,
+        req: Request<proto::RequestPubkeyRequest>,
+    ) -> Result<Response<proto::RequestPubkeyResponse>, Status> {
+        let address: UserAddress = decode_request("address", &req.into_inner().address)?;
+        match self.store.load(&address) {
+            Ok(Some(pub_key)) => {
+                trace!(
+                    "RequestPubkey address: {:?} -> pub_key: {:?}",
+                    &address,
+                    &pub_key
+                );
+                Ok(Response::new(proto::RequestPubkeyResponse {
+                    pub_key: encode(&pub_key)?,
+                }))
+            }
+            Ok(None) => {
+                trace!("RequestPubkey not found: {:?}", &address);
+                Err(Status::not_found("address not found"))
+            }
+            Err(err) => Err(Status::internal(err.to_string())),
+        }
+    }
+}
+
+/// A router serving all of the query service APIs from `data_source`.
+pub fn query_routes<D>(data_source: Arc<RwLock<D>>) -> Router
+where
+    D: MetaStateDataSource + ValidatorDataSource + Send + Sync + 'static,
+    for<'a> &'a D: AvailabilityDataSource + CatchUpDataSource,
+{
+    let services = QueryServices::new(data_source);
+    Server::builder()
+        .add_service(ValidatorServer::new(services.clone()))
+        .add_service(CatchUpServer::new(services.clone()))
+        .add_service(AvailabilityServer::new(services.clone()))
+        .add_service(MetaStateServer::new(services))
+}
+
+/// A router serving public key lookup from `store`.
+pub fn address_book_routes<S: Store + 'static>(store: S) -> Router {
+    Server::builder().add_service(AddressBookServer::new(AddressBookService::new(store)))
+}
+
+/// Serve `router` on `addr` in the background.
+///
+/// The server runs on a tokio runtime in a thread of its own, so this can be called from async-std
+/// code. It is listening on `addr` by the time this returns, and fails if it cannot bind to `addr`.
+/// The returned handle completes if the server exits, which it only does on error, and yields that
+/// error.
+pub fn serve(router: Router, addr: SocketAddr) -> io::Result<thread::JoinHandle<io::Result<()>>> {
+    // Bind before spawning the server, so that the caller finds out if the port is unavailable.
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    Ok(thread::spawn(move || {
+        runtime.block_on(async move {
+            let incoming = TcpListenerStream::new(tokio::net::TcpListener::from_std(listener)?);
+            router.serve_with_incoming(incoming).await.map_err(|err| {
+                tracing::error!("gRPC server exited due to {}", err);
+                io::Error::new(io::ErrorKind::Other, err)
+            })
+        })
+    }))
+}
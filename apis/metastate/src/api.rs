@@ -6,12 +6,13 @@ use clap::Args;
 use derive_more::From;
 use espresso_core::state::SetMerkleProof;
 use futures::FutureExt;
+use jf_cap::structs::Nullifier;
 use serde::{Deserialize, Serialize};
 use snafu::{OptionExt, Snafu};
 use std::path::PathBuf;
 use tide_disco::{
     api::{Api, ApiError},
-    method::ReadState,
+    method::{ReadState, WriteState},
     RequestError, StatusCode,
 };
 
@@ -42,10 +43,25 @@ pub struct NullifierCheck {
     pub proof: SetMerkleProof,
 }
 
+/// Whether `nullifier` is in the nullifier set after block `block_id`, with a proof.
+pub fn check_nullifier<State>(
+    state: &State,
+    block_id: u64,
+    nullifier: Nullifier,
+) -> Result<NullifierCheck, Error>
+where
+    State: MetaStateDataSource,
+{
+    let (spent, proof) = state
+        .get_nullifier_proof_for(block_id, nullifier)
+        .context(InvalidBlockIdSnafu { block_id })?;
+    Ok(NullifierCheck { spent, proof })
+}
+
 pub fn define_api<State>(options: &Options) -> Result<Api<State, Error>, ApiError>
 where
-    State: 'static + Send + Sync + ReadState,
-    <State as ReadState>::State: Sync + MetaStateDataSource,
+    State: 'static + Send + Sync + WriteState,
+    <State as ReadState>::State: Send + Sync + MetaStateDataSource,
 {
     let mut api = match &options.api_path {
         Some(path) => Api::<State, Error>::from_file(path)?,
@@ -63,10 +79,21 @@ where
             async move {
                 let block_id = req.integer_param("block_id")?;
                 let nullifier = req.blob_param("nullifier")?;
-                let (spent, proof) = state
-                    .get_nullifier_proof_for(block_id, nullifier)
+                check_nullifier(state, block_id, nullifier)
+            }
+            .boxed()
+        })?
+        .post("check_nullifiers", |req, state| {
+            async move {
+                let block_id = req.integer_param("block_id")?;
+                let nullifiers: Vec<Nullifier> = req.body_auto()?;
+                let proofs = state
+                    .get_nullifier_proofs_for(block_id, &nullifiers)
                     .context(InvalidBlockIdSnafu { block_id })?;
-                Ok(NullifierCheck { spent, proof })
+                Ok(proofs
+                    .into_iter()
+                    .map(|(spent, proof)| NullifierCheck { spent, proof })
+                    .collect::<Vec<_>>())
             }
             .boxed()
         })?;
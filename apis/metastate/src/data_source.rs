@@ -12,6 +12,20 @@ pub trait MetaStateDataSource {
         block_id: u64,
         nullifier: Nullifier,
     ) -> Option<(bool, SetMerkleProof)>;
+
+    /// Proofs for several nullifiers against the nullifier set after the same block, in order.
+    ///
+    /// Returns [None] if `block_id` is invalid.
+    fn get_nullifier_proofs_for(
+        &self,
+        block_id: u64,
+        nullifiers: &[Nullifier],
+    ) -> Option<Vec<(bool, SetMerkleProof)>> {
+        nullifiers
+            .iter()
+            .map(|nullifier| self.get_nullifier_proof_for(block_id, *nullifier))
+            .collect()
+    }
 }
 
 pub trait UpdateMetaStateData {
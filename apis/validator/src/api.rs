@@ -4,9 +4,14 @@
 use crate::data_source::ValidatorDataSource;
 use clap::Args;
 use derive_more::From;
+use espresso_core::{
+    mempool::MempoolError,
+    state::{ElaboratedTransaction, TransactionCommitment},
+};
 use futures::FutureExt;
 use serde::{Deserialize, Serialize};
 use snafu::Snafu;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use tide_disco::{
     api::{Api, ApiError},
@@ -26,6 +31,19 @@ pub enum Error {
         source: RequestError,
     },
 
+    /// The transaction is already in the mempool, and will be proposed (again) without being
+    /// resubmitted.
+    #[from(ignore)]
+    #[snafu(display("transaction {} is already pending", hash))]
+    AlreadyPending {
+        hash: TransactionCommitment,
+    },
+
+    #[snafu(display("transaction rejected: {}", source))]
+    Rejected {
+        source: MempoolError,
+    },
+
     #[from(ignore)]
     Submission {
         reason: String,
@@ -36,11 +54,46 @@ impl Error {
     pub fn status(&self) -> StatusCode {
         match self {
             Self::Request { .. } => StatusCode::BadRequest,
+            Self::AlreadyPending { .. } => StatusCode::Conflict,
+            Self::Rejected { .. } => StatusCode::BadRequest,
             Self::Submission { .. } => StatusCode::InternalServerError,
         }
     }
 }
 
+/// Admit `txn`, submitted by `sender`, to the mempool of `state` and submit it to consensus.
+///
+/// `sender` should identify the submitter, such as the address of the peer which sent the request,
+/// if it is known. It is used to limit the pending transactions of each submitter.
+pub async fn submit<State>(
+    state: &mut State,
+    txn: ElaboratedTransaction,
+    sender: Option<String>,
+) -> Result<(), Error>
+where
+    State: ValidatorDataSource + Send,
+{
+    // Resubmitting a pending transaction is not an error on the client's part (it may be retrying
+    // a submission whose response was lost) so it gets its own result.
+    state.admit(&txn, sender).map_err(|source| match source {
+        MempoolError::Duplicate => Error::AlreadyPending {
+            hash: TransactionCommitment(txn.txn.hash()),
+        },
+        source => Error::Rejected { source },
+    })?;
+    state.submit(txn).await.map_err(|source| Error::Submission {
+        reason: source.to_string(),
+    })
+}
+
+/// The host part of a remote address, so that every connection from a peer counts as one sender.
+fn peer_host(remote: &str) -> String {
+    match remote.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => remote.to_string(),
+    }
+}
+
 pub fn define_api<State>(options: &Options) -> Result<Api<State, Error>, ApiError>
 where
     State: 'static + Send + Sync + WriteState,
@@ -60,10 +113,19 @@ where
     api.with_version(env!("CARGO_PKG_VERSION").parse().unwrap())
         .post("submit", |req, state| {
             async move {
-                let txn = req.body_auto()?;
-                state.submit(txn).await.map_err(|source| Error::Submission {
-                    reason: source.to_string(),
-                })
+                let txn: ElaboratedTransaction = req.body_auto()?;
+                let sender = req.remote().map(peer_host);
+                submit(state, txn, sender).await
+            }
+            .boxed()
+        })?
+        .get("fee_schedule", |_req, state| {
+            async move { Ok(state.fee_schedule()) }.boxed()
+        })?
+        .get("mempool_status", |req, state| {
+            async move {
+                let hash = req.blob_param("hash")?;
+                Ok(state.mempool_status(&hash))
             }
             .boxed()
         })?;
@@ -2,7 +2,10 @@
 // This file is part of the Espresso library.
 
 use async_trait::async_trait;
-use espresso_core::state::{ElaboratedTransaction, ValidatorState};
+use espresso_core::{
+    mempool::{FeeSchedule, MempoolError, MempoolStatus},
+    state::{ElaboratedTransaction, TransactionCommitment, ValidatorState},
+};
 use futures::stream::{unfold, BoxStream, StreamExt};
 use hotshot::{
     traits::NodeImplementation,
@@ -20,6 +23,30 @@ pub trait ValidatorDataSource {
     async fn submit(&mut self, txn: ElaboratedTransaction) -> Result<(), Self::Error>;
     async fn next_event(&mut self) -> Result<ConsensusEvent, Self::Error>;
 
+    /// Check a transaction against the mempool admission policy before it is submitted.
+    ///
+    /// `sender` identifies whoever submitted the transaction, such as the address of the peer it
+    /// was received from, if known. The default implementation admits every transaction.
+    fn admit(
+        &mut self,
+        _txn: &ElaboratedTransaction,
+        _sender: Option<String>,
+    ) -> Result<(), MempoolError> {
+        Ok(())
+    }
+
+    /// The minimum fees this node requires for admission to its mempool.
+    ///
+    /// The default implementation requires no fee.
+    fn fee_schedule(&self) -> FeeSchedule {
+        FeeSchedule::default()
+    }
+
+    /// The status of a transaction in this node's mempool.
+    fn mempool_status(&self, _hash: &TransactionCommitment) -> MempoolStatus {
+        MempoolStatus::Unknown
+    }
+
     fn into_stream(self) -> BoxStream<'static, ConsensusEvent>
     where
         Self: 'static + Send + Sized,
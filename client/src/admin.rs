@@ -0,0 +1,131 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Administrative controls for a running keystore.
+//!
+//! A [SubmissionControl] is shared between a keystore backend and the application which owns the
+//! keystore. While submissions are paused, the backend rejects every new transaction with a
+//! [KeystoreError::Failed] explaining why, but it continues to stream events, so the keystore keeps
+//! ingesting blocks and persisting its state. This is useful during maintenance windows, backend
+//! migrations, or when a spending key is suspected to be compromised.
+//!
+//! If the control is backed by a directory, the paused flag is persisted there, so a keystore which
+//! is paused stays paused across restarts until it is explicitly resumed.
+
+use espresso_core::ledger::EspressoLedger;
+use seahorse::KeystoreError;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const PAUSED_FLAG_FILE: &str = "submissions_paused";
+
+#[derive(Debug, Default)]
+pub struct SubmissionControl {
+    paused: AtomicBool,
+    // If set, the file whose existence indicates that submissions are paused.
+    flag_path: Option<PathBuf>,
+}
+
+impl SubmissionControl {
+    /// A control which is not persisted, and which starts out accepting submissions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load a persistent control from `dir`.
+    ///
+    /// If the keystore was paused the last time it ran with the same `dir`, the new control starts
+    /// out paused.
+    pub fn load(dir: &Path) -> Result<Self, KeystoreError<EspressoLedger>> {
+        fs::create_dir_all(dir).map_err(|err| KeystoreError::Failed {
+            msg: format!(
+                "failed to create admin directory {}: {}",
+                dir.display(),
+                err
+            ),
+        })?;
+        let flag_path = dir.join(PAUSED_FLAG_FILE);
+        Ok(Self {
+            paused: AtomicBool::new(flag_path.exists()),
+            flag_path: Some(flag_path),
+        })
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Stop accepting new submissions.
+    ///
+    /// Transactions which were already submitted are not affected, and events continue to be
+    /// processed as usual.
+    pub fn pause(&self) -> Result<(), KeystoreError<EspressoLedger>> {
+        if let Some(path) = &self.flag_path {
+            fs::write(path, []).map_err(|err| KeystoreError::Failed {
+                msg: format!("failed to persist paused flag: {}", err),
+            })?;
+        }
+        self.paused.store(true, Ordering::SeqCst);
+        tracing::warn!("keystore submissions paused");
+        Ok(())
+    }
+
+    /// Start accepting submissions again after a call to [pause](Self::pause).
+    pub fn resume(&self) -> Result<(), KeystoreError<EspressoLedger>> {
+        if let Some(path) = &self.flag_path {
+            match fs::remove_file(path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => {
+                    return Err(KeystoreError::Failed {
+                        msg: format!("failed to clear paused flag: {}", err),
+                    })
+                }
+            }
+        }
+        self.paused.store(false, Ordering::SeqCst);
+        tracing::info!("keystore submissions resumed");
+        Ok(())
+    }
+
+    /// Fail with a descriptive error if submissions are currently paused.
+    pub fn check(&self) -> Result<(), KeystoreError<EspressoLedger>> {
+        if self.is_paused() {
+            Err(KeystoreError::Failed {
+                msg: "transaction submission is paused by an administrator; \
+                    call resume() to accept new transactions"
+                    .into(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_paused_flag_persists() {
+        let dir = TempDir::new("submission_control").unwrap();
+
+        let control = SubmissionControl::load(dir.path()).unwrap();
+        assert!(!control.is_paused());
+        control.check().unwrap();
+        control.pause().unwrap();
+        control.check().unwrap_err();
+
+        // Reloading from the same directory remembers that we were paused.
+        let control = SubmissionControl::load(dir.path()).unwrap();
+        assert!(control.is_paused());
+        control.resume().unwrap();
+        control.check().unwrap();
+
+        let control = SubmissionControl::load(dir.path()).unwrap();
+        assert!(!control.is_paused());
+    }
+}
@@ -0,0 +1,406 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Off-machine backups of keystore storage.
+//!
+//! A [BackupTarget] is anywhere a keystore's storage files can be copied to: another disk, a
+//! WebDAV share, an object store. [sync] copies the current contents of a storage directory to a
+//! target and [restore] copies the latest backup back into an empty directory. Seahorse encrypts
+//! everything it writes to storage, so the target only ever sees ciphertext.
+//!
+//! Backups are content addressed. Each file is uploaded as a blob named by the hash of its
+//! contents, which is skipped if the target already has it, and a sync finishes by replacing the
+//! manifest, which maps storage paths to blobs. A restore only reads blobs named in the manifest,
+//! so a sync which is interrupted part way through leaves the previous backup intact. Blobs which
+//! are no longer in the manifest are garbage collected at the end of a sync, under an exclusive
+//! [lock](BackupTarget::try_lock) on the backup, while a restore holds a shared lock for as long as
+//! it is reading blobs, so garbage collection never removes a blob out from under a restore.
+//!
+//! Files are read one at a time, so a sync which runs while the keystore is committing can capture
+//! some files from before the commit and some from after. [spawn_sync] runs periodically, so such a
+//! backup is soon superseded, but a backup taken while the keystore is closed is always consistent.
+
+use crate::storage_lock::LOCK_FILE;
+use async_std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    task::{sleep, spawn, JoinHandle},
+};
+use async_trait::async_trait;
+use espresso_core::ledger::EspressoLedger;
+use fd_lock::RwLock;
+use futures::StreamExt;
+use seahorse::KeystoreError;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+
+const MANIFEST: &str = "MANIFEST";
+const BLOBS: &str = "blobs";
+const BACKUP_LOCK: &str = "LOCK";
+
+/// A lock on a backup, released when dropped.
+pub type BackupLock = Box<dyn Send + Sync>;
+
+/// Somewhere to keep backups.
+///
+/// Objects are addressed by `/`-separated relative paths.
+#[async_trait]
+pub trait BackupTarget: Send + Sync {
+    /// Write an object, replacing it if it exists.
+    ///
+    /// Replacing an object must be atomic: a concurrent or subsequent [get](Self::get) sees either
+    /// the old contents or the new contents in full.
+    async fn put(&self, path: &str, contents: Vec<u8>)
+        -> Result<(), KeystoreError<EspressoLedger>>;
+
+    /// Read an object, or return `None` if it does not exist.
+    async fn get(&self, path: &str) -> Result<Option<Vec<u8>>, KeystoreError<EspressoLedger>>;
+
+    /// The paths of all objects whose path starts with `prefix`.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, KeystoreError<EspressoLedger>>;
+
+    async fn remove(&self, path: &str) -> Result<(), KeystoreError<EspressoLedger>>;
+
+    /// Lock the backup, shared or `exclusive`, or return `None` if a conflicting lock is held.
+    ///
+    /// The lock must be visible to every process using the backup, including on other machines if
+    /// the target is shared between them.
+    async fn try_lock(
+        &self,
+        exclusive: bool,
+    ) -> Result<Option<BackupLock>, KeystoreError<EspressoLedger>>;
+}
+
+/// A [BackupTarget] in a local directory, such as a mounted network or removable drive.
+#[derive(Clone, Debug)]
+pub struct FsBackupTarget {
+    root: PathBuf,
+}
+
+impl FsBackupTarget {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+#[async_trait]
+impl BackupTarget for FsBackupTarget {
+    async fn put(
+        &self,
+        path: &str,
+        contents: Vec<u8>,
+    ) -> Result<(), KeystoreError<EspressoLedger>> {
+        let dest = self.root.join(path);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).await.map_err(io_err(parent))?;
+        }
+        // Write to a temporary file and rename it into place, so the object is replaced atomically.
+        let tmp = dest.with_extension("tmp");
+        fs::write(&tmp, contents).await.map_err(io_err(&tmp))?;
+        fs::rename(&tmp, &dest).await.map_err(io_err(&dest))
+    }
+
+    async fn get(&self, path: &str) -> Result<Option<Vec<u8>>, KeystoreError<EspressoLedger>> {
+        let src = self.root.join(path);
+        match fs::read(&src).await {
+            Ok(contents) => Ok(Some(contents)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(io_err(&src)(err)),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, KeystoreError<EspressoLedger>> {
+        Ok(list_files(&self.root)
+            .await?
+            .into_iter()
+            .filter(|path| path.starts_with(prefix))
+            .collect())
+    }
+
+    async fn remove(&self, path: &str) -> Result<(), KeystoreError<EspressoLedger>> {
+        let target = self.root.join(path);
+        match fs::remove_file(&target).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(io_err(&target)(err)),
+        }
+    }
+
+    async fn try_lock(
+        &self,
+        exclusive: bool,
+    ) -> Result<Option<BackupLock>, KeystoreError<EspressoLedger>> {
+        fs::create_dir_all(&self.root)
+            .await
+            .map_err(io_err(&self.root))?;
+        let path = self.root.join(BACKUP_LOCK);
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&path)
+            .map_err(io_err(&path))?;
+        let mut lock = RwLock::new(file);
+        // As with a [StorageLock](crate::storage_lock::StorageLock), the lock lasts as long as the
+        // file is open, so we can let go of the guard and keep the file.
+        let res = if exclusive {
+            lock.try_write().map(std::mem::forget)
+        } else {
+            lock.try_read().map(std::mem::forget)
+        };
+        match res {
+            Ok(()) => Ok(Some(Box::new(lock))),
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(io_err(&path)(err)),
+        }
+    }
+}
+
+/// The contents of a backup: the blob holding each file in the storage directory.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: BTreeMap<String, String>,
+}
+
+/// Back up the storage directory `storage` to `target`.
+///
+/// Returns the manifest of the new backup.
+pub async fn sync(
+    storage: &Path,
+    target: &dyn BackupTarget,
+) -> Result<Manifest, KeystoreError<EspressoLedger>> {
+    let existing = target
+        .list(BLOBS)
+        .await?
+        .into_iter()
+        .collect::<HashSet<_>>();
+    let mut manifest = Manifest::default();
+    for file in list_files(storage).await? {
+        // The lock file is specific to this machine's open keystore.
+        if file == LOCK_FILE {
+            continue;
+        }
+        let path = storage.join(&file);
+        let contents = fs::read(&path).await.map_err(io_err(&path))?;
+        let blob = format!("{}/{}", BLOBS, hex::encode(Sha3_256::digest(&contents)));
+        if !existing.contains(&blob) {
+            target.put(&blob, contents).await?;
+        }
+        manifest.files.insert(file, blob);
+    }
+    target
+        .put(MANIFEST, serde_json::to_vec(&manifest).unwrap())
+        .await?;
+
+    // Now that the new manifest is in place, blobs which are not in it are garbage. A restore may
+    // still be reading them through the old manifest, though, in which case we leave them for the
+    // next sync.
+    match target.try_lock(true).await? {
+        Some(_lock) => {
+            let live = manifest.files.values().collect::<HashSet<_>>();
+            for blob in existing {
+                if !live.contains(&blob) {
+                    target.remove(&blob).await?;
+                }
+            }
+        }
+        None => tracing::debug!("backup is being restored, skipping garbage collection"),
+    }
+    Ok(manifest)
+}
+
+/// Restore the latest backup in `target` to the storage directory `storage`.
+///
+/// `storage` must not contain a keystore already, since restoring over one would mix the files of
+/// two keystores.
+pub async fn restore(
+    target: &dyn BackupTarget,
+    storage: &Path,
+) -> Result<Manifest, KeystoreError<EspressoLedger>> {
+    if !list_files(storage).await?.is_empty() {
+        return Err(KeystoreError::Failed {
+            msg: format!(
+                "cannot restore into {}: directory is not empty",
+                storage.display()
+            ),
+        });
+    }
+    // Keep garbage collection from removing the blobs in the manifest until we have read them.
+    // Garbage collection is quick, so if it is running, just wait for it to finish.
+    let _lock = loop {
+        match target.try_lock(false).await? {
+            Some(lock) => break lock,
+            None => sleep(Duration::from_millis(100)).await,
+        }
+    };
+    let manifest: Manifest = match target.get(MANIFEST).await? {
+        Some(bytes) => serde_json::from_slice(&bytes).map_err(|err| KeystoreError::Failed {
+            msg: format!("malformed backup manifest: {}", err),
+        })?,
+        None => {
+            return Err(KeystoreError::Failed {
+                msg: "no backup found".into(),
+            })
+        }
+    };
+    for (file, blob) in &manifest.files {
+        let contents = target
+            .get(blob)
+            .await?
+            .ok_or_else(|| KeystoreError::Failed {
+                msg: format!("backup is missing {} (for {})", blob, file),
+            })?;
+        if *blob != format!("{}/{}", BLOBS, hex::encode(Sha3_256::digest(&contents))) {
+            return Err(KeystoreError::Failed {
+                msg: format!("backup blob {} is corrupt", blob),
+            });
+        }
+        // Don't let a tampered manifest write outside the storage directory.
+        if file
+            .split('/')
+            .any(|part| part.is_empty() || part == "." || part == "..")
+        {
+            return Err(KeystoreError::Failed {
+                msg: format!("backup manifest contains invalid path {}", file),
+            });
+        }
+        let dest = storage.join(file);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).await.map_err(io_err(parent))?;
+        }
+        fs::write(&dest, contents).await.map_err(io_err(&dest))?;
+    }
+    Ok(manifest)
+}
+
+/// Back up `storage` to `target` every `interval`, until the task is cancelled.
+///
+/// Failures are logged and retried at the next interval.
+pub fn spawn_sync(
+    storage: PathBuf,
+    target: Arc<dyn BackupTarget>,
+    interval: Duration,
+) -> JoinHandle<()> {
+    spawn(async move {
+        loop {
+            match sync(&storage, &*target).await {
+                Ok(manifest) => tracing::debug!(
+                    "backed up {} files from {}",
+                    manifest.files.len(),
+                    storage.display()
+                ),
+                Err(err) => tracing::warn!("backup of {} failed: {}", storage.display(), err),
+            }
+            sleep(interval).await;
+        }
+    })
+}
+
+// The relative paths of all the files under `root`, `/`-separated.
+async fn list_files(root: &Path) -> Result<Vec<String>, KeystoreError<EspressoLedger>> {
+    let mut files = vec![];
+    let mut dirs = vec![(root.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = dirs.pop() {
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(io_err(&dir)(err)),
+        };
+        while let Some(entry) = entries.next().await {
+            let entry = entry.map_err(io_err(&dir))?;
+            let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            let file_type = entry.file_type().await.map_err(io_err(&entry.path()))?;
+            if file_type.is_dir() {
+                dirs.push((entry.path(), format!("{}/", name)));
+            } else if file_type.is_file() {
+                files.push(name);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn io_err(path: &Path) -> impl '_ + FnOnce(std::io::Error) -> KeystoreError<EspressoLedger> {
+    move |err| KeystoreError::Failed {
+        msg: format!("I/O error at {}: {}", path.display(), err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[async_std::test]
+    async fn test_backup() {
+        let dir = TempDir::new("test_backup").unwrap();
+        let storage = PathBuf::from(dir.path().join("storage"));
+        let target = FsBackupTarget::new(dir.path().join("backup"));
+        fs::create_dir_all(storage.join("logs")).await.unwrap();
+        fs::write(storage.join("logs/0"), b"log 0").await.unwrap();
+        fs::write(storage.join("meta"), b"meta").await.unwrap();
+        fs::write(storage.join(LOCK_FILE), b"").await.unwrap();
+
+        let manifest = sync(&storage, &target).await.unwrap();
+        assert_eq!(
+            manifest.files.keys().collect::<Vec<_>>(),
+            vec!["logs/0", "meta"]
+        );
+
+        // Only changed files are uploaded, and unreferenced blobs are removed.
+        fs::write(storage.join("meta"), b"meta 2").await.unwrap();
+        let manifest2 = sync(&storage, &target).await.unwrap();
+        assert_eq!(manifest2.files["logs/0"], manifest.files["logs/0"]);
+        assert_ne!(manifest2.files["meta"], manifest.files["meta"]);
+        assert_eq!(target.list(BLOBS).await.unwrap().len(), 2);
+
+        let restored = PathBuf::from(dir.path().join("restored"));
+        assert_eq!(restore(&target, &restored).await.unwrap(), manifest2);
+        assert_eq!(fs::read(restored.join("logs/0")).await.unwrap(), b"log 0");
+        assert_eq!(fs::read(restored.join("meta")).await.unwrap(), b"meta 2");
+        assert!(!restored.join(LOCK_FILE).exists().await);
+
+        // Restoring over an existing keystore is refused.
+        assert!(restore(&target, &storage).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_backup_lock() {
+        let dir = TempDir::new("test_backup_lock").unwrap();
+        let storage = PathBuf::from(dir.path().join("storage"));
+        let target = FsBackupTarget::new(dir.path().join("backup"));
+        fs::create_dir_all(&storage).await.unwrap();
+        fs::write(storage.join("meta"), b"meta").await.unwrap();
+        sync(&storage, &target).await.unwrap();
+
+        // Shared locks are compatible with each other but not with an exclusive lock.
+        let shared = target.try_lock(false).await.unwrap().unwrap();
+        assert!(target.try_lock(false).await.unwrap().is_some());
+        assert!(target.try_lock(true).await.unwrap().is_none());
+
+        // While a restore holds a shared lock, a sync does not remove the blobs it may be reading.
+        fs::write(storage.join("meta"), b"meta 2").await.unwrap();
+        sync(&storage, &target).await.unwrap();
+        assert_eq!(target.list(BLOBS).await.unwrap().len(), 2);
+        drop(shared);
+        sync(&storage, &target).await.unwrap();
+        assert_eq!(target.list(BLOBS).await.unwrap().len(), 1);
+
+        // While garbage collection holds the exclusive lock, a restore waits for it.
+        let exclusive = target.try_lock(true).await.unwrap().unwrap();
+        let restored = PathBuf::from(dir.path().join("restored"));
+        let restoring = {
+            let target = target.clone();
+            let restored = restored.clone();
+            spawn(async move { restore(&target, &restored).await })
+        };
+        sleep(Duration::from_millis(500)).await;
+        assert!(!restored.join("meta").exists().await);
+        drop(exclusive);
+        restoring.await.unwrap();
+        assert_eq!(fs::read(restored.join("meta")).await.unwrap(), b"meta 2");
+    }
+}
@@ -0,0 +1,701 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+// A load generator for benchmarking a running validator network.
+//
+// The load generator drives a number of keystores, each with its own funded key, and submits a
+// configurable mix of transactions between them at a target rate:
+//  load-generator --esqs-url http://localhost:50087 --validator-url http://localhost:50087 \
+//      --address-book-url http://localhost:50078 --key-path KEY1 --key-path KEY2 \
+//      --tps 2 --duration 5m --mix native-transfer=4,transfer=4,mint=1,freeze=1
+//
+// Each keystore needs a balance of native assets to pay for its transactions. Either pass key
+// files for keys which were given native assets at genesis (see `--faucet-pub-key` on the
+// validator) with `--key-path`, or pass `--faucet-url` and the load generator will request native
+// assets for newly generated keys.
+//
+// When the run completes, the load generator reports, for each kind of transaction, how many were
+// submitted, how many were rejected (either when submitting or by the validators), and the
+// percentiles of the latency from submission until the transaction was committed or rejected.
+// This gives a more realistic benchmark than the validator's internal transaction generation
+// (`espresso-validator-testing --num-txns`), since transactions are built by real keystores and
+// submitted through the public APIs.
+
+use async_std::future::timeout;
+use async_std::sync::{Arc, Mutex};
+use async_std::task::{sleep, spawn};
+use async_trait::async_trait;
+use clap::Parser;
+use espresso_client::{ledger_state::TransactionUID, network::NetworkBackend, perf::MetricSummary};
+use espresso_core::{ledger::EspressoLedger, universal_params::UNIVERSAL_PARAM};
+use espresso_validator::parse_duration;
+use faucet_types::FaucetError;
+use futures::future::join_all;
+use jf_cap::{
+    keys::{UserKeyPair, UserPubKey},
+    structs::{AssetCode, AssetDefinition, AssetPolicy, FreezeFlag},
+    TransactionNote,
+};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::seq::SliceRandom;
+use rand::{Rng, RngCore};
+use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+use seahorse::{events::EventIndex, hd::KeyTree, loader::KeystoreLoader, KeystoreError};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::PathBuf;
+use std::process::exit;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use surf_disco::{StatusCode, Url};
+use tempdir::TempDir;
+use tracing::{event, Level};
+
+type Keystore = seahorse::Keystore<'static, NetworkBackend<'static>, EspressoLedger, ()>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Operation {
+    /// Transfer native assets to another keystore.
+    NativeTransfer,
+    /// Transfer a custom asset to another keystore.
+    Transfer,
+    /// Mint more of a keystore's custom asset for another keystore.
+    Mint,
+    /// Freeze a record of a keystore's custom asset owned by another keystore, or unfreeze one if
+    /// some are already frozen.
+    Freeze,
+}
+
+impl Operation {
+    const ALL: [Self; 4] = [
+        Self::NativeTransfer,
+        Self::Transfer,
+        Self::Mint,
+        Self::Freeze,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::NativeTransfer => "native-transfer",
+            Self::Transfer => "transfer",
+            Self::Mint => "mint",
+            Self::Freeze => "freeze",
+        }
+    }
+}
+
+impl FromStr for Operation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|op| op.name() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown operation {} (expected one of {})",
+                    s,
+                    Self::ALL.map(|op| op.name()).join(", ")
+                )
+            })
+    }
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Relative weights of each kind of transaction, like `native-transfer=4,mint=1`.
+#[derive(Clone, Debug)]
+struct Mix(Vec<(Operation, u32)>);
+
+impl FromStr for Mix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mix = s
+            .split(',')
+            .map(|entry| {
+                let (op, weight) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected OPERATION=WEIGHT, got {}", entry))?;
+                let weight = weight
+                    .parse()
+                    .map_err(|err| format!("invalid weight {}: {}", weight, err))?;
+                Ok((op.trim().parse()?, weight))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if mix.iter().all(|(_, weight)| *weight == 0) {
+            return Err("at least one operation must have a non-zero weight".into());
+        }
+        Ok(Self(mix))
+    }
+}
+
+impl Mix {
+    fn sample(&self, rng: &mut ChaChaRng) -> Operation {
+        let index = WeightedIndex::new(self.0.iter().map(|(_, weight)| *weight)).unwrap();
+        self.0[index.sample(rng)].0
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "Espresso load generator",
+    about = "Drives keystores against a validator network and reports latency and rejection rates"
+)]
+struct Args {
+    /// Path to a private key file for one of the keystores.
+    ///
+    /// May be given multiple times. The keys should have a balance of native assets.
+    #[arg(short, long)]
+    key_path: Vec<PathBuf>,
+
+    /// Number of keystores to run.
+    ///
+    /// Keystores beyond those given by `--key-path` use new keys, funded by the faucet.
+    #[arg(short, long)]
+    num_wallets: Option<usize>,
+
+    /// Seed for random number generation.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Target rate of transactions, per second, across all keystores.
+    #[arg(long, default_value = "1")]
+    tps: f64,
+
+    /// How long to generate load for.
+    #[arg(long, value_parser = parse_duration, default_value = "1m")]
+    duration: Duration,
+
+    /// Relative weights of each kind of transaction.
+    ///
+    /// A comma-separated list of OPERATION=WEIGHT, where OPERATION is one of native-transfer,
+    /// transfer, mint or freeze.
+    #[arg(long, default_value = "native-transfer=4,transfer=4,mint=1,freeze=1")]
+    mix: Mix,
+
+    /// How long to wait for outstanding transactions after the run.
+    #[arg(long, value_parser = parse_duration, default_value = "2m")]
+    drain_timeout: Duration,
+
+    /// URL of a server for querying with the ledger
+    #[arg(short, long, env = "ESPRESSO_ESQS_URL")]
+    esqs_url: Url,
+
+    /// URL of a server for interacting with the ledger
+    #[arg(short, long, env = "ESPRESSO_SUBMIT_URL")]
+    validator_url: Url,
+
+    /// URL of a server for address book
+    #[arg(short, long, env = "ESPRESSO_ADDRESS_BOOK_URL")]
+    address_book_url: Url,
+
+    /// URL of a faucet, used to fund keystores which were not given a key.
+    #[arg(short, long, env = "ESPRESSO_FAUCET_URL")]
+    faucet_url: Option<Url>,
+
+    /// Whether to color log output with ANSI color codes.
+    #[arg(long, env = "ESPRESSO_COLORED_LOGS")]
+    colored_logs: bool,
+}
+
+struct TrivialKeystoreLoader {
+    pub dir: PathBuf,
+    pub key_tree: KeyTree,
+}
+
+#[async_trait]
+impl KeystoreLoader<EspressoLedger> for TrivialKeystoreLoader {
+    type Meta = ();
+
+    fn location(&self) -> PathBuf {
+        self.dir.clone()
+    }
+
+    async fn create(&mut self) -> Result<((), KeyTree), KeystoreError<EspressoLedger>> {
+        Ok(((), self.key_tree.clone()))
+    }
+
+    async fn load(&mut self, _meta: &mut ()) -> Result<KeyTree, KeystoreError<EspressoLedger>> {
+        Ok(self.key_tree.clone())
+    }
+}
+
+#[derive(Debug, Default)]
+struct OperationStats {
+    submitted: u64,
+    /// Transactions which could not be built or submitted.
+    submit_errors: u64,
+    /// Transactions which were submitted but rejected by the validators.
+    rejected: u64,
+    committed: u64,
+    /// Time from submission until each transaction was committed or rejected, in microseconds.
+    latencies: Vec<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Stats(BTreeMap<Operation, OperationStats>);
+
+impl Stats {
+    fn op(&mut self, op: Operation) -> &mut OperationStats {
+        self.0.entry(op).or_default()
+    }
+}
+
+fn summarize(mut micros: Vec<u64>) -> Option<MetricSummary> {
+    if micros.is_empty() {
+        return None;
+    }
+    micros.sort_unstable();
+    let percentile = |p: usize| micros[(micros.len() - 1) * p / 100];
+    Some(MetricSummary {
+        count: micros.len(),
+        p50: percentile(50),
+        p90: percentile(90),
+        p99: percentile(99),
+        max: *micros.last().unwrap(),
+    })
+}
+
+fn report(stats: Stats, duration: Duration) {
+    let mut total_committed = 0;
+    println!("load generated for {:?}", duration);
+    for (op, s) in stats.0 {
+        total_committed += s.committed;
+        let attempted = s.submitted + s.submit_errors;
+        let rejection_rate = if attempted == 0 {
+            0.0
+        } else {
+            100.0 * (s.submit_errors + s.rejected) as f64 / attempted as f64
+        };
+        println!(
+            "  {}: attempted={} submit_errors={} rejected={} committed={} rejection_rate={:.1}%",
+            op, attempted, s.submit_errors, s.rejected, s.committed, rejection_rate
+        );
+        if let Some(l) = summarize(s.latencies) {
+            println!(
+                "    latency: n={} p50={:.3}s p90={:.3}s p99={:.3}s max={:.3}s",
+                l.count,
+                l.p50 as f64 / 1e6,
+                l.p90 as f64 / 1e6,
+                l.p99 as f64 / 1e6,
+                l.max as f64 / 1e6,
+            );
+        }
+    }
+    println!(
+        "  committed throughput: {:.3} txn/s",
+        total_committed as f64 / duration.as_secs_f64()
+    );
+}
+
+async fn retry_delay() {
+    sleep(Duration::from_secs(1)).await
+}
+
+async fn get_native_from_faucet(keystore: &Keystore, pub_key: &UserPubKey, url: &Url) {
+    loop {
+        match surf_disco::post::<(), FaucetError>(url.join("api/request_fee_assets").unwrap())
+            .body_binary(pub_key)
+            .unwrap()
+            .send()
+            .await
+        {
+            Ok(_) => break,
+            Err(err) if err.status() == StatusCode::TooManyRequests => break,
+            Err(err) => {
+                tracing::error!("Retrying faucet because of {:?}", err);
+                retry_delay().await;
+            }
+        }
+    }
+    while keystore.balance(&AssetCode::native()).await == 0u64.into() {
+        retry_delay().await;
+    }
+}
+
+/// A keystore generating load, along with the custom asset it mints and freezes.
+struct Wallet {
+    keystore: Keystore,
+    pub_key: UserPubKey,
+    asset: AssetDefinition,
+    // Keeps the keystore storage alive.
+    _dir: TempDir,
+}
+
+async fn create_wallet(args: &Args, rng: &mut ChaChaRng, key_pair: Option<UserKeyPair>) -> Wallet {
+    let dir = TempDir::new("load_generator").unwrap();
+    let mut loader = TrivialKeystoreLoader {
+        dir: dir.path().to_owned(),
+        key_tree: KeyTree::random(rng).0,
+    };
+    let backend = NetworkBackend::new(
+        &UNIVERSAL_PARAM,
+        args.esqs_url.clone(),
+        args.address_book_url.clone(),
+        args.validator_url.clone(),
+    )
+    .await
+    .expect("failed to connect to backend");
+    let mut keystore = Keystore::new(backend, &mut loader)
+        .await
+        .expect("error loading keystore");
+    keystore
+        .generate_viewing_account("view key".to_string(), Some(EventIndex::default()))
+        .await
+        .unwrap();
+    keystore
+        .generate_freezing_account("freeze key".to_string(), Some(EventIndex::default()))
+        .await
+        .unwrap();
+    let pub_key = match key_pair {
+        Some(key_pair) => {
+            keystore
+                .add_account(
+                    key_pair.clone(),
+                    "Load generator key".to_string(),
+                    EventIndex::default(),
+                )
+                .await
+                .unwrap_or_else(|err| panic!("error loading key: {}", err));
+            key_pair.pub_key()
+        }
+        None => keystore
+            .generate_sending_account(
+                "Load generator key".to_string(),
+                Some(EventIndex::default()),
+            )
+            .await
+            .unwrap(),
+    };
+    keystore
+        .await_sending_key_scan(&pub_key.address())
+        .await
+        .unwrap();
+
+    if keystore.balance(&AssetCode::native()).await == 0u64.into() {
+        match &args.faucet_url {
+            Some(url) => get_native_from_faucet(&keystore, &pub_key, url).await,
+            None => panic!(
+                "key {} has no native assets and no faucet was given",
+                pub_key.address()
+            ),
+        }
+    }
+
+    // Define and mint a custom asset which we can transfer, mint and freeze.
+    let asset = keystore
+        .define_asset(
+            "Load generator asset".to_string(),
+            &[],
+            AssetPolicy::default()
+                .set_viewer_pub_key(keystore.viewing_pub_keys().await[0].clone())
+                .set_freezer_pub_key(keystore.freezing_pub_keys().await[0].clone())
+                .reveal_record_opening()
+                .unwrap(),
+        )
+        .await
+        .expect("failed to define asset");
+    loop {
+        let txn = keystore
+            .mint(
+                Some(&pub_key.address()),
+                0,
+                &asset.code,
+                1u64 << 32,
+                pub_key.clone(),
+            )
+            .await
+            .expect("failed to generate mint transaction");
+        if keystore
+            .await_transaction(&txn)
+            .await
+            .expect("error waiting for mint to complete")
+            .succeeded()
+        {
+            break;
+        }
+        event!(Level::WARN, "initial mint failed, retrying...");
+        retry_delay().await;
+    }
+
+    Wallet {
+        keystore,
+        pub_key,
+        asset,
+        _dir: dir,
+    }
+}
+
+impl Wallet {
+    /// Build and submit a transaction of type `op` to `peer`.
+    async fn submit(
+        &mut self,
+        rng: &mut ChaChaRng,
+        op: Operation,
+        peer: &UserPubKey,
+    ) -> Result<TransactionUID<EspressoLedger>, KeystoreError<EspressoLedger>> {
+        let address = self.pub_key.address();
+        match op {
+            Operation::NativeTransfer | Operation::Transfer => {
+                let asset = if op == Operation::NativeTransfer {
+                    AssetCode::native()
+                } else {
+                    self.asset.code
+                };
+                let (note, params) = self
+                    .keystore
+                    .build_transfer(
+                        Some(&address),
+                        &asset,
+                        &[(peer.clone(), 1u64, false)],
+                        0,
+                        vec![],
+                        None,
+                    )
+                    .await?;
+                self.keystore
+                    .submit_cap(TransactionNote::Transfer(Box::new(note)), params)
+                    .await
+            }
+            Operation::Mint => self.mint(peer).await,
+            Operation::Freeze => {
+                let frozen = self.freezable(FreezeFlag::Frozen).await;
+                let (record, unfreeze) = match frozen.choose(rng) {
+                    Some(record) => (record.clone(), true),
+                    None => match self.freezable(FreezeFlag::Unfrozen).await.choose(rng) {
+                        Some(record) => (record.clone(), false),
+                        // Until one of our peers receives some of our asset, there is nothing to
+                        // freeze, so just mint some for them instead.
+                        None => return self.mint(peer).await,
+                    },
+                };
+                let owner = record.pub_key().address();
+                if unfreeze {
+                    self.keystore
+                        .unfreeze(Some(&address), 0, &self.asset.code, record.amount(), owner)
+                        .await
+                } else {
+                    self.keystore
+                        .freeze(Some(&address), 0, &self.asset.code, record.amount(), owner)
+                        .await
+                }
+            }
+        }
+    }
+
+    async fn mint(
+        &mut self,
+        peer: &UserPubKey,
+    ) -> Result<TransactionUID<EspressoLedger>, KeystoreError<EspressoLedger>> {
+        self.keystore
+            .mint(
+                Some(&self.pub_key.address()),
+                0,
+                &self.asset.code,
+                100u64,
+                peer.clone(),
+            )
+            .await
+    }
+
+    /// Records of our asset owned by other keystores, with the given freeze flag.
+    async fn freezable(&self, flag: FreezeFlag) -> Vec<seahorse::records::Record> {
+        self.keystore
+            .records()
+            .await
+            .into_iter()
+            .filter(|r| {
+                let ro = r.record_opening();
+                ro.asset_def.code == self.asset.code
+                    && ro.pub_key != self.pub_key
+                    && ro.freeze_flag == flag
+            })
+            .collect()
+    }
+}
+
+type Pending = VecDeque<(Operation, TransactionUID<EspressoLedger>, Instant)>;
+
+/// Record the outcome of any pending transactions which have completed.
+async fn poll_pending(keystore: &Keystore, pending: &mut Pending, stats: &Mutex<Stats>) {
+    for (op, txn, submitted) in std::mem::take(pending) {
+        match keystore.transaction_status(&txn).await {
+            Ok(status) if status.is_final() => {
+                let mut stats = stats.lock().await;
+                let s = stats.op(op);
+                if status.succeeded() {
+                    s.committed += 1;
+                } else {
+                    s.rejected += 1;
+                }
+                s.latencies.push(submitted.elapsed().as_micros() as u64);
+            }
+            // If we failed to fetch the status, keep the transaction. Maybe we will succeed next
+            // time.
+            _ => pending.push_back((op, txn, submitted)),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn generate_load(
+    mut wallet: Wallet,
+    peers: Vec<UserPubKey>,
+    mut rng: ChaChaRng,
+    mix: Mix,
+    interval: Duration,
+    deadline: Instant,
+    drain_timeout: Duration,
+    stats: Arc<Mutex<Stats>>,
+) -> Result<(), String> {
+    let mut pending = Pending::new();
+    // Stagger the keystores so their transactions are spread evenly over each interval.
+    sleep(interval.mul_f64(rng.gen_range(0.0..1.0))).await;
+    let mut next = Instant::now();
+    while next < deadline {
+        let op = mix.sample(&mut rng);
+        let peer = match peers.choose(&mut rng) {
+            Some(peer) => peer,
+            None => return Err("no peers to transfer to".into()),
+        };
+        match wallet.submit(&mut rng, op, peer).await {
+            Ok(txn) => {
+                stats.lock().await.op(op).submitted += 1;
+                pending.push_back((op, txn, Instant::now()));
+            }
+            Err(err) => {
+                event!(Level::WARN, "error submitting {}: {}", op, err);
+                stats.lock().await.op(op).submit_errors += 1;
+            }
+        }
+        poll_pending(&wallet.keystore, &mut pending, &stats).await;
+
+        // If we are behind schedule, catch up rather than trying to make up for lost time with a
+        // burst of transactions.
+        next = (next + interval).max(Instant::now());
+        sleep(next.saturating_duration_since(Instant::now())).await;
+    }
+
+    let drained = timeout(drain_timeout, async {
+        while !pending.is_empty() {
+            poll_pending(&wallet.keystore, &mut pending, &stats).await;
+            retry_delay().await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        event!(
+            Level::WARN,
+            "{} transactions were still pending after {:?}",
+            pending.len(),
+            drain_timeout
+        );
+    }
+    Ok(())
+}
+
+/// The public keys of every keystore other than `own`, with each key listed once.
+///
+/// The result is sorted, so that runs with the same seed pick the same peers.
+fn peers_of(pub_keys: &[UserPubKey], own: &UserPubKey) -> Vec<UserPubKey> {
+    let mut peers = pub_keys
+        .iter()
+        .filter(|key| *key != own)
+        .cloned()
+        .collect::<Vec<_>>();
+    peers.sort_by_cached_key(|key| bincode::serialize(key).unwrap());
+    peers.dedup();
+    peers
+}
+
+#[async_std::main]
+async fn main() {
+    let args = Args::parse();
+
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_ansi(args.colored_logs)
+        .init();
+    let seed = args
+        .seed
+        .unwrap_or_else(|| ChaChaRng::from_entropy().next_u64());
+    event!(Level::INFO, "Using Seed {}", seed);
+    let mut rng = ChaChaRng::seed_from_u64(seed);
+
+    let num_wallets = args
+        .num_wallets
+        .unwrap_or_else(|| args.key_path.len().max(2));
+    if num_wallets < 2 {
+        panic!("at least 2 keystores are needed to transfer between");
+    }
+    if num_wallets > args.key_path.len() && args.faucet_url.is_none() {
+        panic!("--faucet-url is required to fund keystores without a --key-path");
+    }
+    if args.tps <= 0.0 {
+        panic!("--tps must be positive");
+    }
+
+    let mut wallets = vec![];
+    for i in 0..num_wallets {
+        let key_pair = args.key_path.get(i).map(|path| {
+            let bytes = fs::read(path)
+                .unwrap_or_else(|err| panic!("cannot read private key file: {}", err));
+            bincode::deserialize::<UserKeyPair>(&bytes)
+                .unwrap_or_else(|err| panic!("invalid private key file: {}", err))
+        });
+        wallets.push(create_wallet(&args, &mut rng, key_pair).await);
+        event!(Level::INFO, "keystore {}/{} ready", i + 1, num_wallets);
+    }
+
+    let pub_keys = wallets
+        .iter()
+        .map(|wallet| wallet.pub_key.clone())
+        .collect::<Vec<_>>();
+    let peers = wallets
+        .iter()
+        .map(|wallet| peers_of(&pub_keys, &wallet.pub_key))
+        .collect::<Vec<_>>();
+    if peers.iter().any(Vec::is_empty) {
+        // Keystores created from the same key file share a public key, so they cannot be peers.
+        eprintln!("at least 2 distinct keys are needed to transfer between");
+        exit(1);
+    }
+    // Each keystore submits its share of the target rate.
+    let interval = Duration::from_secs_f64(num_wallets as f64 / args.tps);
+    let stats = Arc::new(Mutex::new(Stats::default()));
+    let start = Instant::now();
+    let deadline = start + args.duration;
+    let tasks = wallets.into_iter().zip(peers).map(|(wallet, peers)| {
+        spawn(generate_load(
+            wallet,
+            peers,
+            ChaChaRng::from_rng(&mut rng).unwrap(),
+            args.mix.clone(),
+            interval,
+            deadline,
+            args.drain_timeout,
+            stats.clone(),
+        ))
+    });
+    let errors = join_all(tasks)
+        .await
+        .into_iter()
+        .filter_map(Result::err)
+        .collect::<Vec<_>>();
+
+    let stats = Arc::try_unwrap(stats).unwrap().into_inner();
+    report(stats, args.duration);
+    if !errors.is_empty() {
+        for err in errors {
+            eprintln!("load generation failed: {}", err);
+        }
+        exit(1);
+    }
+}
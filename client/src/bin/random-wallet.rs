@@ -27,12 +27,14 @@ use async_std::task::sleep;
 use async_trait::async_trait;
 use clap::Parser;
 use derive_more::Deref;
-use espresso_client::{ledger_state::TransactionUID, network::NetworkBackend, RecordAmount};
+use espresso_client::{
+    freezing::FreezerView, ledger_state::TransactionUID, network::NetworkBackend, RecordAmount,
+};
 use espresso_core::{ledger::EspressoLedger, universal_params::UNIVERSAL_PARAM};
 use faucet_types::FaucetError;
 use human_bytes::human_bytes;
 use jf_cap::{
-    keys::{FreezerPubKey, UserKeyPair, UserPubKey},
+    keys::{UserKeyPair, UserPubKey},
     structs::{AssetCode, AssetPolicy, FreezeFlag},
     TransactionNote,
 };
@@ -46,7 +48,7 @@ use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
 use seahorse::records::Record;
 use seahorse::{events::EventIndex, hd::KeyTree, loader::KeystoreLoader, KeystoreError};
 use std::cmp::min;
-use std::collections::{HashSet, VecDeque};
+use std::collections::VecDeque;
 use std::fmt::{self, Display, Formatter};
 use std::fs::File;
 use std::io::Read;
@@ -82,29 +84,19 @@ type Keystore = seahorse::Keystore<'static, NetworkBackend<'static>, EspressoLed
 /// Return records the freezer has access to freeze or unfreeze but does not own.
 /// Will only return records with freeze_flag the same as the frozen arg.
 pub async fn find_freezable_records<'a>(freezer: &Keystore, frozen: FreezeFlag) -> Vec<Record> {
-    let pks: HashSet<UserPubKey> = freezer
-        .sending_keys()
+    let view = FreezerView::new(
+        freezer
+            .sending_keys()
+            .await
+            .into_iter()
+            .map(|pair| pair.pub_key()),
+        freezer.freezing_pub_keys().await,
+    );
+    freezer
+        .records()
         .await
         .into_iter()
-        .map(|pair| pair.pub_key())
-        .collect();
-    let freeze_keys: HashSet<FreezerPubKey> =
-        freezer.freezing_pub_keys().await.into_iter().collect();
-    let records = freezer.records().await;
-    records
-        .into_iter()
-        .filter(|r| {
-            let ro = &r.record_opening();
-            // Ignore records we own
-            if pks.contains(&ro.pub_key) {
-                return false;
-            }
-            // Check we can freeeze
-            if !(freeze_keys.contains(ro.asset_def.policy_ref().freezer_pub_key())) {
-                return false;
-            }
-            ro.freeze_flag == frozen
-        })
+        .filter(|r| view.can_act_on(r) && r.record_opening().freeze_flag == frozen)
         .collect()
 }
 
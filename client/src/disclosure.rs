@@ -0,0 +1,179 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Selective disclosure of past payments.
+//!
+//! The outputs of a CAP transaction are hiding commitments, so an observer of the ledger cannot
+//! tell who was paid or how much. The owner of an output can prove a specific payment to a
+//! counterparty or regulator by revealing the output's record opening: anyone can recompute the
+//! commitment from the opening and check with the query service that a committed transaction
+//! created it. [export_transaction_proof] builds a [TransactionProof] disclosing the outputs of a
+//! transaction which belong to a keystore, and [verify_transaction_proof] checks one against an
+//! EsQS.
+//!
+//! Disclosing an opening reveals the owner, asset, amount and blinding factor of that one record.
+//! It does not reveal any keys, so the verifier cannot spend or trace the keystore's other records.
+
+use crate::network::NetworkBackend;
+use espresso_availability_api::query_data::{RecordQueryData, TransactionQueryData};
+use espresso_core::state::TransactionCommitment;
+use espresso_esqs::ApiError;
+use jf_cap::structs::{RecordCommitment, RecordOpening};
+use seahorse::records::Record;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use snafu::Snafu;
+use surf_disco::Url;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub))]
+pub enum DisclosureError {
+    #[snafu(display("EsQS request GET {} failed: {}", uri, source))]
+    Query { uri: String, source: ApiError },
+    #[snafu(display("none of the outputs of transaction {} belong to this keystore", hash))]
+    NoOwnedOutputs { hash: TransactionCommitment },
+    #[snafu(display("proof refers to the wrong transaction"))]
+    WrongTransaction,
+    #[snafu(display("transaction has no output {}", output_index))]
+    NoSuchOutput { output_index: u64 },
+    #[snafu(display("disclosed opening does not match output {}", output_index))]
+    CommitmentMismatch { output_index: u64 },
+}
+
+/// An output of a transaction, together with its opening.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DisclosedOutput {
+    /// The index of this output within its transaction.
+    pub output_index: u64,
+    /// The index of this output in the record Merkle tree.
+    pub uid: u64,
+    pub opening: RecordOpening,
+}
+
+/// Evidence that a committed transaction paid the disclosed records.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransactionProof {
+    pub transaction_hash: TransactionCommitment,
+    pub block_id: u64,
+    pub txn_id: u64,
+    pub outputs: Vec<DisclosedOutput>,
+}
+
+impl TransactionProof {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        bincode::deserialize(bytes).ok()
+    }
+}
+
+/// Disclose the outputs of the transaction with hash `hash` which are among `records`.
+///
+/// `records` is usually the full record set of a keystore, which includes the outputs it received
+/// as well as any change it paid to itself. The transaction is looked up in the EsQS at
+/// `query_url`.
+pub async fn export_transaction_proof(
+    query_url: Url,
+    records: impl IntoIterator<Item = Record>,
+    hash: TransactionCommitment,
+) -> Result<TransactionProof, DisclosureError> {
+    let query = Query::new(query_url);
+    let txn: TransactionQueryData = query
+        .get(format!("availability/gettransaction/hash/{}", hash))
+        .await?;
+    let commitments = txn.raw_transaction.txn.output_commitments();
+
+    let mut outputs = vec![];
+    for record in records {
+        let opening = record.record_opening().clone();
+        let commitment = RecordCommitment::from(&opening);
+        if let Some(output_index) = commitments.iter().position(|comm| *comm == commitment) {
+            let output_index = output_index as u64;
+            let info: RecordQueryData = query
+                .get(format!(
+                    "availability/getrecord/{}/{}/{}",
+                    txn.block_id, txn.txn_id, output_index
+                ))
+                .await?;
+            outputs.push(DisclosedOutput {
+                output_index,
+                uid: info.uid,
+                opening,
+            });
+        }
+    }
+    if outputs.is_empty() {
+        return Err(DisclosureError::NoOwnedOutputs { hash });
+    }
+    outputs.sort_by_key(|output| output.output_index);
+
+    Ok(TransactionProof {
+        transaction_hash: hash,
+        block_id: txn.block_id,
+        txn_id: txn.txn_id,
+        outputs,
+    })
+}
+
+/// Check `proof` against the EsQS at `query_url`.
+///
+/// On success, every opening in the proof belongs to an output of a committed transaction, at the
+/// position and UID the proof claims. The caller can then inspect the openings to see who was paid
+/// what. This trusts the EsQS to report the ledger faithfully.
+pub async fn verify_transaction_proof(
+    query_url: Url,
+    proof: &TransactionProof,
+) -> Result<(), DisclosureError> {
+    let query = Query::new(query_url);
+    let txn: TransactionQueryData = query
+        .get(format!(
+            "availability/gettransaction/{}/{}",
+            proof.block_id, proof.txn_id
+        ))
+        .await?;
+    if txn.transaction_hash != proof.transaction_hash {
+        return Err(DisclosureError::WrongTransaction);
+    }
+
+    let commitments = txn.raw_transaction.txn.output_commitments();
+    for output in &proof.outputs {
+        let output_index = output.output_index;
+        let commitment = commitments
+            .get(output_index as usize)
+            .ok_or(DisclosureError::NoSuchOutput { output_index })?;
+        if *commitment != RecordCommitment::from(&output.opening) {
+            return Err(DisclosureError::CommitmentMismatch { output_index });
+        }
+        let info: RecordQueryData = query
+            .get(format!("availability/getrecord/uid/{}", output.uid))
+            .await?;
+        if info.commitment != *commitment
+            || (info.block_id, info.txn_id, info.output_index)
+                != (proof.block_id, proof.txn_id, output_index)
+        {
+            return Err(DisclosureError::CommitmentMismatch { output_index });
+        }
+    }
+    Ok(())
+}
+
+struct Query {
+    client: surf_disco::Client<ApiError>,
+}
+
+impl Query {
+    fn new(url: Url) -> Self {
+        Self {
+            client: NetworkBackend::client(url),
+        }
+    }
+
+    async fn get<T: DeserializeOwned>(&self, uri: String) -> Result<T, DisclosureError> {
+        self.client
+            .get(&uri)
+            .send()
+            .await
+            .map_err(|source| DisclosureError::Query { uri, source })
+    }
+}
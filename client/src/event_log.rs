@@ -0,0 +1,201 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! A bounded, persistent log of notable keystore events.
+//!
+//! Failures which happen in the background, such as a dropped event stream message or a rejected
+//! submission, are not reported to any caller. Instead of printing them, the backend records them
+//! in a [KeystoreLog], which applications can query with [KeystoreLog::recent_errors] to surface
+//! problems to the user. Every entry is also emitted as a `tracing` event.
+
+use espresso_core::ledger::EspressoLedger;
+use seahorse::KeystoreError;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The default number of entries to retain.
+pub const DEFAULT_LOG_CAPACITY: usize = 1000;
+
+const LOG_FILE: &str = "keystore_log.json";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogKind {
+    /// A block was rejected by the ledger.
+    InvalidBlock,
+    /// A transaction could not be submitted.
+    SubmitFailed,
+    /// A transaction was resubmitted.
+    Resubmission,
+    /// The event stream delivered an error or malformed data.
+    EventStream,
+    /// A request to a remote service failed.
+    Request,
+    /// The keystore's copy of the ledger state disagreed with the network's.
+    StateDivergence,
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub level: LogLevel,
+    pub kind: LogKind,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub struct KeystoreLog {
+    entries: Mutex<VecDeque<LogEntry>>,
+    capacity: usize,
+    path: Option<PathBuf>,
+}
+
+impl Default for KeystoreLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_LOG_CAPACITY)
+    }
+}
+
+impl KeystoreLog {
+    /// An in-memory log retaining at most `capacity` entries.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            path: None,
+        }
+    }
+
+    /// Load a persistent log from `dir`, retaining at most `capacity` entries.
+    pub fn load(dir: &Path, capacity: usize) -> Result<Self, KeystoreError<EspressoLedger>> {
+        fs::create_dir_all(dir).map_err(|err| KeystoreError::Failed {
+            msg: format!("failed to create log directory {}: {}", dir.display(), err),
+        })?;
+        let path = dir.join(LOG_FILE);
+        let mut entries: VecDeque<LogEntry> = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|err| KeystoreError::Failed {
+                msg: format!("corrupt keystore log {}: {}", path.display(), err),
+            })?,
+            Err(_) => VecDeque::new(),
+        };
+        while entries.len() > capacity {
+            entries.pop_front();
+        }
+        Ok(Self {
+            entries: Mutex::new(entries),
+            capacity,
+            path: Some(path),
+        })
+    }
+
+    pub fn info(&self, kind: LogKind, message: impl Into<String>) {
+        self.record(LogLevel::Info, kind, message.into())
+    }
+
+    pub fn warn(&self, kind: LogKind, message: impl Into<String>) {
+        self.record(LogLevel::Warning, kind, message.into())
+    }
+
+    pub fn error(&self, kind: LogKind, message: impl Into<String>) {
+        self.record(LogLevel::Error, kind, message.into())
+    }
+
+    /// All retained entries, oldest first.
+    pub fn entries(&self) -> Vec<LogEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Up to `limit` of the most recent warnings and errors, newest first.
+    pub fn recent_errors(&self, limit: usize) -> Vec<LogEntry> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|entry| entry.level >= LogLevel::Warning)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    fn record(&self, level: LogLevel, kind: LogKind, message: String) {
+        match level {
+            LogLevel::Info => tracing::info!(?kind, "{}", message),
+            LogLevel::Warning => tracing::warn!(?kind, "{}", message),
+            LogLevel::Error => tracing::error!(?kind, "{}", message),
+        }
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut entries = self.entries.lock().unwrap();
+        if self.capacity == 0 {
+            return;
+        }
+        while entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(LogEntry {
+            time,
+            level,
+            kind,
+            message,
+        });
+
+        if let Some(path) = &self.path {
+            // Failing to persist the log is not fatal; the entry is still available in memory.
+            let res = serde_json::to_vec(&*entries)
+                .map_err(|err| err.to_string())
+                .and_then(|bytes| fs::write(path, bytes).map_err(|err| err.to_string()));
+            if let Err(err) = res {
+                tracing::error!("failed to persist keystore log: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_log_bounded_and_persistent() {
+        let dir = TempDir::new("keystore_log").unwrap();
+        let log = KeystoreLog::load(dir.path(), 3).unwrap();
+        log.info(LogKind::Other, "starting");
+        log.warn(LogKind::Resubmission, "resubmitting transaction");
+        log.error(LogKind::InvalidBlock, "received invalid block");
+        log.error(LogKind::SubmitFailed, "submit failed");
+
+        // The oldest entry was evicted.
+        assert_eq!(log.entries().len(), 3);
+        let errors = log.recent_errors(10);
+        assert_eq!(
+            errors.iter().map(|e| e.kind).collect::<Vec<_>>(),
+            vec![
+                LogKind::SubmitFailed,
+                LogKind::InvalidBlock,
+                LogKind::Resubmission
+            ]
+        );
+
+        let log = KeystoreLog::load(dir.path(), 2).unwrap();
+        assert_eq!(log.recent_errors(10), errors[..2]);
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! The records a freezer keystore can act on.
+//!
+//! A keystore holding the freezer key of an asset learns about other users' records of that asset
+//! from the viewing memos of the transactions which create them, and keeps those records alongside
+//! its own. [FreezerView] picks out those records, so that compliance operators can see what they
+//! are able to freeze and whose holdings they have already frozen.
+
+use jf_cap::{
+    keys::{FreezerPubKey, UserAddress, UserPubKey},
+    structs::{AssetCode, FreezeFlag},
+};
+use primitive_types::U256;
+use seahorse::records::Record;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// A record of another user which a freezer can freeze or unfreeze.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FreezableRecord {
+    pub owner: UserAddress,
+    pub amount: U256,
+    pub frozen: bool,
+}
+
+impl From<&Record> for FreezableRecord {
+    fn from(record: &Record) -> Self {
+        Self {
+            owner: record.pub_key().address(),
+            amount: U256::from(u128::from(record.amount())),
+            frozen: record.record_opening().freeze_flag == FreezeFlag::Frozen,
+        }
+    }
+}
+
+/// Filters a keystore's records down to those its freezer keys can act on.
+#[derive(Clone, Debug, Default)]
+pub struct FreezerView {
+    own_keys: HashSet<UserPubKey>,
+    freezer_keys: HashSet<FreezerPubKey>,
+}
+
+impl FreezerView {
+    /// A view for a keystore with sending keys `own_keys` and freezer keys `freezer_keys`.
+    pub fn new(
+        own_keys: impl IntoIterator<Item = UserPubKey>,
+        freezer_keys: impl IntoIterator<Item = FreezerPubKey>,
+    ) -> Self {
+        Self {
+            own_keys: own_keys.into_iter().collect(),
+            freezer_keys: freezer_keys.into_iter().collect(),
+        }
+    }
+
+    /// Whether `record` belongs to another user and can be frozen or unfrozen with our keys.
+    pub fn can_act_on(&self, record: &Record) -> bool {
+        let ro = record.record_opening();
+        !self.own_keys.contains(&ro.pub_key)
+            && self
+                .freezer_keys
+                .contains(ro.asset_def.policy_ref().freezer_pub_key())
+    }
+
+    /// The records of `asset`, frozen or not, which we can act on.
+    pub fn freezable_records(
+        &self,
+        records: impl IntoIterator<Item = Record>,
+        asset: &AssetCode,
+    ) -> Vec<Record> {
+        records
+            .into_iter()
+            .filter(|record| {
+                record.record_opening().asset_def.code == *asset && self.can_act_on(record)
+            })
+            .collect()
+    }
+
+    /// The total frozen amount of `asset` held by each owner.
+    ///
+    /// Owners are listed in the order their first frozen record appears in `records`.
+    pub fn frozen_holdings_by_owner(
+        &self,
+        records: impl IntoIterator<Item = Record>,
+        asset: &AssetCode,
+    ) -> Vec<(UserAddress, U256)> {
+        let mut holdings: Vec<(UserAddress, U256)> = vec![];
+        for record in self.freezable_records(records, asset) {
+            let record = FreezableRecord::from(&record);
+            if !record.frozen {
+                continue;
+            }
+            match holdings
+                .iter_mut()
+                .find(|(owner, _)| *owner == record.owner)
+            {
+                Some((_, total)) => *total += record.amount,
+                None => holdings.push((record.owner, record.amount)),
+            }
+        }
+        holdings
+    }
+}
@@ -1,10 +1,26 @@
 // Copyright (c) 2022 Espresso Systems (espressosys.com)
 // This file is part of the Espresso library.
 
+pub mod admin;
+pub mod backup;
 pub mod cli_client;
+pub mod disclosure;
+pub mod event_log;
+pub mod export;
+pub mod freezing;
+pub mod metrics;
 pub mod network;
+pub mod payment_channel;
+pub mod perf;
+pub mod policy;
+pub mod profiles;
+pub mod proof_cache;
+pub mod pub_key_cache;
+pub mod storage_lock;
 #[cfg(any(test, feature = "testing"))]
 pub mod testing;
+pub mod wallet_api;
+pub mod wallet_ffi;
 
 pub use cli_client::CliClient;
 pub use seahorse::*;
@@ -12,7 +12,12 @@ mod cli_client;
 
 use async_trait::async_trait;
 use clap::Parser;
-use espresso_client::network::NetworkBackend;
+use espresso_client::{
+    network::NetworkBackend,
+    policy::SpendingPolicy,
+    profiles::{ProfileError, Profiles},
+    storage_lock::StorageLock,
+};
 use espresso_core::ledger::EspressoLedger;
 use jf_cap::proof::UniversalParam;
 use seahorse::{
@@ -24,6 +29,10 @@ use seahorse::{
 };
 use std::path::PathBuf;
 use std::process::exit;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 use surf_disco::Url;
 
 #[derive(Parser)]
@@ -47,6 +56,29 @@ pub struct Args {
     #[arg(long, conflicts_with("storage"), hide(true))]
     pub tmp_storage: bool,
 
+    /// Use the keystore of the named profile in --profiles-dir.
+    ///
+    /// The profile is created if it does not exist. This option is mutually exclusive with
+    /// --storage.
+    #[arg(
+        long,
+        env = "ESPRESSO_KEYSTORE_PROFILE",
+        conflicts_with_all(["storage", "tmp_storage"]),
+        requires("profiles_dir")
+    )]
+    pub profile: Option<String>,
+
+    /// Directory containing keystore profiles.
+    #[arg(long, env = "ESPRESSO_KEYSTORE_PROFILES_DIR")]
+    pub profiles_dir: Option<PathBuf>,
+
+    /// Open the keystore even if another process has it open.
+    ///
+    /// Two processes using the same keystore will corrupt its storage. This is only meant for
+    /// recovering from a process which is stuck holding the keystore.
+    #[arg(long)]
+    pub force_unlock: bool,
+
     #[arg(long)]
     /// Run in a mode which is friendlier to automated scripting.
     ///
@@ -62,6 +94,17 @@ pub struct Args {
     )]
     pub esqs_url: Url,
 
+    /// URLs for additional query services to receive events from.
+    ///
+    /// The keystore merges the event streams from all the query services, so it keeps receiving
+    /// events if one of them goes down.
+    #[arg(
+        long = "esqs-mirror",
+        env = "ESPRESSO_ESQS_MIRRORS",
+        value_delimiter = ','
+    )]
+    pub esqs_mirrors: Vec<Url>,
+
     /// URL for the Espresso address book.
     #[arg(
         long,
@@ -77,6 +120,12 @@ pub struct Args {
         default_value = "http://localhost:50089"
     )]
     pub submit_url: Url,
+
+    /// The id of the chain this keystore is meant for.
+    ///
+    /// If given, the keystore refuses to run against services for any other chain.
+    #[arg(long, env = "ESPRESSO_CHAIN_ID")]
+    pub chain_id: Option<u16>,
 }
 
 impl CLIArgs for Args {
@@ -85,7 +134,10 @@ impl CLIArgs for Args {
     }
 
     fn storage_path(&self) -> Option<PathBuf> {
-        self.storage.clone()
+        match (&self.profile, &self.profiles_dir) {
+            (Some(profile), Some(dir)) => Profiles::new(dir).storage(profile).ok(),
+            _ => self.storage.clone(),
+        }
     }
 
     fn io(&self) -> Option<SharedIO> {
@@ -101,8 +153,27 @@ impl CLIArgs for Args {
     }
 }
 
+impl Args {
+    /// The directory the keystore is stored in, or [None] if it is stored in a temporary location.
+    ///
+    /// The CLI resolves the default location itself, after creating the backend, so this repeats
+    /// its default.
+    fn keystore_dir(&self) -> Option<PathBuf> {
+        if self.use_tmp_storage() {
+            return None;
+        }
+        self.storage_path().or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".translucence/keystore"))
+        })
+    }
+}
+
 struct EspressoCli;
 
+// Set from the command line before the keystore is loaded, since `init_loader` does not get the
+// arguments.
+static FORCE_UNLOCK: AtomicBool = AtomicBool::new(false);
+
 #[async_trait]
 impl<'a> CLI<'a> for EspressoCli {
     type Ledger = EspressoLedger;
@@ -115,26 +186,64 @@ impl<'a> CLI<'a> for EspressoCli {
         univ_param: &'a UniversalParam,
         args: Self::Args,
     ) -> Result<Self::Backend, KeystoreError<EspressoLedger>> {
-        NetworkBackend::new(
+        // Enforce the spending policy stored alongside the keystore. A temporary keystore has no
+        // policy.
+        let policy = args
+            .keystore_dir()
+            .map(|dir| SpendingPolicy::load(&dir))
+            .transpose()?;
+        let mut backend = NetworkBackend::new(
             univ_param,
             args.esqs_url,
             args.address_book_url,
             args.submit_url,
         )
-        .await
+        .await?
+        .with_query_mirrors(args.esqs_mirrors);
+        if let Some(chain_id) = args.chain_id {
+            backend = backend.with_chain_id(chain_id);
+        }
+        if let Some(policy) = policy {
+            backend = backend.with_spending_policy(Arc::new(policy));
+        }
+        Ok(backend)
     }
 
     async fn init_loader(
         storage: PathBuf,
         input: Reader,
     ) -> Result<Self::Loader, KeystoreError<Self::Ledger>> {
+        // Hold the lock on the storage until the process exits.
+        std::mem::forget(StorageLock::acquire(
+            &storage,
+            FORCE_UNLOCK.load(Ordering::Relaxed),
+        )?);
         Ok(InteractiveLoader::new(storage, input))
     }
 }
 
 #[async_std::main]
 async fn main() {
-    if let Err(err) = cli_main::<EspressoLedger, EspressoCli>(Args::parse()).await {
+    let args = Args::parse();
+    FORCE_UNLOCK.store(args.force_unlock, Ordering::Relaxed);
+    if let (Some(profile), Some(dir)) = (&args.profile, &args.profiles_dir) {
+        let profiles = Profiles::new(dir);
+        match profiles.get(profile) {
+            Ok(_) => {}
+            Err(ProfileError::NotFound { .. }) => {
+                if let Err(err) = profiles.create(profile, "") {
+                    println!("{}", err);
+                    exit(1);
+                }
+                println!("Created profile {} in {}", profile, dir.display());
+            }
+            Err(err) => {
+                println!("{}", err);
+                exit(1);
+            }
+        }
+    }
+    if let Err(err) = cli_main::<EspressoLedger, EspressoCli>(args).await {
         println!("{}", err);
         exit(1);
     }
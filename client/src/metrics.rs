@@ -0,0 +1,190 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Prometheus metrics for keystores running as services.
+//!
+//! [KeystoreMetrics] counts the work a [NetworkBackend](crate::network::NetworkBackend) does on
+//! behalf of its keystore: ledger events delivered to the keystore's event loop, submissions and
+//! resubmissions, duplicate submissions, and nullifier proof lookups. Attach a shared handle with
+//! [NetworkBackend::with_metrics](crate::network::NetworkBackend::with_metrics) before moving the
+//! backend into a keystore, and serve [KeystoreMetrics::render] from the operator's HTTP endpoint
+//! of choice.
+//!
+//! Memo decryption, viewing and proof generation happen inside seahorse's keystore, which does
+//! not expose hooks for them, so they are not counted here.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 10] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    // Cumulative counts for each bucket in [LATENCY_BUCKETS].
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&mut self.buckets) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count).unwrap();
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count).unwrap();
+        writeln!(out, "{}_sum {}", name, self.sum).unwrap();
+        writeln!(out, "{}_count {}", name, self.count).unwrap();
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct KeystoreMetrics {
+    events: AtomicU64,
+    submissions: AtomicU64,
+    resubmissions: AtomicU64,
+    duplicate_submissions: AtomicU64,
+    submit_failures: AtomicU64,
+    nullifier_cache_hits: AtomicU64,
+    submit_latency: Mutex<Histogram>,
+    nullifier_proof_latency: Mutex<Histogram>,
+}
+
+impl KeystoreMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn event_received(&self) {
+        self.events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn submitted(&self, elapsed: Duration, success: bool) {
+        self.submissions.fetch_add(1, Ordering::Relaxed);
+        if !success {
+            self.submit_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.submit_latency
+            .lock()
+            .unwrap()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub(crate) fn resubmitted(&self) {
+        self.resubmissions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn duplicate_submitted(&self) {
+        self.duplicate_submissions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn nullifier_proof_fetched(&self, elapsed: Duration) {
+        self.nullifier_proof_latency
+            .lock()
+            .unwrap()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub(crate) fn nullifier_proof_cached(&self) {
+        self.nullifier_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Render the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        counter(
+            &mut out,
+            "espresso_keystore_events_total",
+            "Ledger events delivered to the keystore.",
+            &self.events,
+        );
+        counter(
+            &mut out,
+            "espresso_keystore_submissions_total",
+            "Transactions submitted to validators.",
+            &self.submissions,
+        );
+        counter(
+            &mut out,
+            "espresso_keystore_resubmissions_total",
+            "Submission attempts retried after a transient failure.",
+            &self.resubmissions,
+        );
+        counter(
+            &mut out,
+            "espresso_keystore_duplicate_submissions_total",
+            "Submissions of transactions the validator already had pending.",
+            &self.duplicate_submissions,
+        );
+        counter(
+            &mut out,
+            "espresso_keystore_submit_failures_total",
+            "Submissions which failed after all retries.",
+            &self.submit_failures,
+        );
+        counter(
+            &mut out,
+            "espresso_keystore_nullifier_cache_hits_total",
+            "Nullifier proofs served from the shared proof cache.",
+            &self.nullifier_cache_hits,
+        );
+        self.submit_latency.lock().unwrap().render(
+            &mut out,
+            "espresso_keystore_submit_seconds",
+            "Time to submit a transaction, including retries.",
+        );
+        self.nullifier_proof_latency.lock().unwrap().render(
+            &mut out,
+            "espresso_keystore_nullifier_proof_seconds",
+            "Time to fetch a nullifier proof from the EsQS.",
+        );
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} counter", name).unwrap();
+    writeln!(out, "{} {}", name, value.load(Ordering::Relaxed)).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = KeystoreMetrics::new();
+        metrics.event_received();
+        metrics.event_received();
+        metrics.resubmitted();
+        metrics.duplicate_submitted();
+        metrics.submitted(Duration::from_millis(30), true);
+        metrics.submitted(Duration::from_secs(3), false);
+
+        let out = metrics.render();
+        assert!(out.contains("espresso_keystore_events_total 2\n"));
+        assert!(out.contains("espresso_keystore_submissions_total 2\n"));
+        assert!(out.contains("espresso_keystore_resubmissions_total 1\n"));
+        assert!(out.contains("espresso_keystore_duplicate_submissions_total 1\n"));
+        assert!(out.contains("espresso_keystore_submit_failures_total 1\n"));
+        assert!(out.contains("espresso_keystore_submit_seconds_bucket{le=\"0.05\"} 1\n"));
+        assert!(out.contains("espresso_keystore_submit_seconds_bucket{le=\"5\"} 2\n"));
+        assert!(out.contains("espresso_keystore_submit_seconds_count 2\n"));
+        assert!(out.contains("espresso_keystore_nullifier_proof_seconds_count 0\n"));
+    }
+}
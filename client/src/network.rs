@@ -1,24 +1,39 @@
 // Copyright (c) 2022 Espresso Systems (espressosys.com)
 // This file is part of the Espresso library.
 
-use address_book::{error::AddressBookError, InsertPubKey};
+use crate::{
+    admin::SubmissionControl,
+    event_log::{KeystoreLog, LogKind},
+    metrics::KeystoreMetrics,
+    perf::{PerfHistory, PerfMetric, PerformanceReport},
+    policy::SpendingPolicy,
+    proof_cache::ProofCacheClient,
+    pub_key_cache::PubKeyCache,
+};
+use address_book::{error::AddressBookError, InsertPubKey, MAX_PUBKEYS_PER_REQUEST};
+use async_std::future::timeout;
 use async_std::sync::Arc;
+use async_std::task::sleep;
 use async_trait::async_trait;
-use espresso_availability_api::query_data::StateQueryData;
+use espresso_availability_api::query_data::{RecordProofQueryData, StateQueryData};
 use espresso_core::{
     ledger::EspressoLedger,
+    mempool::{note_weight, FeeSchedule},
     set_merkle_tree::{SetMerkleProof, SetMerkleTree},
-    state::ElaboratedTransaction,
-    universal_params::MERKLE_HEIGHT,
+    snapshot::SignedSnapshot,
+    state::{ElaboratedTransaction, LedgerStateCommitment, TransactionCommitment, ValidatorState},
+    universal_params::prover_keys_for,
+    StakingKey,
 };
 use espresso_esqs::ApiError;
 use espresso_metastate_api::api::NullifierCheck;
-use futures::future::ready;
+use espresso_validator_api::api as validator;
 use futures::prelude::*;
+use futures::stream;
 use jf_cap::keys::{UserAddress, UserKeyPair, UserPubKey};
-use jf_cap::proof::{freeze::FreezeProvingKey, transfer::TransferProvingKey, UniversalParam};
-use jf_cap::structs::Nullifier;
-use jf_cap::MerkleTree;
+use jf_cap::proof::UniversalParam;
+use jf_cap::structs::{NoteType, Nullifier, ReceiverMemo};
+use jf_cap::{MerkleCommitment, MerkleLeafProof, MerkleTree, Signature};
 use key_set::{ProverKeySet, SizedKey};
 use reef::Ledger;
 use seahorse::transactions::Transaction;
@@ -30,15 +45,85 @@ use seahorse::{
 };
 use serde::{de::DeserializeOwned, Serialize};
 use snafu::ResultExt;
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::ops::Range;
 use std::pin::Pin;
-use std::time::Duration;
-use surf_disco::{Client, Url};
+use std::time::{Duration, Instant};
+use surf_disco::{Client, Error as _, StatusCode, Url};
+
+/// A disagreement between a keystore's copy of the ledger state and the network's.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StateDivergence {
+    /// The block height of the state which was compared.
+    pub block_height: u64,
+    pub local: LedgerStateCommitment,
+    pub network: LedgerStateCommitment,
+    /// The names of the fields of [ValidatorState] which differ.
+    pub fields: Vec<&'static str>,
+}
+
+/// How a [NetworkBackend] retries submissions which fail for transient reasons.
+///
+/// A submission is retried only if the validator could not be reached or failed with a server
+/// error. Submissions which the validator rejects are not retried. If the validator reports that
+/// the transaction is already pending, as it does when a retry follows an attempt whose response
+/// was lost, the submission succeeds.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// The maximum number of times to attempt each submission, including the first attempt.
+    pub max_attempts: usize,
+    /// The delay before the first retry. The delay doubles after each failed retry.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy which makes a single attempt at each submission.
+    pub fn no_retries() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    fn is_transient(status: StatusCode) -> bool {
+        status.is_server_error()
+            || status == StatusCode::RequestTimeout
+            || status == StatusCode::TooManyRequests
+    }
+}
 
 pub struct NetworkBackend<'a> {
     univ_param: &'a UniversalParam,
+    query_url: Url,
     query_client: Client<ApiError>,
+    query_mirrors: Vec<Url>,
     address_book_client: Client<AddressBookError>,
     validator_client: Client<ApiError>,
+    submissions: Arc<SubmissionControl>,
+    log: Arc<KeystoreLog>,
+    proof_cache: Option<Arc<ProofCacheClient>>,
+    pub_keys: Arc<PubKeyCache>,
+    perf: Arc<PerfHistory>,
+    metrics: Arc<KeystoreMetrics>,
+    separate_memos: bool,
+    retry: RetryPolicy,
+    trusted_snapshot_signers: Vec<StakingKey>,
+    spending_policy: Option<Arc<SpendingPolicy>>,
+    chain_id: Option<u16>,
+    // Whether the EsQS has been confirmed to serve the chain `chain_id`.
+    chain_id_checked: bool,
 }
 
 impl<'a> NetworkBackend<'a> {
@@ -49,15 +134,501 @@ impl<'a> NetworkBackend<'a> {
         validator_url: Url,
     ) -> Result<NetworkBackend<'a>, KeystoreError<EspressoLedger>> {
         let backend = Self {
-            query_client: Self::client(query_url),
+            query_client: Self::client(query_url.clone()),
+            query_url,
+            query_mirrors: Vec::new(),
             address_book_client: Self::client(address_book_url),
             validator_client: Self::client(validator_url),
+            submissions: Default::default(),
+            log: Default::default(),
+            proof_cache: None,
+            pub_keys: Default::default(),
+            perf: Default::default(),
+            metrics: Default::default(),
+            separate_memos: false,
+            retry: Default::default(),
+            trusted_snapshot_signers: Vec::new(),
+            spending_policy: None,
+            chain_id: None,
+            chain_id_checked: false,
             univ_param,
         };
         backend.wait_for_esqs().await?;
         Ok(backend)
     }
 
+    /// Use `control` to pause and resume submissions through this backend.
+    ///
+    /// The control is shared, so the caller can keep a reference to it after moving the backend
+    /// into a keystore.
+    pub fn with_submission_control(mut self, control: Arc<SubmissionControl>) -> Self {
+        self.submissions = control;
+        self
+    }
+
+    /// A handle which can be used to pause and resume submissions through this backend.
+    pub fn submission_control(&self) -> Arc<SubmissionControl> {
+        self.submissions.clone()
+    }
+
+    /// Record background failures in `log` instead of the default in-memory log.
+    pub fn with_log(mut self, log: Arc<KeystoreLog>) -> Self {
+        self.log = log;
+        self
+    }
+
+    /// The log of background failures observed by this backend.
+    pub fn log(&self) -> Arc<KeystoreLog> {
+        self.log.clone()
+    }
+
+    /// Share nullifier proofs and record Merkle paths with other keystores on this host through
+    /// `cache`.
+    pub fn with_proof_cache(mut self, cache: Arc<ProofCacheClient>) -> Self {
+        self.proof_cache = Some(cache);
+        self
+    }
+
+    /// Cache public keys fetched from the address book in `cache` instead of the default cache.
+    ///
+    /// The cache can be shared between keystores, so that each receiver's key is only fetched
+    /// once per process.
+    pub fn with_pub_key_cache(mut self, cache: Arc<PubKeyCache>) -> Self {
+        self.pub_keys = cache;
+        self
+    }
+
+    /// Record performance samples in `perf` instead of the default in-memory history.
+    pub fn with_perf_history(mut self, perf: Arc<PerfHistory>) -> Self {
+        self.perf = perf;
+        self
+    }
+
+    /// Summarize the performance of this backend over the last `window`.
+    pub fn performance_report(&self, window: Duration) -> PerformanceReport {
+        self.perf.report(window)
+    }
+
+    /// Count the work done by this backend in `metrics`.
+    ///
+    /// The metrics are shared, so the caller can keep a reference to render them after moving the
+    /// backend into a keystore.
+    pub fn with_metrics(mut self, metrics: Arc<KeystoreMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Publish receiver memos on the EsQS memo bulletin board instead of bundling them with
+    /// transactions.
+    ///
+    /// When enabled, submitted transactions carry no memos. Once a transaction is committed, its
+    /// memos are posted separately, which keeps them out of the consensus payload.
+    pub fn with_separate_memos(mut self, enabled: bool) -> Self {
+        self.separate_memos = enabled;
+        self
+    }
+
+    /// Retry failed submissions according to `policy`.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Bootstrap new keystores from state snapshots signed by one of `signers`.
+    ///
+    /// By default, a new keystore starts from the latest state reported by the EsQS, which it
+    /// trusts implicitly. With trusted signers, the EsQS must instead serve a snapshot signed by
+    /// one of the given staking keys, and creation fails if the snapshot does not verify.
+    pub fn with_trusted_snapshot_signers(mut self, signers: Vec<StakingKey>) -> Self {
+        self.trusted_snapshot_signers = signers;
+        self
+    }
+
+    /// Check every transaction against `policy` before submitting it.
+    ///
+    /// Transactions which violate the policy fail without being submitted, and the spending of
+    /// submitted transactions counts towards the policy's limits. The policy is shared, so a
+    /// frontend can keep a reference to it to check transactions before building them.
+    pub fn with_spending_policy(mut self, policy: Arc<SpendingPolicy>) -> Self {
+        self.spending_policy = Some(policy);
+        self
+    }
+
+    /// Only create keystores for, and submit transactions to, the chain with id `chain_id`.
+    ///
+    /// This prevents a keystore configured for one network (say, a testnet) from being pointed at
+    /// the services for another by mistake. The chain id is part of the genesis block, so it is
+    /// checked against the state reported by the EsQS.
+    pub fn with_chain_id(mut self, chain_id: u16) -> Self {
+        self.chain_id = Some(chain_id);
+        self.chain_id_checked = false;
+        self
+    }
+
+    /// Follow the event stream from the query services at `urls` as well as the primary one.
+    ///
+    /// Each keystore subscription connects to every query service and merges their event streams,
+    /// so events keep arriving if one of the services goes down or falls behind. The mirrors are
+    /// only used for events; all other queries go to the primary query service.
+    pub fn with_query_mirrors(mut self, urls: impl IntoIterator<Item = Url>) -> Self {
+        self.query_mirrors = urls.into_iter().collect();
+        self
+    }
+
+    /// Look up the public keys of several receivers at once.
+    ///
+    /// Keys which are not cached are fetched from the address book, in as few requests as the
+    /// address book allows. Fails if any of the addresses has no public key, or if the address book
+    /// returns a key which does not belong to the address it was requested for.
+    pub async fn get_public_keys(
+        &self,
+        addresses: &[UserAddress],
+    ) -> Result<Vec<UserPubKey>, KeystoreError<EspressoLedger>> {
+        let mut keys = addresses
+            .iter()
+            .map(|address| self.pub_keys.get(address))
+            .collect::<Vec<_>>();
+        let missing = addresses
+            .iter()
+            .zip(&keys)
+            .filter(|(_, key)| key.is_none())
+            .map(|(address, _)| address.clone())
+            .collect::<Vec<_>>();
+        let mut fetched = Vec::with_capacity(missing.len());
+        for chunk in missing.chunks(MAX_PUBKEYS_PER_REQUEST) {
+            let response = self
+                .address_book_client
+                .post::<Vec<Option<UserPubKey>>>("request_pubkeys")
+                .body_json(&chunk)
+                .unwrap()
+                .send()
+                .await
+                .map_err(|source| KeystoreError::Failed {
+                    msg: format!(
+                        "Address book request POST /request_pubkeys failed: {}",
+                        source
+                    ),
+                })?;
+            if response.len() != chunk.len() {
+                return Err(KeystoreError::Failed {
+                    msg: format!(
+                        "address book returned {} keys for {} addresses",
+                        response.len(),
+                        chunk.len()
+                    ),
+                });
+            }
+            fetched.extend(response);
+        }
+        // Check every key before caching any of them, so that a misbehaving address book cannot
+        // poison the cache.
+        let fetched = missing
+            .iter()
+            .zip(fetched)
+            .map(|(address, pub_key)| match pub_key {
+                Some(pub_key) => check_pub_key(address, pub_key),
+                None => Err(KeystoreError::Failed {
+                    msg: format!("no public key registered for address {}", address),
+                }),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut fetched = fetched.into_iter();
+        for key in keys.iter_mut().filter(|key| key.is_none()) {
+            let pub_key = fetched.next().unwrap();
+            self.pub_keys.insert(pub_key.clone());
+            *key = Some(pub_key);
+        }
+        Ok(keys.into_iter().map(Option::unwrap).collect())
+    }
+
+    /// Fetch a Merkle path for the record with global UID `uid`, relative to `root`.
+    ///
+    /// A keystore which has forgotten the Merkle path for one of its records can use this to get
+    /// the witness back from the query service and `remember` it, rather than failing to build a
+    /// transaction. The query service only serves paths relative to its latest state, so this
+    /// fails if the service is at a different state than `root`; the caller should catch up to the
+    /// latest state and try again. With a [proof cache](Self::with_proof_cache), paths fetched by
+    /// any keystore on the host are served from the cache.
+    pub async fn get_merkle_path(
+        &self,
+        uid: u64,
+        root: &MerkleCommitment,
+    ) -> Result<MerkleLeafProof, KeystoreError<EspressoLedger>> {
+        self.fetch_merkle_path(format!("/availability/getrecordproof/{}", uid), uid, root)
+            .await
+    }
+
+    /// Fetch a Merkle path for the record with global UID `uid`, relative to `root`, which is the
+    /// record Merkle root after block `block_id`.
+    ///
+    /// Validators accept transactions built against any recent record Merkle root, so a
+    /// transaction built against a slightly stale root can have its witnesses rebuilt for that
+    /// root, instead of being rebuilt from scratch against the latest one. The query service only
+    /// serves paths for roots recent enough to still be accepted.
+    pub async fn get_merkle_path_at(
+        &self,
+        uid: u64,
+        block_id: u64,
+        root: &MerkleCommitment,
+    ) -> Result<MerkleLeafProof, KeystoreError<EspressoLedger>> {
+        self.fetch_merkle_path(
+            format!("/availability/getrecordproof/{}/{}", uid, block_id),
+            uid,
+            root,
+        )
+        .await
+    }
+
+    async fn fetch_merkle_path(
+        &self,
+        route: String,
+        uid: u64,
+        root: &MerkleCommitment,
+    ) -> Result<MerkleLeafProof, KeystoreError<EspressoLedger>> {
+        if let Some(cache) = &self.proof_cache {
+            match cache.get_record_path(*root, uid).await {
+                Ok(Some(proof)) => return Ok(proof),
+                Ok(None) => {}
+                // The cache is only an optimization, so fall back to the query service.
+                Err(err) => self.log.warn(LogKind::Request, err.to_string()),
+            }
+        }
+        let RecordProofQueryData {
+            proof,
+            merkle_commitment,
+            block_id,
+            ..
+        } = self.get(route).await?;
+        if merkle_commitment != *root {
+            return Err(KeystoreError::Failed {
+                msg: format!(
+                    "Merkle path for record {} is relative to the state after block {}, \
+                    which does not match the requested root",
+                    uid, block_id
+                ),
+            });
+        }
+        if MerkleTree::check_proof(root.root_value, uid, &proof).is_err() {
+            let msg = format!(
+                "query service returned an invalid Merkle path for record {}",
+                uid
+            );
+            self.log.error(LogKind::Request, &msg);
+            return Err(KeystoreError::Failed { msg });
+        }
+        if let Some(cache) = &self.proof_cache {
+            if let Err(err) = cache.put_record_path(*root, uid, proof.clone()).await {
+                self.log.warn(LogKind::Request, err.to_string());
+            }
+        }
+        Ok(proof)
+    }
+
+    /// The transfer arities, as `(inputs, outputs)`, which the chain accepts in the next block.
+    ///
+    /// The arities can change at runtime when a verifier key upgrade takes effect (see
+    /// [espresso_core::state::VerifierKeyUpgrade]). A keystore generates its
+    /// proving keys when it is created, so if this includes arities the keystore does not have
+    /// keys for, it can use [NetworkBackend::proving_keys] to generate the new keys.
+    pub async fn supported_transfer_sizes(
+        &self,
+    ) -> Result<Vec<(usize, usize)>, KeystoreError<EspressoLedger>> {
+        transfer_sizes(&self.latest_state().await?)
+    }
+
+    /// The minimum fees the validator requires of submitted transactions.
+    pub async fn fee_schedule(&self) -> Result<FeeSchedule, KeystoreError<EspressoLedger>> {
+        self.validator_client
+            .get("/validator/fee_schedule")
+            .send()
+            .await
+            .map_err(|source| {
+                let msg = format!("request GET /validator/fee_schedule failed: {}", source);
+                self.log.warn(LogKind::Request, &msg);
+                KeystoreError::Failed { msg }
+            })
+    }
+
+    /// The minimum fee the validator accepts for a note of type `note_type` with `inputs` inputs
+    /// and `outputs` outputs.
+    ///
+    /// The fee input and the change output of a transaction count towards its size, so a keystore
+    /// estimating the fee for a transfer should include them.
+    pub async fn estimate_fee(
+        &self,
+        note_type: NoteType,
+        inputs: usize,
+        outputs: usize,
+    ) -> Result<u128, KeystoreError<EspressoLedger>> {
+        Ok(self
+            .fee_schedule()
+            .await?
+            .min_fee_for_weight(note_weight(note_type, inputs, outputs)))
+    }
+
+    /// Generate proving keys for every arity the chain accepts in the next block.
+    pub async fn proving_keys(&self) -> Result<ProverKeySet<'a>, KeystoreError<EspressoLedger>> {
+        let state = self.latest_state().await?;
+        let verif_crs = state
+            .chain
+            .verif_crs_at(state.block_height)
+            .context(CryptoSnafu)?;
+        prover_keys_for(self.univ_param, &verif_crs).context(CryptoSnafu)
+    }
+
+    async fn latest_state(&self) -> Result<ValidatorState, KeystoreError<EspressoLedger>> {
+        let block_id: u64 = self.get("status/latest_block_id").await?;
+        let snapshot: StateQueryData = self
+            .get(format!("availability/getstate/{}", block_id))
+            .await?;
+        Ok(snapshot.state)
+    }
+
+    /// The commitment to the ledger state at block height `height`, that is, the state after the
+    /// first `height` blocks.
+    pub async fn get_state_commitment(
+        &self,
+        height: u64,
+    ) -> Result<LedgerStateCommitment, KeystoreError<EspressoLedger>> {
+        if height == 0 {
+            // The pre-genesis state was not created by any block, so the EsQS does not store it.
+            return Ok(ValidatorState::default().commit());
+        }
+        self.get(format!("availability/getstatecomm/{}", height - 1))
+            .await
+    }
+
+    /// Cross-check a keystore's copy of the ledger state against the network's.
+    ///
+    /// A keystore mirrors the validator state by applying the blocks it receives, so a missed or
+    /// misapplied event makes its copy drift from the network's without any immediate error. This
+    /// compares `local` with the network's state at the same height, and describes the difference
+    /// if they disagree. Divergences are also recorded in the keystore log.
+    pub async fn check_state(
+        &self,
+        local: &ValidatorState,
+    ) -> Result<Option<StateDivergence>, KeystoreError<EspressoLedger>> {
+        let network = self.get_state_commitment(local.block_height).await?;
+        let local_commitment = local.commit();
+        if network == local_commitment {
+            return Ok(None);
+        }
+        // Fetch the full state to find out which fields differ.
+        let fields = if local.block_height == 0 {
+            local.diff(&ValidatorState::default())
+        } else {
+            let snapshot: StateQueryData = self
+                .get(format!("availability/getstate/{}", local.block_height - 1))
+                .await?;
+            local.diff(&snapshot.state)
+        };
+        let divergence = StateDivergence {
+            block_height: local.block_height,
+            local: local_commitment,
+            network,
+            fields,
+        };
+        self.log.error(
+            LogKind::StateDivergence,
+            format!(
+                "local ledger state at height {} diverges from the network in {:?}",
+                divergence.block_height, divergence.fields
+            ),
+        );
+        Ok(Some(divergence))
+    }
+
+    /// The event index a keystore must reach to have processed the block which brought the ledger
+    /// to height `height`.
+    ///
+    /// Waits up to `timeout` for that block to be committed if it has not been yet, and fails if it
+    /// is still missing after that. A keystore synced to the result (see `Keystore::sync`) reflects
+    /// every block up to and including it, so applications can use this to wait until the ledger
+    /// has included a particular block.
+    pub async fn event_index_at_height(
+        &self,
+        height: u64,
+        timeout: Duration,
+    ) -> Result<EventIndex, KeystoreError<EspressoLedger>> {
+        if height == 0 {
+            return Ok(EventIndex::from_source(EventSource::QueryService, 0));
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let latest: u64 = self.get("status/latest_block_id").await?;
+            if latest + 1 >= height {
+                break;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(KeystoreError::Failed {
+                    msg: format!(
+                        "timed out after {:?} waiting for height {} (current height {})",
+                        timeout,
+                        height,
+                        latest + 1
+                    ),
+                });
+            }
+            sleep(min(HEIGHT_POLL_INTERVAL, deadline - now)).await;
+        }
+        self.continuation_event_index(height - 1).await
+    }
+
+    /// The event index a keystore must reach to have processed the block which produced the state
+    /// with commitment `commitment`.
+    ///
+    /// A commitment does not reveal the height of its state, so this searches the `depth` most
+    /// recent states, and returns [None] if none of them match. In that case the state is either
+    /// older, or has not been committed yet.
+    pub async fn event_index_at_commitment(
+        &self,
+        commitment: &LedgerStateCommitment,
+        depth: u64,
+    ) -> Result<Option<EventIndex>, KeystoreError<EspressoLedger>> {
+        let latest: u64 = self.get("status/latest_block_id").await?;
+        for block_id in (0..=latest).rev().take(depth as usize) {
+            let state_commitment: LedgerStateCommitment = self
+                .get(format!("availability/getstatecomm/{}", block_id))
+                .await?;
+            if state_commitment == *commitment {
+                return self.continuation_event_index(block_id).await.map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    /// The index of the first event following the committed block `block_id`.
+    async fn continuation_event_index(
+        &self,
+        block_id: u64,
+    ) -> Result<EventIndex, KeystoreError<EspressoLedger>> {
+        let snapshot: StateQueryData = self
+            .get(format!("availability/getstate/{}", block_id))
+            .await?;
+        Ok(EventIndex::from_source(
+            EventSource::QueryService,
+            snapshot.continuation_event_index as usize,
+        ))
+    }
+
+    /// Fetch the events with indices in `range` directly from the EsQS.
+    ///
+    /// An event is [None] if the EsQS does not have it. The result may be shorter than `range` if
+    /// the EsQS has not received all the requested events yet.
+    pub async fn fetch_events(
+        &self,
+        range: Range<usize>,
+    ) -> Result<Vec<Option<LedgerEvent<EspressoLedger>>>, KeystoreError<EspressoLedger>> {
+        fetch_events(&self.query_client, range.clone())
+            .await
+            .map_err(|err| {
+                let msg = format!("failed to fetch events {:?}: {}", range, err);
+                self.log.warn(LogKind::Request, &msg);
+                KeystoreError::Failed { msg }
+            })
+    }
+
     async fn get<T: DeserializeOwned>(
         &self,
         uri: impl AsRef<str>,
@@ -66,8 +637,10 @@ impl<'a> NetworkBackend<'a> {
             .get(uri.as_ref())
             .send()
             .await
-            .map_err(|source| KeystoreError::Failed {
-                msg: format!("EsQS request GET {} failed: {}", uri.as_ref(), source),
+            .map_err(|source| {
+                let msg = format!("EsQS request GET {} failed: {}", uri.as_ref(), source);
+                self.log.warn(LogKind::Request, &msg);
+                KeystoreError::Failed { msg }
             })
     }
 
@@ -89,6 +662,132 @@ impl<'a> NetworkBackend<'a> {
             })
     }
 
+    async fn submit_with_retries(
+        &self,
+        txn: &ElaboratedTransaction,
+    ) -> Result<(), KeystoreError<EspressoLedger>> {
+        let mut backoff = self.retry.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            let res = self
+                .validator_client
+                .post::<()>("/validator/submit")
+                .body_binary(txn)
+                .map_err(|source| KeystoreError::Failed {
+                    msg: format!("failed to build request POST /validator/submit: {}", source),
+                })?
+                .send()
+                .await;
+            match res {
+                Ok(()) => return Ok(()),
+                Err(ApiError::Validator {
+                    source: validator::Error::AlreadyPending { hash },
+                }) if hash == TransactionCommitment(txn.txn.hash()) => {
+                    // The validator already has this transaction pending, most likely because an
+                    // earlier attempt succeeded but its response was lost. Either way, the
+                    // transaction is on its way to a block, which is all the caller asked for. Any
+                    // other conflict is not about this transaction, so it falls through to the
+                    // non-retryable error case below.
+                    self.log.info(
+                        LogKind::Resubmission,
+                        format!(
+                            "transaction {} is already pending (attempt {}/{})",
+                            txn.txn.hash(),
+                            attempt,
+                            self.retry.max_attempts
+                        ),
+                    );
+                    self.metrics.duplicate_submitted();
+                    return Ok(());
+                }
+                Err(err)
+                    if attempt < self.retry.max_attempts
+                        && RetryPolicy::is_transient(err.status()) =>
+                {
+                    self.log.warn(
+                        LogKind::Resubmission,
+                        format!(
+                            "submission of transaction {} failed (attempt {}/{}), \
+                            retrying in {:?}: {}",
+                            txn.txn.hash(),
+                            attempt,
+                            self.retry.max_attempts,
+                            backoff,
+                            err
+                        ),
+                    );
+                    self.metrics.resubmitted();
+                    sleep(backoff).await;
+                    backoff = min(backoff * 2, self.retry.max_backoff);
+                    attempt += 1;
+                }
+                Err(err) => {
+                    return Err(KeystoreError::Failed {
+                        msg: format!(
+                            "request POST /validator/submit failed after {} attempts: {}",
+                            attempt, err
+                        ),
+                    })
+                }
+            }
+        }
+    }
+
+    fn check_chain_id(
+        &mut self,
+        state: &ValidatorState,
+    ) -> Result<(), KeystoreError<EspressoLedger>> {
+        match self.chain_id {
+            Some(expected) if state.chain.chain_id != expected => Err(KeystoreError::Failed {
+                msg: format!(
+                    "wrong network: expected chain id {}, but the query service is following \
+                    chain {}",
+                    expected, state.chain.chain_id
+                ),
+            }),
+            _ => {
+                self.chain_id_checked = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Fail unless the EsQS is following the chain `chain_id`, if one was configured.
+    ///
+    /// The EsQS is only asked once; after that the result is remembered.
+    async fn ensure_chain_id(&mut self) -> Result<(), KeystoreError<EspressoLedger>> {
+        if self.chain_id.is_some() && !self.chain_id_checked {
+            let state = self.latest_state().await?;
+            self.check_chain_id(&state)?;
+        }
+        Ok(())
+    }
+
+    /// Publish the memos for the committed transaction `txn_id` in block `block_id`.
+    ///
+    /// Failures are logged rather than returned, since the transaction itself has already been
+    /// committed.
+    async fn post_memos(
+        &self,
+        block_id: u64,
+        txn_id: u64,
+        memos: Vec<ReceiverMemo>,
+        sig: Signature,
+    ) {
+        if let Err(err) = Self::post(
+            &self.query_client,
+            format!("catchup/post_memos/{}/{}", block_id, txn_id),
+            &(memos, sig),
+        )
+        .await
+        {
+            self.log.error(
+                LogKind::SubmitFailed,
+                format!("failed to post memos for {}/{}: {}", block_id, txn_id, err),
+            );
+        }
+    }
+
     async fn wait_for_esqs(&self) -> Result<(), KeystoreError<EspressoLedger>> {
         let timeout = Duration::from_secs(300);
         if self.query_client.connect(Some(timeout)).await {
@@ -100,13 +799,429 @@ impl<'a> NetworkBackend<'a> {
         }
     }
 
-    fn client<E: surf_disco::Error>(url: Url) -> Client<E> {
+    async fn cached_nullifier_proof(
+        &self,
+        set: &SetMerkleTree,
+        nullifier: Nullifier,
+    ) -> Option<(bool, SetMerkleProof)> {
+        match self.proof_cache.as_ref()?.get(set.hash(), nullifier).await {
+            Ok(res) => res,
+            Err(err) => {
+                // The cache is only an optimization, so fall back to the query service.
+                self.log.warn(LogKind::Request, err.to_string());
+                None
+            }
+        }
+    }
+
+    pub(crate) fn client<E: surf_disco::Error>(url: Url) -> Client<E> {
         Client::builder(url)
             .set_timeout(Some(Duration::from_secs(5 * 60)))
             .build()
     }
 }
 
+/// The transfer arities, as `(inputs, outputs)`, which the chain accepts in the next block,
+/// according to the EsQS at `query_url`.
+///
+/// This is the same as [NetworkBackend::supported_transfer_sizes], for callers whose backend has
+/// already been moved into a keystore.
+pub async fn fetch_supported_transfer_sizes(
+    query_url: Url,
+) -> Result<Vec<(usize, usize)>, KeystoreError<EspressoLedger>> {
+    let client = NetworkBackend::client::<ApiError>(query_url);
+    let failed = |source: ApiError| KeystoreError::Failed {
+        msg: format!("failed to fetch the latest state from the EsQS: {}", source),
+    };
+    let block_id: u64 = client
+        .get("status/latest_block_id")
+        .send()
+        .await
+        .map_err(failed)?;
+    let snapshot: StateQueryData = client
+        .get(&format!("availability/getstate/{}", block_id))
+        .send()
+        .await
+        .map_err(failed)?;
+    transfer_sizes(&snapshot.state)
+}
+
+fn transfer_sizes(
+    state: &ValidatorState,
+) -> Result<Vec<(usize, usize)>, KeystoreError<EspressoLedger>> {
+    Ok(state
+        .chain
+        .verif_crs_at(state.block_height)
+        .context(CryptoSnafu)?
+        .xfr
+        .iter()
+        .map(|k| (k.num_inputs(), k.num_outputs()))
+        .collect())
+}
+
+/// Check that a key returned by the address book for `address` actually belongs to `address`.
+fn check_pub_key(
+    address: &UserAddress,
+    pub_key: UserPubKey,
+) -> Result<UserPubKey, KeystoreError<EspressoLedger>> {
+    if pub_key.address() == *address {
+        Ok(pub_key)
+    } else {
+        Err(KeystoreError::Failed {
+            msg: format!(
+                "address book returned a public key for {} instead of {}",
+                pub_key.address(),
+                address
+            ),
+        })
+    }
+}
+
+const HEIGHT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+const MIRROR_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+type IndexedEvent = (usize, Option<LedgerEvent<EspressoLedger>>);
+type EventConnection = Pin<Box<dyn Send + Stream<Item = Result<IndexedEvent, String>>>>;
+
+/// Fetch the events with indices in `range` from the EsQS.
+///
+/// The result may be shorter than `range` if the EsQS does not have all the requested events yet.
+async fn fetch_events(
+    client: &Client<ApiError>,
+    range: Range<usize>,
+) -> Result<Vec<Option<LedgerEvent<EspressoLedger>>>, String> {
+    client
+        .get(&format!(
+            "catchup/get_events_since/{}/{}",
+            range.start,
+            range.len()
+        ))
+        .send()
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// Reorders indexed items into a sequence with no gaps or duplicates.
+#[derive(Debug)]
+struct Sequencer<T> {
+    next: usize,
+    buffer: BTreeMap<usize, T>,
+}
+
+impl<T> Sequencer<T> {
+    fn new(next: usize) -> Self {
+        Self {
+            next,
+            buffer: BTreeMap::new(),
+        }
+    }
+
+    /// Buffer the item at `index`.
+    ///
+    /// Returns `false` if an item with this index has already been delivered or buffered.
+    fn insert(&mut self, index: usize, item: T) -> bool {
+        if index < self.next || self.buffer.contains_key(&index) {
+            return false;
+        }
+        self.buffer.insert(index, item);
+        true
+    }
+
+    /// The next item in the sequence, if it has been received.
+    fn pop(&mut self) -> Option<T> {
+        let item = self.buffer.remove(&self.next)?;
+        self.next += 1;
+        Some(item)
+    }
+
+    /// The indices of the missing items preceding the earliest buffered item, if there are any.
+    fn missing(&self) -> Option<Range<usize>> {
+        let first = *self.buffer.keys().next()?;
+        if first > self.next {
+            Some(self.next..first)
+        } else {
+            None
+        }
+    }
+}
+
+/// The state of an event subscription to the EsQS.
+///
+/// The EsQS tags each event with its index. The subscription discards events it has already seen,
+/// buffers events which arrive ahead of their turn, and fetches any missing ranges with a separate
+/// request, so the keystore never sees gaps or duplicates in the event stream. Whenever the
+/// connection fails, or the server sends something we can't interpret, the connection is dropped
+/// and a new one is opened starting from the next expected index. Reconnection attempts back off
+/// exponentially until the server delivers events again. An event the EsQS reports it does not have
+/// is fetched again, from any mirror, until one has it.
+///
+/// If the backend has mirrors (see [NetworkBackend::with_query_mirrors]) the subscription holds a
+/// connection to each query service at once and merges their streams, so the keystore keeps
+/// receiving events as long as any one of them is reachable. A mirror which has not kept up with
+/// the latest event for [MIRROR_STALL_TIMEOUT], while another mirror has, is assumed to be stuck
+/// and is reconnected from the next expected index.
+struct Subscription {
+    mirrors: Vec<Mirror>,
+    log: Arc<KeystoreLog>,
+    events: Sequencer<Option<LedgerEvent<EspressoLedger>>>,
+    to: Option<usize>,
+    backoff: Duration,
+}
+
+/// One query service feeding a [Subscription].
+struct Mirror {
+    url: Url,
+    client: Client<ApiError>,
+    conn: Option<EventConnection>,
+    // The last time this mirror delivered an event we had not already seen.
+    last_progress: Instant,
+    // When to next try to connect, if the last connection failed or was dropped.
+    retry_at: Option<Instant>,
+    // Reset only once the mirror makes progress, so that a server which accepts connections and
+    // then immediately drops them is not reconnected to in a loop.
+    backoff: Duration,
+}
+
+impl Mirror {
+    fn new(url: Url) -> Self {
+        Self {
+            client: NetworkBackend::client(url.clone()),
+            url,
+            conn: None,
+            last_progress: Instant::now(),
+            retry_at: None,
+            backoff: MIN_RECONNECT_BACKOFF,
+        }
+    }
+
+    /// Drop the connection, if any, and wait out the backoff before reconnecting.
+    fn disconnect(&mut self) {
+        self.conn = None;
+        self.retry_at = Some(Instant::now() + self.backoff);
+        self.backoff = min(self.backoff * 2, MAX_RECONNECT_BACKOFF);
+    }
+}
+
+impl Subscription {
+    async fn next_event(&mut self) -> Option<LedgerEvent<EspressoLedger>> {
+        loop {
+            if matches!(self.to, Some(to) if self.events.next >= to) {
+                return None;
+            }
+            if let Some(event) = self.events.pop() {
+                let event = match event {
+                    Some(event) => event,
+                    None => self.fetch_missing_event(self.events.next - 1).await,
+                };
+                self.backoff = MIN_RECONNECT_BACKOFF;
+                if let LedgerEvent::Reject { error, .. } = &event {
+                    self.log.error(
+                        LogKind::InvalidBlock,
+                        format!("received invalid block: {}", error),
+                    );
+                }
+                return Some(event);
+            }
+            if let Some(missing) = self.events.missing() {
+                self.fill_gap(missing).await;
+                continue;
+            }
+            if !self.connect().await {
+                continue;
+            }
+
+            // Wait for the next message from any connected mirror. We wake up periodically even if
+            // no messages arrive, to retry mirrors which are disconnected.
+            let (i, msg) = match timeout(
+                MIRROR_STALL_TIMEOUT,
+                future::select_all(self.mirrors.iter_mut().enumerate().filter_map(
+                    |(i, mirror)| {
+                        let conn = mirror.conn.as_mut()?;
+                        Some(conn.next().map(move |msg| (i, msg)))
+                    },
+                )),
+            )
+            .await
+            {
+                Ok(((i, msg), _, _)) => (i, msg),
+                Err(_) => continue,
+            };
+            match msg {
+                Some(Ok((index, event))) => {
+                    // A mirror which delivers the latest event is keeping up, even if another
+                    // mirror delivered it first.
+                    if index + 1 >= self.events.next {
+                        self.mirrors[i].last_progress = Instant::now();
+                        self.mirrors[i].backoff = MIN_RECONNECT_BACKOFF;
+                    }
+                    if !self.events.insert(index, event) {
+                        self.log.info(
+                            LogKind::EventStream,
+                            format!("discarding duplicate event {}", index),
+                        );
+                    }
+                    self.drop_stalled_mirrors();
+                }
+                Some(Err(err)) => {
+                    self.log.error(
+                        LogKind::EventStream,
+                        format!(
+                            "error in event stream from {} at event {}, reconnecting in {:?}: {}",
+                            self.mirrors[i].url, self.events.next, self.mirrors[i].backoff, err
+                        ),
+                    );
+                    self.mirrors[i].disconnect();
+                }
+                None => {
+                    self.log.warn(
+                        LogKind::EventStream,
+                        format!(
+                            "event stream from {} closed at event {}, reconnecting in {:?}",
+                            self.mirrors[i].url, self.events.next, self.mirrors[i].backoff
+                        ),
+                    );
+                    self.mirrors[i].disconnect();
+                }
+            }
+        }
+    }
+
+    async fn fill_gap(&mut self, missing: Range<usize>) {
+        self.log.warn(
+            LogKind::EventStream,
+            format!("event stream skipped events {:?}, fetching them", missing),
+        );
+        let mut errors = vec![];
+        for mirror in &self.mirrors {
+            match fetch_events(&mirror.client, missing.clone()).await {
+                Ok(events) if !events.is_empty() => {
+                    for (i, event) in events.into_iter().enumerate() {
+                        self.events.insert(missing.start + i, event);
+                    }
+                    return;
+                }
+                res => errors.push(res.err().unwrap_or_else(|| "no events returned".into())),
+            }
+        }
+        self.log.warn(
+            LogKind::EventStream,
+            format!(
+                "failed to fetch events {:?}, retrying in {:?}: {}",
+                missing,
+                self.backoff,
+                errors.join("; ")
+            ),
+        );
+        sleep(self.backoff).await;
+        self.backoff = min(self.backoff * 2, MAX_RECONNECT_BACKOFF);
+    }
+
+    /// Fetch the event at `index`, which the mirror that delivered it did not have.
+    ///
+    /// The keystore counts events to track its position in the stream, so the event can be neither
+    /// skipped nor replaced. It is fetched from whichever mirror has it, waiting with backoff until
+    /// one does, for example once the EsQS fills in a block it missed.
+    async fn fetch_missing_event(&mut self, index: usize) -> LedgerEvent<EspressoLedger> {
+        loop {
+            for mirror in &self.mirrors {
+                if let Ok(events) = fetch_events(&mirror.client, index..index + 1).await {
+                    if let Some(Some(event)) = events.into_iter().next() {
+                        return event;
+                    }
+                }
+            }
+            self.log.error(
+                LogKind::EventStream,
+                format!(
+                    "event {} is missing from every query service, retrying in {:?}",
+                    index, self.backoff
+                ),
+            );
+            sleep(self.backoff).await;
+            self.backoff = min(self.backoff * 2, MAX_RECONNECT_BACKOFF);
+        }
+    }
+
+    /// Disconnect mirrors which have fallen behind the others, so they resubscribe from the next
+    /// expected event.
+    fn drop_stalled_mirrors(&mut self) {
+        if !self
+            .mirrors
+            .iter()
+            .any(|mirror| mirror.last_progress.elapsed() <= MIRROR_STALL_TIMEOUT)
+        {
+            // No one is making progress, the chain is probably just idle.
+            return;
+        }
+        for mirror in &mut self.mirrors {
+            if mirror.conn.is_some() && mirror.last_progress.elapsed() > MIRROR_STALL_TIMEOUT {
+                self.log.warn(
+                    LogKind::EventStream,
+                    format!(
+                        "query service {} is behind, resubscribing from event {}",
+                        mirror.url, self.events.next
+                    ),
+                );
+                mirror.disconnect();
+            }
+        }
+    }
+
+    /// Try to connect each disconnected mirror which is not backing off.
+    ///
+    /// Returns `true` if at least one mirror is connected. Otherwise, waits until it is time to
+    /// retry one of the mirrors and returns `false`.
+    async fn connect(&mut self) -> bool {
+        let now = Instant::now();
+        for mirror in &mut self.mirrors {
+            if mirror.conn.is_some() || matches!(mirror.retry_at, Some(at) if at > now) {
+                continue;
+            }
+            match mirror
+                .client
+                .socket(&format!(
+                    "catchup/subscribe_for_indexed_events/{}",
+                    self.events.next
+                ))
+                .subscribe()
+                .await
+            {
+                Ok(conn) => {
+                    mirror.conn = Some(Box::pin(conn.map(|msg| -> Result<IndexedEvent, String> {
+                        msg.map_err(|err| err.to_string())
+                    })));
+                    mirror.last_progress = Instant::now();
+                    mirror.retry_at = None;
+                }
+                Err(err) => {
+                    self.log.warn(
+                        LogKind::EventStream,
+                        format!(
+                            "failed to subscribe to events from {} at {}, retrying in {:?}: {}",
+                            self.events.next, mirror.url, mirror.backoff, err
+                        ),
+                    );
+                    mirror.disconnect();
+                }
+            }
+        }
+        if self.mirrors.iter().any(|mirror| mirror.conn.is_some()) {
+            return true;
+        }
+        if let Some(retry_at) = self
+            .mirrors
+            .iter()
+            .filter_map(|mirror| mirror.retry_at)
+            .min()
+        {
+            sleep(retry_at.saturating_duration_since(Instant::now())).await;
+        }
+        false
+    }
+}
+
 #[async_trait]
 impl<'a> KeystoreBackend<'a, EspressoLedger> for NetworkBackend<'a> {
     type EventStream =
@@ -115,112 +1230,90 @@ impl<'a> KeystoreBackend<'a, EspressoLedger> for NetworkBackend<'a> {
     async fn create(
         &mut self,
     ) -> Result<LedgerState<'a, EspressoLedger>, KeystoreError<EspressoLedger>> {
-        let block_id: u64 = self.get("status/latest_block_id").await?;
-        let snapshot: StateQueryData = self
-            .get(format!("availability/getstate/{}", block_id))
-            .await?;
+        let (validator_state, continuation_event_index) =
+            if self.trusted_snapshot_signers.is_empty() {
+                let block_id: u64 = self.get("status/latest_block_id").await?;
+                let snapshot: StateQueryData = self
+                    .get(format!("availability/getstate/{}", block_id))
+                    .await?;
+                (snapshot.state, snapshot.continuation_event_index)
+            } else {
+                let snapshot: SignedSnapshot = self.get("availability/getsnapshot/latest").await?;
+                snapshot
+                    .verify(&self.trusted_snapshot_signers)
+                    .map_err(|err| KeystoreError::Failed {
+                        msg: format!("invalid state snapshot: {}", err),
+                    })?;
+                (snapshot.state, snapshot.continuation_event_index)
+            };
+        self.check_chain_id(&validator_state)?;
 
         // Construct proving keys of the same arities as the verifier keys from the validator.
-        let univ_param = self.univ_param;
-        let proving_keys = Arc::new(ProverKeySet {
-            mint: jf_cap::proof::mint::preprocess(univ_param, MERKLE_HEIGHT)
-                .context(CryptoSnafu)?
-                .0,
-            freeze: snapshot
-                .state
-                .chain
-                .verif_crs
-                .freeze
-                .iter()
-                .map(|k| {
-                    Ok::<FreezeProvingKey, KeystoreError<EspressoLedger>>(
-                        jf_cap::proof::freeze::preprocess(
-                            univ_param,
-                            k.num_inputs(),
-                            MERKLE_HEIGHT,
-                        )
-                        .context(CryptoSnafu)?
-                        .0,
-                    )
-                })
-                .collect::<Result<_, _>>()?,
-            xfr: snapshot
-                .state
-                .chain
-                .verif_crs
-                .xfr
-                .iter()
-                .map(|k| {
-                    Ok::<TransferProvingKey, KeystoreError<EspressoLedger>>(
-                        jf_cap::proof::transfer::preprocess(
-                            univ_param,
-                            k.num_inputs(),
-                            k.num_outputs(),
-                            MERKLE_HEIGHT,
-                        )
-                        .context(CryptoSnafu)?
-                        .0,
-                    )
-                })
-                .collect::<Result<_, _>>()?,
-        });
+        let proving_keys = Arc::new(
+            prover_keys_for(
+                self.univ_param,
+                &validator_state
+                    .chain
+                    .verif_crs_at(validator_state.block_height)
+                    .context(CryptoSnafu)?,
+            )
+            .context(CryptoSnafu)?,
+        );
 
         let state = LedgerState::new(
             proving_keys,
-            EventIndex::from_source(
-                EventSource::QueryService,
-                snapshot.continuation_event_index as usize,
-            ),
-            snapshot.state.clone(),
+            EventIndex::from_source(EventSource::QueryService, continuation_event_index as usize),
+            validator_state.clone(),
             LWMerkleTree::restore_from_frontier(
-                snapshot.state.record_merkle_commitment,
-                &snapshot.state.record_merkle_frontier,
+                validator_state.record_merkle_commitment,
+                &validator_state.record_merkle_frontier,
             )
             .ok_or_else(|| KeystoreError::Failed {
                 msg: "failed to restore sparse Merkle tree from frontier".to_string(),
             })?,
-            SetMerkleTree::sparse(snapshot.state.nullifiers_root()),
+            SetMerkleTree::sparse(validator_state.nullifiers_root()),
         );
 
         Ok(state)
     }
 
     async fn subscribe(&self, from: EventIndex, to: Option<EventIndex>) -> Self::EventStream {
-        // All events come from a single source, the EsQS, which aggregates blocks and memos.
+        // All events come from a single source, the EsQS, which aggregates blocks and memos. Mirrors
+        // of the EsQS index events the same way, so their streams can be merged by index.
         let from = from.index(EventSource::QueryService);
         let to = to.map(|to| to.index(EventSource::QueryService));
 
-        //todo !jeb.bearer handle connection failures.
-        //      https://github.com/EspressoSystems/seahorse/issues/117
-        // This should only fail if the server is incorrect or down, so we should handle by retrying
-        // or failing over to a different server.
-        let all_events = self
-            .query_client
-            .socket(&format!("catchup/subscribe_for_events/{}", from))
-            .subscribe()
-            .await
-            .expect("failed to connect to server");
-        let chosen_events: Pin<Box<dyn Stream<Item = _> + Send>> = if let Some(to) = to {
-            Box::pin(all_events.take(to - from))
-        } else {
-            Box::pin(all_events)
+        let state = Subscription {
+            mirrors: std::iter::once(&self.query_url)
+                .chain(&self.query_mirrors)
+                .cloned()
+                .map(Mirror::new)
+                .collect(),
+            log: self.log.clone(),
+            events: Sequencer::new(from),
+            to,
+            backoff: MIN_RECONNECT_BACKOFF,
         };
-
-        Box::pin(
-            chosen_events
-                //todo !jeb.bearer handle stream errors
-                //      https://github.com/EspressoSystems/seahorse/issues/117
-                // If there is an error in the stream, or the server sends us invalid data, we
-                // should retry or fail over to a different server.
-                .filter_map(|msg| ready(msg.ok().map(|e| (e, EventSource::QueryService)))),
-        )
+        let metrics = self.metrics.clone();
+        Box::pin(stream::unfold(state, move |mut state| {
+            let metrics = metrics.clone();
+            async move {
+                let event = state.next_event().await?;
+                metrics.event_received();
+                Some(((event, EventSource::QueryService), state))
+            }
+        }))
     }
 
     async fn get_public_key(
         &self,
         address: &UserAddress,
     ) -> Result<UserPubKey, KeystoreError<EspressoLedger>> {
-        self.address_book_client
+        if let Some(pub_key) = self.pub_keys.get(address) {
+            return Ok(pub_key);
+        }
+        let pub_key: UserPubKey = self
+            .address_book_client
             .post("request_pubkey")
             .body_json(address)
             .unwrap()
@@ -231,7 +1324,10 @@ impl<'a> KeystoreBackend<'a, EspressoLedger> for NetworkBackend<'a> {
                     "Address book request POST /request_pubkey failed: {}",
                     source
                 ),
-            })
+            })?;
+        let pub_key = check_pub_key(address, pub_key)?;
+        self.pub_keys.insert(pub_key.clone());
+        Ok(pub_key)
     }
 
     async fn get_nullifier_proof(
@@ -248,7 +1344,11 @@ impl<'a> KeystoreBackend<'a, EspressoLedger> for NetworkBackend<'a> {
                 // default, empty set.
                 assert_eq!(*set, SetMerkleTree::default());
                 set.contains(nullifier).unwrap()
+            } else if let Some(cached) = self.cached_nullifier_proof(set, nullifier).await {
+                self.metrics.nullifier_proof_cached();
+                cached
             } else {
+                let start = Instant::now();
                 let NullifierCheck { proof, spent } = self
                     .get(format!(
                         "/metastate/check_nullifier/{}/{}",
@@ -256,6 +1356,14 @@ impl<'a> KeystoreBackend<'a, EspressoLedger> for NetworkBackend<'a> {
                         nullifier
                     ))
                     .await?;
+                self.perf
+                    .record(PerfMetric::NullifierProof, start.elapsed());
+                self.metrics.nullifier_proof_fetched(start.elapsed());
+                if let Some(cache) = &self.proof_cache {
+                    if let Err(err) = cache.put(set.hash(), nullifier, spent, proof.clone()).await {
+                        self.log.warn(LogKind::Request, err.to_string());
+                    }
+                }
                 (spent, proof)
             };
             set.remember(nullifier, proof.clone()).unwrap();
@@ -271,14 +1379,17 @@ impl<'a> KeystoreBackend<'a, EspressoLedger> for NetworkBackend<'a> {
         let sig = key_pair.sign(&pub_key_bytes);
         let json_request = InsertPubKey { pub_key_bytes, sig };
         self.address_book_client
-            .post("insert_pubkey")
+            .post::<()>("insert_pubkey")
             .body_json(&json_request)
             .unwrap()
             .send()
             .await
             .map_err(|err| KeystoreError::Failed {
                 msg: format!("error inserting public key: {}", err),
-            })
+            })?;
+        // Replace any stale key we may have cached for this address.
+        self.pub_keys.insert(key_pair.pub_key());
+        Ok(())
     }
 
     async fn submit(
@@ -286,18 +1397,68 @@ impl<'a> KeystoreBackend<'a, EspressoLedger> for NetworkBackend<'a> {
         mut txn: ElaboratedTransaction,
         txn_info: Transaction<EspressoLedger>,
     ) -> Result<(), KeystoreError<EspressoLedger>> {
-        if let Some(signed_memos) = txn_info.memos() {
+        self.submissions.check()?;
+        self.ensure_chain_id().await?;
+        if let Some(policy) = &self.spending_policy {
+            policy
+                .check_transaction(&txn, &txn_info)
+                .map_err(|rule| KeystoreError::Failed {
+                    msg: format!("transaction violates spending policy: {}", rule),
+                })?;
+        }
+        if self.separate_memos {
+            // The memos will be posted in `finalize`, once the transaction has been committed.
+        } else if let Some(signed_memos) = txn_info.memos() {
             txn.memos = Some((
                 signed_memos.memos.iter().flatten().cloned().collect(),
                 signed_memos.sig.clone(),
             ));
         }
 
-        Self::post(&self.validator_client, "/validator/submit", &txn).await
+        let start = Instant::now();
+        let res = self.submit_with_retries(&txn).await;
+        self.perf.record(PerfMetric::Submit, start.elapsed());
+        self.metrics.submitted(start.elapsed(), res.is_ok());
+        match (&res, &self.spending_policy) {
+            (Err(err), _) => {
+                self.log.error(
+                    LogKind::SubmitFailed,
+                    format!("failed to submit transaction {}: {}", txn.txn.hash(), err),
+                );
+            }
+            (Ok(()), Some(policy)) => {
+                // The transaction has already been submitted, so failing here would only invite
+                // the caller to submit it again. If the spending cannot be persisted, it still
+                // counts until the keystore restarts.
+                if let Err(err) = policy.record_transaction(&txn, &txn_info) {
+                    self.log.error(
+                        LogKind::Other,
+                        format!(
+                            "failed to record spending of transaction {}: {}",
+                            txn.txn.hash(),
+                            err
+                        ),
+                    );
+                }
+            }
+            (Ok(()), None) => {}
+        }
+        res
     }
 
-    async fn finalize(&mut self, _txn: Transaction<EspressoLedger>, _txid: Option<(u64, u64)>) {
-        // -> Result<(), KeystoreError<EspressoLedger>>
+    async fn finalize(&mut self, txn: Transaction<EspressoLedger>, txid: Option<(u64, u64)>) {
+        if !self.separate_memos {
+            return;
+        }
+        if let (Some((block_id, txn_id)), Some(signed_memos)) = (txid, txn.memos()) {
+            self.post_memos(
+                block_id,
+                txn_id,
+                signed_memos.memos.iter().flatten().cloned().collect(),
+                signed_memos.sig.clone(),
+            )
+            .await;
+        }
     }
 
     async fn get_initial_scan_state(
@@ -310,3 +1471,583 @@ impl<'a> KeystoreBackend<'a, EspressoLedger> for NetworkBackend<'a> {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::mock_esqs::{MockEsqs, MockEsqsData};
+    use espresso_core::{
+        state::{ConsensusTime, ElaboratedBlock},
+        testing::{genesis_txn, MultiXfrRecordSpec, MultiXfrTestState, TestTxSpec, TxnPrintInfo},
+        universal_params::UNIVERSAL_PARAM,
+    };
+    use portpicker::pick_unused_port;
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+
+    async fn backend(esqs: &MockEsqs) -> NetworkBackend<'static> {
+        NetworkBackend::new(&UNIVERSAL_PARAM, esqs.url(), esqs.url(), esqs.url())
+            .await
+            .unwrap()
+            .with_retry_policy(RetryPolicy {
+                max_attempts: 3,
+                initial_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(10),
+            })
+    }
+
+    fn already_pending(txn: &ElaboratedTransaction) -> ApiError {
+        ApiError::Validator {
+            source: validator::Error::AlreadyPending {
+                hash: TransactionCommitment(txn.txn.hash()),
+            },
+        }
+    }
+
+    // The state after block `block_id` in a mock ledger where each block produces 10 events.
+    fn state(block_id: u64) -> ValidatorState {
+        ValidatorState {
+            block_height: block_id + 1,
+            ..Default::default()
+        }
+    }
+
+    async fn esqs_with_blocks(num_blocks: u64) -> MockEsqs {
+        let mut data = MockEsqsData::default();
+        for block_id in 0..num_blocks {
+            data.push_state(state(block_id), 10 * (block_id + 1));
+        }
+        MockEsqs::start(data).await
+    }
+
+    fn query_service_index(index: usize) -> EventIndex {
+        EventIndex::from_source(EventSource::QueryService, index)
+    }
+
+    #[async_std::test]
+    async fn test_event_index_at_height() {
+        let esqs = esqs_with_blocks(3).await;
+        let backend = backend(&esqs).await;
+        let timeout = Duration::from_secs(5);
+
+        assert_eq!(
+            backend.event_index_at_height(0, timeout).await.unwrap(),
+            query_service_index(0)
+        );
+        for height in 1..=3 {
+            assert_eq!(
+                backend
+                    .event_index_at_height(height, timeout)
+                    .await
+                    .unwrap(),
+                query_service_index(10 * height as usize)
+            );
+        }
+
+        // A height which is never reached fails once the timeout expires.
+        let err = backend
+            .event_index_at_height(5, Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+
+        // A height which is reached while waiting succeeds.
+        let (res, ()) = futures::join!(backend.event_index_at_height(4, timeout), async {
+            sleep(Duration::from_millis(100)).await;
+            esqs.data().await.push_state(state(3), 40);
+        });
+        assert_eq!(res.unwrap(), query_service_index(40));
+    }
+
+    #[async_std::test]
+    async fn test_event_index_at_commitment() {
+        let esqs = esqs_with_blocks(5).await;
+        let backend = backend(&esqs).await;
+
+        // Commitments within `depth` of the latest block are found.
+        for block_id in 2..5 {
+            assert_eq!(
+                backend
+                    .event_index_at_commitment(&state(block_id).commit(), 3)
+                    .await
+                    .unwrap(),
+                Some(query_service_index(10 * (block_id as usize + 1)))
+            );
+        }
+        // Older commitments are not searched.
+        for block_id in 0..2 {
+            assert_eq!(
+                backend
+                    .event_index_at_commitment(&state(block_id).commit(), 3)
+                    .await
+                    .unwrap(),
+                None
+            );
+        }
+        // Unless the depth covers them.
+        assert_eq!(
+            backend
+                .event_index_at_commitment(&state(0).commit(), 5)
+                .await
+                .unwrap(),
+            Some(query_service_index(10))
+        );
+        // A commitment which was never committed is not found at any depth.
+        assert_eq!(
+            backend
+                .event_index_at_commitment(&state(5).commit(), 100)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[async_std::test]
+    async fn test_check_state() {
+        let esqs = esqs_with_blocks(2).await;
+        let backend = backend(&esqs).await;
+
+        // States which match the network's, including the pre-genesis state, do not diverge.
+        assert_eq!(
+            backend
+                .check_state(&ValidatorState::default())
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(backend.check_state(&state(1)).await.unwrap(), None);
+        assert!(backend.log().recent_errors(10).is_empty());
+
+        // A state which differs from the network's in exactly one component is reported, and the
+        // diff names only that component.
+        let mut local = state(1);
+        local.transaction_count += 1;
+        let divergence = backend.check_state(&local).await.unwrap().unwrap();
+        assert_eq!(divergence.block_height, 2);
+        assert_eq!(divergence.local, local.commit());
+        assert_eq!(divergence.network, state(1).commit());
+        assert_eq!(divergence.fields, vec!["transaction_count"]);
+        let errors = backend.log().recent_errors(10);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, LogKind::StateDivergence);
+    }
+
+    fn state_on_chain(chain_id: u16) -> ValidatorState {
+        let mut state = state(0);
+        state.chain.chain_id = chain_id;
+        state
+    }
+
+    #[async_std::test]
+    async fn test_wrong_chain_id() {
+        let mut data = MockEsqsData::default();
+        data.push_state(state_on_chain(2), 10);
+        let esqs = MockEsqs::start(data).await;
+
+        // A keystore for chain 1 cannot be created from a query service following chain 2.
+        let mut backend = backend(&esqs).await.with_chain_id(1);
+        match backend.create().await {
+            Err(KeystoreError::Failed { msg }) => assert!(msg.contains("wrong network"), "{}", msg),
+            Err(err) => panic!("expected wrong network error, got {}", err),
+            Ok(_) => panic!("created keystore on the wrong network"),
+        }
+
+        // Nor can it submit transactions there.
+        match backend.ensure_chain_id().await {
+            Err(KeystoreError::Failed { msg }) => assert!(msg.contains("wrong network"), "{}", msg),
+            res => panic!(
+                "expected wrong network error, got {:?}",
+                res.map_err(|e| e.to_string())
+            ),
+        }
+        assert!(!backend.chain_id_checked);
+
+        // A backend for the right chain passes the check, and remembers that it did.
+        let mut backend = backend.with_chain_id(2);
+        backend.ensure_chain_id().await.unwrap();
+        assert!(backend.chain_id_checked);
+        esqs.data().await.states[0].state.chain.chain_id = 3;
+        backend.ensure_chain_id().await.unwrap();
+
+        // Without a configured chain id, any chain is accepted.
+        let mut backend = self::backend(&esqs).await;
+        backend.ensure_chain_id().await.unwrap();
+        assert!(esqs.data().await.submissions.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_retry_transient_failures() {
+        let esqs = MockEsqs::start(MockEsqsData::default()).await;
+        let metrics = Arc::new(KeystoreMetrics::new());
+        let backend = backend(&esqs).await.with_metrics(metrics.clone());
+        let txn = genesis_txn(0);
+
+        // Transient failures are retried until the submission goes through.
+        for status in [StatusCode::ServiceUnavailable, StatusCode::TooManyRequests] {
+            esqs.data()
+                .await
+                .submit_errors
+                .push_back(ApiError::catch_all(status, "try again".into()));
+        }
+        backend.submit_with_retries(&txn).await.unwrap();
+        assert_eq!(esqs.data().await.submissions.len(), 3);
+        assert!(metrics
+            .render()
+            .contains("espresso_keystore_resubmissions_total 2\n"));
+
+        // But only up to the limit set by the retry policy.
+        for _ in 0..4 {
+            esqs.data()
+                .await
+                .submit_errors
+                .push_back(ApiError::catch_all(
+                    StatusCode::InternalServerError,
+                    "still broken".into(),
+                ));
+        }
+        backend.submit_with_retries(&txn).await.unwrap_err();
+        assert_eq!(esqs.data().await.submissions.len(), 6);
+    }
+
+    #[async_std::test]
+    async fn test_no_retry_on_rejection() {
+        let esqs = MockEsqs::start(MockEsqsData::default()).await;
+        let metrics = Arc::new(KeystoreMetrics::new());
+        let backend = backend(&esqs).await.with_metrics(metrics.clone());
+        let txn = genesis_txn(0);
+
+        // A rejected transaction would be rejected again, so it is not retried.
+        esqs.data()
+            .await
+            .submit_errors
+            .push_back(ApiError::catch_all(
+                StatusCode::BadRequest,
+                "invalid transaction".into(),
+            ));
+        backend.submit_with_retries(&txn).await.unwrap_err();
+        assert_eq!(esqs.data().await.submissions.len(), 1);
+        assert!(metrics
+            .render()
+            .contains("espresso_keystore_resubmissions_total 0\n"));
+
+        // Nor is anything when retries are disabled.
+        let backend = backend.with_retry_policy(RetryPolicy::no_retries());
+        esqs.data()
+            .await
+            .submit_errors
+            .push_back(ApiError::catch_all(
+                StatusCode::ServiceUnavailable,
+                "try again".into(),
+            ));
+        backend.submit_with_retries(&txn).await.unwrap_err();
+        assert_eq!(esqs.data().await.submissions.len(), 2);
+    }
+
+    // A commit event which can be told apart from the others by its block ID.
+    fn commit_event(block_id: u64) -> LedgerEvent<EspressoLedger> {
+        LedgerEvent::Commit {
+            block: ElaboratedBlock::new(state(block_id).commit()),
+            block_id,
+            state_comm: state(block_id + 1).commit(),
+            proof: ConsensusTime::genesis(),
+        }
+    }
+
+    fn commit_events(num_events: u64) -> Vec<Option<LedgerEvent<EspressoLedger>>> {
+        (0..num_events).map(commit_event).map(Some).collect()
+    }
+
+    // Subscribe to the events in `range` and return their block IDs.
+    async fn subscribe_block_ids(backend: &NetworkBackend<'_>, range: Range<usize>) -> Vec<u64> {
+        let events = backend
+            .subscribe(
+                query_service_index(range.start),
+                Some(query_service_index(range.end)),
+            )
+            .await
+            .collect::<Vec<_>>();
+        timeout(Duration::from_secs(30), events)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(event, source)| {
+                assert_eq!(source, EventSource::QueryService);
+                match event {
+                    LedgerEvent::Commit { block_id, .. } => block_id,
+                    event => panic!("expected commit event, got {:?}", event),
+                }
+            })
+            .collect()
+    }
+
+    #[async_std::test]
+    async fn test_out_of_order_events() {
+        let esqs = MockEsqs::start(MockEsqsData {
+            events: commit_events(6),
+            // Deliver events out of order and with duplicates, and never deliver event 1, so the
+            // subscription has to buffer events and fetch the gap itself.
+            delivery_order: Some(vec![2, 0, 0, 4, 3, 2, 5]),
+            ..Default::default()
+        })
+        .await;
+        let backend = backend(&esqs).await;
+        assert_eq!(
+            subscribe_block_ids(&backend, 0..6).await,
+            vec![0, 1, 2, 3, 4, 5]
+        );
+        // Subscriptions starting partway through the stream skip the earlier events.
+        assert_eq!(subscribe_block_ids(&backend, 3..5).await, vec![3, 4]);
+    }
+
+    #[async_std::test]
+    async fn test_reconnect_closed_stream() {
+        let esqs = MockEsqs::start(MockEsqsData {
+            events: commit_events(5),
+            // The server hangs up after every other event, so the subscription has to reconnect
+            // from the next event it needs, twice.
+            events_per_subscription: Some(2),
+            ..Default::default()
+        })
+        .await;
+        let backend = backend(&esqs).await;
+        assert_eq!(
+            subscribe_block_ids(&backend, 0..5).await,
+            vec![0, 1, 2, 3, 4]
+        );
+        let reconnects = backend
+            .log()
+            .entries()
+            .into_iter()
+            .filter(|entry| entry.kind == LogKind::EventStream)
+            .map(|entry| entry.message)
+            .filter(|msg| msg.contains("closed at event"))
+            .collect::<Vec<_>>();
+        assert_eq!(reconnects.len(), 2, "{:?}", reconnects);
+        assert!(reconnects[0].contains("closed at event 2, reconnecting in"));
+        assert!(reconnects[1].contains("closed at event 4, reconnecting in"));
+    }
+
+    #[async_std::test]
+    async fn test_reconnect_backoff() {
+        let esqs = MockEsqs::start(MockEsqsData {
+            events: commit_events(1),
+            // The server accepts every subscription and closes it straight away.
+            events_per_subscription: Some(0),
+            ..Default::default()
+        })
+        .await;
+        let backend = backend(&esqs).await;
+        let mut events = backend
+            .subscribe(query_service_index(0), Some(query_service_index(1)))
+            .await;
+        assert!(timeout(Duration::from_secs(1), events.next())
+            .await
+            .is_err());
+
+        // Reconnections back off even though each one succeeds, so there are only a handful of
+        // them in a second, not one per round trip.
+        let reconnects = backend
+            .log()
+            .entries()
+            .into_iter()
+            .filter(|entry| entry.message.contains("closed at event 0"))
+            .count();
+        assert!((1..=5).contains(&reconnects), "{} reconnects", reconnects);
+    }
+
+    #[async_std::test]
+    async fn test_missing_event() {
+        let mut events = commit_events(3);
+        events[1] = None;
+        let esqs = MockEsqs::start(MockEsqsData {
+            events,
+            ..Default::default()
+        })
+        .await;
+        let backend = backend(&esqs).await;
+
+        // The subscription waits for the missing event rather than skipping or replacing it, and
+        // picks it up once the EsQS has it.
+        let (block_ids, ()) = futures::join!(subscribe_block_ids(&backend, 0..3), async {
+            sleep(Duration::from_millis(500)).await;
+            esqs.data().await.events[1] = Some(commit_event(1));
+        });
+        assert_eq!(block_ids, vec![0, 1, 2]);
+        assert!(backend
+            .log()
+            .recent_errors(10)
+            .iter()
+            .any(|entry| entry.message.contains("event 1 is missing")));
+    }
+
+    #[async_std::test]
+    async fn test_failing_mirrors() {
+        let primary = MockEsqs::start(MockEsqsData {
+            events: commit_events(4),
+            ..Default::default()
+        })
+        .await;
+        // A mirror which accepts subscriptions but never delivers anything, and has no events to
+        // fill gaps with.
+        let stalled = MockEsqs::start(MockEsqsData {
+            delivery_order: Some(vec![]),
+            ..Default::default()
+        })
+        .await;
+        // A mirror which is not running at all.
+        let unreachable: Url = format!("http://localhost:{}", pick_unused_port().unwrap())
+            .parse()
+            .unwrap();
+
+        let backend = backend(&primary)
+            .await
+            .with_query_mirrors(vec![unreachable, stalled.url()]);
+        assert_eq!(subscribe_block_ids(&backend, 0..4).await, vec![0, 1, 2, 3]);
+        assert!(backend
+            .log()
+            .entries()
+            .iter()
+            .any(|entry| entry.kind == LogKind::EventStream
+                && entry.message.contains("failed to subscribe")));
+    }
+
+    #[async_std::test]
+    async fn test_post_memos() {
+        let mut state = MultiXfrTestState::initialize(
+            [0x97u8; 32],
+            2,
+            1,
+            (
+                MultiXfrRecordSpec {
+                    asset_def_ix: 1,
+                    owner_key_ix: 0,
+                    asset_amount: 1,
+                },
+                vec![],
+            ),
+        )
+        .unwrap();
+        let txn = state
+            .generate_transactions(
+                vec![(TestTxSpec::OneInput { rec: 0, key: 1 }, false)],
+                TxnPrintInfo::new_no_time(0, 1),
+            )
+            .unwrap()
+            .remove(0);
+        let memos = txn
+            .keys_and_memos
+            .into_iter()
+            .map(|(_, memo)| memo)
+            .collect::<Vec<_>>();
+
+        let mut data = MockEsqsData::default();
+        data.push_state(state.validator.clone(), 1);
+        let esqs = MockEsqs::start(data).await;
+        let backend = backend(&esqs).await.with_separate_memos(true);
+
+        // The memos for a committed transaction are posted to the EsQS bulletin board.
+        backend
+            .post_memos(0, 0, memos.clone(), txn.signature.clone())
+            .await;
+        assert_eq!(
+            esqs.data().await.posted_memos,
+            vec![(0, 0, memos.clone(), txn.signature.clone())]
+        );
+        assert!(backend.log().recent_errors(10).is_empty());
+
+        // If the EsQS rejects them, the failure is logged.
+        backend.post_memos(1, 0, memos, txn.signature).await;
+        assert_eq!(esqs.data().await.posted_memos.len(), 1);
+        let errors = backend.log().recent_errors(10);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, LogKind::SubmitFailed);
+        assert!(errors[0].message.contains("failed to post memos for 1/0"));
+    }
+
+    #[async_std::test]
+    async fn test_resubmit_pending_transaction() {
+        let esqs = MockEsqs::start(MockEsqsData::default()).await;
+        let metrics = Arc::new(KeystoreMetrics::new());
+        let backend = backend(&esqs).await.with_metrics(metrics.clone());
+        let txn = genesis_txn(0);
+
+        // A 409 for this very transaction means an earlier attempt got through, so the
+        // resubmission succeeds without retrying.
+        esqs.data()
+            .await
+            .submit_errors
+            .push_back(already_pending(&txn));
+        backend.submit_with_retries(&txn).await.unwrap();
+        assert_eq!(esqs.data().await.submissions, vec![txn.clone()]);
+        assert!(metrics
+            .render()
+            .contains("espresso_keystore_duplicate_submissions_total 1\n"));
+    }
+
+    #[async_std::test]
+    async fn test_conflict_for_other_transaction() {
+        let esqs = MockEsqs::start(MockEsqsData::default()).await;
+        let metrics = Arc::new(KeystoreMetrics::new());
+        let backend = backend(&esqs).await.with_metrics(metrics.clone());
+        let other = genesis_txn(1);
+        let txn = genesis_txn(0);
+
+        // A 409 naming a different transaction says nothing about whether ours was accepted. The
+        // submission fails, and a conflict is not transient, so it is not retried.
+        esqs.data()
+            .await
+            .submit_errors
+            .push_back(already_pending(&other));
+        backend.submit_with_retries(&txn).await.unwrap_err();
+        assert_eq!(esqs.data().await.submissions, vec![txn.clone()]);
+        assert!(metrics
+            .render()
+            .contains("espresso_keystore_duplicate_submissions_total 0\n"));
+
+        // The same goes for a 409 with no transaction hash at all.
+        esqs.data()
+            .await
+            .submit_errors
+            .push_back(ApiError::catch_all(StatusCode::Conflict, "conflict".into()));
+        backend.submit_with_retries(&txn).await.unwrap_err();
+        assert_eq!(esqs.data().await.submissions.len(), 2);
+    }
+
+    #[test]
+    fn test_sequencer() {
+        let mut seq = Sequencer::new(5);
+        assert_eq!(seq.pop(), None);
+        assert_eq!(seq.missing(), None);
+
+        // Events which arrive out of order are buffered until the gap is filled.
+        assert!(seq.insert(7, "g"));
+        assert!(seq.insert(9, "i"));
+        assert_eq!(seq.pop(), None);
+        assert_eq!(seq.missing(), Some(5..7));
+        assert!(seq.insert(6, "f"));
+        assert!(seq.insert(5, "e"));
+        assert_eq!(seq.pop(), Some("e"));
+        assert_eq!(seq.pop(), Some("f"));
+        assert_eq!(seq.pop(), Some("g"));
+        assert_eq!(seq.pop(), None);
+        assert_eq!(seq.missing(), Some(8..9));
+
+        // Duplicates of delivered or buffered events are rejected.
+        assert!(!seq.insert(4, "d"));
+        assert!(!seq.insert(6, "f"));
+        assert!(!seq.insert(9, "i"));
+        assert!(seq.insert(8, "h"));
+        assert_eq!(seq.pop(), Some("h"));
+        assert_eq!(seq.pop(), Some("i"));
+        assert_eq!(seq.next, 10);
+        assert_eq!(seq.missing(), None);
+    }
+
+    #[test]
+    fn test_check_pub_key() {
+        let mut rng = ChaChaRng::from_seed([0x17; 32]);
+        let key = UserKeyPair::generate(&mut rng).pub_key();
+        let other = UserKeyPair::generate(&mut rng).pub_key();
+        assert_eq!(check_pub_key(&key.address(), key.clone()).unwrap(), key);
+        check_pub_key(&key.address(), other).unwrap_err();
+    }
+}
@@ -0,0 +1,383 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Two-party payment channels on top of CAP transfers.
+//!
+//! Counterparties who pay each other frequently can use a channel to batch many payments into two
+//! on-ledger transactions:
+//!
+//! 1. The funder sends the channel capacity to a record owned by the channel's escrow key, using
+//!    the outputs from [PaymentChannel::funding_outputs].
+//! 2. The parties exchange [SignedUpdate]s off-ledger. Each update is a new [ChannelState] with a
+//!    strictly greater nonce, signed by both parties. Updates are serialized with `bincode`, like
+//!    the rest of the messages exchanged by Espresso services.
+//! 3. To close the channel cooperatively, the holder of the escrow key builds a final transfer
+//!    from the escrow record using [PaymentChannel::close_outputs], which pays each party its
+//!    balance in the latest state signed by both of them.
+//!
+//! CAP records have a single owner, so this is scaffolding rather than a trustless protocol: the
+//! escrow record is controlled by whoever holds the escrow key, and the co-signed state serves as
+//! evidence of what each party is owed should the close not be honored.
+
+use jf_cap::{
+    keys::{UserKeyPair, UserPubKey},
+    structs::AssetCode,
+    Signature,
+};
+use seahorse::RecordAmount;
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub))]
+pub enum ChannelError {
+    #[snafu(display("update does not belong to this channel"))]
+    WrongChannel,
+    #[snafu(display("stale update: nonce {} is not greater than {}", nonce, current))]
+    StaleUpdate { nonce: u64, current: u64 },
+    #[snafu(display("update changes the total channel balance"))]
+    BalanceMismatch,
+    #[snafu(display("insufficient channel balance: have {}, need {}", balance, amount))]
+    InsufficientBalance { balance: u64, amount: u64 },
+    #[snafu(display("update lowers the balance of the countersigning party"))]
+    UnauthorizedDebit,
+    #[snafu(display("update is missing a valid signature from {}", party))]
+    BadSignature { party: String },
+    #[snafu(display("key is not a party to this channel"))]
+    NotAParty,
+    #[snafu(display("failed to serialize channel message: {}", msg))]
+    Serialization { msg: String },
+}
+
+/// The balances in a channel at a point in time.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelState {
+    pub channel_id: u64,
+    pub nonce: u64,
+    /// The balance owed to the funder.
+    pub funder_balance: u64,
+    /// The balance owed to the counterparty.
+    pub counterparty_balance: u64,
+}
+
+impl ChannelState {
+    /// The total balance of the channel, or [None] if it overflows.
+    fn total(&self) -> Option<u64> {
+        self.funder_balance.checked_add(self.counterparty_balance)
+    }
+
+    fn signing_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+}
+
+/// A [ChannelState] along with the signatures of both parties.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SignedUpdate {
+    pub state: ChannelState,
+    pub funder_sig: Option<Signature>,
+    pub counterparty_sig: Option<Signature>,
+}
+
+impl SignedUpdate {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ChannelError> {
+        bincode::deserialize(bytes).map_err(|err| ChannelError::Serialization {
+            msg: err.to_string(),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PaymentChannel {
+    funder: UserPubKey,
+    counterparty: UserPubKey,
+    escrow: UserPubKey,
+    asset: AssetCode,
+    latest: SignedUpdate,
+}
+
+impl PaymentChannel {
+    /// Describe a new channel in which `funder` deposits `capacity` units of `asset`.
+    pub fn open(
+        channel_id: u64,
+        funder: UserPubKey,
+        counterparty: UserPubKey,
+        escrow: UserPubKey,
+        asset: AssetCode,
+        capacity: u64,
+    ) -> Self {
+        Self {
+            funder,
+            counterparty,
+            escrow,
+            asset,
+            latest: SignedUpdate {
+                state: ChannelState {
+                    channel_id,
+                    nonce: 0,
+                    funder_balance: capacity,
+                    counterparty_balance: 0,
+                },
+                funder_sig: None,
+                counterparty_sig: None,
+            },
+        }
+    }
+
+    pub fn asset(&self) -> &AssetCode {
+        &self.asset
+    }
+
+    /// The latest state accepted by this end of the channel.
+    pub fn state(&self) -> &ChannelState {
+        &self.latest.state
+    }
+
+    /// Outputs of the funding transaction, which moves the channel capacity to the escrow key.
+    pub fn funding_outputs(&self) -> Vec<(UserPubKey, RecordAmount)> {
+        // Every accepted state has passed `check_transition` (or is the opening state), so its total
+        // does not overflow.
+        let total = self.latest.state.total().unwrap();
+        vec![(self.escrow.clone(), total.into())]
+    }
+
+    /// Propose a payment of `amount` from `payer` to the other party.
+    ///
+    /// The returned update is signed by `payer` and must be countersigned by the other party with
+    /// [countersign](Self::countersign) before it takes effect.
+    pub fn pay(&self, payer: &UserKeyPair, amount: u64) -> Result<SignedUpdate, ChannelError> {
+        let mut state = self.latest.state.clone();
+        state.nonce += 1;
+        let payer_key = payer.pub_key();
+        let (from, to) = if payer_key == self.funder {
+            (&mut state.funder_balance, &mut state.counterparty_balance)
+        } else if payer_key == self.counterparty {
+            (&mut state.counterparty_balance, &mut state.funder_balance)
+        } else {
+            return Err(ChannelError::NotAParty);
+        };
+        if *from < amount {
+            return Err(ChannelError::InsufficientBalance {
+                balance: *from,
+                amount,
+            });
+        }
+        *from -= amount;
+        *to += amount;
+
+        let mut update = SignedUpdate {
+            state,
+            funder_sig: None,
+            counterparty_sig: None,
+        };
+        self.sign(&mut update, payer)?;
+        Ok(update)
+    }
+
+    /// Check a proposed update from the other party and add our signature to it.
+    ///
+    /// The update must be signed by the other party, and must not lower the balance of the party
+    /// countersigning it. A party wanting to pay from its own balance proposes the update with
+    /// [pay](Self::pay) instead.
+    pub fn countersign(
+        &self,
+        mut update: SignedUpdate,
+        key: &UserKeyPair,
+    ) -> Result<SignedUpdate, ChannelError> {
+        self.check_transition(&update.state)?;
+        let current = &self.latest.state;
+        let pub_key = key.pub_key();
+        let (proposer, proposer_key, proposer_sig, old_balance, new_balance) =
+            if pub_key == self.funder {
+                (
+                    "counterparty",
+                    &self.counterparty,
+                    &update.counterparty_sig,
+                    current.funder_balance,
+                    update.state.funder_balance,
+                )
+            } else if pub_key == self.counterparty {
+                (
+                    "funder",
+                    &self.funder,
+                    &update.funder_sig,
+                    current.counterparty_balance,
+                    update.state.counterparty_balance,
+                )
+            } else {
+                return Err(ChannelError::NotAParty);
+            };
+        match proposer_sig {
+            Some(sig)
+                if proposer_key
+                    .verify_sig(&update.state.signing_bytes(), sig)
+                    .is_ok() => {}
+            _ => {
+                return Err(ChannelError::BadSignature {
+                    party: proposer.into(),
+                })
+            }
+        }
+        if new_balance < old_balance {
+            return Err(ChannelError::UnauthorizedDebit);
+        }
+        self.sign(&mut update, key)?;
+        Ok(update)
+    }
+
+    /// Accept an update signed by both parties as the new latest state.
+    pub fn apply(&mut self, update: SignedUpdate) -> Result<(), ChannelError> {
+        self.check_transition(&update.state)?;
+        let bytes = update.state.signing_bytes();
+        for (party, key, sig) in [
+            ("funder", &self.funder, &update.funder_sig),
+            ("counterparty", &self.counterparty, &update.counterparty_sig),
+        ] {
+            match sig {
+                Some(sig) if key.verify_sig(&bytes, sig).is_ok() => {}
+                _ => {
+                    return Err(ChannelError::BadSignature {
+                        party: party.into(),
+                    })
+                }
+            }
+        }
+        self.latest = update;
+        Ok(())
+    }
+
+    /// Outputs of the cooperative close transaction, spending the escrow record.
+    ///
+    /// Parties with a zero balance are omitted.
+    pub fn close_outputs(&self) -> Vec<(UserPubKey, RecordAmount)> {
+        let state = &self.latest.state;
+        [
+            (&self.funder, state.funder_balance),
+            (&self.counterparty, state.counterparty_balance),
+        ]
+        .into_iter()
+        .filter(|(_, amount)| *amount > 0)
+        .map(|(key, amount)| (key.clone(), amount.into()))
+        .collect()
+    }
+
+    fn check_transition(&self, state: &ChannelState) -> Result<(), ChannelError> {
+        let current = &self.latest.state;
+        if state.channel_id != current.channel_id {
+            return Err(ChannelError::WrongChannel);
+        }
+        if state.nonce <= current.nonce {
+            return Err(ChannelError::StaleUpdate {
+                nonce: state.nonce,
+                current: current.nonce,
+            });
+        }
+        // The balances come from the other party, so the total may overflow.
+        match state.total() {
+            Some(total) if Some(total) == current.total() => {}
+            _ => return Err(ChannelError::BalanceMismatch),
+        }
+        Ok(())
+    }
+
+    fn sign(&self, update: &mut SignedUpdate, key: &UserKeyPair) -> Result<(), ChannelError> {
+        let sig = Some(key.sign(&update.state.signing_bytes()));
+        let pub_key = key.pub_key();
+        if pub_key == self.funder {
+            update.funder_sig = sig;
+        } else if pub_key == self.counterparty {
+            update.counterparty_sig = sig;
+        } else {
+            return Err(ChannelError::NotAParty);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+
+    #[test]
+    fn test_channel_updates() {
+        let mut rng = ChaChaRng::from_seed([42; 32]);
+        let alice = UserKeyPair::generate(&mut rng);
+        let bob = UserKeyPair::generate(&mut rng);
+        let escrow = UserKeyPair::generate(&mut rng);
+        let open = |id| {
+            PaymentChannel::open(
+                id,
+                alice.pub_key(),
+                bob.pub_key(),
+                escrow.pub_key(),
+                AssetCode::native(),
+                100,
+            )
+        };
+        let mut alice_view = open(0);
+        let mut bob_view = open(0);
+        assert_eq!(alice_view.funding_outputs().len(), 1);
+
+        // Alice pays Bob 30, Bob countersigns, both sides apply.
+        let update = alice_view.pay(&alice, 30).unwrap();
+        // A half-signed update is not accepted.
+        bob_view.clone().apply(update.clone()).unwrap_err();
+        let update = bob_view.countersign(update, &bob).unwrap();
+        let update = SignedUpdate::from_bytes(&update.to_bytes()).unwrap();
+        alice_view.apply(update.clone()).unwrap();
+        bob_view.apply(update.clone()).unwrap();
+        assert_eq!(bob_view.state().counterparty_balance, 30);
+
+        // Replaying the same update is rejected.
+        assert!(matches!(
+            bob_view.apply(update),
+            Err(ChannelError::StaleUpdate { .. })
+        ));
+        // So is an update for a different channel.
+        let other = open(1).pay(&alice, 1).unwrap();
+        assert!(matches!(
+            bob_view.countersign(other, &bob),
+            Err(ChannelError::WrongChannel)
+        ));
+        // Bob won't countersign an update Alice didn't sign, or one which takes his funds.
+        let mut unsigned = alice_view.pay(&alice, 1).unwrap();
+        unsigned.funder_sig = None;
+        assert!(matches!(
+            bob_view.countersign(unsigned, &bob),
+            Err(ChannelError::BadSignature { .. })
+        ));
+        let mut theft = alice_view.pay(&alice, 1).unwrap();
+        theft.state.funder_balance = 80;
+        theft.state.counterparty_balance = 20;
+        alice_view.sign(&mut theft, &alice).unwrap();
+        assert!(matches!(
+            bob_view.countersign(theft, &bob),
+            Err(ChannelError::UnauthorizedDebit)
+        ));
+        // Nor one whose balances overflow to the right total.
+        let mut overflow = alice_view.pay(&alice, 1).unwrap();
+        overflow.state.funder_balance = u64::MAX;
+        overflow.state.counterparty_balance = 101;
+        alice_view.sign(&mut overflow, &alice).unwrap();
+        assert!(matches!(
+            bob_view.countersign(overflow, &bob),
+            Err(ChannelError::BalanceMismatch)
+        ));
+
+        // Bob can't overspend.
+        assert!(matches!(
+            bob_view.pay(&bob, 31),
+            Err(ChannelError::InsufficientBalance { .. })
+        ));
+
+        let outputs = alice_view.close_outputs();
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs[0], (alice.pub_key(), 70u64.into()));
+        assert_eq!(outputs[1], (bob.pub_key(), 30u64.into()));
+    }
+}
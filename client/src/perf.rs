@@ -0,0 +1,255 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! A persistent history of keystore performance samples.
+//!
+//! [PerfHistory] keeps a bounded ring buffer of timing samples, optionally flushed to disk, so that
+//! performance regressions in the field can be diagnosed after the fact without any external
+//! monitoring infrastructure. [PerfHistory::report] summarizes the samples in a recent window as
+//! percentiles per metric.
+//!
+//! The samples are recorded by the [NetworkBackend](crate::network::NetworkBackend), so they cover
+//! the operations it performs itself: submitting transactions and fetching nullifier proofs.
+//! Proving, event handling and persistence happen inside the keystore library, which the backend
+//! cannot time.
+
+use espresso_core::ledger::EspressoLedger;
+use seahorse::KeystoreError;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The default number of samples to retain.
+pub const DEFAULT_HISTORY_CAPACITY: usize = 10_000;
+
+const HISTORY_FILE: &str = "perf_history.json";
+// Number of samples to record between writes to disk.
+const FLUSH_INTERVAL: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PerfMetric {
+    /// Round trip time to submit a transaction.
+    Submit,
+    /// Round trip time to fetch a nullifier proof.
+    NullifierProof,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Sample {
+    /// Seconds since the Unix epoch.
+    time: u64,
+    metric: PerfMetric,
+    micros: u64,
+}
+
+/// Summary statistics for one metric, in microseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetricSummary {
+    pub count: usize,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PerformanceReport {
+    pub window: Duration,
+    pub metrics: BTreeMap<PerfMetric, MetricSummary>,
+}
+
+impl Display for PerformanceReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "performance over the last {:?}:", self.window)?;
+        for (metric, s) in &self.metrics {
+            writeln!(
+                f,
+                "  {:?}: n={} p50={}us p90={}us p99={}us max={}us",
+                metric, s.count, s.p50, s.p90, s.p99, s.max
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    samples: VecDeque<Sample>,
+    unflushed: usize,
+}
+
+#[derive(Debug)]
+pub struct PerfHistory {
+    inner: Mutex<Inner>,
+    capacity: usize,
+    path: Option<PathBuf>,
+}
+
+impl Default for PerfHistory {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_CAPACITY)
+    }
+}
+
+impl PerfHistory {
+    /// An in-memory history retaining at most `capacity` samples.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                samples: VecDeque::new(),
+                unflushed: 0,
+            }),
+            capacity,
+            path: None,
+        }
+    }
+
+    /// Load a persistent history from `dir`, retaining at most `capacity` samples.
+    pub fn load(dir: &Path, capacity: usize) -> Result<Self, KeystoreError<EspressoLedger>> {
+        fs::create_dir_all(dir).map_err(|err| KeystoreError::Failed {
+            msg: format!("failed to create perf directory {}: {}", dir.display(), err),
+        })?;
+        let path = dir.join(HISTORY_FILE);
+        let mut samples: VecDeque<Sample> = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|err| KeystoreError::Failed {
+                msg: format!("corrupt perf history {}: {}", path.display(), err),
+            })?,
+            Err(_) => VecDeque::new(),
+        };
+        while samples.len() > capacity {
+            samples.pop_front();
+        }
+        Ok(Self {
+            inner: Mutex::new(Inner {
+                samples,
+                unflushed: 0,
+            }),
+            capacity,
+            path: Some(path),
+        })
+    }
+
+    pub fn record(&self, metric: PerfMetric, elapsed: Duration) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut inner = self.inner.lock().unwrap();
+        if self.capacity == 0 {
+            return;
+        }
+        while inner.samples.len() >= self.capacity {
+            inner.samples.pop_front();
+        }
+        inner.samples.push_back(Sample {
+            time,
+            metric,
+            micros: elapsed.as_micros() as u64,
+        });
+        inner.unflushed += 1;
+        if inner.unflushed >= FLUSH_INTERVAL {
+            self.flush_locked(&mut inner);
+        }
+    }
+
+    /// Run `f`, recording how long it took under `metric`.
+    pub fn time<T>(&self, metric: PerfMetric, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let res = f();
+        self.record(metric, start.elapsed());
+        res
+    }
+
+    /// Write any buffered samples to disk.
+    pub fn flush(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        self.flush_locked(&mut inner);
+    }
+
+    /// Summarize the samples recorded in the last `window`.
+    pub fn report(&self, window: Duration) -> PerformanceReport {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let cutoff = now.saturating_sub(window.as_secs());
+
+        let mut by_metric: BTreeMap<PerfMetric, Vec<u64>> = BTreeMap::new();
+        for sample in self.inner.lock().unwrap().samples.iter() {
+            if sample.time >= cutoff {
+                by_metric
+                    .entry(sample.metric)
+                    .or_default()
+                    .push(sample.micros);
+            }
+        }
+
+        let metrics = by_metric
+            .into_iter()
+            .map(|(metric, mut micros)| {
+                micros.sort_unstable();
+                let percentile = |p: usize| micros[(micros.len() - 1) * p / 100];
+                let summary = MetricSummary {
+                    count: micros.len(),
+                    p50: percentile(50),
+                    p90: percentile(90),
+                    p99: percentile(99),
+                    max: micros[micros.len() - 1],
+                };
+                (metric, summary)
+            })
+            .collect();
+        PerformanceReport { window, metrics }
+    }
+
+    fn flush_locked(&self, inner: &mut Inner) {
+        inner.unflushed = 0;
+        if let Some(path) = &self.path {
+            let res = serde_json::to_vec(&inner.samples)
+                .map_err(|err| err.to_string())
+                .and_then(|bytes| fs::write(path, bytes).map_err(|err| err.to_string()));
+            if let Err(err) = res {
+                tracing::error!("failed to persist perf history: {}", err);
+            }
+        }
+    }
+}
+
+impl Drop for PerfHistory {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_perf_report() {
+        let dir = TempDir::new("perf_history").unwrap();
+        {
+            let history = PerfHistory::load(dir.path(), 1000).unwrap();
+            for i in 1..=100 {
+                history.record(PerfMetric::Submit, Duration::from_micros(i));
+            }
+            history.record(PerfMetric::NullifierProof, Duration::from_secs(2));
+        }
+
+        // Samples survive a restart.
+        let history = PerfHistory::load(dir.path(), 1000).unwrap();
+        let report = history.report(Duration::from_secs(3600));
+        let submit = report.metrics[&PerfMetric::Submit];
+        assert_eq!(submit.count, 100);
+        assert_eq!(submit.p50, 50);
+        assert_eq!(submit.p99, 99);
+        assert_eq!(submit.max, 100);
+        assert_eq!(report.metrics[&PerfMetric::NullifierProof].max, 2_000_000);
+    }
+}
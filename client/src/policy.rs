@@ -0,0 +1,460 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Spending rules enforced on every transaction a keystore submits.
+//!
+//! A [SpendingPolicy] limits how much of each asset can be sent in a rolling 24 hour window,
+//! restricts which addresses may receive transfers, and requires an explicit confirmation for
+//! transfers above a threshold. Transaction fees are paid in the native asset, so they count
+//! towards its daily limit.
+//!
+//! A [NetworkBackend](crate::network::NetworkBackend) configured with a policy checks every
+//! transfer, mint and freeze against it before submitting, and records the spending once the
+//! transaction is submitted, so the policy holds whether the keystore is driven by the CLI, the
+//! wallet API or the FFI. The backend cannot ask the user to confirm a transaction, so frontends
+//! which can should also check a transaction with [SpendingPolicy::check] before building it. This
+//! also saves the proving time of a transaction which would be rejected anyway.
+//!
+//! The policy is stored alongside the keystore, in its storage directory, so the rules and the
+//! record of recent spending move with the keystore and restarting it does not reset the limits.
+
+use espresso_core::{
+    ledger::EspressoLedger,
+    mempool::fee,
+    state::{ElaboratedTransaction, EspressoTransaction},
+};
+use jf_cap::{keys::UserAddress, structs::AssetCode, TransactionNote};
+use seahorse::{transactions::Transaction, KeystoreError};
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const POLICY_FILE: &str = "spending_policy.json";
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AssetLimits {
+    pub asset: AssetCode,
+    /// The most that can be sent in any 24 hour window.
+    pub daily_limit: Option<u128>,
+    /// Transfers of more than this amount must be explicitly confirmed.
+    pub confirmation_threshold: Option<u128>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyRules {
+    pub limits: Vec<AssetLimits>,
+    /// If set, only these addresses may receive transfers.
+    pub allow: Option<HashSet<UserAddress>>,
+    /// Addresses which may never receive transfers.
+    pub deny: HashSet<UserAddress>,
+}
+
+/// The rule a rejected transfer would have broken.
+#[derive(Clone, Debug, PartialEq, Eq, Snafu, Serialize, Deserialize)]
+pub enum PolicyRule {
+    #[snafu(display(
+        "transfer of {} {} would exceed the daily limit of {} ({} already sent)",
+        requested,
+        asset,
+        limit,
+        spent
+    ))]
+    DailyLimit {
+        asset: AssetCode,
+        limit: u128,
+        spent: u128,
+        requested: u128,
+    },
+
+    #[snafu(display(
+        "transfer of {} {} exceeds {} and must be confirmed",
+        amount,
+        asset,
+        threshold
+    ))]
+    ConfirmationRequired {
+        asset: AssetCode,
+        threshold: u128,
+        amount: u128,
+    },
+
+    #[snafu(display("receiver {} is not on the allow list", receiver))]
+    NotAllowed { receiver: UserAddress },
+
+    #[snafu(display("receiver {} is on the deny list", receiver))]
+    Denied { receiver: UserAddress },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Spend {
+    /// Seconds since the Unix epoch.
+    time: u64,
+    asset: AssetCode,
+    amount: u128,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct PolicyState {
+    rules: PolicyRules,
+    spends: Vec<Spend>,
+}
+
+impl PolicyState {
+    fn limits(&self, asset: &AssetCode) -> Option<&AssetLimits> {
+        self.rules.limits.iter().find(|l| &l.asset == asset)
+    }
+
+    fn check_daily_limit(&self, asset: &AssetCode, amount: u128) -> Result<(), PolicyRule> {
+        let limit = match self.limits(asset).and_then(|limits| limits.daily_limit) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let since = now().saturating_sub(DAY_SECS);
+        let spent = self
+            .spends
+            .iter()
+            .filter(|spend| &spend.asset == asset && spend.time > since)
+            .fold(0u128, |total, spend| total.saturating_add(spend.amount));
+        match spent.checked_add(amount) {
+            Some(total) if total <= limit => Ok(()),
+            _ => Err(PolicyRule::DailyLimit {
+                asset: *asset,
+                limit,
+                spent,
+                requested: amount,
+            }),
+        }
+    }
+}
+
+/// The amount of each asset spent by a transaction sending `amount` of `asset` and paying `fee`.
+fn spends(asset: &AssetCode, amount: u128, fee: u128) -> Vec<(AssetCode, u128)> {
+    let native = AssetCode::native();
+    if *asset == native {
+        vec![(native, amount.saturating_add(fee))]
+    } else {
+        vec![(*asset, amount), (native, fee)]
+    }
+}
+
+/// The asset `txn` sends, its receivers and its fee, given the keystore's record `info` of it.
+///
+/// Amounts in a CAP transaction are hidden, so they come from the keystore's record of what it
+/// built rather than from the transaction itself.
+fn spending(
+    txn: &ElaboratedTransaction,
+    info: &Transaction<EspressoLedger>,
+) -> (AssetCode, Vec<(UserAddress, u128)>, u128) {
+    let asset: &AssetCode = &info.asset();
+    let receivers = match &txn.txn {
+        // Freezing moves no value, so only the fee is subject to the policy.
+        EspressoTransaction::CAP(TransactionNote::Freeze(_)) => vec![],
+        _ => info
+            .receivers()
+            .iter()
+            .map(|(receiver, amount)| (receiver.clone(), u128::from(*amount)))
+            .collect(),
+    };
+    (*asset, receivers, fee(&txn.txn))
+}
+
+#[derive(Debug, Default)]
+pub struct SpendingPolicy {
+    state: Mutex<PolicyState>,
+    path: Option<PathBuf>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl SpendingPolicy {
+    /// A policy which is not persisted.
+    pub fn new(rules: PolicyRules) -> Self {
+        Self {
+            state: Mutex::new(PolicyState {
+                rules,
+                spends: vec![],
+            }),
+            path: None,
+        }
+    }
+
+    /// Load a persistent policy from `dir`, normally the storage directory of the keystore it
+    /// applies to.
+    ///
+    /// If there is no policy in `dir` yet, the new policy has no rules.
+    pub fn load(dir: &Path) -> Result<Self, KeystoreError<EspressoLedger>> {
+        fs::create_dir_all(dir).map_err(|err| KeystoreError::Failed {
+            msg: format!(
+                "failed to create policy directory {}: {}",
+                dir.display(),
+                err
+            ),
+        })?;
+        let path = dir.join(POLICY_FILE);
+        let state = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|err| KeystoreError::Failed {
+                msg: format!("corrupt spending policy {}: {}", path.display(), err),
+            })?,
+            Err(err) if err.kind() == ErrorKind::NotFound => PolicyState::default(),
+            // Any other error means there may be a policy we cannot read, and silently running
+            // without it would lift every limit.
+            Err(err) => {
+                return Err(KeystoreError::Failed {
+                    msg: format!("failed to read spending policy {}: {}", path.display(), err),
+                })
+            }
+        };
+        Ok(Self {
+            state: Mutex::new(state),
+            path: Some(path),
+        })
+    }
+
+    pub fn rules(&self) -> PolicyRules {
+        self.state.lock().unwrap().rules.clone()
+    }
+
+    /// Replace the rules of this policy.
+    ///
+    /// Spending which has already been recorded still counts towards the new limits.
+    pub fn set_rules(&self, rules: PolicyRules) -> Result<(), KeystoreError<EspressoLedger>> {
+        let mut state = self.state.lock().unwrap();
+        state.rules = rules;
+        self.persist(&state)
+    }
+
+    /// Check a transaction sending `asset` to `receivers` and paying `fee` against the policy.
+    ///
+    /// For a transfer, `receivers` are the outputs of the transfer. For a mint, they are the
+    /// receiver of the new records, and the minted amount counts towards the limits of the minted
+    /// asset. A freeze has no receivers, and only its fee is checked. The fee counts towards the
+    /// daily limit of the native asset, in addition to any native amount sent to `receivers`.
+    ///
+    /// `confirmed` indicates that the user has explicitly confirmed this transaction, which is
+    /// required when sending more than an asset's confirmation threshold.
+    pub fn check(
+        &self,
+        asset: &AssetCode,
+        receivers: &[(UserAddress, u128)],
+        fee: u128,
+        confirmed: bool,
+    ) -> Result<(), PolicyRule> {
+        let state = self.state.lock().unwrap();
+        for (receiver, _) in receivers {
+            if state.rules.deny.contains(receiver) {
+                return Err(PolicyRule::Denied {
+                    receiver: receiver.clone(),
+                });
+            }
+            if let Some(allow) = &state.rules.allow {
+                if !allow.contains(receiver) {
+                    return Err(PolicyRule::NotAllowed {
+                        receiver: receiver.clone(),
+                    });
+                }
+            }
+        }
+
+        let amount = receivers
+            .iter()
+            .fold(0u128, |total, (_, amount)| total.saturating_add(*amount));
+        if let Some(limits) = state.limits(asset) {
+            if let Some(threshold) = limits.confirmation_threshold {
+                if amount > threshold && !confirmed {
+                    return Err(PolicyRule::ConfirmationRequired {
+                        asset: *asset,
+                        threshold,
+                        amount,
+                    });
+                }
+            }
+        }
+        for (asset, amount) in spends(asset, amount, fee) {
+            state.check_daily_limit(&asset, amount)?;
+        }
+        Ok(())
+    }
+
+    /// Record that a transaction sent `amount` of `asset` and paid `fee`, counting both towards
+    /// the daily limits.
+    ///
+    /// The spending counts towards the limits even if it cannot be persisted, but in that case it
+    /// will be forgotten when the keystore restarts.
+    pub fn record(
+        &self,
+        asset: &AssetCode,
+        amount: u128,
+        fee: u128,
+    ) -> Result<(), KeystoreError<EspressoLedger>> {
+        let mut state = self.state.lock().unwrap();
+        let now = now();
+        // Spending older than a day no longer counts towards any limit.
+        state
+            .spends
+            .retain(|spend| spend.time > now.saturating_sub(DAY_SECS));
+        for (asset, amount) in spends(asset, amount, fee) {
+            state.spends.push(Spend {
+                time: now,
+                asset,
+                amount,
+            });
+        }
+        self.persist(&state)
+    }
+
+    /// Check a transaction which a keystore is about to submit against the policy.
+    ///
+    /// `info` is the keystore's record of the transaction. There is no one to ask for confirmation
+    /// at this point, so the transaction counts as confirmed.
+    pub fn check_transaction(
+        &self,
+        txn: &ElaboratedTransaction,
+        info: &Transaction<EspressoLedger>,
+    ) -> Result<(), PolicyRule> {
+        let (asset, receivers, fee) = spending(txn, info);
+        self.check(&asset, &receivers, fee, true)
+    }
+
+    /// Record the spending of a transaction which a keystore has submitted.
+    pub fn record_transaction(
+        &self,
+        txn: &ElaboratedTransaction,
+        info: &Transaction<EspressoLedger>,
+    ) -> Result<(), KeystoreError<EspressoLedger>> {
+        let (asset, receivers, fee) = spending(txn, info);
+        let amount = receivers
+            .iter()
+            .fold(0u128, |total, (_, amount)| total.saturating_add(*amount));
+        self.record(&asset, amount, fee)
+    }
+
+    fn persist(&self, state: &PolicyState) -> Result<(), KeystoreError<EspressoLedger>> {
+        if let Some(path) = &self.path {
+            // Write the new state alongside the old one and then rename it into place, so that a
+            // crash part way through cannot leave a truncated policy behind.
+            let tmp = path.with_extension("json.tmp");
+            fs::write(&tmp, serde_json::to_vec(state).unwrap())
+                .and_then(|()| fs::rename(&tmp, path))
+                .map_err(|err| KeystoreError::Failed {
+                    msg: format!("failed to persist spending policy: {}", err),
+                })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jf_cap::{keys::UserKeyPair, structs::AssetCodeSeed};
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_spending_policy() {
+        let mut rng = ChaChaRng::from_seed([0x91; 32]);
+        let friend = UserKeyPair::generate(&mut rng).address();
+        let stranger = UserKeyPair::generate(&mut rng).address();
+        let asset = AssetCode::native();
+        let dir = TempDir::new("spending_policy").unwrap();
+
+        let policy = SpendingPolicy::load(dir.path()).unwrap();
+        policy
+            .check(&asset, &[(stranger.clone(), 1000)], 0, false)
+            .unwrap();
+        policy
+            .set_rules(PolicyRules {
+                limits: vec![AssetLimits {
+                    asset,
+                    daily_limit: Some(100),
+                    confirmation_threshold: Some(50),
+                }],
+                allow: Some([friend.clone()].into_iter().collect()),
+                deny: Default::default(),
+            })
+            .unwrap();
+
+        assert!(matches!(
+            policy.check(&asset, &[(stranger, 10)], 0, false),
+            Err(PolicyRule::NotAllowed { .. })
+        ));
+        assert!(matches!(
+            policy.check(&asset, &[(friend.clone(), 60)], 0, false),
+            Err(PolicyRule::ConfirmationRequired { amount: 60, .. })
+        ));
+        policy
+            .check(&asset, &[(friend.clone(), 60)], 0, true)
+            .unwrap();
+        policy.record(&asset, 60, 0).unwrap();
+
+        // Both the rules and the spending survive a restart.
+        let policy = SpendingPolicy::load(dir.path()).unwrap();
+        assert_eq!(
+            policy.check(
+                &asset,
+                &[(friend.clone(), 30), (friend.clone(), 20)],
+                0,
+                false
+            ),
+            Err(PolicyRule::DailyLimit {
+                asset,
+                limit: 100,
+                spent: 60,
+                requested: 50,
+            })
+        );
+        policy
+            .check(&asset, &[(friend.clone(), 40)], 0, false)
+            .unwrap();
+
+        // Fees count towards the native limit, whatever asset is sent.
+        assert_eq!(
+            policy.check(&asset, &[(friend.clone(), 35)], 6, false),
+            Err(PolicyRule::DailyLimit {
+                asset,
+                limit: 100,
+                spent: 60,
+                requested: 41,
+            })
+        );
+        let other = AssetCode::new_domestic(AssetCodeSeed::generate(&mut rng), b"other");
+        assert!(matches!(
+            policy.check(&other, &[(friend.clone(), 1000)], 41, false),
+            Err(PolicyRule::DailyLimit { requested: 41, .. })
+        ));
+        policy.check(&other, &[], 40, false).unwrap();
+        policy.record(&other, 1000, 40).unwrap();
+        assert!(matches!(
+            policy.check(&asset, &[(friend.clone(), 1)], 0, false),
+            Err(PolicyRule::DailyLimit { spent: 100, .. })
+        ));
+
+        // A request large enough to overflow the running total is rejected, not wrapped.
+        assert!(matches!(
+            policy.check(&asset, &[(friend, u128::MAX)], 0, true),
+            Err(PolicyRule::DailyLimit { .. })
+        ));
+
+        // No temporary file is left behind after persisting.
+        assert!(!dir.path().join("spending_policy.json.tmp").exists());
+    }
+
+    #[test]
+    fn test_unreadable_policy() {
+        let dir = TempDir::new("unreadable_policy").unwrap();
+        // A policy file we cannot read must not be mistaken for a missing one.
+        fs::create_dir(dir.path().join(POLICY_FILE)).unwrap();
+        assert!(SpendingPolicy::load(dir.path()).is_err());
+    }
+}
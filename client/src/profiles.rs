@@ -0,0 +1,306 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Several named keystores under one data root.
+//!
+//! A service or user with several keystores (say, a personal keystore, an auditor and a freezer)
+//! can keep them all in one place with [Profiles]. Each profile is a subdirectory of the root,
+//! holding a `profile.json` metadata file and a `keystore` directory which is used as the storage
+//! for that keystore:
+//!
+//! ```text
+//! <root>/
+//!     personal/
+//!         profile.json
+//!         keystore/
+//!     auditor/
+//!         ...
+//! ```
+//!
+//! [Profiles::wipe] destroys a keystore's storage more thoroughly than deleting it: every file is
+//! overwritten with zeros before it is unlinked. The profile's metadata is kept, with a record of
+//! when the wipe happened.
+
+use crate::storage_lock::StorageLock;
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const METADATA_FILE: &str = "profile.json";
+const STORAGE_DIR: &str = "keystore";
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub))]
+pub enum ProfileError {
+    #[snafu(display(
+        "invalid profile name {:?}: names may only contain letters, digits, '-' and '_'",
+        name
+    ))]
+    InvalidName { name: String },
+    #[snafu(display("profile {} already exists", name))]
+    AlreadyExists { name: String },
+    #[snafu(display("profile {} does not exist", name))]
+    NotFound { name: String },
+    #[snafu(display("profile {} is in use: {}", name, msg))]
+    InUse { name: String, msg: String },
+    #[snafu(display("I/O error at {}: {}", path.display(), source))]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("malformed profile metadata at {}: {}", path.display(), source))]
+    Metadata {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+}
+
+/// The metadata of a profile.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProfileInfo {
+    pub name: String,
+    pub description: String,
+    /// When the profile was created, in seconds since the Unix epoch.
+    pub created: u64,
+    /// When the keystore was wiped, if it has been, in seconds since the Unix epoch.
+    #[serde(default)]
+    pub wiped: Option<u64>,
+}
+
+/// The profiles under a data root.
+#[derive(Clone, Debug)]
+pub struct Profiles {
+    root: PathBuf,
+}
+
+impl Profiles {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Every profile under the root, sorted by name.
+    ///
+    /// Subdirectories without profile metadata are ignored.
+    pub fn list(&self) -> Result<Vec<ProfileInfo>, ProfileError> {
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(source) => {
+                return Err(ProfileError::Io {
+                    path: self.root.clone(),
+                    source,
+                })
+            }
+        };
+        let mut profiles = vec![];
+        for entry in entries {
+            let entry = entry.context(IoSnafu { path: &self.root })?;
+            if entry.path().join(METADATA_FILE).is_file() {
+                profiles.push(read_metadata(&entry.path())?);
+            }
+        }
+        profiles.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(profiles)
+    }
+
+    pub fn get(&self, name: &str) -> Result<ProfileInfo, ProfileError> {
+        let dir = self.dir(name)?;
+        if !dir.join(METADATA_FILE).is_file() {
+            return Err(ProfileError::NotFound { name: name.into() });
+        }
+        read_metadata(&dir)
+    }
+
+    /// Create a new, empty profile.
+    ///
+    /// The keystore itself is created the first time its storage is opened.
+    pub fn create(&self, name: &str, description: &str) -> Result<ProfileInfo, ProfileError> {
+        let dir = self.dir(name)?;
+        if dir.exists() {
+            return Err(ProfileError::AlreadyExists { name: name.into() });
+        }
+        let storage = dir.join(STORAGE_DIR);
+        fs::create_dir_all(&storage).context(IoSnafu { path: &storage })?;
+        let info = ProfileInfo {
+            name: name.into(),
+            description: description.into(),
+            created: now(),
+            wiped: None,
+        };
+        write_metadata(&dir, &info)?;
+        Ok(info)
+    }
+
+    /// The storage directory of the keystore for profile `name`.
+    pub fn storage(&self, name: &str) -> Result<PathBuf, ProfileError> {
+        self.get(name)?;
+        Ok(self.dir(name)?.join(STORAGE_DIR))
+    }
+
+    /// Delete a profile and its keystore.
+    ///
+    /// Fails if the keystore is open, in this or any other process.
+    pub fn delete(&self, name: &str) -> Result<(), ProfileError> {
+        let storage = self.storage(name)?;
+        let _lock = StorageLock::acquire(&storage, false).map_err(|err| ProfileError::InUse {
+            name: name.into(),
+            msg: err.to_string(),
+        })?;
+        let dir = self.dir(name)?;
+        fs::remove_dir_all(&dir).context(IoSnafu { path: dir })
+    }
+
+    /// Securely erase the keystore of a profile, keeping its metadata.
+    ///
+    /// Each file in the keystore storage is overwritten with zeros and synced to disk before it is
+    /// removed. On file systems which do not overwrite in place (copy-on-write or journaling data)
+    /// old blocks may survive, so this is a best effort, not a guarantee. Like
+    /// [delete](Self::delete), this fails if the keystore is open.
+    pub fn wipe(&self, name: &str) -> Result<ProfileInfo, ProfileError> {
+        let storage = self.storage(name)?;
+        let lock = StorageLock::acquire(&storage, false).map_err(|err| ProfileError::InUse {
+            name: name.into(),
+            msg: err.to_string(),
+        })?;
+        wipe_dir(&storage, lock.path())?;
+
+        let dir = self.dir(name)?;
+        let mut info = read_metadata(&dir)?;
+        info.wiped = Some(now());
+        write_metadata(&dir, &info)?;
+        tracing::info!("wiped keystore for profile {}", name);
+        Ok(info)
+    }
+
+    fn dir(&self, name: &str) -> Result<PathBuf, ProfileError> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(ProfileError::InvalidName { name: name.into() });
+        }
+        Ok(self.root.join(name))
+    }
+}
+
+// Overwrite and remove everything in `dir` except the file at `keep`, which is the lock we are
+// holding.
+fn wipe_dir(dir: &Path, keep: &Path) -> Result<(), ProfileError> {
+    for entry in fs::read_dir(dir).context(IoSnafu { path: dir })? {
+        let path = entry.context(IoSnafu { path: dir })?.path();
+        if path == keep {
+            continue;
+        }
+        // Don't follow symlinks, which may point outside the storage.
+        let file_type = fs::symlink_metadata(&path)
+            .context(IoSnafu { path: &path })?
+            .file_type();
+        if file_type.is_dir() {
+            wipe_dir(&path, keep)?;
+            fs::remove_dir(&path).context(IoSnafu { path })?;
+        } else {
+            if file_type.is_file() {
+                overwrite(&path).context(IoSnafu { path: &path })?;
+            }
+            fs::remove_file(&path).context(IoSnafu { path })?;
+        }
+    }
+    Ok(())
+}
+
+fn overwrite(path: &Path) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let mut remaining = file.metadata()?.len();
+    let zeros = [0u8; 4096];
+    while remaining > 0 {
+        let n = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..n])?;
+        remaining -= n as u64;
+    }
+    file.sync_all()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn read_metadata(dir: &Path) -> Result<ProfileInfo, ProfileError> {
+    let path = dir.join(METADATA_FILE);
+    let bytes = fs::read(&path).context(IoSnafu { path: &path })?;
+    serde_json::from_slice(&bytes).context(MetadataSnafu { path })
+}
+
+fn write_metadata(dir: &Path, info: &ProfileInfo) -> Result<(), ProfileError> {
+    let path = dir.join(METADATA_FILE);
+    let bytes = serde_json::to_vec_pretty(info).context(MetadataSnafu { path: &path })?;
+    fs::write(&path, bytes).context(IoSnafu { path })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_profiles() {
+        let dir = TempDir::new("test_profiles").unwrap();
+        let profiles = Profiles::new(dir.path().join("profiles"));
+        assert_eq!(profiles.list().unwrap(), vec![]);
+
+        let auditor = profiles.create("auditor", "compliance").unwrap();
+        let personal = profiles.create("personal", "").unwrap();
+        assert_eq!(profiles.list().unwrap(), vec![auditor.clone(), personal]);
+        assert_eq!(profiles.get("auditor").unwrap(), auditor);
+        assert!(profiles.storage("auditor").unwrap().is_dir());
+        assert!(matches!(
+            profiles.create("auditor", ""),
+            Err(ProfileError::AlreadyExists { .. })
+        ));
+        assert!(matches!(
+            profiles.create("../escape", ""),
+            Err(ProfileError::InvalidName { .. })
+        ));
+
+        // A profile can't be deleted while its keystore is open.
+        let lock = StorageLock::acquire(&profiles.storage("auditor").unwrap(), false).unwrap();
+        assert!(matches!(
+            profiles.delete("auditor"),
+            Err(ProfileError::InUse { .. })
+        ));
+        drop(lock);
+        profiles.delete("auditor").unwrap();
+        assert!(matches!(
+            profiles.get("auditor"),
+            Err(ProfileError::NotFound { .. })
+        ));
+        assert_eq!(profiles.list().unwrap().len(), 1);
+
+        // Wiping removes the keystore files but keeps the profile.
+        let storage = profiles.storage("personal").unwrap();
+        fs::create_dir_all(storage.join("logs")).unwrap();
+        fs::write(storage.join("logs").join("0"), [0xaa; 10_000]).unwrap();
+        fs::write(storage.join("meta"), b"secret").unwrap();
+        let info = profiles.wipe("personal").unwrap();
+        assert!(info.wiped.is_some());
+        assert_eq!(profiles.get("personal").unwrap(), info);
+        let remaining = fs::read_dir(&storage)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            remaining,
+            vec![std::ffi::OsString::from(crate::storage_lock::LOCK_FILE)]
+        );
+    }
+}
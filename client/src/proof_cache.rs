@@ -0,0 +1,471 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! A cache of ledger proofs shared between keystores on the same host.
+//!
+//! Hosts which run many keystores would otherwise fetch the same proofs from the query service once
+//! per keystore. A [ProofCacheServer] keeps one copy of each proof and serves it to any number of
+//! [ProofCacheClient]s over a loopback socket. It caches two kinds of proofs: nullifier
+//! non-membership proofs, and record Merkle paths, which keystores use to rebuild the witnesses of
+//! their records.
+//!
+//! Proofs are keyed by the commitment to the tree they were generated against (the root hash of
+//! the nullifier set, or the record Merkle commitment), so a proof is only ever served for the
+//! exact state it is valid for. The server checks every proof against its root before accepting
+//! it, so a misbehaving client cannot poison the cache.
+//!
+//! Messages are `bincode`-encoded and prefixed with their length as a little-endian `u32`.
+
+use async_std::{
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    task::spawn,
+};
+use espresso_core::{
+    ledger::EspressoLedger,
+    set_merkle_tree::{set_hash, SetMerkleProof},
+};
+use futures::{io::BufReader, AsyncReadExt, AsyncWriteExt, StreamExt};
+use jf_cap::structs::Nullifier;
+use jf_cap::{MerkleCommitment, MerkleLeafProof, MerkleTree};
+use seahorse::KeystoreError;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+/// The default number of proofs retained by a [ProofCacheServer].
+pub const DEFAULT_CACHE_CAPACITY: usize = 100_000;
+
+// Upper bound on the size of a single message, to protect against garbage on the socket.
+const MAX_MESSAGE_SIZE: u32 = 1 << 20;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum Request {
+    Get {
+        root: set_hash::Hash,
+        nullifier: Nullifier,
+    },
+    Put {
+        root: set_hash::Hash,
+        nullifier: Nullifier,
+        spent: bool,
+        proof: SetMerkleProof,
+    },
+    GetRecordPath {
+        root: MerkleCommitment,
+        uid: u64,
+    },
+    PutRecordPath {
+        root: MerkleCommitment,
+        uid: u64,
+        proof: MerkleLeafProof,
+    },
+}
+
+type Response = Option<(bool, SetMerkleProof)>;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Nullifier(set_hash::Hash, Nullifier),
+    // Record Merkle commitments are keyed by their serialization.
+    RecordPath(Vec<u8>, u64),
+}
+
+impl Key {
+    fn record_path(root: &MerkleCommitment, uid: u64) -> Self {
+        Self::RecordPath(bincode::serialize(root).unwrap(), uid)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Proof {
+    Nullifier(bool, SetMerkleProof),
+    RecordPath(MerkleLeafProof),
+}
+
+/// An in-memory cache of nullifier proofs and record Merkle paths, evicting the oldest entries
+/// beyond `capacity`.
+#[derive(Debug)]
+pub struct ProofCache {
+    proofs: HashMap<Key, Proof>,
+    order: VecDeque<Key>,
+    capacity: usize,
+}
+
+impl ProofCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            proofs: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn get(&self, root: set_hash::Hash, nullifier: Nullifier) -> Response {
+        match self.proofs.get(&Key::Nullifier(root, nullifier)) {
+            Some(Proof::Nullifier(spent, proof)) => Some((*spent, proof.clone())),
+            _ => None,
+        }
+    }
+
+    /// Insert a proof, if it is valid for `root`.
+    ///
+    /// Returns `false` if the proof was rejected.
+    pub fn insert(
+        &mut self,
+        root: set_hash::Hash,
+        nullifier: Nullifier,
+        spent: bool,
+        proof: SetMerkleProof,
+    ) -> bool {
+        if proof.check(nullifier, &root) != Ok(spent) {
+            return false;
+        }
+        self.insert_checked(
+            Key::Nullifier(root, nullifier),
+            Proof::Nullifier(spent, proof),
+        );
+        true
+    }
+
+    pub fn get_record_path(&self, root: &MerkleCommitment, uid: u64) -> Option<MerkleLeafProof> {
+        match self.proofs.get(&Key::record_path(root, uid)) {
+            Some(Proof::RecordPath(proof)) => Some(proof.clone()),
+            _ => None,
+        }
+    }
+
+    /// Insert the Merkle path for the record with UID `uid`, if it is valid for `root`.
+    ///
+    /// Returns `false` if the path was rejected.
+    pub fn insert_record_path(
+        &mut self,
+        root: &MerkleCommitment,
+        uid: u64,
+        proof: MerkleLeafProof,
+    ) -> bool {
+        if MerkleTree::check_proof(root.root_value, uid, &proof).is_err() {
+            return false;
+        }
+        self.insert_checked(Key::record_path(root, uid), Proof::RecordPath(proof));
+        true
+    }
+
+    fn insert_checked(&mut self, key: Key, proof: Proof) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.proofs.insert(key.clone(), proof).is_none() {
+            self.order.push_back(key);
+            while self.order.len() > self.capacity {
+                if let Some(old) = self.order.pop_front() {
+                    self.proofs.remove(&old);
+                }
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.proofs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.proofs.is_empty()
+    }
+}
+
+/// Serves a [ProofCache] to local keystores.
+pub struct ProofCacheServer {
+    listener: TcpListener,
+    cache: Arc<Mutex<ProofCache>>,
+}
+
+impl ProofCacheServer {
+    pub async fn bind(
+        addr: impl ToSocketAddrs,
+        capacity: usize,
+    ) -> Result<Self, KeystoreError<EspressoLedger>> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|err| KeystoreError::Failed {
+                msg: format!("failed to bind proof cache socket: {}", err),
+            })?;
+        Ok(Self {
+            listener,
+            cache: Arc::new(Mutex::new(ProofCache::new(capacity))),
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accept and serve connections until the listener fails.
+    pub async fn serve(self) {
+        let mut incoming = self.listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => {
+                    spawn(Self::serve_connection(stream, self.cache.clone()));
+                }
+                Err(err) => {
+                    tracing::warn!("proof cache failed to accept connection: {}", err);
+                }
+            }
+        }
+    }
+
+    async fn serve_connection(stream: TcpStream, cache: Arc<Mutex<ProofCache>>) {
+        let mut reader = BufReader::new(stream.clone());
+        let mut writer = stream;
+        loop {
+            let req: Request = match read_message(&mut reader).await {
+                Ok(req) => req,
+                // The client hung up or sent garbage; either way we are done with it.
+                Err(_) => return,
+            };
+            let res = match req {
+                Request::Get { root, nullifier } => {
+                    let res: Response = cache.lock().await.get(root, nullifier);
+                    write_message(&mut writer, &res).await
+                }
+                Request::Put {
+                    root,
+                    nullifier,
+                    spent,
+                    proof,
+                } => {
+                    if !cache.lock().await.insert(root, nullifier, spent, proof) {
+                        tracing::warn!("proof cache rejected invalid proof for {}", nullifier);
+                    }
+                    write_message(&mut writer, &()).await
+                }
+                Request::GetRecordPath { root, uid } => {
+                    let res = cache.lock().await.get_record_path(&root, uid);
+                    write_message(&mut writer, &res).await
+                }
+                Request::PutRecordPath { root, uid, proof } => {
+                    if !cache.lock().await.insert_record_path(&root, uid, proof) {
+                        tracing::warn!("proof cache rejected invalid Merkle path for {}", uid);
+                    }
+                    write_message(&mut writer, &()).await
+                }
+            };
+            if res.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// A connection to a [ProofCacheServer].
+pub struct ProofCacheClient {
+    stream: Mutex<TcpStream>,
+}
+
+impl ProofCacheClient {
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self, KeystoreError<EspressoLedger>> {
+        let stream = TcpStream::connect(addr)
+            .await
+            .map_err(|err| KeystoreError::Failed {
+                msg: format!("failed to connect to proof cache: {}", err),
+            })?;
+        Ok(Self {
+            stream: Mutex::new(stream),
+        })
+    }
+
+    pub async fn get(
+        &self,
+        root: set_hash::Hash,
+        nullifier: Nullifier,
+    ) -> Result<Response, KeystoreError<EspressoLedger>> {
+        self.request(&Request::Get { root, nullifier }).await
+    }
+
+    pub async fn put(
+        &self,
+        root: set_hash::Hash,
+        nullifier: Nullifier,
+        spent: bool,
+        proof: SetMerkleProof,
+    ) -> Result<(), KeystoreError<EspressoLedger>> {
+        self.request(&Request::Put {
+            root,
+            nullifier,
+            spent,
+            proof,
+        })
+        .await
+    }
+
+    pub async fn get_record_path(
+        &self,
+        root: MerkleCommitment,
+        uid: u64,
+    ) -> Result<Option<MerkleLeafProof>, KeystoreError<EspressoLedger>> {
+        self.request(&Request::GetRecordPath { root, uid }).await
+    }
+
+    pub async fn put_record_path(
+        &self,
+        root: MerkleCommitment,
+        uid: u64,
+        proof: MerkleLeafProof,
+    ) -> Result<(), KeystoreError<EspressoLedger>> {
+        self.request(&Request::PutRecordPath { root, uid, proof })
+            .await
+    }
+
+    async fn request<T: DeserializeOwned>(
+        &self,
+        req: &Request,
+    ) -> Result<T, KeystoreError<EspressoLedger>> {
+        let mut stream = self.stream.lock().await;
+        write_message(&mut *stream, req).await?;
+        read_message(&mut *stream).await
+    }
+}
+
+async fn read_message<T: DeserializeOwned>(
+    r: &mut (impl AsyncReadExt + Unpin),
+) -> Result<T, KeystoreError<EspressoLedger>> {
+    let io_err = |err: std::io::Error| KeystoreError::Failed {
+        msg: format!("proof cache I/O error: {}", err),
+    };
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len).await.map_err(io_err)?;
+    let len = u32::from_le_bytes(len);
+    if len > MAX_MESSAGE_SIZE {
+        return Err(KeystoreError::Failed {
+            msg: format!("proof cache message too large ({} bytes)", len),
+        });
+    }
+    let mut buf = vec![0u8; len as usize];
+    r.read_exact(&mut buf).await.map_err(io_err)?;
+    bincode::deserialize(&buf).map_err(|err| KeystoreError::Failed {
+        msg: format!("malformed proof cache message: {}", err),
+    })
+}
+
+async fn write_message<T: Serialize>(
+    w: &mut (impl AsyncWriteExt + Unpin),
+    msg: &T,
+) -> Result<(), KeystoreError<EspressoLedger>> {
+    let buf = bincode::serialize(msg).unwrap();
+    let mut frame = (buf.len() as u32).to_le_bytes().to_vec();
+    frame.extend(buf);
+    w.write_all(&frame)
+        .await
+        .map_err(|err| KeystoreError::Failed {
+            msg: format!("proof cache I/O error: {}", err),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use espresso_core::{set_merkle_tree::SetMerkleTree, universal_params::MERKLE_HEIGHT};
+    use jf_cap::{
+        keys::UserKeyPair,
+        structs::{AssetDefinition, FreezeFlag, Nullifier, RecordCommitment, RecordOpening},
+    };
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+
+    #[async_std::test]
+    async fn test_shared_proof_cache() {
+        let mut rng = ChaChaRng::from_seed([7; 32]);
+        let spent = Nullifier::random_for_test(&mut rng);
+        let unspent = Nullifier::random_for_test(&mut rng);
+        let mut set = SetMerkleTree::default();
+        set.insert(spent);
+        let root = set.hash();
+
+        let server = ProofCacheServer::bind("127.0.0.1:0", 10).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        spawn(server.serve());
+
+        let writer = ProofCacheClient::connect(addr).await.unwrap();
+        let reader = ProofCacheClient::connect(addr).await.unwrap();
+        assert!(reader.get(root, spent).await.unwrap().is_none());
+
+        let (is_spent, proof) = set.contains(spent).unwrap();
+        assert!(is_spent);
+        writer.put(root, spent, true, proof.clone()).await.unwrap();
+        assert_eq!(reader.get(root, spent).await.unwrap(), Some((true, proof)));
+
+        // A proof that claims the wrong membership status is not cached.
+        let (_, proof) = set.contains(unspent).unwrap();
+        writer.put(root, unspent, true, proof).await.unwrap();
+        assert!(reader.get(root, unspent).await.unwrap().is_none());
+
+        // Proofs are only served for the root they were checked against.
+        assert!(reader
+            .get(SetMerkleTree::default().hash(), spent)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[async_std::test]
+    async fn test_shared_record_path_cache() {
+        let mut rng = ChaChaRng::from_seed([8; 32]);
+        let mut record = || {
+            let owner = UserKeyPair::generate(&mut rng).pub_key();
+            let ro = RecordOpening::new(
+                &mut rng,
+                1u64.into(),
+                AssetDefinition::native(),
+                owner,
+                FreezeFlag::Unfrozen,
+            );
+            RecordCommitment::from(&ro).to_field_element()
+        };
+        let mut tree = MerkleTree::new(MERKLE_HEIGHT).unwrap();
+        tree.push(record());
+        tree.push(record());
+        let root = tree.commitment();
+        let (_, proof) = tree.get_leaf(0).expect_ok().unwrap();
+
+        let server = ProofCacheServer::bind("127.0.0.1:0", 10).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        spawn(server.serve());
+
+        let writer = ProofCacheClient::connect(addr).await.unwrap();
+        let reader = ProofCacheClient::connect(addr).await.unwrap();
+        assert!(reader.get_record_path(root, 0).await.unwrap().is_none());
+        writer
+            .put_record_path(root, 0, proof.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            reader.get_record_path(root, 0).await.unwrap(),
+            Some(proof.clone())
+        );
+
+        // A path for a different record is not cached.
+        writer
+            .put_record_path(root, 1, proof.clone())
+            .await
+            .unwrap();
+        assert!(reader.get_record_path(root, 1).await.unwrap().is_none());
+
+        // Paths are only served for the root they were checked against.
+        let (_, stale) = tree.get_leaf(1).expect_ok().unwrap();
+        tree.push(record());
+        assert!(reader
+            .get_record_path(tree.commitment(), 0)
+            .await
+            .unwrap()
+            .is_none());
+        // And a path checked against an old root is rejected for a new one.
+        writer
+            .put_record_path(tree.commitment(), 1, stale)
+            .await
+            .unwrap();
+        assert!(reader
+            .get_record_path(tree.commitment(), 1)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! A cache of public keys fetched from the address book.
+//!
+//! Every transfer needs the public key of each receiver, to encrypt its owner memo. A
+//! [NetworkBackend](crate::network::NetworkBackend) remembers the keys it has fetched in a
+//! [PubKeyCache], which can be shared between keystores in the same process. The address book lets
+//! a user replace the key published at their address, so entries expire after a configurable time
+//! to live and are fetched again on the next use.
+
+use jf_cap::keys::{UserAddress, UserPubKey};
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The default number of keys retained by a [PubKeyCache].
+pub const DEFAULT_CACHE_CAPACITY: usize = 10_000;
+/// The default time to live of an entry in a [PubKeyCache].
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(600);
+
+#[derive(Debug)]
+struct Entry {
+    pub_key: UserPubKey,
+    fetched: Instant,
+}
+
+/// A least-recently-used cache of public keys, with expiring entries.
+///
+/// Lookups, insertions and evictions all take constant time.
+#[derive(Debug)]
+pub struct PubKeyCache {
+    ttl: Duration,
+    /// `None` if the cache has capacity 0, and so caches nothing.
+    entries: Option<Mutex<LruCache<UserAddress, Entry>>>,
+}
+
+impl Default for PubKeyCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_CAPACITY, DEFAULT_CACHE_TTL)
+    }
+}
+
+impl PubKeyCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: NonZeroUsize::new(capacity).map(|cap| Mutex::new(LruCache::new(cap))),
+        }
+    }
+
+    /// The cached key for `address`, if there is one and it has not expired.
+    pub fn get(&self, address: &UserAddress) -> Option<UserPubKey> {
+        let mut entries = self.entries.as_ref()?.lock().unwrap();
+        match entries.get(address) {
+            Some(entry) if entry.fetched.elapsed() < self.ttl => Some(entry.pub_key.clone()),
+            Some(_) => {
+                entries.pop(address);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Cache a key which was just fetched, evicting the least recently used key if the cache is
+    /// full.
+    pub fn insert(&self, pub_key: UserPubKey) {
+        if let Some(entries) = &self.entries {
+            entries.lock().unwrap().put(
+                pub_key.address(),
+                Entry {
+                    pub_key,
+                    fetched: Instant::now(),
+                },
+            );
+        }
+    }
+
+    /// Forget the key for `address`, so that it is fetched again on the next use.
+    pub fn invalidate(&self, address: &UserAddress) {
+        if let Some(entries) = &self.entries {
+            entries.lock().unwrap().pop(address);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries
+            .as_ref()
+            .map_or(0, |entries| entries.lock().unwrap().len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jf_cap::keys::UserKeyPair;
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+
+    #[test]
+    fn test_pub_key_cache() {
+        let mut rng = ChaChaRng::from_seed([5; 32]);
+        let keys = (0..3)
+            .map(|_| UserKeyPair::generate(&mut rng).pub_key())
+            .collect::<Vec<_>>();
+
+        let cache = PubKeyCache::new(2, Duration::from_secs(3600));
+        cache.insert(keys[0].clone());
+        cache.insert(keys[1].clone());
+        assert_eq!(cache.get(&keys[0].address()), Some(keys[0].clone()));
+
+        // Inserting a third key evicts the least recently used one.
+        cache.insert(keys[2].clone());
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&keys[1].address()), None);
+        assert_eq!(cache.get(&keys[0].address()), Some(keys[0].clone()));
+        assert_eq!(cache.get(&keys[2].address()), Some(keys[2].clone()));
+
+        cache.invalidate(&keys[0].address());
+        assert_eq!(cache.get(&keys[0].address()), None);
+
+        // Expired entries are not served.
+        let cache = PubKeyCache::new(2, Duration::ZERO);
+        cache.insert(keys[0].clone());
+        assert_eq!(cache.get(&keys[0].address()), None);
+        assert!(cache.is_empty());
+
+        // A cache with no capacity caches nothing.
+        let cache = PubKeyCache::new(0, Duration::from_secs(3600));
+        cache.insert(keys[0].clone());
+        assert_eq!(cache.get(&keys[0].address()), None);
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Exclusive access to a keystore's storage directory.
+//!
+//! Two processes writing to the same keystore storage will corrupt its logs. A [StorageLock] takes
+//! an advisory lock on a file in the storage directory, so a second process opening the same
+//! keystore fails up front instead. The lock is released by the operating system when the lock is
+//! dropped or the process exits, so a crash never leaves a stale lock behind.
+
+use espresso_core::ledger::EspressoLedger;
+use fd_lock::RwLock;
+use seahorse::KeystoreError;
+use std::fs::{self, File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// The name of the lock file inside a storage directory.
+pub const LOCK_FILE: &str = "keystore.lock";
+
+/// Holds the lock on a storage directory until dropped.
+#[derive(Debug)]
+pub struct StorageLock {
+    path: PathBuf,
+    _lock: RwLock<File>,
+}
+
+impl StorageLock {
+    /// Lock the storage directory `dir`, creating it if necessary.
+    ///
+    /// Fails if another process holds the lock, unless `force` is set, in which case a warning is
+    /// logged and the storage is opened without the lock. Forcing is only meant for recovery
+    /// tooling, when the other process is known to be stuck rather than writing.
+    pub fn acquire(dir: &Path, force: bool) -> Result<Self, KeystoreError<EspressoLedger>> {
+        let path = dir.join(LOCK_FILE);
+        let err = |msg: String| KeystoreError::Failed { msg };
+        fs::create_dir_all(dir).map_err(|source| {
+            err(format!(
+                "failed to create storage directory {}: {}",
+                dir.display(),
+                source
+            ))
+        })?;
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&path)
+            .map_err(|source| {
+                err(format!(
+                    "failed to open lock file {}: {}",
+                    path.display(),
+                    source
+                ))
+            })?;
+        let mut lock = RwLock::new(file);
+        match lock.try_write() {
+            // The lock lasts as long as the file is open, not as long as the guard, so we can let
+            // go of the guard and keep the file.
+            Ok(guard) => std::mem::forget(guard),
+            Err(source) if source.kind() == ErrorKind::WouldBlock => {
+                if !force {
+                    return Err(err(format!(
+                        "keystore at {} is already open in another process",
+                        dir.display()
+                    )));
+                }
+                tracing::warn!(
+                    "keystore at {} is open in another process, opening it anyway",
+                    dir.display()
+                );
+            }
+            Err(source) => {
+                return Err(err(format!(
+                    "failed to lock {}: {}",
+                    path.display(),
+                    source
+                )));
+            }
+        }
+        Ok(Self { path, _lock: lock })
+    }
+
+    /// The path of the lock file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_storage_lock() {
+        let dir = TempDir::new("test_storage_lock").unwrap();
+        let storage = dir.path().join("keystore");
+
+        let lock = StorageLock::acquire(&storage, false).unwrap();
+        assert!(lock.path().exists());
+        assert!(StorageLock::acquire(&storage, false).is_err());
+        let forced = StorageLock::acquire(&storage, true).unwrap();
+        drop(forced);
+
+        // Once released, the storage can be locked again.
+        drop(lock);
+        StorageLock::acquire(&storage, false).unwrap();
+    }
+}
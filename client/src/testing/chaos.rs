@@ -0,0 +1,117 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Adversarial behavior for the mock network.
+//!
+//! A [MockEspressoNetwork](super::mocks::MockEspressoNetwork) with a [ChaosConfig] misbehaves at
+//! random: it drops or reorders submitted blocks, publishes events twice, attaches garbage memos to
+//! committed outputs, and answers nullifier proof queries from the nullifier set as it was before
+//! the latest block. Every decision is drawn from an RNG seeded by the config, so a failing run can
+//! be reproduced exactly.
+//!
+//! Dropped blocks produce no event at all, so tests which enable [ChaosConfig::drop_block] must not
+//! wait indefinitely for their transactions to be committed or rejected.
+
+use jf_cap::{
+    keys::UserKeyPair,
+    structs::{Amount, AssetDefinition, FreezeFlag, ReceiverMemo, RecordOpening},
+};
+use rand::Rng;
+use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+
+/// How often each kind of misbehavior occurs.
+///
+/// Each field other than `seed` is a probability between 0 and 1. The default config never
+/// misbehaves.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChaosConfig {
+    pub seed: u64,
+    /// A submitted block is discarded without being committed or rejected.
+    pub drop_block: f64,
+    /// A submitted block is held back and applied after the next block.
+    pub reorder_block: f64,
+    /// An event is published twice.
+    pub duplicate_event: f64,
+    /// The memos posted for a transaction are replaced with memos for unrelated records.
+    pub corrupt_memos: f64,
+    /// A nullifier proof is served from the nullifier set preceding the latest block.
+    pub stale_nullifier_proof: f64,
+}
+
+/// The seeded decision-maker for a [ChaosConfig].
+#[derive(Clone, Debug)]
+pub struct Chaos {
+    config: ChaosConfig,
+    rng: ChaChaRng,
+}
+
+impl Chaos {
+    pub fn new(config: ChaosConfig) -> Self {
+        Self {
+            config,
+            rng: ChaChaRng::seed_from_u64(config.seed),
+        }
+    }
+
+    pub fn config(&self) -> &ChaosConfig {
+        &self.config
+    }
+
+    pub fn drop_block(&mut self) -> bool {
+        self.happens(self.config.drop_block)
+    }
+
+    pub fn reorder_block(&mut self) -> bool {
+        self.happens(self.config.reorder_block)
+    }
+
+    pub fn duplicate_event(&mut self) -> bool {
+        self.happens(self.config.duplicate_event)
+    }
+
+    pub fn corrupt_memos(&mut self) -> bool {
+        self.happens(self.config.corrupt_memos)
+    }
+
+    pub fn stale_nullifier_proof(&mut self) -> bool {
+        self.happens(self.config.stale_nullifier_proof)
+    }
+
+    /// A well-formed memo which does not open the record it is posted for.
+    pub fn garbage_memo(&mut self) -> ReceiverMemo {
+        let ro = RecordOpening::new(
+            &mut self.rng,
+            Amount::from(1u64),
+            AssetDefinition::native(),
+            UserKeyPair::generate(&mut self.rng).pub_key(),
+            FreezeFlag::Unfrozen,
+        );
+        ReceiverMemo::from_ro(&mut self.rng, &ro, &[]).unwrap()
+    }
+
+    fn happens(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.gen_bool(probability.min(1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chaos_is_reproducible() {
+        let config = ChaosConfig {
+            seed: 7,
+            drop_block: 0.5,
+            duplicate_event: 1.0,
+            ..Default::default()
+        };
+        let mut a = Chaos::new(config);
+        let mut b = Chaos::new(config);
+        for _ in 0..100 {
+            assert_eq!(a.drop_block(), b.drop_block());
+            assert!(a.duplicate_event() && b.duplicate_event());
+            assert!(!a.reorder_block() && !b.reorder_block());
+        }
+    }
+}
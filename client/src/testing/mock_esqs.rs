@@ -0,0 +1,319 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! An in-process stand-in for the EsQS, for testing how a
+//! [NetworkBackend](crate::network::NetworkBackend) talks to the network.
+//!
+//! A [MockEsqs] serves the routes the network backend uses from a [MockEsqsData] which the test
+//! controls: the ledger states it reports, the events it streams (in any order, with gaps), and the
+//! errors it returns for submissions. It also records what the backend sent it, so tests can check
+//! submissions and posted memos. Unlike a [minimal test
+//! network](espresso_validator::testing::minimal_test_network), it runs no consensus, so it starts
+//! instantly and behaves deterministically.
+
+use async_std::{
+    sync::{Arc, RwLock, RwLockWriteGuard},
+    task::{spawn, JoinHandle},
+};
+use espresso_availability_api::query_data::StateQueryData;
+use espresso_core::{
+    ledger::EspressoLedger,
+    mempool::FeeSchedule,
+    state::{ElaboratedTransaction, ValidatorState},
+};
+use espresso_esqs::ApiError;
+use futures::{
+    stream::{iter, pending},
+    FutureExt, StreamExt, TryFutureExt,
+};
+use jf_cap::{structs::ReceiverMemo, Signature};
+use portpicker::pick_unused_port;
+use seahorse::events::LedgerEvent;
+use std::collections::VecDeque;
+use std::io;
+use surf_disco::Url;
+use tide_disco::{api::Api, method::ReadState, App, Error as _, RequestError, StatusCode};
+
+type State = Arc<RwLock<MockEsqsData>>;
+
+/// The data served by a [MockEsqs].
+#[derive(Default)]
+pub struct MockEsqsData {
+    /// The state after each block, indexed by block ID.
+    pub states: Vec<StateQueryData>,
+    /// The events served by the `catchup` API, indexed by event index.
+    ///
+    /// An event which is [None] is one the EsQS does not have.
+    pub events: Vec<Option<LedgerEvent<EspressoLedger>>>,
+    /// The order in which event subscriptions deliver events, if not in order of their indices.
+    ///
+    /// Events which are not in the list are never delivered by a subscription, so the subscriber
+    /// has to fetch them with `get_events_since`.
+    pub delivery_order: Option<Vec<usize>>,
+    /// If set, each event subscription is closed by the server after delivering this many events.
+    pub events_per_subscription: Option<usize>,
+    /// Errors to return for the next submissions, in order. Once these run out, submissions
+    /// succeed.
+    pub submit_errors: VecDeque<ApiError>,
+    /// Every submitted transaction, including those which failed.
+    pub submissions: Vec<ElaboratedTransaction>,
+    /// Every successful call to `post_memos`, as `(block_id, txn_id, memos, signature)`. Memos
+    /// for blocks not in `states` are rejected.
+    pub posted_memos: Vec<(u64, u64, Vec<ReceiverMemo>, Signature)>,
+    pub fee_schedule: FeeSchedule,
+}
+
+impl MockEsqsData {
+    /// Append the state after a new block.
+    ///
+    /// `continuation_event_index` is the index of the first event following the block.
+    pub fn push_state(&mut self, state: ValidatorState, continuation_event_index: u64) {
+        let block_id = self.states.len() as u64;
+        self.states.push(StateQueryData {
+            commitment: state.commit(),
+            state,
+            block_id,
+            continuation_event_index,
+        });
+    }
+
+    fn state(&self, block_id: usize) -> Result<&StateQueryData, ApiError> {
+        self.states.get(block_id).ok_or_else(|| {
+            ApiError::catch_all(StatusCode::NotFound, format!("no block {}", block_id))
+        })
+    }
+
+    fn delivery(&self, first: usize) -> Vec<(usize, Option<LedgerEvent<EspressoLedger>>)> {
+        let order = match &self.delivery_order {
+            Some(order) => order.clone(),
+            None => (0..self.events.len()).collect(),
+        };
+        order
+            .into_iter()
+            .filter(|i| *i >= first && *i < self.events.len())
+            .map(|i| (i, self.events[i].clone()))
+            .collect()
+    }
+}
+
+/// A running mock EsQS.
+pub struct MockEsqs {
+    port: u16,
+    data: State,
+    _server: JoinHandle<io::Result<()>>,
+}
+
+impl MockEsqs {
+    /// Start serving `data` on a free port.
+    pub async fn start(data: MockEsqsData) -> Self {
+        let data = Arc::new(RwLock::new(data));
+        let mut app = App::<State, ApiError>::with_state(data.clone());
+        app.register_module("status", status_api())
+            .unwrap()
+            .register_module("availability", availability_api())
+            .unwrap()
+            .register_module("validator", validator_api())
+            .unwrap()
+            .register_module("catchup", catchup_api())
+            .unwrap();
+        let port = pick_unused_port().unwrap();
+        let server = spawn(app.serve(format!("127.0.0.1:{}", port)));
+        let esqs = Self {
+            port,
+            data,
+            _server: server,
+        };
+        assert!(surf_disco::connect::<ApiError>(esqs.url(), None).await);
+        esqs
+    }
+
+    pub fn url(&self) -> Url {
+        format!("http://localhost:{}", self.port).parse().unwrap()
+    }
+
+    /// Inspect or change the data being served.
+    pub async fn data(&self) -> RwLockWriteGuard<'_, MockEsqsData> {
+        self.data.write().await
+    }
+}
+
+fn bad_request(err: RequestError) -> ApiError {
+    ApiError::catch_all(StatusCode::BadRequest, err.to_string())
+}
+
+fn api(toml: &str) -> Api<State, ApiError> {
+    Api::new(toml::from_str(toml).unwrap()).unwrap()
+}
+
+fn status_api() -> Api<State, ApiError> {
+    let mut api = api(r#"
+        [meta]
+        NAME = "mock-status"
+        DESCRIPTION = "Mock EsQS status API"
+        FORMAT_VERSION = "0.1.0"
+
+        [route.latest_block_id]
+        PATH = ["/latest_block_id"]
+    "#);
+    api.get("latest_block_id", |_req, data| {
+        async move {
+            match data.states.len() {
+                0 => Err(ApiError::catch_all(
+                    StatusCode::NotFound,
+                    "no blocks".into(),
+                )),
+                n => Ok(n as u64 - 1),
+            }
+        }
+        .boxed()
+    })
+    .unwrap();
+    api
+}
+
+fn availability_api() -> Api<State, ApiError> {
+    let mut api = api(r#"
+        [meta]
+        NAME = "mock-availability"
+        DESCRIPTION = "Mock EsQS availability API"
+        FORMAT_VERSION = "0.1.0"
+
+        [route.getstate]
+        PATH = ["/getstate/:block_id"]
+        ":block_id" = "Integer"
+
+        [route.getstatecomm]
+        PATH = ["/getstatecomm/:block_id"]
+        ":block_id" = "Integer"
+    "#);
+    api.get("getstate", |req, data| {
+        async move {
+            let block_id = req.integer_param("block_id").map_err(bad_request)?;
+            Ok(data.state(block_id)?.clone())
+        }
+        .boxed()
+    })
+    .unwrap()
+    .get("getstatecomm", |req, data| {
+        async move {
+            let block_id = req.integer_param("block_id").map_err(bad_request)?;
+            Ok(data.state(block_id)?.commitment)
+        }
+        .boxed()
+    })
+    .unwrap();
+    api
+}
+
+fn validator_api() -> Api<State, ApiError> {
+    let mut api = api(r#"
+        [meta]
+        NAME = "mock-validator"
+        DESCRIPTION = "Mock EsQS validator API"
+        FORMAT_VERSION = "0.1.0"
+
+        [route.submit]
+        PATH = ["/submit"]
+        METHOD = "POST"
+
+        [route.fee_schedule]
+        PATH = ["/fee_schedule"]
+    "#);
+    api.post("submit", |req, data| {
+        async move {
+            let txn = req
+                .body_auto::<ElaboratedTransaction>()
+                .map_err(bad_request)?;
+            data.submissions.push(txn);
+            match data.submit_errors.pop_front() {
+                Some(err) => Err(err),
+                None => Ok(()),
+            }
+        }
+        .boxed()
+    })
+    .unwrap()
+    .get("fee_schedule", |_req, data| {
+        async move { Ok(data.fee_schedule) }.boxed()
+    })
+    .unwrap();
+    api
+}
+
+fn catchup_api() -> Api<State, ApiError> {
+    let mut api = api(r#"
+        [meta]
+        NAME = "mock-catchup"
+        DESCRIPTION = "Mock EsQS catchup API"
+        FORMAT_VERSION = "0.1.0"
+
+        [route.get_events_since]
+        PATH = ["/get_events_since/:first", "/get_events_since/:first/:count"]
+        ":first" = "Integer"
+        ":count" = "Integer"
+
+        [route.subscribe_for_indexed_events]
+        PATH = ["/subscribe_for_indexed_events/:first"]
+        METHOD = "SOCKET"
+        ":first" = "Integer"
+
+        [route.post_memos]
+        PATH = ["/post_memos/:block_id/:txn_id"]
+        METHOD = "POST"
+        ":block_id" = "Integer"
+        ":txn_id" = "Integer"
+    "#);
+    api.get("get_events_since", |req, data| {
+        async move {
+            let first: usize = req.integer_param("first").map_err(bad_request)?;
+            let count = req
+                .opt_integer_param("count")
+                .map_err(bad_request)?
+                .unwrap_or(usize::MAX);
+            Ok(data
+                .events
+                .iter()
+                .skip(first)
+                .take(count)
+                .cloned()
+                .collect::<Vec<_>>())
+        }
+        .boxed()
+    })
+    .unwrap()
+    .stream("subscribe_for_indexed_events", |req, state| {
+        async move {
+            let first = req.integer_param("first").map_err(bad_request)?;
+            let (events, limit) = state
+                .read(|data| {
+                    async move { (data.delivery(first), data.events_per_subscription) }.boxed()
+                })
+                .await;
+            let events = iter(events.into_iter().map(Ok));
+            Ok(match limit {
+                Some(limit) => events.take(limit).boxed(),
+                // Keep the connection open after the last event, like a real EsQS waiting for the
+                // next block.
+                None => events.chain(pending()).boxed(),
+            })
+        }
+        .try_flatten_stream()
+        .boxed()
+    })
+    .unwrap()
+    .post("post_memos", |req, data| {
+        async move {
+            let block_id = req.integer_param("block_id").map_err(bad_request)?;
+            let txn_id = req.integer_param("txn_id").map_err(bad_request)?;
+            let (memos, sig) = req
+                .body_auto::<(Vec<ReceiverMemo>, Signature)>()
+                .map_err(bad_request)?;
+            data.state(block_id as usize)?;
+            data.posted_memos.push((block_id, txn_id, memos, sig));
+            Ok(())
+        }
+        .boxed()
+    })
+    .unwrap();
+    api
+}
@@ -1,6 +1,8 @@
 // Copyright (c) 2022 Espresso Systems (espressosys.com)
 // This file is part of the Espresso library.
 
+use super::chaos::{Chaos, ChaosConfig};
+use super::replicas::ReplicaSet;
 use espresso_validator::COMMITTEE_SIZE;
 pub use seahorse::testing::MockLedger;
 
@@ -45,31 +47,49 @@ pub struct MockEspressoNetwork<'a> {
     proving_keys: Arc<ProverKeySet<'a, key_set::OrderByOutputs>>,
     address_map: HashMap<UserAddress, UserPubKey>,
     events: MockEventSource<EspressoLedger>,
+    replicas: ReplicaSet,
+    chaos: Option<Chaos>,
+    // A block held back by chaos, to be applied after the next one.
+    held_block: Option<ElaboratedBlock>,
+    // The nullifier set before the latest block, for serving stale proofs.
+    prev_nullifiers: SetMerkleTree,
 }
 
-impl<'a> MockNetwork<'a, EspressoLedger> for MockEspressoNetwork<'a> {
-    fn now(&self) -> EventIndex {
-        self.events.now()
+impl<'a> MockEspressoNetwork<'a> {
+    /// Start a new replica validator, which catches up on every block committed so far.
+    ///
+    /// Returns the index of the replica in [replicas](Self::replicas).
+    pub fn add_replica(&mut self) -> usize {
+        let history = self
+            .committed_blocks
+            .iter()
+            .map(|(block, _)| block.clone())
+            .collect::<Vec<_>>();
+        self.replicas.add(history)
     }
 
-    fn state(&self) -> &ValidatorState {
-        &self.validator
+    /// The replica validators following this network through gossip.
+    pub fn replicas(&self) -> &ReplicaSet {
+        &self.replicas
     }
 
-    fn event(
-        &self,
-        index: EventIndex,
-        source: EventSource,
-    ) -> Result<LedgerEvent<EspressoLedger>, KeystoreError<EspressoLedger>> {
-        match source {
-            EventSource::QueryService => self.events.get(index),
-            _ => Err(KeystoreError::Failed {
-                msg: String::from("invalid event source"),
-            }),
-        }
+    pub fn replicas_mut(&mut self) -> &mut ReplicaSet {
+        &mut self.replicas
     }
 
-    fn submit(&mut self, block: ElaboratedBlock) -> Result<usize, KeystoreError<EspressoLedger>> {
+    /// Make the network misbehave as described by `config`.
+    pub fn set_chaos(&mut self, config: ChaosConfig) {
+        self.chaos = Some(Chaos::new(config));
+    }
+
+    fn chaos(&mut self, decide: impl FnOnce(&mut Chaos) -> bool) -> bool {
+        self.chaos.as_mut().map(decide).unwrap_or(false)
+    }
+
+    fn apply_block(
+        &mut self,
+        block: ElaboratedBlock,
+    ) -> Result<usize, KeystoreError<EspressoLedger>> {
         match self.validator.validate_and_apply(
             &(self.validator.prev_commit_time + 1),
             block.parent_state,
@@ -78,6 +98,7 @@ impl<'a> MockNetwork<'a, EspressoLedger> for MockEspressoNetwork<'a> {
         ) {
             Ok(ValidationOutputs { mut uids, .. }) => {
                 // Add nullifiers
+                self.prev_nullifiers = self.nullifiers.clone();
                 for txn in &block.block.0 {
                     for nullifier in txn.input_nullifiers() {
                         self.nullifiers.insert(nullifier);
@@ -105,6 +126,7 @@ impl<'a> MockNetwork<'a, EspressoLedger> for MockEspressoNetwork<'a> {
                     block_uids.push(this_txn_uids);
                 }
                 self.committed_blocks.push((block.clone(), block_uids));
+                self.replicas.gossip(&block, self.validator.commit());
 
                 // Broadcast the memos.
                 let mut num_memos = 0;
@@ -118,11 +140,96 @@ impl<'a> MockNetwork<'a, EspressoLedger> for MockEspressoNetwork<'a> {
                 Ok(num_memos)
             }
             Err(error) => {
-                self.generate_event(LedgerEvent::Reject { block, error });
-                Ok(0)
+                // Rather than rejecting the whole block, drop the invalid transactions and commit
+                // the rest. Each dropped transaction is rejected in its own event, with its own
+                // error, so keystores can tell exactly which of their transactions failed and why.
+                let results = match self.validator.check_transactions(
+                    &(self.validator.prev_commit_time + 1),
+                    block.parent_state,
+                    &block.block,
+                    &block.proofs,
+                ) {
+                    Ok(results)
+                        if results.iter().any(|res| res.is_ok())
+                            && results.iter().any(|res| res.is_err()) =>
+                    {
+                        results
+                    }
+                    // Either the block is invalid as a whole, or none of its transactions are
+                    // valid, or the failure can't be pinned on any one transaction; there is
+                    // nothing to salvage.
+                    _ => {
+                        self.generate_event(LedgerEvent::Reject { block, error });
+                        return Ok(0);
+                    }
+                };
+                let mut valid = ElaboratedBlock::new(block.parent_state);
+                for (res, txn, proofs, memos) in
+                    izip!(results, block.block.0, block.proofs, block.memos)
+                {
+                    match res {
+                        Ok(()) => {
+                            valid.block.0.push(txn);
+                            valid.proofs.push(proofs);
+                            valid.memos.push(memos);
+                        }
+                        Err(error) => {
+                            let mut rejected = ElaboratedBlock::new(block.parent_state);
+                            rejected.block.0.push(txn);
+                            rejected.proofs.push(proofs);
+                            rejected.memos.push(memos);
+                            self.generate_event(LedgerEvent::Reject {
+                                block: rejected,
+                                error,
+                            });
+                        }
+                    }
+                }
+                self.apply_block(valid)
             }
         }
     }
+}
+
+impl<'a> MockNetwork<'a, EspressoLedger> for MockEspressoNetwork<'a> {
+    fn now(&self) -> EventIndex {
+        self.events.now()
+    }
+
+    fn state(&self) -> &ValidatorState {
+        &self.validator
+    }
+
+    fn event(
+        &self,
+        index: EventIndex,
+        source: EventSource,
+    ) -> Result<LedgerEvent<EspressoLedger>, KeystoreError<EspressoLedger>> {
+        match source {
+            EventSource::QueryService => self.events.get(index),
+            _ => Err(KeystoreError::Failed {
+                msg: String::from("invalid event source"),
+            }),
+        }
+    }
+
+    fn submit(&mut self, block: ElaboratedBlock) -> Result<usize, KeystoreError<EspressoLedger>> {
+        if self.chaos(Chaos::drop_block) {
+            tracing::info!("chaos: dropping block");
+            return Ok(0);
+        }
+        if self.held_block.is_none() && self.chaos(Chaos::reorder_block) {
+            tracing::info!("chaos: holding back block");
+            self.held_block = Some(block);
+            return Ok(0);
+        }
+        let mut num_memos = self.apply_block(block)?;
+        if let Some(held) = self.held_block.take() {
+            tracing::info!("chaos: applying held block");
+            num_memos += self.apply_block(held)?;
+        }
+        Ok(num_memos)
+    }
 
     fn post_memos(
         &mut self,
@@ -131,6 +238,19 @@ impl<'a> MockNetwork<'a, EspressoLedger> for MockEspressoNetwork<'a> {
         memos: Vec<ReceiverMemo>,
         sig: Signature,
     ) -> Result<(), KeystoreError<EspressoLedger>> {
+        // Decide whether to corrupt the memos before borrowing the block history.
+        let garbage = if self.chaos(Chaos::corrupt_memos) {
+            let chaos = self.chaos.as_mut().unwrap();
+            Some(
+                memos
+                    .iter()
+                    .map(|_| chaos.garbage_memo())
+                    .collect::<Vec<_>>(),
+            )
+        } else {
+            None
+        };
+
         let (block, uids) = match self.committed_blocks.get(block_id as usize) {
             Some(block) => block,
             None => {
@@ -176,6 +296,13 @@ impl<'a> MockNetwork<'a, EspressoLedger> for MockEspressoNetwork<'a> {
             .collect::<Vec<_>>();
 
         // Broadcast the new memos.
+        let memos = match garbage {
+            Some(garbage) => {
+                tracing::info!("chaos: corrupting memos");
+                garbage
+            }
+            None => memos,
+        };
         let memos = izip!(
             memos,
             txn.output_commitments(),
@@ -197,7 +324,7 @@ impl<'a> MockNetwork<'a, EspressoLedger> for MockEspressoNetwork<'a> {
     }
 
     fn generate_event(&mut self, e: LedgerEvent<EspressoLedger>) {
-        println!(
+        tracing::debug!(
             "generating event {}: {}",
             self.now(),
             match &e {
@@ -206,6 +333,10 @@ impl<'a> MockNetwork<'a, EspressoLedger> for MockEspressoNetwork<'a> {
                 LedgerEvent::Memos { .. } => "Memos",
             }
         );
+        if self.chaos(Chaos::duplicate_event) {
+            tracing::info!("chaos: duplicating event");
+            self.events.publish(e.clone());
+        }
         self.events.publish(e);
     }
 }
@@ -214,6 +345,19 @@ impl<'a> MockNetwork<'a, EspressoLedger> for MockEspressoNetwork<'a> {
 pub struct MockEspressoBackend<'a> {
     ledger: Arc<Mutex<MockLedger<'a, EspressoLedger, MockEspressoNetwork<'a>>>>,
     initial_grants: Vec<(RecordOpening, u64)>,
+    // The replica whose nullifier set this backend reads, if not the canonical validator.
+    view: Option<usize>,
+}
+
+impl<'a> MockEspressoBackend<'a> {
+    /// Serve nullifier proofs from the replica at `index` instead of the canonical validator.
+    ///
+    /// If the replica is behind the canonical chain, the keystore will get proofs against a stale
+    /// nullifier set.
+    pub fn with_view(mut self, index: usize) -> Self {
+        self.view = Some(index);
+        self
+    }
 }
 
 #[async_trait]
@@ -263,9 +407,20 @@ impl<'a> KeystoreBackend<'a, EspressoLedger> for MockEspressoBackend<'a> {
         nullifier: Nullifier,
     ) -> Result<(bool, SetMerkleProof), KeystoreError<EspressoLedger>> {
         let mut ledger = self.ledger.lock().await;
-        assert_eq!(block_id, ledger.network().committed_blocks.len() as u64);
-        if set.hash() == ledger.network().nullifiers.hash() {
-            Ok(ledger.network().nullifiers.contains(nullifier).unwrap())
+        let network = ledger.network();
+        if network.chaos(Chaos::stale_nullifier_proof) {
+            tracing::info!("chaos: serving stale nullifier proof");
+            return Ok(network.prev_nullifiers.contains(nullifier).unwrap());
+        }
+        let nullifiers = match self.view {
+            Some(index) => network.replicas.replica(index).nullifiers(),
+            None => {
+                assert_eq!(block_id, network.committed_blocks.len() as u64);
+                &network.nullifiers
+            }
+        };
+        if set.hash() == nullifiers.hash() {
+            Ok(nullifiers.contains(nullifier).unwrap())
         } else {
             Err(KeystoreError::Failed {
                 msg: "invalid nullifier root".into(),
@@ -331,7 +486,7 @@ impl<'a> testing::SystemUnderTest<'a> for EspressoTest {
         records: MerkleTree,
         initial_grants: Vec<(RecordOpening, u64)>,
     ) -> Self::MockNetwork {
-        println!("[espresso] creating network");
+        tracing::info!("[espresso] creating network");
         let verif_crs = Arc::new(verif_crs);
         let mut ret = MockEspressoNetwork {
             validator: ValidatorState::default(),
@@ -341,6 +496,10 @@ impl<'a> testing::SystemUnderTest<'a> for EspressoTest {
             proving_keys: Arc::new(proof_crs),
             address_map: HashMap::default(),
             events: MockEventSource::new(EventSource::QueryService),
+            replicas: ReplicaSet::new(),
+            chaos: None,
+            held_block: None,
+            prev_nullifiers: SetMerkleTree::default(),
         };
 
         // Commit a [Genesis] block to initialize the ledger.
@@ -354,7 +513,7 @@ impl<'a> testing::SystemUnderTest<'a> for EspressoTest {
         assert_eq!(ret.validator.record_merkle_commitment, records.commitment());
         assert_eq!(ret.records.commitment(), records.commitment());
 
-        println!("[espresso] created network");
+        tracing::info!("[espresso] created network");
         ret
     }
 
@@ -366,7 +525,152 @@ impl<'a> testing::SystemUnderTest<'a> for EspressoTest {
         MockEspressoBackend {
             ledger,
             initial_grants,
+            view: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use espresso_core::{
+        state::ValidationError,
+        testing::{MultiXfrRecordSpec, MultiXfrTestState, TestTxSpec, TxnPrintInfo},
+        universal_params::PROVER_CRS,
+    };
+
+    #[test]
+    fn test_partial_block() {
+        let mut state = MultiXfrTestState::initialize(
+            [0x86u8; 32],
+            2,
+            1,
+            (
+                MultiXfrRecordSpec {
+                    asset_def_ix: 1,
+                    owner_key_ix: 0,
+                    asset_amount: 1,
+                },
+                vec![MultiXfrRecordSpec {
+                    asset_def_ix: 1,
+                    owner_key_ix: 1,
+                    asset_amount: 1,
+                }],
+            ),
+        )
+        .unwrap();
+        let mut network = MockEspressoNetwork {
+            validator: state.validator.clone(),
+            nullifiers: state.nullifiers.clone(),
+            records: state.record_merkle_tree.clone(),
+            committed_blocks: Vec::new(),
+            proving_keys: PROVER_CRS.clone(),
+            address_map: HashMap::default(),
+            events: MockEventSource::new(EventSource::QueryService),
+            replicas: ReplicaSet::new(),
+            chaos: None,
+            held_block: None,
+            prev_nullifiers: SetMerkleTree::default(),
+        };
+        let initial_records = network.records.num_leaves();
+
+        // Two independent transfers, and a third which spends the same record as the first.
+        let txns = state
+            .generate_transactions(
+                vec![
+                    (TestTxSpec::OneInput { rec: 0, key: 1 }, true),
+                    (TestTxSpec::OneInput { rec: 2, key: 1 }, false),
+                    (TestTxSpec::OneInput { rec: 0, key: 1 }, false),
+                ],
+                TxnPrintInfo::new_no_time(0, 3),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|txn| txn.transaction)
+            .collect::<Vec<_>>();
+        let mut block = network.validator.next_block();
+        for txn in &txns {
+            block.block.0.push(txn.txn.clone());
+            block.proofs.push(txn.proofs.clone());
+            block.memos.push(None);
+        }
+        network.submit(block).unwrap();
+
+        // The valid transactions are committed in a block of their own.
+        assert_eq!(network.committed_blocks.len(), 1);
+        let (committed, uids) = &network.committed_blocks[0];
+        assert_eq!(
+            committed
+                .block
+                .0
+                .iter()
+                .map(|txn| txn.hash())
+                .collect::<Vec<_>>(),
+            vec![txns[0].txn.hash(), txns[1].txn.hash()]
+        );
+
+        // The nullifier set and the record Merkle tree reflect only the committed transactions.
+        let mut nullifiers = state.nullifiers.clone();
+        for txn in &txns[..2] {
+            for nullifier in txn.txn.input_nullifiers() {
+                assert!(network.nullifiers.contains(nullifier).unwrap().0);
+                nullifiers.insert(nullifier);
+            }
+        }
+        assert_eq!(network.nullifiers.hash(), nullifiers.hash());
+        assert_eq!(
+            network.nullifiers.hash(),
+            network.validator.nullifiers_root()
+        );
+        let outputs = txns[..2]
+            .iter()
+            .map(|txn| txn.txn.output_len() as u64)
+            .sum::<u64>();
+        assert_eq!(network.records.num_leaves(), initial_records + outputs);
+        assert_eq!(
+            uids.iter().flatten().cloned().collect::<Vec<_>>(),
+            (initial_records..initial_records + outputs).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            network.records.commitment(),
+            network.validator.record_merkle_commitment
+        );
+
+        // The invalid transaction is rejected on its own, before the rest of the block commits.
+        let events = (0..network.now().index(EventSource::QueryService))
+            .map(|i| {
+                network
+                    .event(
+                        EventIndex::from_source(EventSource::QueryService, i),
+                        EventSource::QueryService,
+                    )
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 2);
+        match &events[0] {
+            LedgerEvent::Reject { block, error } => {
+                assert_eq!(
+                    block
+                        .block
+                        .0
+                        .iter()
+                        .map(|txn| txn.hash())
+                        .collect::<Vec<_>>(),
+                    vec![txns[2].txn.hash()]
+                );
+                assert!(
+                    matches!(error, ValidationError::NullifierAlreadyExists { .. }),
+                    "{:?}",
+                    error
+                );
+            }
+            event => panic!("expected Reject event, got {:?}", event),
         }
+        assert!(matches!(
+            &events[1],
+            LedgerEvent::Commit { block_id: 0, .. }
+        ));
     }
 }
 
@@ -3,4 +3,7 @@
 // This file is part of the Espresso library.
 
 pub use seahorse::testing::*;
+pub mod chaos;
+pub mod mock_esqs;
 pub mod mocks;
+pub mod replicas;
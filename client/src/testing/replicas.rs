@@ -0,0 +1,283 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Mock validators with independent views of the ledger.
+//!
+//! [MockEspressoNetwork](super::mocks::MockEspressoNetwork) is a single omniscient validator:
+//! every block it commits is instantly visible to every keystore. A [ReplicaSet] runs additional
+//! validators alongside it, each with its own [ValidatorState] and nullifier set, which only learn
+//! about committed blocks through a simulated gossip layer. Gossip to an individual replica can be
+//! paused and resumed, or released a few blocks at a time, so a keystore reading from a lagging
+//! replica (see [MockEspressoBackend::with_view](super::mocks::MockEspressoBackend::with_view))
+//! sees stale nullifier proofs and late blocks.
+//!
+//! As each replica applies a block, its new state commitment is checked against the commitment the
+//! canonical validator reached at the same height. A replica which rejects a block or reaches a
+//! different state is recorded as a [Divergence] and receives no further blocks.
+
+use espresso_core::{
+    set_merkle_tree::SetMerkleTree,
+    state::{ElaboratedBlock, LedgerStateCommitment, ValidatorState},
+};
+use std::collections::VecDeque;
+
+/// A point at which a replica's view of the ledger split from the canonical chain.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    pub replica: usize,
+    /// The index of the block which caused the divergence.
+    pub block_height: u64,
+    /// The canonical state commitment after this block, if the canonical chain has reached it.
+    pub expected: Option<LedgerStateCommitment>,
+    /// What the replica made of the block.
+    pub actual: Result<LedgerStateCommitment, String>,
+}
+
+/// A mock validator fed by gossip.
+#[derive(Clone, Debug, Default)]
+pub struct MockReplica {
+    validator: ValidatorState,
+    nullifiers: SetMerkleTree,
+    // Blocks gossiped to this replica which it has not applied yet.
+    inbox: VecDeque<ElaboratedBlock>,
+    paused: bool,
+    diverged: bool,
+}
+
+impl MockReplica {
+    pub fn state(&self) -> &ValidatorState {
+        &self.validator
+    }
+
+    pub fn nullifiers(&self) -> &SetMerkleTree {
+        &self.nullifiers
+    }
+
+    /// The number of blocks this replica has applied.
+    pub fn block_height(&self) -> u64 {
+        self.validator.block_height
+    }
+
+    /// The number of blocks gossiped to this replica which it has not applied yet.
+    pub fn pending(&self) -> usize {
+        self.inbox.len()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn has_diverged(&self) -> bool {
+        self.diverged
+    }
+
+    fn apply(&mut self, block: ElaboratedBlock) -> Result<LedgerStateCommitment, String> {
+        self.validator
+            .validate_and_apply(
+                &(self.validator.prev_commit_time + 1),
+                block.parent_state,
+                block.block.clone(),
+                block.proofs,
+            )
+            .map_err(|err| err.to_string())?;
+        for txn in &block.block.0 {
+            for nullifier in txn.input_nullifiers() {
+                self.nullifiers.insert(nullifier);
+            }
+        }
+        Ok(self.validator.commit())
+    }
+}
+
+/// A set of [MockReplica]s following a canonical chain.
+#[derive(Clone, Debug, Default)]
+pub struct ReplicaSet {
+    replicas: Vec<MockReplica>,
+    // The canonical state commitment after each block.
+    canonical: Vec<LedgerStateCommitment>,
+    divergences: Vec<Divergence>,
+}
+
+impl ReplicaSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a replica which starts from genesis and catches up on `history`.
+    ///
+    /// `history` should contain every block committed to the canonical chain so far, in order.
+    /// Returns the index of the new replica.
+    pub fn add(&mut self, history: impl IntoIterator<Item = ElaboratedBlock>) -> usize {
+        let index = self.replicas.len();
+        self.replicas.push(MockReplica {
+            inbox: history.into_iter().collect(),
+            ..Default::default()
+        });
+        self.deliver(index, usize::MAX);
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.replicas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.replicas.is_empty()
+    }
+
+    pub fn replica(&self, index: usize) -> &MockReplica {
+        &self.replicas[index]
+    }
+
+    /// Gossip a block committed to the canonical chain.
+    ///
+    /// `state_comm` is the canonical state commitment after applying `block`. Replicas which are
+    /// not paused apply the block immediately.
+    pub fn gossip(&mut self, block: &ElaboratedBlock, state_comm: LedgerStateCommitment) {
+        self.canonical.push(state_comm);
+        for index in 0..self.replicas.len() {
+            self.replicas[index].inbox.push_back(block.clone());
+            if !self.replicas[index].paused {
+                self.deliver(index, usize::MAX);
+            }
+        }
+    }
+
+    /// Hold back gossip from a replica until it is resumed.
+    pub fn pause(&mut self, index: usize) {
+        self.replicas[index].paused = true;
+    }
+
+    /// Resume gossip to a replica, delivering every block it missed while paused.
+    ///
+    /// Returns the number of blocks delivered.
+    pub fn resume(&mut self, index: usize) -> usize {
+        self.replicas[index].paused = false;
+        self.deliver(index, usize::MAX)
+    }
+
+    /// Deliver up to `count` pending blocks to a replica, even if it is paused.
+    ///
+    /// Returns the number of blocks delivered.
+    pub fn deliver(&mut self, index: usize, count: usize) -> usize {
+        let mut delivered = 0;
+        while delivered < count {
+            let replica = &mut self.replicas[index];
+            if replica.diverged {
+                break;
+            }
+            let block = match replica.inbox.pop_front() {
+                Some(block) => block,
+                None => break,
+            };
+            let block_height = replica.block_height();
+            let actual = replica.apply(block);
+            delivered += 1;
+
+            let expected = self.canonical.get(block_height as usize).copied();
+            let agrees =
+                matches!((expected, &actual), (Some(expected), Ok(actual)) if expected == *actual);
+            if !agrees {
+                tracing::warn!(
+                    "replica {} diverged at block {}: expected {:?}, got {:?}",
+                    index,
+                    block_height,
+                    expected,
+                    actual
+                );
+                self.replicas[index].diverged = true;
+                self.divergences.push(Divergence {
+                    replica: index,
+                    block_height,
+                    expected,
+                    actual,
+                });
+            }
+        }
+        delivered
+    }
+
+    /// Gossip a block which is not part of the canonical chain to a single replica.
+    ///
+    /// The block is queued ahead of any pending canonical blocks, so it will be applied the next
+    /// time blocks are delivered to the replica.
+    pub fn inject(&mut self, index: usize, block: ElaboratedBlock) {
+        self.replicas[index].inbox.push_front(block);
+        if !self.replicas[index].paused {
+            self.deliver(index, usize::MAX);
+        }
+    }
+
+    /// Every divergence detected so far.
+    pub fn divergences(&self) -> &[Divergence] {
+        &self.divergences
+    }
+
+    /// Whether no replica has diverged from the canonical chain.
+    pub fn is_consistent(&self) -> bool {
+        self.divergences.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use espresso_core::{genesis::GenesisNote, state::ChainVariables, universal_params::VERIF_CRS};
+    use espresso_validator::COMMITTEE_SIZE;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    fn genesis(chain_id: u16) -> ElaboratedBlock {
+        ElaboratedBlock::genesis(GenesisNote::new(
+            ChainVariables::new(chain_id, VERIF_CRS.clone(), COMMITTEE_SIZE),
+            Arc::new(vec![]),
+            BTreeMap::new(),
+        ))
+    }
+
+    #[test]
+    fn test_replica_divergence() {
+        let block = genesis(42);
+        let mut canonical = ValidatorState::default();
+        canonical
+            .validate_and_apply(
+                &(canonical.prev_commit_time + 1),
+                block.parent_state,
+                block.block.clone(),
+                block.proofs.clone(),
+            )
+            .unwrap();
+
+        let mut replicas = ReplicaSet::new();
+        let caught_up = replicas.add(vec![]);
+        let lagging = replicas.add(vec![]);
+        let forked = replicas.add(vec![]);
+        replicas.pause(lagging);
+        replicas.pause(forked);
+        replicas.inject(forked, genesis(43));
+
+        replicas.gossip(&block, canonical.commit());
+        assert_eq!(replicas.replica(caught_up).block_height(), 1);
+        assert_eq!(replicas.replica(lagging).block_height(), 0);
+        assert_eq!(replicas.replica(lagging).pending(), 1);
+        assert!(replicas.is_consistent());
+
+        // A late block still leads to the canonical state.
+        assert_eq!(replicas.resume(lagging), 1);
+        assert_eq!(
+            replicas.replica(lagging).state().commit(),
+            canonical.commit()
+        );
+        assert!(replicas.is_consistent());
+
+        // A replica which applies a different block is caught, and stops receiving blocks.
+        assert_eq!(replicas.resume(forked), 1);
+        assert_eq!(replicas.replica(forked).pending(), 1);
+        assert!(replicas.replica(forked).has_diverged());
+        let divergence = &replicas.divergences()[0];
+        assert_eq!(divergence.replica, forked);
+        assert_eq!(divergence.block_height, 0);
+        assert_eq!(divergence.expected, Some(canonical.commit()));
+        assert_ne!(divergence.actual, Ok(canonical.commit()));
+    }
+}
@@ -0,0 +1,949 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! An HTTP server exposing a keystore to other processes.
+//!
+//! The server wraps a single [EspressoKeystore] backed by a [NetworkBackend] and serves its
+//! balances, assets and transaction history, as well as endpoints to define, transfer, mint and
+//! freeze assets. If the keystore's backend enforces a spending policy, the server also checks
+//! each transaction against it before building it, so that it can ask for confirmation.
+//! Changes to the transaction history are streamed over a WebSocket. The routes are specified in
+//! `api/wallet.toml`.
+//!
+//! The server has full spending authority over the keystore, so every request must carry the
+//! header `Authorization: Bearer <token>`, where `<token>` is the secret configured in [Options].
+//! By default, the server only accepts connections from the local host.
+
+use crate::{
+    disclosure::{export_transaction_proof, TransactionProof},
+    freezing::{FreezableRecord, FreezerView},
+    ledger_state::TransactionUID,
+    network::{fetch_supported_transfer_sizes, NetworkBackend},
+    policy::{PolicyRule, SpendingPolicy},
+    EspressoKeystore, RecordAmount,
+};
+use async_std::{
+    sync::{Arc, Mutex},
+    task::{sleep, spawn, JoinHandle},
+};
+use clap::Args;
+use espresso_core::{ledger::EspressoLedger, state::TransactionCommitment};
+use espresso_validator::parse_duration;
+use futures::{
+    stream::{iter, unfold},
+    FutureExt, StreamExt, TryFutureExt,
+};
+use jf_cap::{
+    keys::{UserAddress, UserPubKey},
+    structs::{AssetCode, AssetDefinition, AssetPolicy},
+    TransactionNote,
+};
+use primitive_types::U256;
+use seahorse::{transactions::Transaction, KeystoreError};
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::Hasher;
+use std::path::PathBuf;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use surf_disco::Url;
+use tide_disco::{
+    api::{Api, ApiError},
+    App, RequestError, RequestParams, StatusCode,
+};
+
+#[derive(Args, Clone, Debug)]
+pub struct Options {
+    /// Port on which to serve the keystore API.
+    #[arg(
+        long = "wallet-api-port",
+        env = "ESPRESSO_WALLET_API_PORT",
+        default_value = "60000"
+    )]
+    pub port: u16,
+
+    /// Address on which to serve the keystore API.
+    ///
+    /// The server has full spending authority over the keystore, so by default it only accepts
+    /// connections from this host. Serving on a public interface should be combined with TLS
+    /// termination in front of the server, since the token is sent in every request.
+    #[arg(
+        long = "wallet-api-address",
+        env = "ESPRESSO_WALLET_API_ADDRESS",
+        default_value = "127.0.0.1"
+    )]
+    pub address: String,
+
+    /// Secret token which clients must present to use the API. Must not be empty.
+    #[arg(
+        long = "wallet-api-token",
+        env = "ESPRESSO_WALLET_API_TOKEN",
+        value_parser = parse_token
+    )]
+    pub token: String,
+
+    /// Override the path to the API specification.
+    #[arg(long = "wallet-api-path", env = "ESPRESSO_WALLET_API_PATH")]
+    pub api_path: Option<PathBuf>,
+
+    /// How often to check for new transactions when streaming events.
+    #[arg(
+        long = "wallet-api-poll-interval",
+        env = "ESPRESSO_WALLET_API_POLL_INTERVAL",
+        value_parser = parse_duration,
+        default_value = "1s"
+    )]
+    pub poll_interval: Duration,
+
+    /// URL of the EsQS, used to check that transfers which pin an arity request one the chain
+    /// supports, and to look up transactions for disclosure.
+    ///
+    /// If not given, an unsupported arity is only reported when the transfer fails to build, and
+    /// transaction proofs are not available.
+    #[arg(long = "wallet-api-esqs-url", env = "ESPRESSO_ESQS_URL")]
+    pub esqs_url: Option<Url>,
+}
+
+fn parse_token(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        Err("the API token must not be empty".into())
+    } else {
+        Ok(s.to_string())
+    }
+}
+
+#[derive(Clone, Debug, Snafu, Deserialize, Serialize)]
+pub enum Error {
+    #[snafu(display("bad request: {}", source))]
+    Request { source: RequestError },
+
+    #[snafu(display("missing or invalid API token"))]
+    Unauthorized,
+
+    #[snafu(display("keystore error: {}", msg))]
+    Keystore { msg: String },
+
+    #[snafu(display("transaction violates spending policy: {}", rule))]
+    PolicyViolation { rule: PolicyRule },
+
+    #[snafu(display(
+        "no transfer key with {} inputs and {} outputs (available arities: {})",
+        requested.0,
+        requested.1,
+        available.iter().map(|(i, o)| format!("{}x{}", i, o)).collect::<Vec<_>>().join(", ")
+    ))]
+    UnsupportedArity {
+        requested: (usize, usize),
+        available: Vec<(usize, usize)>,
+    },
+
+    #[snafu(display("this server is not configured with an EsQS URL"))]
+    NoEsqs,
+
+    #[snafu(display("disclosure failed: {}", msg))]
+    Disclosure { msg: String },
+
+    #[snafu(display("internal server error: {}", msg))]
+    Internal { msg: String, status: StatusCode },
+}
+
+impl tide_disco::Error for Error {
+    fn catch_all(status: StatusCode, msg: String) -> Self {
+        Self::Internal { status, msg }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::Request { .. } => StatusCode::BadRequest,
+            Self::Unauthorized => StatusCode::Unauthorized,
+            Self::Keystore { .. } => StatusCode::BadRequest,
+            Self::PolicyViolation { .. } => StatusCode::Forbidden,
+            Self::UnsupportedArity { .. } => StatusCode::BadRequest,
+            Self::NoEsqs => StatusCode::NotImplemented,
+            Self::Disclosure { .. } => StatusCode::BadRequest,
+            Self::Internal { status, .. } => *status,
+        }
+    }
+}
+
+impl From<RequestError> for Error {
+    fn from(source: RequestError) -> Self {
+        Self::Request { source }
+    }
+}
+
+impl From<KeystoreError<EspressoLedger>> for Error {
+    fn from(source: KeystoreError<EspressoLedger>) -> Self {
+        Self::Keystore {
+            msg: source.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TransferRequest {
+    pub asset: AssetCode,
+    pub receivers: Vec<(UserPubKey, u64)>,
+    pub fee: u64,
+    /// Whether the user has confirmed a transfer which exceeds the spending policy's confirmation
+    /// threshold.
+    #[serde(default)]
+    pub confirmed: bool,
+    /// Build the transfer with exactly this many `(inputs, outputs)`, rather than the smallest
+    /// arity that fits.
+    ///
+    /// Pinning the arity lets a keystore give all of its transfers the same shape, so they are
+    /// harder to tell apart, and lets tests exercise specific circuit sizes.
+    #[serde(default)]
+    pub arity: Option<(usize, usize)>,
+}
+
+impl TransferRequest {
+    /// Check this transfer against `policy` before building it.
+    pub(crate) fn check_policy(&self, policy: &SpendingPolicy) -> Result<(), Error> {
+        let amounts = self
+            .receivers
+            .iter()
+            .map(|(key, amount)| (key.address(), *amount as u128))
+            .collect::<Vec<_>>();
+        policy
+            .check(&self.asset, &amounts, self.fee as u128, self.confirmed)
+            .map_err(|rule| Error::PolicyViolation { rule })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MintRequest {
+    pub asset: AssetCode,
+    pub amount: u64,
+    pub receiver: UserPubKey,
+    pub fee: u64,
+    /// Whether the user has confirmed a mint which exceeds the spending policy's confirmation
+    /// threshold.
+    #[serde(default)]
+    pub confirmed: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FreezeRequest {
+    pub asset: AssetCode,
+    /// The owner of the records to freeze or unfreeze.
+    pub owner: UserAddress,
+    pub amount: u64,
+    pub fee: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DefineAssetRequest {
+    pub name: String,
+    pub description: String,
+    pub policy: AssetPolicy,
+}
+
+pub type ApiKeystore<Meta> = EspressoKeystore<'static, NetworkBackend<'static>, Meta>;
+
+pub struct WalletState<Meta> {
+    keystore: Arc<Mutex<ApiKeystore<Meta>>>,
+    token: Arc<String>,
+    policy: Option<Arc<SpendingPolicy>>,
+    poll_interval: Duration,
+    esqs_url: Option<Url>,
+}
+
+// Derived `Clone` would require `Meta: Clone`, but we only clone the shared handles.
+impl<Meta> Clone for WalletState<Meta> {
+    fn clone(&self) -> Self {
+        Self {
+            keystore: self.keystore.clone(),
+            token: self.token.clone(),
+            policy: self.policy.clone(),
+            poll_interval: self.poll_interval,
+            esqs_url: self.esqs_url.clone(),
+        }
+    }
+}
+
+impl<Meta> WalletState<Meta> {
+    fn authorize(&self, req: &RequestParams) -> Result<(), Error> {
+        check_token(
+            &self.token,
+            req.header("Authorization").map(|value| value.as_str()),
+        )
+    }
+
+    /// Check a transaction against the spending policy, if there is one, before building it.
+    ///
+    /// The keystore's backend checks the transaction again when it is submitted, and records its
+    /// spending, but only this check can require confirmation.
+    fn check_policy(
+        &self,
+        asset: &AssetCode,
+        receivers: &[(UserAddress, u128)],
+        fee: u64,
+        confirmed: bool,
+    ) -> Result<(), Error> {
+        match &self.policy {
+            Some(policy) => policy
+                .check(asset, receivers, fee as u128, confirmed)
+                .map_err(|rule| Error::PolicyViolation { rule }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Check the value of an `Authorization` header against `token`.
+///
+/// An empty token authorizes nothing, so a server started without a token cannot be used.
+fn check_token(token: &str, header: Option<&str>) -> Result<(), Error> {
+    if token.is_empty() {
+        return Err(Error::Unauthorized);
+    }
+    let expected = format!("Bearer {}", token);
+    match header {
+        // Compare in constant time, so the response time does not reveal how much of a guessed
+        // token is correct.
+        Some(value) if bool::from(value.as_bytes().ct_eq(expected.as_bytes())) => Ok(()),
+        _ => Err(Error::Unauthorized),
+    }
+}
+
+async fn pub_keys<Meta>(
+    req: RequestParams,
+    state: &WalletState<Meta>,
+) -> Result<Vec<UserPubKey>, Error>
+where
+    Meta: 'static + Send + Serialize + for<'a> Deserialize<'a>,
+{
+    state.authorize(&req)?;
+    Ok(state.keystore.lock().await.sending_keys().await)
+}
+
+async fn balances<Meta>(
+    req: RequestParams,
+    state: &WalletState<Meta>,
+) -> Result<Vec<(AssetCode, U256)>, Error>
+where
+    Meta: 'static + Send + Serialize + for<'a> Deserialize<'a>,
+{
+    state.authorize(&req)?;
+    let keystore = state.keystore.lock().await;
+    let mut balances = vec![];
+    for asset in keystore.assets().await {
+        let code = asset.code();
+        balances.push((code, keystore.balance(&code).await));
+    }
+    Ok(balances)
+}
+
+async fn balance<Meta>(req: RequestParams, state: &WalletState<Meta>) -> Result<U256, Error>
+where
+    Meta: 'static + Send + Serialize + for<'a> Deserialize<'a>,
+{
+    state.authorize(&req)?;
+    let asset: AssetCode = req.blob_param("asset")?;
+    Ok(state.keystore.lock().await.balance(&asset).await)
+}
+
+async fn freezer_view<Meta>(keystore: &ApiKeystore<Meta>) -> FreezerView
+where
+    Meta: 'static + Send + Serialize + for<'a> Deserialize<'a>,
+{
+    FreezerView::new(
+        keystore
+            .sending_keys()
+            .await
+            .into_iter()
+            .map(|pair| pair.pub_key()),
+        keystore.freezing_pub_keys().await,
+    )
+}
+
+async fn freezable_records<Meta>(
+    req: RequestParams,
+    state: &WalletState<Meta>,
+) -> Result<Vec<FreezableRecord>, Error>
+where
+    Meta: 'static + Send + Serialize + for<'a> Deserialize<'a>,
+{
+    state.authorize(&req)?;
+    let asset: AssetCode = req.blob_param("asset")?;
+    let keystore = state.keystore.lock().await;
+    let view = freezer_view(&keystore).await;
+    Ok(view
+        .freezable_records(keystore.records().await, &asset)
+        .iter()
+        .map(FreezableRecord::from)
+        .collect())
+}
+
+async fn frozen_holdings<Meta>(
+    req: RequestParams,
+    state: &WalletState<Meta>,
+) -> Result<Vec<(UserAddress, U256)>, Error>
+where
+    Meta: 'static + Send + Serialize + for<'a> Deserialize<'a>,
+{
+    state.authorize(&req)?;
+    let asset: AssetCode = req.blob_param("asset")?;
+    let keystore = state.keystore.lock().await;
+    let view = freezer_view(&keystore).await;
+    Ok(view.frozen_holdings_by_owner(keystore.records().await, &asset))
+}
+
+async fn transaction_proof<Meta>(
+    req: RequestParams,
+    state: &WalletState<Meta>,
+) -> Result<TransactionProof, Error>
+where
+    Meta: 'static + Send + Serialize + for<'a> Deserialize<'a>,
+{
+    state.authorize(&req)?;
+    let hash: TransactionCommitment = req.blob_param("hash")?;
+    let url = state.esqs_url.clone().ok_or(Error::NoEsqs)?;
+    let records = state.keystore.lock().await.records().await;
+    export_transaction_proof(url, records, hash)
+        .await
+        .map_err(|err| Error::Disclosure {
+            msg: err.to_string(),
+        })
+}
+
+async fn assets<Meta>(
+    req: RequestParams,
+    state: &WalletState<Meta>,
+) -> Result<Vec<AssetDefinition>, Error>
+where
+    Meta: 'static + Send + Serialize + for<'a> Deserialize<'a>,
+{
+    state.authorize(&req)?;
+    Ok(state
+        .keystore
+        .lock()
+        .await
+        .assets()
+        .await
+        .into_iter()
+        .map(|asset| asset.definition().clone())
+        .collect())
+}
+
+async fn history<Meta>(
+    req: RequestParams,
+    state: &WalletState<Meta>,
+) -> Result<Vec<Transaction<EspressoLedger>>, Error>
+where
+    Meta: 'static + Send + Serialize + for<'a> Deserialize<'a>,
+{
+    state.authorize(&req)?;
+    let first = req.opt_integer_param("first")?.unwrap_or(0);
+    let count = req.opt_integer_param("count")?.unwrap_or(usize::MAX);
+    let txns = state.keystore.lock().await.transactions().await?;
+    Ok(txns.into_iter().skip(first).take(count).collect())
+}
+
+/// Find the entries of `txns` at or after index `first` which have changed since the last call.
+///
+/// `seen` holds a digest of each entry from `first` on, as of the last call, and is updated in
+/// place. Entries are compared by a hash of their serialized form, so the memory used does not
+/// grow with the size of the entries.
+fn history_updates<T: Serialize>(seen: &mut Vec<u64>, first: usize, txns: Vec<T>) -> Vec<T> {
+    let mut updates = vec![];
+    for (i, txn) in txns.into_iter().skip(first).enumerate() {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(&bincode::serialize(&txn).unwrap());
+        let digest = hasher.finish();
+        if seen.get(i) != Some(&digest) {
+            if i < seen.len() {
+                seen[i] = digest;
+            } else {
+                seen.push(digest);
+            }
+            updates.push(txn);
+        }
+    }
+    updates
+}
+
+async fn transfer<Meta>(
+    req: RequestParams,
+    state: &WalletState<Meta>,
+) -> Result<TransactionUID<EspressoLedger>, Error>
+where
+    Meta: 'static + Send + Serialize + for<'a> Deserialize<'a>,
+{
+    state.authorize(&req)?;
+    let request: TransferRequest = req.body_auto()?;
+    // Check everything we can before taking the keystore lock, so that other requests are not
+    // held up by a transfer which would be rejected, or by the round trip to the EsQS.
+    if let Some(policy) = &state.policy {
+        request.check_policy(policy)?;
+    }
+    check_arity(&request, state.esqs_url.as_ref()).await?;
+    submit_transfer(&mut *state.keystore.lock().await, &request).await
+}
+
+async fn mint<Meta>(
+    req: RequestParams,
+    state: &WalletState<Meta>,
+) -> Result<TransactionUID<EspressoLedger>, Error>
+where
+    Meta: 'static + Send + Serialize + for<'a> Deserialize<'a>,
+{
+    state.authorize(&req)?;
+    let request: MintRequest = req.body_auto()?;
+    let mut keystore = state.keystore.lock().await;
+    state.check_policy(
+        &request.asset,
+        &[(request.receiver.address(), request.amount as u128)],
+        request.fee,
+        request.confirmed,
+    )?;
+    Ok(keystore
+        .mint(
+            None,
+            RecordAmount::from(request.fee),
+            &request.asset,
+            RecordAmount::from(request.amount),
+            request.receiver,
+        )
+        .await?)
+}
+
+async fn freeze<Meta>(
+    req: RequestParams,
+    state: &WalletState<Meta>,
+    freeze: bool,
+) -> Result<TransactionUID<EspressoLedger>, Error>
+where
+    Meta: 'static + Send + Serialize + for<'a> Deserialize<'a>,
+{
+    state.authorize(&req)?;
+    let request: FreezeRequest = req.body_auto()?;
+    let mut keystore = state.keystore.lock().await;
+    // Freezing moves no value, so only the fee is subject to the policy.
+    state.check_policy(&request.asset, &[], request.fee, false)?;
+    let fee = RecordAmount::from(request.fee);
+    let amount = RecordAmount::from(request.amount);
+    let receipt = if freeze {
+        keystore
+            .freeze(None, fee, &request.asset, amount, request.owner)
+            .await?
+    } else {
+        keystore
+            .unfreeze(None, fee, &request.asset, amount, request.owner)
+            .await?
+    };
+    Ok(receipt)
+}
+
+/// Check the arity pinned by `request`, if any, against the arities supported by the chain.
+///
+/// This lets the error list the available arities, rather than failing when the transfer is built.
+/// The check is skipped if there is no `esqs_url` to fetch the arities from.
+pub(crate) async fn check_arity(
+    request: &TransferRequest,
+    esqs_url: Option<&Url>,
+) -> Result<(), Error> {
+    if let (Some(arity), Some(url)) = (request.arity, esqs_url) {
+        let available = fetch_supported_transfer_sizes(url.clone()).await?;
+        if !available.contains(&arity) {
+            return Err(Error::UnsupportedArity {
+                requested: arity,
+                available,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Build and submit the transfer described by `request`.
+pub(crate) async fn submit_transfer<Meta>(
+    keystore: &mut EspressoKeystore<'static, NetworkBackend<'static>, Meta>,
+    request: &TransferRequest,
+) -> Result<TransactionUID<EspressoLedger>, Error>
+where
+    Meta: 'static + Send + Serialize + for<'a> Deserialize<'a>,
+{
+    let receivers = request
+        .receivers
+        .iter()
+        .map(|(key, amount)| (key.clone(), RecordAmount::from(*amount), false))
+        .collect::<Vec<_>>();
+    let (note, info) = keystore
+        .build_transfer(
+            None,
+            &request.asset,
+            &receivers,
+            RecordAmount::from(request.fee),
+            vec![],
+            request.arity,
+        )
+        .await?;
+    Ok(keystore
+        .submit_cap(TransactionNote::Transfer(Box::new(note)), info)
+        .await?)
+}
+
+async fn define_asset<Meta>(
+    req: RequestParams,
+    state: &WalletState<Meta>,
+) -> Result<AssetDefinition, Error>
+where
+    Meta: 'static + Send + Serialize + for<'a> Deserialize<'a>,
+{
+    state.authorize(&req)?;
+    let request: DefineAssetRequest = req.body_auto()?;
+    Ok(state
+        .keystore
+        .lock()
+        .await
+        .define_asset(request.name, request.description.as_bytes(), request.policy)
+        .await?)
+}
+
+/// Start serving `keystore` according to `opt`.
+///
+/// `policy` should be the spending policy the keystore's backend was configured with, if any.
+///
+/// Returns a handle to the server task, which runs until the server fails.
+pub fn serve<Meta>(
+    opt: &Options,
+    keystore: ApiKeystore<Meta>,
+    policy: Option<Arc<SpendingPolicy>>,
+) -> Result<JoinHandle<std::io::Result<()>>, ApiError>
+where
+    Meta: 'static + Send + Sync + Serialize + for<'a> Deserialize<'a>,
+{
+    let state = WalletState {
+        keystore: Arc::new(Mutex::new(keystore)),
+        token: Arc::new(opt.token.clone()),
+        policy,
+        poll_interval: opt.poll_interval,
+        esqs_url: opt.esqs_url.clone(),
+    };
+    let toml = match &opt.api_path {
+        Some(path) => {
+            toml::from_slice(&fs::read(path).map_err(|err| ApiError::CannotReadToml {
+                reason: err.to_string(),
+            })?)
+        }
+        None => toml::from_str(include_str!("../api/wallet.toml")),
+    }
+    .map_err(|err| ApiError::CannotReadToml {
+        reason: err.to_string(),
+    })?;
+
+    let mut api = Api::<WalletState<Meta>, Error>::new(toml)?;
+    api.with_version(env!("CARGO_PKG_VERSION").parse().unwrap())
+        .at("pub_keys", |req, state| pub_keys(req, state).boxed())?
+        .at("balances", |req, state| balances(req, state).boxed())?
+        .at("balance", |req, state| balance(req, state).boxed())?
+        .at("assets", |req, state| assets(req, state).boxed())?
+        .at("freezable_records", |req, state| {
+            freezable_records(req, state).boxed()
+        })?
+        .at("frozen_holdings", |req, state| {
+            frozen_holdings(req, state).boxed()
+        })?
+        .at("transaction_proof", |req, state| {
+            transaction_proof(req, state).boxed()
+        })?
+        .at("history", |req, state| history(req, state).boxed())?
+        .at("transfer", |req, state| transfer(req, state).boxed())?
+        .at("mint", |req, state| mint(req, state).boxed())?
+        .at("freeze", |req, state| freeze(req, state, true).boxed())?
+        .at("unfreeze", |req, state| freeze(req, state, false).boxed())?
+        .at("define_asset", |req, state| {
+            define_asset(req, state).boxed()
+        })?
+        .stream("events", |req, state| {
+            let state = state.clone();
+            async move {
+                state.authorize(&req)?;
+                let first = req.opt_integer_param("first")?.unwrap_or(0);
+                // There is no push notification for history updates, so we poll the keystore and
+                // yield each entry from `first` on which is new or has changed since the last poll.
+                Ok(unfold(vec![], move |mut seen: Vec<u64>| {
+                    let state = state.clone();
+                    async move {
+                        loop {
+                            let txns = state.keystore.lock().await.transactions().await;
+                            match txns {
+                                Ok(txns) => {
+                                    let updates = history_updates(&mut seen, first, txns);
+                                    if !updates.is_empty() {
+                                        return Some((iter(updates.into_iter().map(Ok)), seen));
+                                    }
+                                }
+                                Err(err) => {
+                                    return Some((iter(vec![Err(Error::from(err))]), seen));
+                                }
+                            }
+                            sleep(state.poll_interval).await;
+                        }
+                    }
+                })
+                .flatten())
+            }
+            .try_flatten_stream()
+            .boxed()
+        })?;
+
+    let mut app = App::<WalletState<Meta>, Error>::with_state(state);
+    app.register_module("wallet", api)
+        .map_err(|err| ApiError::CannotReadToml {
+            reason: err.to_string(),
+        })?;
+    Ok(spawn(app.serve(format!("{}:{}", opt.address, opt.port))))
+}
+
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::policy::{AssetLimits, PolicyRules};
+    use jf_cap::keys::UserKeyPair;
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+
+    #[test]
+    fn test_token() {
+        assert!(parse_token("").is_err());
+        assert!(parse_token("  ").is_err());
+        assert_eq!(parse_token("secret").unwrap(), "secret");
+
+        check_token("secret", Some("Bearer secret")).unwrap();
+        for header in [None, Some("Bearer wrong"), Some("secret"), Some("Bearer ")] {
+            assert!(matches!(
+                check_token("secret", header),
+                Err(Error::Unauthorized)
+            ));
+        }
+        // An empty token must not authorize a request with an empty bearer token.
+        assert!(matches!(
+            check_token("", Some("Bearer ")),
+            Err(Error::Unauthorized)
+        ));
+    }
+
+    #[test]
+    fn test_history_updates() {
+        let mut seen = vec![];
+        assert_eq!(history_updates(&mut seen, 0, vec![1, 2, 3]), vec![1, 2, 3]);
+        assert!(history_updates(&mut seen, 0, vec![1, 2, 3]).is_empty());
+        // A changed entry and a new entry are both yielded.
+        assert_eq!(history_updates(&mut seen, 0, vec![1, 5, 3, 4]), vec![5, 4]);
+
+        // Entries before the cursor are never yielded.
+        let mut seen = vec![];
+        assert_eq!(history_updates(&mut seen, 2, vec![1, 2, 3]), vec![3]);
+        assert_eq!(history_updates(&mut seen, 2, vec![7, 2, 3, 4]), vec![4]);
+        assert!(history_updates(&mut seen, 5, vec![1, 2, 3]).is_empty());
+    }
+
+    #[test]
+    fn test_transfer_check_policy() {
+        let mut rng = ChaChaRng::from_seed([0x42; 32]);
+        let receiver = UserKeyPair::generate(&mut rng).pub_key();
+        let asset = AssetCode::native();
+        let policy = SpendingPolicy::new(PolicyRules {
+            limits: vec![AssetLimits {
+                asset,
+                daily_limit: Some(100),
+                confirmation_threshold: Some(50),
+            }],
+            allow: None,
+            deny: Default::default(),
+        });
+        let request = |amount, fee, confirmed| TransferRequest {
+            asset,
+            receivers: vec![(receiver.clone(), amount)],
+            fee,
+            confirmed,
+            arity: None,
+        };
+
+        request(10, 1, false).check_policy(&policy).unwrap();
+        assert!(matches!(
+            request(60, 1, false).check_policy(&policy),
+            Err(Error::PolicyViolation {
+                rule: PolicyRule::ConfirmationRequired { .. }
+            })
+        ));
+        request(60, 1, true).check_policy(&policy).unwrap();
+        // The fee counts towards the daily limit of the native asset.
+        assert!(matches!(
+            request(95, 10, true).check_policy(&policy),
+            Err(Error::PolicyViolation {
+                rule: PolicyRule::DailyLimit { .. }
+            })
+        ));
+    }
+}
+
+#[cfg(all(test, feature = "slow-tests"))]
+mod tests {
+    use super::*;
+    use crate::policy::{AssetLimits, PolicyRules};
+    use espresso_core::universal_params::UNIVERSAL_PARAM;
+    use espresso_validator::testing::{minimal_test_network, retry, UnencryptedKeystoreLoader};
+    use jf_cap::keys::UserKeyPair;
+    use portpicker::pick_unused_port;
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+    use seahorse::events::EventIndex;
+    use surf_disco::Client;
+    use tempdir::TempDir;
+    use tide_disco::Error as _;
+
+    const AUTH: &str = "Bearer secret";
+
+    async fn get_balance(client: &Client<Error>, asset: &AssetCode) -> U256 {
+        client
+            .get(&format!("balance/{}", asset))
+            .header("Authorization", AUTH)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    async fn post_transfer(
+        client: &Client<Error>,
+        request: &TransferRequest,
+    ) -> Result<TransactionUID<EspressoLedger>, Error> {
+        client
+            .post("transfer")
+            .header("Authorization", AUTH)
+            .body_json(request)
+            .unwrap()
+            .send()
+            .await
+    }
+
+    #[async_std::test]
+    async fn test_wallet_api() {
+        let mut rng = ChaChaRng::from_seed([0x48; 32]);
+        let faucet_key = UserKeyPair::generate(&mut rng);
+        let receiver = UserKeyPair::generate(&mut rng).pub_key();
+        let network = minimal_test_network(&mut rng, faucet_key.pub_key(), None).await;
+
+        let mut loader = UnencryptedKeystoreLoader {
+            dir: TempDir::new("wallet_api").unwrap(),
+        };
+        let policy = Arc::new(SpendingPolicy::load(loader.dir.path()).unwrap());
+        let backend = NetworkBackend::new(
+            &UNIVERSAL_PARAM,
+            network.query_api.clone(),
+            network.address_book_api.clone(),
+            network.submit_api.clone(),
+        )
+        .await
+        .unwrap()
+        .with_spending_policy(policy.clone());
+        let mut keystore = ApiKeystore::<()>::new(backend, &mut loader).await.unwrap();
+        keystore
+            .add_account(faucet_key.clone(), "faucet".into(), EventIndex::default())
+            .await
+            .unwrap();
+        keystore
+            .await_sending_key_scan(&faucet_key.address())
+            .await
+            .unwrap();
+        let native = AssetCode::native();
+        let initial_balance = keystore.balance(&native).await;
+        assert!(initial_balance > U256::from(1000u64));
+
+        // Limit transfers to 1000 native tokens a day, fees included.
+        policy
+            .set_rules(PolicyRules {
+                limits: vec![AssetLimits {
+                    asset: native,
+                    daily_limit: Some(1000),
+                    confirmation_threshold: None,
+                }],
+                ..Default::default()
+            })
+            .unwrap();
+
+        // The keystore enforces the policy itself, so a transfer which exceeds it fails even when
+        // it is built without going through the wallet API.
+        let request = TransferRequest {
+            asset: native,
+            receivers: vec![(receiver.clone(), 1000)],
+            fee: 1,
+            confirmed: false,
+            arity: None,
+        };
+        let err = submit_transfer(&mut keystore, &request).await.unwrap_err();
+        assert!(
+            matches!(&err, Error::Keystore { msg } if msg.contains("spending policy")),
+            "{:?}",
+            err
+        );
+        assert_eq!(keystore.balance(&native).await, initial_balance);
+
+        let port = pick_unused_port().unwrap();
+        let opt = Options {
+            port,
+            address: "127.0.0.1".into(),
+            token: "secret".into(),
+            api_path: None,
+            poll_interval: Duration::from_millis(100),
+            esqs_url: None,
+        };
+        serve(&opt, keystore, Some(policy)).unwrap();
+        let client =
+            Client::<Error>::new(format!("http://localhost:{}/wallet", port).parse().unwrap());
+        assert!(client.connect(None).await);
+
+        // Requests without the right token are rejected.
+        let route = format!("balance/{}", native);
+        let err = client.get::<U256>(&route).send().await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::Unauthorized);
+        for header in ["Bearer wrong", "Bearer secretsecret", "secret"] {
+            let err = client
+                .get::<U256>(&route)
+                .header("Authorization", header)
+                .send()
+                .await
+                .unwrap_err();
+            assert_eq!(err.status(), StatusCode::Unauthorized);
+        }
+        assert_eq!(get_balance(&client, &native).await, initial_balance);
+
+        // Through the wallet API, the same transfer is refused before it is built.
+        let err = post_transfer(&client, &request).await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::Forbidden);
+        assert!(
+            matches!(
+                &err,
+                Error::PolicyViolation {
+                    rule: PolicyRule::DailyLimit {
+                        requested: 1001,
+                        ..
+                    }
+                }
+            ),
+            "{:?}",
+            err
+        );
+        assert_eq!(get_balance(&client, &native).await, initial_balance);
+
+        // A transfer within the limits goes through.
+        let request = TransferRequest {
+            receivers: vec![(receiver, 100)],
+            ..request
+        };
+        post_transfer(&client, &request).await.unwrap();
+        retry(|| async {
+            get_balance(&client, &native).await == initial_balance - U256::from(101u64)
+        })
+        .await;
+        let history = client
+            .get::<Vec<Transaction<EspressoLedger>>>("history")
+            .header("Authorization", AUTH)
+            .send()
+            .await
+            .unwrap();
+        assert!(!history.is_empty());
+    }
+}
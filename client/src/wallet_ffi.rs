@@ -0,0 +1,400 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! C-compatible bindings for embedding a keystore in a native application.
+//!
+//! The API is deliberately small and stringly typed so that it is easy to bind from Swift, Kotlin
+//! or any other language with a C FFI. A keystore is represented by an opaque [WalletHandle]
+//! pointer, and all structured inputs and outputs are UTF-8, NUL-terminated JSON strings, using
+//! the same serialization as the [wallet API](crate::wallet_api).
+//!
+//! Calls block the calling thread until they complete. Functions which return a pointer return
+//! NULL on failure, and a description of the most recent failure on the calling thread can then be
+//! retrieved with [espresso_last_error]. A panic inside the library is reported the same way,
+//! rather than unwinding into the caller.
+//!
+//! Every string returned by this API is owned by the caller and must be released with
+//! [espresso_string_free]. Every handle must be released with [espresso_wallet_close].
+//!
+//! Some strings, such as generated mnemonics, are secret. The library wipes its own copies of
+//! secrets, and the memory of every string released with [espresso_string_free], before freeing
+//! them. The caller is responsible for wiping any copies it makes.
+//!
+//! Transfers are checked against the spending policy stored alongside the keystore before they are
+//! built, and again by the keystore when they are submitted.
+
+use crate::{
+    hd::{KeyTree, Mnemonic},
+    loader::{MnemonicPasswordLogin, RecoveryLoader},
+    network::NetworkBackend,
+    policy::SpendingPolicy,
+    storage_lock::StorageLock,
+    wallet_api::{check_arity, submit_transfer, TransferRequest},
+    EspressoKeystore,
+};
+use async_std::{sync::Arc, task::block_on};
+use espresso_core::universal_params::UNIVERSAL_PARAM;
+use jf_cap::structs::AssetCode;
+use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+use surf_disco::Url;
+use zeroize::Zeroize;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+/// Configuration for [espresso_wallet_open], passed as JSON.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WalletConfig {
+    /// Directory in which to store the keystore. Created if it does not exist.
+    pub storage: PathBuf,
+    pub mnemonic: String,
+    pub password: String,
+    pub esqs_url: Url,
+    pub address_book_url: Url,
+    pub submit_url: Url,
+    /// Open the keystore even if another process has it open. Only for recovery tooling.
+    #[serde(default)]
+    pub force_unlock: bool,
+}
+
+impl Drop for WalletConfig {
+    fn drop(&mut self) {
+        self.mnemonic.zeroize();
+        self.password.zeroize();
+    }
+}
+
+/// An open keystore.
+pub struct WalletHandle {
+    keystore: EspressoKeystore<'static, NetworkBackend<'static>, MnemonicPasswordLogin>,
+    esqs_url: Url,
+    // The policy the keystore's backend enforces, so transfers can be checked before proving.
+    policy: Arc<SpendingPolicy>,
+    // Declared last so the keystore is closed before the storage is unlocked.
+    _lock: StorageLock,
+}
+
+fn set_last_error(msg: impl ToString) {
+    // Interior NULs can't be represented in a C string; replace them rather than losing the
+    // message entirely.
+    let msg = msg.to_string().replace('\0', " ");
+    LAST_ERROR.with(|err| *err.borrow_mut() = Some(CString::new(msg).unwrap()));
+}
+
+/// Convert `s` to a C string for the caller, wiping `s`.
+fn into_c_string(mut s: String) -> *mut c_char {
+    // Copy into a buffer with room for the NUL terminator, so that `CString::new` does not
+    // reallocate and free the old buffer without wiping it.
+    let mut bytes = Vec::with_capacity(s.len() + 1);
+    bytes.extend_from_slice(s.as_bytes());
+    s.zeroize();
+    match CString::new(bytes) {
+        Ok(s) => s.into_raw(),
+        Err(err) => {
+            let mut bytes = err.into_vec();
+            bytes.zeroize();
+            set_last_error("string contains an interior NUL");
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Run `f`, returning `on_panic` with the last error set if it panics.
+///
+/// Unwinding out of an `extern "C"` function is undefined behavior, so every entry point runs its
+/// body through this.
+fn catch_panic<T>(on_panic: T, f: impl FnOnce() -> T) -> T {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(res) => res,
+        Err(payload) => {
+            let msg = payload
+                .downcast_ref::<&str>()
+                .map(|msg| msg.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown error".into());
+            set_last_error(format!("panic: {}", msg));
+            on_panic
+        }
+    }
+}
+
+/// Read a caller-provided string.
+///
+/// # Safety
+///
+/// `s` must be NULL or point to a valid NUL-terminated string.
+unsafe fn from_c_str<'a>(s: *const c_char) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err("unexpected null string".into());
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|err| format!("invalid UTF-8: {}", err))
+}
+
+/// Run `f`, converting its result to a JSON string, or NULL with the last error set.
+fn json_result<T: Serialize>(f: impl FnOnce() -> Result<T, String>) -> *mut c_char {
+    match f().and_then(|res| serde_json::to_string(&res).map_err(|err| err.to_string())) {
+        Ok(json) => into_c_string(json),
+        Err(err) => {
+            set_last_error(err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// The message describing the most recent failure on this thread, or NULL if there was none.
+#[no_mangle]
+pub extern "C" fn espresso_last_error() -> *mut c_char {
+    catch_panic(ptr::null_mut(), || {
+        LAST_ERROR.with(|err| match &*err.borrow() {
+            Some(msg) => msg.clone().into_raw(),
+            None => ptr::null_mut(),
+        })
+    })
+}
+
+/// Release a string returned by this API.
+///
+/// # Safety
+///
+/// `s` must be NULL or a string returned by this API which has not already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn espresso_string_free(s: *mut c_char) {
+    catch_panic((), || {
+        if !s.is_null() {
+            CString::from_raw(s).into_bytes().zeroize();
+        }
+    })
+}
+
+/// Generate a new random mnemonic phrase, which can be used to create a keystore.
+#[no_mangle]
+pub extern "C" fn espresso_wallet_generate_mnemonic() -> *mut c_char {
+    catch_panic(ptr::null_mut(), || {
+        let (_, mnemonic) = KeyTree::random(&mut ChaChaRng::from_entropy());
+        into_c_string(mnemonic.to_string())
+    })
+}
+
+/// Open the keystore described by `config`, a JSON [WalletConfig].
+///
+/// If there is no keystore in the configured storage directory, a new one is created from the
+/// mnemonic. Otherwise, the existing keystore is opened with the password, or recovered from the
+/// mnemonic if the password is wrong. The keystore enforces the spending policy stored in the same
+/// directory, if there is one.
+///
+/// # Safety
+///
+/// `config` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn espresso_wallet_open(config: *const c_char) -> *mut WalletHandle {
+    catch_panic(ptr::null_mut(), || wallet_open(config))
+}
+
+/// The body of [espresso_wallet_open].
+///
+/// # Safety
+///
+/// `config` must be a valid NUL-terminated string.
+unsafe fn wallet_open(config: *const c_char) -> *mut WalletHandle {
+    let res = from_c_str(config).and_then(|config| {
+        let mut config: WalletConfig =
+            serde_json::from_str(config).map_err(|err| err.to_string())?;
+        let mnemonic: Mnemonic = config
+            .mnemonic
+            .parse()
+            .map_err(|_| String::from("invalid mnemonic"))?;
+        let lock = StorageLock::acquire(&config.storage, config.force_unlock)
+            .map_err(|err| err.to_string())?;
+        let policy =
+            Arc::new(SpendingPolicy::load(&config.storage).map_err(|err| err.to_string())?);
+        // Move the secrets into the loader rather than copying them, so that no copy outlives the
+        // loader except what `config` wipes when it is dropped.
+        let password = std::mem::take(&mut config.password);
+        block_on(async move {
+            let mut rng = ChaChaRng::from_entropy();
+            let mut loader =
+                RecoveryLoader::new(&mut rng, config.storage.clone(), mnemonic, password);
+            let backend = NetworkBackend::new(
+                &UNIVERSAL_PARAM,
+                config.esqs_url.clone(),
+                config.address_book_url.clone(),
+                config.submit_url.clone(),
+            )
+            .await
+            .map_err(|err| err.to_string())?
+            .with_spending_policy(policy.clone());
+            let keystore = EspressoKeystore::new(backend, &mut loader)
+                .await
+                .map_err(|err| err.to_string())?;
+            Ok(WalletHandle {
+                keystore,
+                esqs_url: config.esqs_url.clone(),
+                policy,
+                _lock: lock,
+            })
+        })
+    });
+    match res {
+        Ok(handle) => Box::into_raw(Box::new(handle)),
+        Err(err) => {
+            set_last_error(err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Close a keystore opened with [espresso_wallet_open].
+///
+/// # Safety
+///
+/// `handle` must be NULL or a handle returned by [espresso_wallet_open] which has not already been
+/// closed.
+#[no_mangle]
+pub unsafe extern "C" fn espresso_wallet_close(handle: *mut WalletHandle) {
+    catch_panic((), || {
+        if !handle.is_null() {
+            drop(Box::from_raw(handle));
+        }
+    })
+}
+
+/// The public keys of the keystore's sending accounts, as a JSON list.
+///
+/// # Safety
+///
+/// `handle` must be a valid handle returned by [espresso_wallet_open].
+#[no_mangle]
+pub unsafe extern "C" fn espresso_wallet_pub_keys(handle: *mut WalletHandle) -> *mut c_char {
+    catch_panic(ptr::null_mut(), || {
+        let wallet = &*handle;
+        json_result(|| Ok(block_on(wallet.keystore.sending_keys())))
+    })
+}
+
+/// The keystore's total balance of `asset`, a `TaggedBase64` asset code, as a JSON number.
+///
+/// # Safety
+///
+/// `handle` must be a valid handle returned by [espresso_wallet_open], and `asset` must be a valid
+/// NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn espresso_wallet_balance(
+    handle: *mut WalletHandle,
+    asset: *const c_char,
+) -> *mut c_char {
+    catch_panic(ptr::null_mut(), || {
+        let wallet = &*handle;
+        json_result(|| {
+            let asset: AssetCode = from_c_str(asset)?
+                .parse()
+                .map_err(|_| String::from("invalid asset code"))?;
+            Ok(block_on(wallet.keystore.balance(&asset)))
+        })
+    })
+}
+
+/// Build, prove and submit a transfer described by `request`, a JSON [TransferRequest].
+///
+/// Fails without building the transfer if it violates the keystore's spending policy, including
+/// if it exceeds a confirmation threshold and `confirmed` is not set in the request. Returns the receipt of the submitted transaction as JSON. The transaction may still fail after
+/// it is submitted; its outcome is reported by [espresso_wallet_poll_events].
+///
+/// # Safety
+///
+/// `handle` must be a valid handle returned by [espresso_wallet_open], and `request` must be a
+/// valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn espresso_wallet_transfer(
+    handle: *mut WalletHandle,
+    request: *const c_char,
+) -> *mut c_char {
+    catch_panic(ptr::null_mut(), || {
+        let wallet = &mut *handle;
+        json_result(|| {
+            let request: TransferRequest =
+                serde_json::from_str(from_c_str(request)?).map_err(|err| err.to_string())?;
+            request
+                .check_policy(&wallet.policy)
+                .map_err(|err| err.to_string())?;
+            block_on(async {
+                check_arity(&request, Some(&wallet.esqs_url)).await?;
+                submit_transfer(&mut wallet.keystore, &request).await
+            })
+            .map_err(|err| err.to_string())
+        })
+    })
+}
+
+/// Transaction history entries starting from index `from`, as a JSON list.
+///
+/// This does not block waiting for new entries. Callers poll with `from` set to the number of
+/// entries they have already seen.
+///
+/// # Safety
+///
+/// `handle` must be a valid handle returned by [espresso_wallet_open].
+#[no_mangle]
+pub unsafe extern "C" fn espresso_wallet_poll_events(
+    handle: *mut WalletHandle,
+    from: usize,
+) -> *mut c_char {
+    catch_panic(ptr::null_mut(), || {
+        let wallet = &*handle;
+        json_result(|| {
+            let txns = block_on(wallet.keystore.transactions()).map_err(|err| err.to_string())?;
+            Ok(txns.into_iter().skip(from).collect::<Vec<_>>())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strings_and_errors() {
+        unsafe {
+            let mnemonic = espresso_wallet_generate_mnemonic();
+            assert!(!mnemonic.is_null());
+            let phrase = CStr::from_ptr(mnemonic).to_str().unwrap().to_string();
+            assert_eq!(phrase.split_whitespace().count(), 12);
+            espresso_string_free(mnemonic);
+
+            // A malformed config fails cleanly and leaves an error message for the caller.
+            let config = CString::new("not json").unwrap();
+            assert!(espresso_wallet_open(config.as_ptr()).is_null());
+            let err = espresso_last_error();
+            assert!(!err.is_null());
+            assert!(!CStr::from_ptr(err).to_str().unwrap().is_empty());
+            espresso_string_free(err);
+
+            // Null handles and strings are accepted by the release functions.
+            espresso_wallet_close(ptr::null_mut());
+            espresso_string_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn test_catch_panic() {
+        let res = catch_panic(ptr::null_mut::<c_char>(), || panic!("boom"));
+        assert!(res.is_null());
+        unsafe {
+            let err = espresso_last_error();
+            assert_eq!(CStr::from_ptr(err).to_str().unwrap(), "panic: boom");
+            espresso_string_free(err);
+        }
+
+        // Strings with interior NULs are rejected rather than truncated.
+        assert!(into_c_string("a\0b".into()).is_null());
+    }
+}
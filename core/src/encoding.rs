@@ -0,0 +1,179 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Versioned binary encoding of transactions and blocks.
+//!
+//! Transactions and blocks are exchanged between keystores, query services and validators which
+//! may be running different versions of this library. [CanonicalEncoding] wraps the canonical
+//! (`ark-serialize`) form of a type in a short header: a 4-byte tag identifying the type,
+//! followed by the format version as a little-endian `u16`. A decoder rejects bytes with the wrong
+//! tag, an unknown version or trailing data, rather than misinterpreting them.
+//!
+//! The canonical form is also what commitments are computed over, so any change to it is a
+//! breaking change and must come with a new [ENCODING_VERSION]. The golden-file test in this
+//! module catches accidental changes.
+
+use crate::state::{ElaboratedBlock, ElaboratedTransaction};
+use crate::util::canonical;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use snafu::Snafu;
+
+/// The version of the encoding produced by [CanonicalEncoding::encode].
+pub const ENCODING_VERSION: u16 = 1;
+
+const HEADER_LEN: usize = 6;
+
+#[derive(Clone, Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum EncodingError {
+    #[snafu(display("encoding is too short to contain a header"))]
+    MissingHeader,
+    #[snafu(display("expected an encoding of {:?}, got {:?}", expected, actual))]
+    WrongTag { expected: [u8; 4], actual: [u8; 4] },
+    #[snafu(display("unsupported encoding version {}", version))]
+    UnsupportedVersion { version: u16 },
+    #[snafu(display("malformed encoding: {}", msg))]
+    Malformed { msg: String },
+    #[snafu(display("{} unexpected bytes after the end of the encoding", len))]
+    TrailingBytes { len: usize },
+}
+
+pub trait CanonicalEncoding: Sized + CanonicalSerialize + CanonicalDeserialize {
+    /// Identifies the type of an encoded value.
+    const TAG: [u8; 4];
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Self::TAG.to_vec();
+        bytes.extend_from_slice(&ENCODING_VERSION.to_le_bytes());
+        bytes.extend(canonical::serialize(self).unwrap());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, EncodingError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(EncodingError::MissingHeader);
+        }
+        let tag: [u8; 4] = bytes[..4].try_into().unwrap();
+        if tag != Self::TAG {
+            return Err(EncodingError::WrongTag {
+                expected: Self::TAG,
+                actual: tag,
+            });
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != ENCODING_VERSION {
+            return Err(EncodingError::UnsupportedVersion { version });
+        }
+        let mut body = &bytes[HEADER_LEN..];
+        let value = Self::deserialize(&mut body).map_err(|err| EncodingError::Malformed {
+            msg: err.to_string(),
+        })?;
+        if !body.is_empty() {
+            return Err(EncodingError::TrailingBytes { len: body.len() });
+        }
+        Ok(value)
+    }
+}
+
+impl CanonicalEncoding for ElaboratedTransaction {
+    const TAG: [u8; 4] = *b"ETXN";
+}
+
+impl CanonicalEncoding for ElaboratedBlock {
+    const TAG: [u8; 4] = *b"EBLK";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::GenesisNote;
+    use crate::state::{ChainVariables, EspressoTransaction, EspressoTxnHelperProofs};
+    use jf_cap::keys::UserKeyPair;
+    use jf_cap::structs::{AssetDefinition, FreezeFlag, RecordOpening};
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+    use sha3::{Digest, Sha3_256};
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn genesis() -> GenesisNote {
+        let mut rng = ChaChaRng::from_seed([0; 32]);
+        let ro = RecordOpening::new(
+            &mut rng,
+            1u64.into(),
+            AssetDefinition::native(),
+            UserKeyPair::generate(&mut rng).pub_key(),
+            FreezeFlag::Unfrozen,
+        );
+        GenesisNote::new(
+            ChainVariables::default(),
+            Arc::new(vec![ro]),
+            BTreeMap::new(),
+        )
+    }
+
+    // Compare the digest of `bytes` with the one recorded in `testdata/encoding/<name>.sha3`.
+    //
+    // A missing golden file is recorded from the current encoding; set ESPRESSO_UPDATE_GOLDEN=1 to
+    // re-record all of them after an intentional format change (which requires a new
+    // ENCODING_VERSION).
+    fn check_golden(name: &str, bytes: &[u8]) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata")
+            .join("encoding")
+            .join(format!("{}.sha3", name));
+        let digest = hex::encode(Sha3_256::digest(bytes));
+        match std::fs::read_to_string(&path) {
+            Ok(golden) if std::env::var("ESPRESSO_UPDATE_GOLDEN").is_err() => {
+                assert_eq!(
+                    golden.trim(),
+                    digest,
+                    "encoding of {} changed; bump ENCODING_VERSION",
+                    name
+                );
+            }
+            _ => {
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(&path, format!("{}\n", digest)).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        let txn = ElaboratedTransaction {
+            txn: EspressoTransaction::Genesis(genesis()),
+            proofs: EspressoTxnHelperProofs::Genesis,
+            memos: None,
+        };
+        let block = ElaboratedBlock::genesis(genesis());
+
+        let txn_bytes = txn.encode();
+        let block_bytes = block.encode();
+        assert_eq!(ElaboratedTransaction::decode(&txn_bytes).unwrap(), txn);
+        assert_eq!(ElaboratedBlock::decode(&block_bytes).unwrap(), block);
+        check_golden("elaborated_transaction", &txn_bytes);
+        check_golden("elaborated_block", &block_bytes);
+
+        assert!(matches!(
+            ElaboratedBlock::decode(&txn_bytes),
+            Err(EncodingError::WrongTag { .. })
+        ));
+        let mut future = txn_bytes.clone();
+        future[4] = 0xff;
+        assert!(matches!(
+            ElaboratedTransaction::decode(&future),
+            Err(EncodingError::UnsupportedVersion { .. })
+        ));
+        let mut padded = txn_bytes.clone();
+        padded.push(0);
+        assert!(matches!(
+            ElaboratedTransaction::decode(&padded),
+            Err(EncodingError::TrailingBytes { len: 1 })
+        ));
+        assert!(matches!(
+            ElaboratedTransaction::decode(&txn_bytes[..3]),
+            Err(EncodingError::MissingHeader)
+        ));
+    }
+}
@@ -68,12 +68,10 @@ impl traits::NullifierSet for SetMerkleTree {
                 return Err(proof.clone());
             }
         }
-        // Now we can add the new nullifiers to the tree.
-        for (nullifier, _) in nullifiers {
-            // This should not fail, since we remembered all the relevant nullifiers in the previous
-            // loop, so we can unwrap().
-            self.insert(*nullifier).unwrap();
-        }
+        // Now we can add the new nullifiers to the tree. This should not fail, since we remembered
+        // all the relevant nullifiers in the previous loop, so we can unwrap().
+        self.insert_batch(nullifiers.iter().map(|(nullifier, _)| *nullifier))
+            .unwrap();
         // Now that the new nullifiers have all been inserted, we can prune our nullifiers set back
         // down to restore sparseness.
         for (nullifier, _) in nullifiers {
@@ -2,13 +2,17 @@
 // Copyright (c) 2022 Espresso Systems (espressosys.com)
 // This file is part of the Espresso library.
 
+pub mod encoding;
 pub mod genesis;
 pub mod kv_merkle_tree;
 pub mod ledger;
 pub mod lw_persistence;
+pub mod mempool;
 pub mod merkle_tree;
 pub mod reward;
 pub mod set_merkle_tree;
+pub mod sim_ledger;
+pub mod snapshot;
 pub mod stake_table;
 pub mod state;
 pub mod testing;
@@ -0,0 +1,1103 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! A pool of transactions waiting to be included in a block.
+//!
+//! The [Mempool] admits transactions which do not conflict with any pending transaction (that is,
+//! which do not spend a nullifier already spent by another pending transaction), orders them by fee
+//! (highest first, ties broken by arrival order), limits the number of pending transactions per
+//! sender, and drops transactions which have been pending for longer than the configured
+//! expiration. When the pool is full, a new transaction is only admitted if it pays a higher fee
+//! than the lowest-priority pending transaction, which it then replaces.
+//!
+//! CAP transactions do not reveal their sender, so they are attributed to their submitter, such as
+//! the address of the peer which submitted them. Reward transactions are attributed to the staking
+//! key claiming the reward.
+//!
+//! Each node may also require a minimum fee per unit of transaction [weight], advertised as its
+//! [FeeSchedule]. Transactions paying less are rejected on admission.
+//!
+//! Pending transactions are released for inclusion in a block according to a [BlockPolicy]: a
+//! batch is flushed once enough transactions or bytes have accumulated, or once the oldest pending
+//! transaction has waited long enough, whichever comes first. Transactions forwarded to consensus
+//! stay in the mempool, so that conflicts with them are still detected, until they are committed or
+//! expire. If one is not committed within a timeout, it is queued to be proposed again.
+
+use crate::state::{ElaboratedTransaction, EspressoTransaction, TransactionCommitment};
+use ark_serialize::CanonicalSerialize;
+use jf_cap::{
+    structs::{NoteType, Nullifier},
+    TransactionNote,
+};
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MempoolConfig {
+    /// The maximum number of pending transactions.
+    pub capacity: usize,
+    /// The maximum number of pending transactions from a single sender.
+    pub max_per_sender: usize,
+    /// How long a transaction may remain pending before it is dropped.
+    pub expiration: Duration,
+    /// How long a transaction forwarded to consensus may go uncommitted before it is queued to be
+    /// proposed again.
+    pub resubmit_after: Duration,
+    /// The minimum fees required for admission.
+    pub fee_schedule: FeeSchedule,
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            max_per_sender: 100,
+            expiration: Duration::from_secs(600),
+            resubmit_after: Duration::from_secs(60),
+            fee_schedule: Default::default(),
+        }
+    }
+}
+
+/// The minimum fees a node requires of the transactions it admits.
+///
+/// The default schedule requires no fee.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeeSchedule {
+    /// The minimum fee per unit of [weight], in native asset units.
+    pub min_fee_per_weight: u128,
+}
+
+impl FeeSchedule {
+    /// The minimum fee for a transaction of weight `weight`.
+    pub fn min_fee_for_weight(&self, weight: u64) -> u128 {
+        self.min_fee_per_weight.saturating_mul(weight as u128)
+    }
+
+    /// The minimum fee for `txn`.
+    pub fn min_fee(&self, txn: &EspressoTransaction) -> u128 {
+        self.min_fee_for_weight(weight(txn))
+    }
+
+    /// Check that a fee of `fee` is enough for a transaction of weight `weight`.
+    pub fn check(&self, fee: u128, weight: u64) -> Result<(), MempoolError> {
+        let required = self.min_fee_for_weight(weight);
+        if fee < required {
+            Err(MempoolError::FeeTooLow { fee, required })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// When to release pending transactions for inclusion in a block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockPolicy {
+    /// Flush once this many transactions are pending. This is also the most transactions flushed
+    /// at once.
+    pub max_transactions: usize,
+    /// Flush once this many bytes of transactions are pending. This is also the most bytes flushed
+    /// at once, unless a single transaction is larger.
+    pub max_bytes: usize,
+    /// Flush once the oldest pending transaction has waited this long.
+    pub max_delay: Duration,
+}
+
+impl Default for BlockPolicy {
+    /// Flush every transaction as soon as it is received.
+    fn default() -> Self {
+        Self {
+            max_transactions: 1,
+            max_bytes: 1 << 20,
+            max_delay: Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum FlushReason {
+    Transactions,
+    Bytes,
+    Timeout,
+}
+
+/// Statistics about the batches flushed by a [Mempool].
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BlockMetrics {
+    pub blocks: u64,
+    pub transactions: u64,
+    pub bytes: u64,
+    /// The number of blocks flushed for each reason.
+    pub flushed_by: BTreeMap<FlushReason, u64>,
+    // Sum over all blocks of the fraction of the transaction or byte limit (whichever is larger)
+    // that the block used.
+    fullness_sum: f64,
+}
+
+impl BlockMetrics {
+    /// The average fraction of the block limits used by flushed blocks, between 0 and 1.
+    pub fn mean_fullness(&self) -> f64 {
+        if self.blocks == 0 {
+            0.0
+        } else {
+            self.fullness_sum / self.blocks as f64
+        }
+    }
+}
+
+#[derive(Clone, Debug, Snafu, Serialize, Deserialize)]
+#[snafu(visibility(pub(crate)))]
+pub enum MempoolError {
+    /// The transaction is already queued, or was forwarded to consensus recently and will be
+    /// queued again if it is not committed.
+    #[snafu(display("transaction is already pending"))]
+    Duplicate,
+    #[snafu(display("nullifier {} is already spent by a pending transaction", nullifier))]
+    Conflict { nullifier: Nullifier },
+    #[snafu(display("sender {} already has {} pending transactions", sender, limit))]
+    SenderLimit { sender: String, limit: usize },
+    #[snafu(display(
+        "mempool is full and a fee of {} is too low to replace any transaction",
+        fee
+    ))]
+    Full { fee: u128 },
+    #[snafu(display("fee of {} is below the minimum fee of {}", fee, required))]
+    FeeTooLow { fee: u128, required: u128 },
+    #[snafu(display("invalid receiver memos: {}", reason))]
+    InvalidMemos { reason: String },
+}
+
+impl MempoolError {
+    /// A short name for the kind of error, suitable for use as a metrics label.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Duplicate => "duplicate",
+            Self::Conflict { .. } => "conflict",
+            Self::SenderLimit { .. } => "sender_limit",
+            Self::Full { .. } => "full",
+            Self::FeeTooLow { .. } => "fee_too_low",
+            Self::InvalidMemos { .. } => "invalid_memos",
+        }
+    }
+}
+
+/// Why a transaction was removed from the mempool without being committed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DropReason {
+    /// The transaction was pending for longer than the expiration period.
+    Expired,
+    /// The transaction was replaced by a transaction paying a higher fee.
+    Evicted,
+    /// A committed transaction spent one of the same nullifiers.
+    Conflict,
+    /// The transaction failed validation when it was about to be proposed, and was left out of
+    /// the block.
+    Invalid,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MempoolStatus {
+    /// The transaction is waiting to be included in a block. `position` is the number of pending
+    /// transactions with higher priority.
+    Pending { position: usize },
+    /// The transaction has been flushed to consensus and is waiting to be committed.
+    Submitted,
+    /// The transaction was recently dropped from the mempool.
+    Dropped { reason: DropReason },
+    /// The mempool knows nothing about this transaction. It may have been committed, or it may
+    /// never have been submitted.
+    Unknown,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    txn: ElaboratedTransaction,
+    priority: (Reverse<u128>, u64),
+    nullifiers: Vec<Nullifier>,
+    sender: Option<String>,
+    received: Instant,
+    size: usize,
+    // When the transaction was forwarded to consensus, if it has been.
+    submitted: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct Mempool {
+    config: MempoolConfig,
+    entries: HashMap<TransactionCommitment, Entry>,
+    // Transactions which have not yet been flushed, in priority order.
+    by_priority: BTreeMap<(Reverse<u128>, u64), TransactionCommitment>,
+    // All transactions, oldest first, so that expired transactions can be found without scanning
+    // the whole pool. Ties are broken by sequence number.
+    by_received: BTreeMap<(Instant, u64), TransactionCommitment>,
+    // Transactions which have been forwarded to consensus, oldest first, so that those which are
+    // never committed can be found and proposed again.
+    by_submitted: BTreeMap<(Instant, u64), TransactionCommitment>,
+    queued_bytes: usize,
+    spent: HashMap<Nullifier, TransactionCommitment>,
+    per_sender: HashMap<String, usize>,
+    // Recently dropped transactions, so that submitters can find out what happened to them. This is
+    // bounded by the mempool capacity.
+    dropped: HashMap<TransactionCommitment, DropReason>,
+    dropped_order: VecDeque<TransactionCommitment>,
+    next_seq: u64,
+    metrics: BlockMetrics,
+    // The number of transactions rejected on admission, by kind of error.
+    rejected: BTreeMap<&'static str, u64>,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl Mempool {
+    pub fn new(config: MempoolConfig) -> Self {
+        Self {
+            config,
+            entries: Default::default(),
+            by_priority: Default::default(),
+            by_received: Default::default(),
+            by_submitted: Default::default(),
+            queued_bytes: 0,
+            spent: Default::default(),
+            per_sender: Default::default(),
+            dropped: Default::default(),
+            dropped_order: Default::default(),
+            next_seq: 0,
+            metrics: Default::default(),
+            rejected: Default::default(),
+        }
+    }
+
+    pub fn config(&self) -> &MempoolConfig {
+        &self.config
+    }
+
+    pub fn block_metrics(&self) -> &BlockMetrics {
+        &self.metrics
+    }
+
+    /// The number of transactions rejected on admission, by [MempoolError::kind].
+    pub fn rejected(&self) -> &BTreeMap<&'static str, u64> {
+        &self.rejected
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Admit a transaction submitted by `sender`.
+    ///
+    /// `sender` identifies the submitter of a CAP transaction, which counts towards the submitter's
+    /// limit of pending transactions. A CAP transaction with no known submitter is not subject to
+    /// the limit. Reward transactions always count towards the limit of their staking key. Receiver
+    /// memos bundled with a CAP transaction must be signed by the transaction and have one memo
+    /// per output, and the transaction must pay at least the minimum fee for its weight, otherwise
+    /// the transaction is rejected before it takes up space in the pool.
+    pub fn insert(
+        &mut self,
+        txn: ElaboratedTransaction,
+        sender: Option<String>,
+    ) -> Result<(), MempoolError> {
+        let fee = fee(&txn.txn);
+        let checked =
+            check_memos(&txn).and_then(|()| self.config.fee_schedule.check(fee, weight(&txn.txn)));
+        if let Err(err) = checked {
+            *self.rejected.entry(err.kind()).or_default() += 1;
+            return Err(err);
+        }
+        let sender = match &txn.txn {
+            EspressoTransaction::Reward(note) => Some(note.staking_key().to_string()),
+            _ => sender,
+        };
+        let nullifiers = txn.txn.input_nullifiers();
+        self.admit(
+            TransactionCommitment(txn.txn.hash()),
+            txn,
+            fee,
+            nullifiers,
+            sender,
+        )
+    }
+
+    /// The status of the transaction with hash `hash`.
+    pub fn status(&self, hash: &TransactionCommitment) -> MempoolStatus {
+        if let Some(entry) = self.entries.get(hash) {
+            if entry.submitted.is_some() {
+                return MempoolStatus::Submitted;
+            }
+            MempoolStatus::Pending {
+                position: self.by_priority.range(..entry.priority).count(),
+            }
+        } else if let Some(reason) = self.dropped.get(hash) {
+            MempoolStatus::Dropped { reason: *reason }
+        } else {
+            MempoolStatus::Unknown
+        }
+    }
+
+    /// Transactions which have not yet been flushed, highest priority first.
+    pub fn pending(&self) -> impl Iterator<Item = &ElaboratedTransaction> {
+        self.by_priority
+            .values()
+            .map(move |hash| &self.entries[hash].txn)
+    }
+
+    /// Whether `policy` calls for a block to be flushed at time `now`, and if so, why.
+    pub fn ready(&self, policy: &BlockPolicy, now: Instant) -> Option<FlushReason> {
+        if self.by_priority.is_empty() {
+            None
+        } else if self.by_priority.len() >= policy.max_transactions {
+            Some(FlushReason::Transactions)
+        } else if self.queued_bytes >= policy.max_bytes {
+            Some(FlushReason::Bytes)
+        } else if self
+            .by_received
+            .values()
+            .map(|hash| &self.entries[hash])
+            // The oldest transactions are likely to have been submitted already, but there are
+            // only ever a few blocks' worth of those to skip.
+            .find(|entry| entry.submitted.is_none())
+            .map_or(false, |oldest| {
+                now.saturating_duration_since(oldest.received) >= policy.max_delay
+            })
+        {
+            Some(FlushReason::Timeout)
+        } else {
+            None
+        }
+    }
+
+    /// If `policy` calls for a block at time `now`, return the highest priority pending
+    /// transactions which fit within the policy's limits.
+    ///
+    /// The transactions stay pending until they are [marked as submitted](Self::mark_submitted),
+    /// so that any which cannot be forwarded to consensus are included in the next batch instead
+    /// of being lost. Before choosing the batch, transactions which were submitted at least
+    /// [resubmit_after](MempoolConfig::resubmit_after) ago and have not been committed are queued
+    /// again, since the block they were proposed in will evidently never be decided.
+    pub fn flush(
+        &mut self,
+        policy: &BlockPolicy,
+        now: Instant,
+    ) -> Option<(FlushReason, Vec<ElaboratedTransaction>)> {
+        self.prune_expired(now);
+        self.requeue_stale(now);
+        let reason = self.ready(policy, now)?;
+
+        let mut hashes = vec![];
+        let mut bytes = 0;
+        for hash in self.by_priority.values() {
+            if hashes.len() >= policy.max_transactions {
+                break;
+            }
+            let size = self.entries[hash].size;
+            // Always include at least one transaction, so that an oversized transaction can't
+            // block the queue forever.
+            if !hashes.is_empty() && bytes + size > policy.max_bytes {
+                continue;
+            }
+            hashes.push(*hash);
+            bytes += size;
+        }
+
+        let txns = hashes
+            .iter()
+            .map(|hash| self.entries[hash].txn.clone())
+            .collect::<Vec<_>>();
+
+        let fullness = f64::max(
+            txns.len() as f64 / policy.max_transactions.max(1) as f64,
+            bytes as f64 / policy.max_bytes.max(1) as f64,
+        );
+        self.metrics.blocks += 1;
+        self.metrics.transactions += txns.len() as u64;
+        self.metrics.bytes += bytes as u64;
+        *self.metrics.flushed_by.entry(reason).or_default() += 1;
+        self.metrics.fullness_sum += fullness.min(1.0);
+        tracing::debug!(
+            "flushing {} transactions ({} bytes) from mempool: {:?}",
+            txns.len(),
+            bytes,
+            reason
+        );
+        Some((reason, txns))
+    }
+
+    /// Record that the transaction with hash `hash` was forwarded to consensus at time `now`.
+    pub fn mark_submitted(&mut self, hash: &TransactionCommitment, now: Instant) {
+        if let Some(entry) = self.entries.get_mut(hash) {
+            if entry.submitted.is_none() {
+                entry.submitted = Some(now);
+                self.by_priority.remove(&entry.priority);
+                self.queued_bytes -= entry.size;
+                self.by_submitted.insert((now, entry.priority.1), *hash);
+            }
+        }
+    }
+
+    /// Queue transactions which were submitted before `now - resubmit_after` to be proposed again.
+    pub fn requeue_stale(&mut self, now: Instant) {
+        let resubmit_after = self.config.resubmit_after;
+        let stale = self
+            .by_submitted
+            .iter()
+            .take_while(|((submitted, _), _)| {
+                now.saturating_duration_since(*submitted) >= resubmit_after
+            })
+            .map(|(key, hash)| (*key, *hash))
+            .collect::<Vec<_>>();
+        for (key, hash) in stale {
+            self.by_submitted.remove(&key);
+            let entry = self.entries.get_mut(&hash).unwrap();
+            tracing::debug!(
+                "transaction {} was not committed after {:?}, proposing it again",
+                hash,
+                resubmit_after
+            );
+            entry.submitted = None;
+            self.by_priority.insert(entry.priority, hash);
+            self.queued_bytes += entry.size;
+        }
+    }
+
+    /// Remove transactions which have been committed, along with any pending transactions that
+    /// conflict with them.
+    pub fn remove_committed<'a>(
+        &mut self,
+        txns: impl IntoIterator<Item = &'a EspressoTransaction>,
+    ) {
+        for txn in txns {
+            self.remove(&TransactionCommitment(txn.hash()));
+            for nullifier in txn.input_nullifiers() {
+                if let Some(hash) = self.spent.get(&nullifier).cloned() {
+                    self.drop_txn(hash, DropReason::Conflict);
+                }
+            }
+        }
+        self.prune_expired(Instant::now());
+    }
+
+    /// Drop a transaction which failed validation, so that it is not proposed again.
+    pub fn drop_invalid(&mut self, hash: &TransactionCommitment) {
+        self.drop_txn(*hash, DropReason::Invalid);
+    }
+
+    /// Drop transactions which were received before `now - expiration`.
+    pub fn prune_expired(&mut self, now: Instant) {
+        let expiration = self.config.expiration;
+        let expired = self
+            .by_received
+            .iter()
+            .take_while(|((received, _), _)| now.saturating_duration_since(*received) >= expiration)
+            .map(|(_, hash)| *hash)
+            .collect::<Vec<_>>();
+        for hash in expired {
+            self.drop_txn(hash, DropReason::Expired);
+        }
+    }
+
+    fn admit(
+        &mut self,
+        hash: TransactionCommitment,
+        txn: ElaboratedTransaction,
+        fee: u128,
+        nullifiers: Vec<Nullifier>,
+        sender: Option<String>,
+    ) -> Result<(), MempoolError> {
+        let res = self.try_admit(hash, txn, fee, nullifiers, sender);
+        if let Err(err) = &res {
+            *self.rejected.entry(err.kind()).or_default() += 1;
+        }
+        res
+    }
+
+    fn try_admit(
+        &mut self,
+        hash: TransactionCommitment,
+        txn: ElaboratedTransaction,
+        fee: u128,
+        nullifiers: Vec<Nullifier>,
+        sender: Option<String>,
+    ) -> Result<(), MempoolError> {
+        let now = Instant::now();
+        self.prune_expired(now);
+        // Requeue stale submissions first, so that a duplicate is only ever reported for a
+        // transaction which is queued, or was forwarded recently enough that it will be queued
+        // again if it is not committed.
+        self.requeue_stale(now);
+        if self.entries.contains_key(&hash) {
+            return Err(MempoolError::Duplicate);
+        }
+        if let Some(nullifier) = nullifiers.iter().find(|n| self.spent.contains_key(n)) {
+            return Err(MempoolError::Conflict {
+                nullifier: *nullifier,
+            });
+        }
+        if let Some(sender) = &sender {
+            if self.per_sender.get(sender).copied().unwrap_or(0) >= self.config.max_per_sender {
+                return Err(MempoolError::SenderLimit {
+                    sender: sender.clone(),
+                    limit: self.config.max_per_sender,
+                });
+            }
+        }
+        if self.entries.len() >= self.config.capacity {
+            // Replace the lowest priority transaction, if it pays a lower fee than this one. Only
+            // transactions which have not been flushed yet can be replaced.
+            match self.by_priority.iter().next_back() {
+                Some(((Reverse(lowest_fee), _), lowest)) if *lowest_fee < fee => {
+                    let lowest = *lowest;
+                    self.drop_txn(lowest, DropReason::Evicted);
+                }
+                _ => return Err(MempoolError::Full { fee }),
+            }
+        }
+
+        let priority = (Reverse(fee), self.next_seq);
+        self.next_seq += 1;
+        let size = txn.serialized_size();
+        self.queued_bytes += size;
+        for nullifier in &nullifiers {
+            self.spent.insert(*nullifier, hash);
+        }
+        if let Some(sender) = &sender {
+            *self.per_sender.entry(sender.clone()).or_default() += 1;
+        }
+        self.by_priority.insert(priority, hash);
+        let received = Instant::now();
+        self.by_received.insert((received, priority.1), hash);
+        self.dropped.remove(&hash);
+        self.entries.insert(
+            hash,
+            Entry {
+                txn,
+                priority,
+                nullifiers,
+                sender,
+                received,
+                size,
+                submitted: None,
+            },
+        );
+        Ok(())
+    }
+
+    fn remove(&mut self, hash: &TransactionCommitment) -> Option<Entry> {
+        let entry = self.entries.remove(hash)?;
+        self.by_received.remove(&(entry.received, entry.priority.1));
+        match entry.submitted {
+            Some(submitted) => {
+                self.by_submitted.remove(&(submitted, entry.priority.1));
+            }
+            None => {
+                self.by_priority.remove(&entry.priority);
+                self.queued_bytes -= entry.size;
+            }
+        }
+        for nullifier in &entry.nullifiers {
+            self.spent.remove(nullifier);
+        }
+        if let Some(sender) = &entry.sender {
+            if let Some(count) = self.per_sender.get_mut(sender) {
+                *count -= 1;
+                if *count == 0 {
+                    self.per_sender.remove(sender);
+                }
+            }
+        }
+        Some(entry)
+    }
+
+    fn drop_txn(&mut self, hash: TransactionCommitment, reason: DropReason) {
+        if self.remove(&hash).is_some() {
+            tracing::debug!("dropping transaction {} from mempool: {:?}", hash, reason);
+            self.dropped.insert(hash, reason);
+            self.dropped_order.push_back(hash);
+            while self.dropped_order.len() > self.config.capacity {
+                if let Some(old) = self.dropped_order.pop_front() {
+                    self.dropped.remove(&old);
+                }
+            }
+        }
+    }
+}
+
+/// Check the receiver memos bundled with a transaction, if any.
+pub fn check_memos(txn: &ElaboratedTransaction) -> Result<(), MempoolError> {
+    if let (EspressoTransaction::CAP(note), Some((memos, sig))) = (&txn.txn, &txn.memos) {
+        let outputs = note.output_commitments().len();
+        if memos.len() != outputs {
+            return Err(MempoolError::InvalidMemos {
+                reason: format!("expected {} memos, got {}", outputs, memos.len()),
+            });
+        }
+        note.verify_receiver_memos_signature(memos, sig)
+            .map_err(|err| MempoolError::InvalidMemos {
+                reason: err.to_string(),
+            })?;
+    }
+    Ok(())
+}
+
+/// The fee paid by a transaction, in native asset units.
+pub fn fee(txn: &EspressoTransaction) -> u128 {
+    match txn {
+        EspressoTransaction::CAP(TransactionNote::Transfer(note)) => note.aux_info.fee.into(),
+        EspressoTransaction::CAP(TransactionNote::Mint(note)) => note.aux_info.fee.into(),
+        EspressoTransaction::CAP(TransactionNote::Freeze(note)) => note.aux_info.fee.into(),
+        EspressoTransaction::Genesis(_) | EspressoTransaction::Reward(_) => 0,
+    }
+}
+
+/// The weight of a transaction, which measures the work it takes validators to verify and store it.
+///
+/// Genesis and reward transactions do not pay fees, and have no weight.
+pub fn weight(txn: &EspressoTransaction) -> u64 {
+    let note_type = match txn {
+        EspressoTransaction::CAP(TransactionNote::Transfer(_)) => NoteType::Transfer,
+        EspressoTransaction::CAP(TransactionNote::Mint(_)) => NoteType::Mint,
+        EspressoTransaction::CAP(TransactionNote::Freeze(_)) => NoteType::Freeze,
+        EspressoTransaction::Genesis(_) | EspressoTransaction::Reward(_) => return 0,
+    };
+    note_weight(
+        note_type,
+        txn.input_nullifiers().len(),
+        txn.output_commitments().len(),
+    )
+}
+
+/// The weight of a CAP note of type `note_type` with `inputs` inputs and `outputs` outputs.
+///
+/// Every note has a base weight for verifying its proof, plus one unit for each nullifier and each
+/// record commitment the ledger has to store. A mint also defines a new asset, so its base weight
+/// is one unit higher. This lets a keystore compute the weight of a transaction before building it.
+pub fn note_weight(note_type: NoteType, inputs: usize, outputs: usize) -> u64 {
+    let base = match note_type {
+        NoteType::Transfer | NoteType::Freeze => 2,
+        NoteType::Mint => 3,
+    };
+    base + inputs as u64 + outputs as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        genesis_txn, MultiXfrRecordSpec, MultiXfrTestState, TestTxSpec, TxnPrintInfo,
+    };
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+
+    // The mempool only looks at the transaction hash; fees and nullifiers are supplied separately
+    // in these tests.
+    fn txn(seed: u8) -> (TransactionCommitment, ElaboratedTransaction) {
+        let txn = genesis_txn(seed);
+        (TransactionCommitment(txn.txn.hash()), txn)
+    }
+
+    #[test]
+    fn test_mempool_ordering_and_admission() {
+        let mut rng = ChaChaRng::from_seed([0; 32]);
+        let n1 = Nullifier::random_for_test(&mut rng);
+        let n2 = Nullifier::random_for_test(&mut rng);
+        let mut pool = Mempool::new(MempoolConfig {
+            capacity: 2,
+            max_per_sender: 1,
+            expiration: Duration::from_secs(3600),
+            ..Default::default()
+        });
+
+        let (h1, t1) = txn(1);
+        let (h2, t2) = txn(2);
+        pool.admit(h1, t1.clone(), 1, vec![n1], Some("alice".into()))
+            .unwrap();
+        pool.admit(h2, t2, 5, vec![n2], None).unwrap();
+        assert_eq!(pool.status(&h2), MempoolStatus::Pending { position: 0 });
+        assert_eq!(pool.status(&h1), MempoolStatus::Pending { position: 1 });
+
+        // Duplicates, double spends, and senders over their limit are rejected.
+        assert!(matches!(
+            pool.admit(h1, t1, 1, vec![n1], None),
+            Err(MempoolError::Duplicate)
+        ));
+        let (h3, t3) = txn(3);
+        assert!(matches!(
+            pool.admit(h3, t3.clone(), 10, vec![n1], None),
+            Err(MempoolError::Conflict { .. })
+        ));
+        assert!(matches!(
+            pool.admit(h3, t3.clone(), 10, vec![], Some("alice".into())),
+            Err(MempoolError::SenderLimit { .. })
+        ));
+
+        // When full, a low fee transaction is rejected but a high fee one evicts the lowest.
+        assert!(matches!(
+            pool.admit(h3, t3.clone(), 1, vec![], None),
+            Err(MempoolError::Full { .. })
+        ));
+        pool.admit(h3, t3, 10, vec![], None).unwrap();
+        assert_eq!(
+            pool.rejected()
+                .iter()
+                .map(|(k, n)| (*k, *n))
+                .collect::<Vec<_>>(),
+            vec![
+                ("conflict", 1),
+                ("duplicate", 1),
+                ("full", 1),
+                ("sender_limit", 1)
+            ]
+        );
+        assert_eq!(
+            pool.status(&h1),
+            MempoolStatus::Dropped {
+                reason: DropReason::Evicted
+            }
+        );
+        let policy = BlockPolicy {
+            max_transactions: 1,
+            ..Default::default()
+        };
+        let (reason, block) = pool.flush(&policy, Instant::now()).unwrap();
+        assert_eq!(reason, FlushReason::Transactions);
+        assert_eq!(TransactionCommitment(block[0].txn.hash()), h3);
+        pool.mark_submitted(&h3, Instant::now());
+        assert_eq!(pool.status(&h3), MempoolStatus::Submitted);
+        assert_eq!(pool.status(&h2), MempoolStatus::Pending { position: 0 });
+
+        // A submitted transaction which fails validation is dropped, not proposed again.
+        pool.drop_invalid(&h3);
+        assert_eq!(
+            pool.status(&h3),
+            MempoolStatus::Dropped {
+                reason: DropReason::Invalid
+            }
+        );
+
+        // Expired transactions are dropped.
+        pool.prune_expired(Instant::now() + Duration::from_secs(3600));
+        assert_eq!(
+            pool.status(&h2),
+            MempoolStatus::Dropped {
+                reason: DropReason::Expired
+            }
+        );
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_fee_schedule() {
+        assert_eq!(note_weight(NoteType::Transfer, 2, 3), 7);
+        assert_eq!(note_weight(NoteType::Mint, 1, 2), 6);
+        assert_eq!(note_weight(NoteType::Freeze, 2, 2), 6);
+
+        let schedule = FeeSchedule {
+            min_fee_per_weight: 10,
+        };
+        assert_eq!(schedule.min_fee_for_weight(7), 70);
+
+        // Genesis transactions have no weight, so they are admitted under any fee schedule.
+        let mut pool = Mempool::new(MempoolConfig {
+            fee_schedule: schedule,
+            ..Default::default()
+        });
+        let (hash, txn) = txn(1);
+        assert_eq!(schedule.min_fee(&txn.txn), 0);
+        pool.insert(txn, None).unwrap();
+        assert_eq!(pool.status(&hash), MempoolStatus::Pending { position: 0 });
+    }
+
+    #[test]
+    fn test_min_fee_boundary() {
+        let schedule = FeeSchedule {
+            min_fee_per_weight: 10,
+        };
+        schedule.check(70, 7).unwrap();
+        schedule.check(71, 7).unwrap();
+        assert!(matches!(
+            schedule.check(69, 7),
+            Err(MempoolError::FeeTooLow {
+                fee: 69,
+                required: 70
+            })
+        ));
+
+        // A transfer paying a fee of 1 is admitted when the fee covers its weight, and rejected as
+        // soon as the fee per unit of weight is more than it pays.
+        let mut state = MultiXfrTestState::initialize(
+            [0x42u8; 32],
+            2,
+            1,
+            (
+                MultiXfrRecordSpec {
+                    asset_def_ix: 1,
+                    owner_key_ix: 0,
+                    asset_amount: 1,
+                },
+                vec![],
+            ),
+        )
+        .unwrap();
+        let txn = state
+            .generate_transactions(
+                vec![(TestTxSpec::OneInput { rec: 0, key: 1 }, false)],
+                TxnPrintInfo::new_no_time(0, 1),
+            )
+            .unwrap()
+            .remove(0)
+            .transaction;
+        assert_eq!(fee(&txn.txn), 1);
+        let txn_weight = weight(&txn.txn);
+        assert!(txn_weight > 1);
+
+        let mut pool = Mempool::new(MempoolConfig {
+            fee_schedule: FeeSchedule {
+                min_fee_per_weight: 1,
+            },
+            ..Default::default()
+        });
+        assert!(matches!(
+            pool.insert(txn.clone(), None),
+            Err(MempoolError::FeeTooLow { fee: 1, required }) if required == txn_weight as u128
+        ));
+        assert_eq!(pool.rejected().get("fee_too_low"), Some(&1));
+        assert!(pool.is_empty());
+
+        let mut pool = Mempool::default();
+        pool.insert(txn, None).unwrap();
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn test_cap_sender_limit() {
+        let spec = MultiXfrRecordSpec {
+            asset_def_ix: 1,
+            owner_key_ix: 0,
+            asset_amount: 1,
+        };
+        let mut state =
+            MultiXfrTestState::initialize([0x43u8; 32], 2, 1, (spec, vec![spec, spec])).unwrap();
+        let txns = state
+            .generate_transactions(
+                (0..3)
+                    .map(|rec| (TestTxSpec::OneInput { rec, key: 1 }, false))
+                    .collect(),
+                TxnPrintInfo::new_no_time(0, 1),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|txn| txn.transaction)
+            .collect::<Vec<_>>();
+        assert!(txns
+            .iter()
+            .all(|txn| matches!(txn.txn, EspressoTransaction::CAP(_))));
+
+        // CAP transactions count towards the limit of the peer which submitted them.
+        let mut pool = Mempool::new(MempoolConfig {
+            max_per_sender: 2,
+            ..Default::default()
+        });
+        let peer = Some("10.0.0.1".to_string());
+        pool.insert(txns[0].clone(), peer.clone()).unwrap();
+        pool.insert(txns[1].clone(), peer.clone()).unwrap();
+        assert!(matches!(
+            pool.insert(txns[2].clone(), peer),
+            Err(MempoolError::SenderLimit { sender, limit: 2 }) if sender == "10.0.0.1"
+        ));
+        pool.insert(txns[2].clone(), Some("10.0.0.2".into()))
+            .unwrap();
+        assert_eq!(pool.len(), 3);
+
+        // Without a known submitter, CAP transactions are not limited.
+        let mut pool = Mempool::new(MempoolConfig {
+            max_per_sender: 1,
+            ..Default::default()
+        });
+        for txn in txns {
+            pool.insert(txn, None).unwrap();
+        }
+        assert_eq!(pool.len(), 3);
+    }
+
+    #[test]
+    fn test_prune_expired() {
+        let mut pool = Mempool::new(MempoolConfig {
+            expiration: Duration::from_secs(60),
+            ..Default::default()
+        });
+        let mut hashes = vec![];
+        for seed in 1..=3 {
+            let (hash, txn) = txn(seed);
+            pool.admit(hash, txn, 0, vec![], None).unwrap();
+            hashes.push(hash);
+        }
+        let received = hashes
+            .iter()
+            .map(|hash| pool.entries[hash].received)
+            .collect::<Vec<_>>();
+
+        // Only transactions received at least `expiration` before `now` are dropped.
+        pool.prune_expired(received[1] + Duration::from_secs(60));
+        for (hash, received_at) in hashes.iter().zip(&received) {
+            if *received_at <= received[1] {
+                assert_eq!(
+                    pool.status(hash),
+                    MempoolStatus::Dropped {
+                        reason: DropReason::Expired
+                    }
+                );
+            } else {
+                assert_eq!(pool.status(hash), MempoolStatus::Pending { position: 0 });
+            }
+        }
+        assert_eq!(pool.by_received.len(), pool.len());
+
+        pool.prune_expired(received[2] + Duration::from_secs(60));
+        assert!(pool.is_empty());
+        assert!(pool.by_received.is_empty());
+    }
+
+    #[test]
+    fn test_block_policy() {
+        let mut pool = Mempool::default();
+        let (h1, t1) = txn(1);
+        let size = t1.serialized_size();
+        let policy = BlockPolicy {
+            max_transactions: 3,
+            max_bytes: 2 * size,
+            max_delay: Duration::from_secs(60),
+        };
+
+        pool.admit(h1, t1, 0, vec![], None).unwrap();
+        let now = Instant::now();
+        assert_eq!(pool.ready(&policy, now), None);
+        assert_eq!(
+            pool.ready(&policy, now + Duration::from_secs(60)),
+            Some(FlushReason::Timeout)
+        );
+
+        for seed in 2..=3 {
+            let (hash, txn) = txn(seed);
+            pool.admit(hash, txn, 0, vec![], None).unwrap();
+        }
+        // Three transactions trigger the transaction limit, but only two fit in the byte limit.
+        let (reason, block) = pool.flush(&policy, now).unwrap();
+        assert_eq!(reason, FlushReason::Transactions);
+        assert_eq!(block.len(), 2);
+        for txn in &block {
+            pool.mark_submitted(&TransactionCommitment(txn.txn.hash()), now);
+        }
+        assert_eq!(pool.pending().count(), 1);
+        assert_eq!(pool.ready(&policy, now), None);
+        // The remaining transaction times out on its own, even though older ones were submitted.
+        assert_eq!(
+            pool.ready(&policy, now + Duration::from_secs(60)),
+            Some(FlushReason::Timeout)
+        );
+
+        let metrics = pool.block_metrics();
+        assert_eq!(metrics.blocks, 1);
+        assert_eq!(metrics.transactions, 2);
+        assert!((metrics.mean_fullness() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_resubmit() {
+        let mut pool = Mempool::new(MempoolConfig {
+            resubmit_after: Duration::from_secs(60),
+            ..Default::default()
+        });
+        let policy = BlockPolicy {
+            max_transactions: 2,
+            ..Default::default()
+        };
+        let (h1, t1) = txn(1);
+        let (h2, t2) = txn(2);
+        pool.admit(h1, t1, 1, vec![], None).unwrap();
+        pool.admit(h2, t2, 0, vec![], None).unwrap();
+
+        // A flushed transaction which is never forwarded to consensus stays pending, and is
+        // included in the next batch.
+        let now = Instant::now();
+        let (_, block) = pool.flush(&policy, now).unwrap();
+        assert_eq!(block.len(), 2);
+        pool.mark_submitted(&h1, now);
+        assert_eq!(pool.status(&h1), MempoolStatus::Submitted);
+        assert_eq!(pool.status(&h2), MempoolStatus::Pending { position: 0 });
+        let (_, block) = pool.flush(&policy, now).unwrap();
+        assert_eq!(
+            block
+                .iter()
+                .map(|txn| TransactionCommitment(txn.txn.hash()))
+                .collect::<Vec<_>>(),
+            vec![h2]
+        );
+        pool.mark_submitted(&h2, now + Duration::from_secs(30));
+
+        // A submitted transaction which is not committed within `resubmit_after` is proposed
+        // again.
+        assert!(pool.flush(&policy, now + Duration::from_secs(59)).is_none());
+        let (_, block) = pool.flush(&policy, now + Duration::from_secs(60)).unwrap();
+        assert_eq!(
+            block
+                .iter()
+                .map(|txn| TransactionCommitment(txn.txn.hash()))
+                .collect::<Vec<_>>(),
+            vec![h1]
+        );
+        assert_eq!(pool.status(&h1), MempoolStatus::Pending { position: 0 });
+        assert_eq!(pool.status(&h2), MempoolStatus::Submitted);
+
+        // Committing removes the transaction, whether it is pending or submitted.
+        pool.remove_committed([&block[0].txn]);
+        assert_eq!(pool.status(&h1), MempoolStatus::Unknown);
+        assert!(pool
+            .by_submitted
+            .contains_key(&(now + Duration::from_secs(30), 1)));
+        pool.remove(&h2);
+        assert!(pool.is_empty());
+        assert!(pool.by_submitted.is_empty());
+        assert!(pool.by_priority.is_empty());
+        assert_eq!(pool.queued_bytes, 0);
+    }
+
+    #[test]
+    fn test_resubmit_duplicate() {
+        let (h1, t1) = txn(1);
+
+        // A duplicate of a recently submitted transaction is rejected, and left to be requeued
+        // when it goes stale.
+        let mut pool = Mempool::new(Default::default());
+        pool.admit(h1, t1.clone(), 0, vec![], None).unwrap();
+        pool.mark_submitted(&h1, Instant::now());
+        assert!(matches!(
+            pool.admit(h1, t1.clone(), 0, vec![], None),
+            Err(MempoolError::Duplicate)
+        ));
+        assert_eq!(pool.status(&h1), MempoolStatus::Submitted);
+
+        // A duplicate of a stale submission is rejected only once the original has been queued to
+        // be proposed again.
+        let mut pool = Mempool::new(MempoolConfig {
+            resubmit_after: Duration::ZERO,
+            ..Default::default()
+        });
+        pool.admit(h1, t1.clone(), 0, vec![], None).unwrap();
+        pool.mark_submitted(&h1, Instant::now());
+        assert!(matches!(
+            pool.admit(h1, t1, 0, vec![], None),
+            Err(MempoolError::Duplicate)
+        ));
+        assert_eq!(pool.status(&h1), MempoolStatus::Pending { position: 0 });
+        assert!(pool.by_submitted.is_empty());
+    }
+}
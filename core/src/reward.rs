@@ -8,7 +8,8 @@ use crate::stake_table::{
     StakingPrivKey,
 };
 use crate::state::{
-    CommitableHash, CommitableHashTag, ConsensusTime, ValidationError, ValidatorState, VrfSeed,
+    CommitableHash, CommitableHashTag, ConsensusTime, EspressoTransaction, ValidationError,
+    ValidatorState, VrfSeed,
 };
 use crate::tree_hash::KVTreeHash;
 pub use crate::util::canonical;
@@ -566,6 +567,25 @@ pub type CollectedRewardsSet = KVMerkleTree<CollectedRewardsHash>;
 pub type CollectedRewardsDigest = <CollectedRewardsHash as KVTreeHash>::Digest;
 pub type CollectedRewardsProof = KVMerkleProof<CollectedRewardsHash>;
 
+/// Insert the rewards collected by `txns` into `set`.
+///
+/// Applying this to each committed block in turn reproduces the full set of collected rewards,
+/// whose root hash is the one tracked by the ledger's [CollectedRewardsHistory].
+pub fn insert_collected_rewards<'a>(
+    set: &mut CollectedRewardsSet,
+    txns: impl IntoIterator<Item = &'a EspressoTransaction>,
+) {
+    for txn in txns {
+        if let EspressoTransaction::Reward(note) = txn {
+            let reward = CollectedRewards {
+                staking_key: note.staking_key(),
+                time: note.time(),
+            };
+            set.insert(reward, ());
+        }
+    }
+}
+
 /// CollectedRewards proofs, organized by the root hash for which they are valid.
 pub type CollectedRewardsProofs = Vec<(
     CollectedRewards,
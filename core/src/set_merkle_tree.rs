@@ -289,6 +289,48 @@ impl SetMerkleProof {
     }
 }
 
+/// A compact proof of membership or non-membership for several elements at once.
+///
+/// The proof is a sparse [SetMerkleTree] containing only the paths to the proven elements. Paths
+/// which share a prefix share the nodes along it, so proving `n` elements in a tree of depth `d`
+/// takes far fewer than `n * d` hashes when the elements are clustered.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetMerkleMultiProof {
+    tree: SetMerkleTree,
+}
+
+impl SetMerkleMultiProof {
+    /// Check that the proof is relative to `root`.
+    ///
+    /// Returns the root hash computed from the proof if it does not match `root`.
+    pub fn check(&self, root: &set_hash::Hash) -> Result<(), set_hash::Hash> {
+        let computed = self.tree.recompute_hash();
+        if &computed == root {
+            Ok(())
+        } else {
+            Err(computed)
+        }
+    }
+
+    /// Whether `elem` is in the set.
+    ///
+    /// Returns `None` if `elem` is not covered by this proof. This is only meaningful once the
+    /// proof has been [checked](Self::check).
+    pub fn contains(&self, elem: Nullifier) -> Option<bool> {
+        self.tree.contains(elem).map(|(in_set, _)| in_set)
+    }
+
+    /// Extract an individual proof for `elem`, if it is covered by this proof.
+    pub fn proof(&self, elem: Nullifier) -> Option<SetMerkleProof> {
+        self.tree.contains(elem).map(|(_, proof)| proof)
+    }
+
+    /// A sparse representation of the set which retains only the proven paths.
+    pub fn into_tree(self) -> SetMerkleTree {
+        self.tree
+    }
+}
+
 impl SetMerkleTree {
     pub fn sparse(root: set_hash::Hash) -> Self {
         Self::ForgottenSubtree { value: root }
@@ -618,6 +660,147 @@ impl SetMerkleTree {
         Ok(())
     }
 
+    /// Insert every element of `elems` into the set.
+    ///
+    /// This is equivalent to calling [insert](Self::insert) once for each element, but each node
+    /// along the paths to the new elements is rehashed only once, rather than once for every new
+    /// element below it. Returns `None` if any of the elements fall in a forgotten subtree, in
+    /// which case the other elements are still inserted.
+    pub fn insert_batch(&mut self, elems: impl IntoIterator<Item = Nullifier>) -> Option<()> {
+        let elems = elems
+            .into_iter()
+            .map(|elem| (elem, set_hash::elem_bits(elem)))
+            .collect::<Vec<_>>();
+        let height = match elems.first() {
+            Some((_, bits)) => bits.len(),
+            None => return Some(()),
+        };
+        let mut complete = true;
+        *self = mem::take(self).insert_batch_at(height, elems, &mut complete);
+        if complete {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn insert_batch_at(
+        self,
+        height: usize,
+        elems: Vec<(Nullifier, BitVec<u8, bitvec::order::Lsb0>)>,
+        complete: &mut bool,
+    ) -> Self {
+        use SetMerkleTree::*;
+        if elems.is_empty() {
+            return self;
+        }
+        match self {
+            ForgottenSubtree { value } => {
+                *complete = false;
+                ForgottenSubtree { value }
+            }
+            Branch { l, r, .. } => {
+                let (r_elems, l_elems) = elems
+                    .into_iter()
+                    .partition::<Vec<_>, _>(|(_, bits)| bits[height - 1]);
+                Self::new_branch(
+                    Box::new(l.insert_batch_at(height - 1, l_elems, complete)),
+                    Box::new(r.insert_batch_at(height - 1, r_elems, complete)),
+                )
+            }
+            Leaf {
+                value,
+                height: leaf_height,
+                elem,
+            } => {
+                debug_assert_eq!(leaf_height, height);
+                if elems.iter().all(|(new_elem, _)| *new_elem == elem) {
+                    Leaf {
+                        value,
+                        height,
+                        elem,
+                    }
+                } else {
+                    // Push the existing leaf down along with the new elements, exactly as if we
+                    // were inserting all of them into an empty subtree.
+                    let mut elems = elems;
+                    elems.push((elem, set_hash::elem_bits(elem)));
+                    EmptySubtree.insert_batch_at(height, elems, complete)
+                }
+            }
+            EmptySubtree => {
+                let first = elems[0].0;
+                if elems.iter().all(|(elem, _)| *elem == first) {
+                    Self::new_leaf(height, first)
+                } else {
+                    assert!(
+                        height > 0,
+                        "This tree has more levels than my hash has bits!"
+                    );
+                    let (r_elems, l_elems) = elems
+                        .into_iter()
+                        .partition::<Vec<_>, _>(|(_, bits)| bits[height - 1]);
+                    Self::new_branch(
+                        Box::new(EmptySubtree.insert_batch_at(height - 1, l_elems, complete)),
+                        Box::new(EmptySubtree.insert_batch_at(height - 1, r_elems, complete)),
+                    )
+                }
+            }
+        }
+    }
+
+    /// A compact proof of membership or non-membership for each of `elems`.
+    ///
+    /// Returns `None` if any of the elements fall in a forgotten subtree.
+    pub fn multi_proof(&self, elems: &[Nullifier]) -> Option<SetMerkleMultiProof> {
+        let bits = elems
+            .iter()
+            .map(|elem| set_hash::elem_bits(*elem))
+            .collect::<Vec<_>>();
+        let height = bits.first().map(|bits| bits.len()).unwrap_or_default();
+        Some(SetMerkleMultiProof {
+            tree: self.prune(height, bits)?,
+        })
+    }
+
+    /// A copy of this subtree which retains only the paths to the elements with bits `elems`.
+    fn prune(&self, height: usize, elems: Vec<BitVec<u8, bitvec::order::Lsb0>>) -> Option<Self> {
+        use SetMerkleTree::*;
+        if elems.is_empty() {
+            return Some(ForgottenSubtree { value: self.hash() });
+        }
+        match self {
+            ForgottenSubtree { .. } => None,
+            EmptySubtree | Leaf { .. } => Some(self.clone()),
+            Branch { value, l, r } => {
+                let (r_elems, l_elems) = elems
+                    .into_iter()
+                    .partition::<Vec<_>, _>(|bits| bits[height - 1]);
+                Some(Branch {
+                    value: *value,
+                    l: Box::new(l.prune(height - 1, l_elems)?),
+                    r: Box::new(r.prune(height - 1, r_elems)?),
+                })
+            }
+        }
+    }
+
+    /// The root hash of this tree, recomputed from the leaves rather than read from the cached
+    /// value in each branch.
+    fn recompute_hash(&self) -> set_hash::Hash {
+        use SetMerkleTree::*;
+        match self {
+            EmptySubtree => *set_hash::EMPTY_HASH,
+            ForgottenSubtree { value } => *value,
+            Leaf { height, elem, .. } => SetMerkleTerminalNode::Leaf {
+                height: *height,
+                elem: *elem,
+            }
+            .value(),
+            Branch { l, r, .. } => set_hash::branch_hash(l.recompute_hash(), r.recompute_hash()),
+        }
+    }
+
     pub fn multi_insert(
         &mut self,
         inserts: impl IntoIterator<Item = (Nullifier, SetMerkleProof)>,
@@ -627,9 +810,7 @@ impl SetMerkleTree {
             self.remember(n, proof)?;
             nulls.push(n);
         }
-        for n in nulls.iter() {
-            self.insert(*n).unwrap();
-        }
+        self.insert_batch(nulls.iter().copied()).unwrap();
         Ok(nulls
             .into_iter()
             .map(|n| self.contains(n).unwrap().1)
@@ -755,6 +936,64 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_insert_batch_and_multi_proof() {
+        let mut prng = ChaChaRng::from_seed([0x5bu8; 32]);
+        let elems = (0..100)
+            .map(|_| Nullifier::random_for_test(&mut prng))
+            .collect::<Vec<_>>();
+        let (old, new) = elems.split_at(50);
+
+        // Batch insertion produces the same tree as inserting one element at a time, including
+        // when the batch contains duplicates and elements which are already in the set.
+        let mut sequential = SetMerkleTree::default();
+        for elem in old.iter().chain(new) {
+            sequential.insert(*elem).unwrap();
+        }
+        let mut batched = SetMerkleTree::default();
+        batched.insert_batch(old.iter().copied()).unwrap();
+        batched
+            .insert_batch(new.iter().chain(new).chain(&old[..5]).copied())
+            .unwrap();
+        assert_eq!(batched.hash(), sequential.hash());
+        for elem in &elems {
+            assert_eq!(batched.contains(*elem), sequential.contains(*elem));
+        }
+
+        // A multi-proof covers members and non-members alike.
+        let before = {
+            let mut t = SetMerkleTree::default();
+            t.insert_batch(old.iter().copied()).unwrap();
+            t
+        };
+        let proof = before.multi_proof(&elems[40..60]).unwrap();
+        proof.check(&before.hash()).unwrap();
+        proof.check(&sequential.hash()).unwrap_err();
+        for (i, elem) in elems.iter().enumerate() {
+            if (40..60).contains(&i) {
+                assert_eq!(proof.contains(*elem), Some(i < 50));
+                assert!(proof
+                    .proof(*elem)
+                    .unwrap()
+                    .check(*elem, &before.hash())
+                    .is_ok());
+            }
+        }
+
+        // The sparse tree from a multi-proof supports the same inserts as the full tree.
+        let mut sparse = proof.into_tree();
+        sparse.insert_batch(new[..10].iter().copied()).unwrap();
+        let mut full = before.clone();
+        full.insert_batch(new[..10].iter().copied()).unwrap();
+        assert_eq!(sparse.hash(), full.hash());
+        assert!(sparse.insert_batch(new[10..].iter().copied()).is_none());
+
+        // Elements in forgotten subtrees cannot be proven.
+        assert!(SetMerkleTree::sparse(before.hash())
+            .multi_proof(&elems[..1])
+            .is_none());
+    }
+
     #[test]
     fn quickcheck_merkle_tree_set_regressions() {
         test_merkle_tree_set(vec![20, 0], vec![Ok(20)]);
@@ -0,0 +1,350 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Deterministic record and replay of ledger activity.
+//!
+//! A [SimRecorder] appends every submitted transaction and every block commit or rejection to a
+//! journal file. A [SimLedger] loads the journal and replays it against a fresh [ValidatorState],
+//! checking at each step that the replayed state matches the recorded one. This makes it possible
+//! to reproduce a consensus bug, or a divergence between a wallet and the validator, found by a
+//! randomized test, and to [bisect](SimLedger::bisect) the journal down to the first event that
+//! replays differently.
+//!
+//! The journal is a sequence of `bincode`-encoded [SimEvent]s, each prefixed with its length as a
+//! little-endian `u64`.
+
+use crate::state::{
+    state_comm::LedgerStateCommitment, ConsensusTime, ElaboratedBlock, ElaboratedTransaction,
+    ValidatorState,
+};
+use serde::{Deserialize, Serialize};
+use snafu::Snafu;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SimEvent {
+    /// The state replay starts from. If a journal does not start with an `Init` event, it is
+    /// replayed against [ValidatorState::default].
+    Init { state: Box<ValidatorState> },
+    /// A transaction was submitted.
+    Submit { txn: ElaboratedTransaction },
+    /// A block was applied at `time`, resulting in the state `state`.
+    Commit {
+        block: ElaboratedBlock,
+        time: ConsensusTime,
+        state: LedgerStateCommitment,
+    },
+    /// A block failed to validate at `time`.
+    Reject {
+        block: ElaboratedBlock,
+        time: ConsensusTime,
+        error: String,
+    },
+}
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum SimError {
+    #[snafu(display("journal I/O error: {}", msg))]
+    Io { msg: String },
+    #[snafu(display("malformed journal entry {}: {}", index, msg))]
+    Malformed { index: usize, msg: String },
+    #[snafu(display("event {}: recorded commit failed to validate: {}", index, error))]
+    UnexpectedRejection { index: usize, error: String },
+    #[snafu(display("event {}: recorded rejection validated successfully", index))]
+    UnexpectedAcceptance { index: usize },
+    #[snafu(display(
+        "event {}: replayed state {} does not match recorded state {}",
+        index,
+        actual,
+        expected
+    ))]
+    StateMismatch {
+        index: usize,
+        expected: LedgerStateCommitment,
+        actual: LedgerStateCommitment,
+    },
+}
+
+impl SimError {
+    /// The index of the event at which replay diverged, if this is a replay error.
+    pub fn index(&self) -> Option<usize> {
+        match self {
+            Self::UnexpectedRejection { index, .. }
+            | Self::UnexpectedAcceptance { index }
+            | Self::StateMismatch { index, .. } => Some(*index),
+            Self::Io { .. } | Self::Malformed { .. } => None,
+        }
+    }
+}
+
+fn io_error(err: std::io::Error) -> SimError {
+    SimError::Io {
+        msg: err.to_string(),
+    }
+}
+
+/// Appends [SimEvent]s to a journal file.
+#[derive(Debug)]
+pub struct SimRecorder {
+    writer: BufWriter<File>,
+}
+
+impl SimRecorder {
+    /// Create a new journal at `path`, replacing any existing file.
+    pub fn create(path: &Path) -> Result<Self, SimError> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(path).map_err(io_error)?),
+        })
+    }
+
+    pub fn record(&mut self, event: &SimEvent) -> Result<(), SimError> {
+        let buf = bincode::serialize(event).map_err(|err| SimError::Io {
+            msg: err.to_string(),
+        })?;
+        self.writer
+            .write_all(&(buf.len() as u64).to_le_bytes())
+            .map_err(io_error)?;
+        self.writer.write_all(&buf).map_err(io_error)?;
+        // Flush after every event, so that the journal is usable even if the process under test
+        // panics.
+        self.writer.flush().map_err(io_error)
+    }
+
+    pub fn init(&mut self, state: &ValidatorState) -> Result<(), SimError> {
+        self.record(&SimEvent::Init {
+            state: Box::new(state.clone()),
+        })
+    }
+
+    pub fn submit(&mut self, txn: &ElaboratedTransaction) -> Result<(), SimError> {
+        self.record(&SimEvent::Submit { txn: txn.clone() })
+    }
+
+    pub fn commit(
+        &mut self,
+        block: &ElaboratedBlock,
+        time: ConsensusTime,
+        state: &ValidatorState,
+    ) -> Result<(), SimError> {
+        self.record(&SimEvent::Commit {
+            block: block.clone(),
+            time,
+            state: state.commit(),
+        })
+    }
+
+    pub fn reject(
+        &mut self,
+        block: &ElaboratedBlock,
+        time: ConsensusTime,
+        error: impl ToString,
+    ) -> Result<(), SimError> {
+        self.record(&SimEvent::Reject {
+            block: block.clone(),
+            time,
+            error: error.to_string(),
+        })
+    }
+}
+
+/// A recorded journal which can be replayed deterministically.
+#[derive(Clone, Debug, Default)]
+pub struct SimLedger {
+    events: Vec<SimEvent>,
+}
+
+impl From<Vec<SimEvent>> for SimLedger {
+    fn from(events: Vec<SimEvent>) -> Self {
+        Self { events }
+    }
+}
+
+impl SimLedger {
+    pub fn load(path: &Path) -> Result<Self, SimError> {
+        let mut reader = BufReader::new(File::open(path).map_err(io_error)?);
+        let mut events = vec![];
+        loop {
+            let mut len = [0u8; 8];
+            match reader.read_exact(&mut len) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(io_error(err)),
+            }
+            let mut buf = vec![0u8; u64::from_le_bytes(len) as usize];
+            let index = events.len();
+            reader
+                .read_exact(&mut buf)
+                .map_err(|err| SimError::Malformed {
+                    index,
+                    msg: err.to_string(),
+                })?;
+            events.push(
+                bincode::deserialize(&buf).map_err(|err| SimError::Malformed {
+                    index,
+                    msg: err.to_string(),
+                })?,
+            );
+        }
+        Ok(Self { events })
+    }
+
+    pub fn events(&self) -> &[SimEvent] {
+        &self.events
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Replay the whole journal, returning the final state.
+    pub fn replay(&self) -> Result<ValidatorState, SimError> {
+        self.replay_prefix(self.events.len())
+    }
+
+    /// Replay the first `n` events of the journal, returning the resulting state.
+    pub fn replay_prefix(&self, n: usize) -> Result<ValidatorState, SimError> {
+        let mut state = ValidatorState::default();
+        for (index, event) in self.events.iter().take(n).enumerate() {
+            match event {
+                SimEvent::Init { state: init } => {
+                    state = (**init).clone();
+                }
+                SimEvent::Submit { .. } => {
+                    // Submissions don't affect the state by themselves; they are recorded so that
+                    // a failing scenario can be resubmitted to a live ledger.
+                }
+                SimEvent::Commit {
+                    block,
+                    time,
+                    state: expected,
+                } => {
+                    state
+                        .validate_and_apply(
+                            time,
+                            block.parent_state,
+                            block.block.clone(),
+                            block.proofs.clone(),
+                        )
+                        .map_err(|err| SimError::UnexpectedRejection {
+                            index,
+                            error: err.to_string(),
+                        })?;
+                    let actual = state.commit();
+                    if actual != *expected {
+                        return Err(SimError::StateMismatch {
+                            index,
+                            expected: *expected,
+                            actual,
+                        });
+                    }
+                }
+                SimEvent::Reject { block, time, .. } => {
+                    if state
+                        .clone()
+                        .validate_and_apply(
+                            time,
+                            block.parent_state,
+                            block.block.clone(),
+                            block.proofs.clone(),
+                        )
+                        .is_ok()
+                    {
+                        return Err(SimError::UnexpectedAcceptance { index });
+                    }
+                }
+            }
+        }
+        Ok(state)
+    }
+
+    /// Find the index of the first event which does not replay as recorded.
+    ///
+    /// Returns [None] if the whole journal replays successfully. Since replay is deterministic
+    /// and stops at the first divergence, every prefix longer than the first divergent event
+    /// fails, so this only needs a logarithmic number of replays.
+    pub fn bisect(&self) -> Option<usize> {
+        if self.replay().is_ok() {
+            return None;
+        }
+        // Invariant: the prefix of length `good` replays, the prefix of length `bad` does not.
+        let (mut good, mut bad) = (0, self.events.len());
+        while bad - good > 1 {
+            let mid = good + (bad - good) / 2;
+            if self.replay_prefix(mid).is_ok() {
+                good = mid;
+            } else {
+                bad = mid;
+            }
+        }
+        Some(bad - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MultiXfrRecordSpec, MultiXfrTestState, TxnPrintInfo};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_record_and_replay() {
+        let dir = TempDir::new("sim_ledger").unwrap();
+        let path = dir.path().join("journal");
+        let mut state = MultiXfrTestState::initialize(
+            [0x5a; 32],
+            2,
+            1,
+            (
+                MultiXfrRecordSpec {
+                    asset_def_ix: 0,
+                    owner_key_ix: 0,
+                    asset_amount: 10,
+                },
+                vec![],
+            ),
+        )
+        .unwrap();
+        state.record_to(&path).unwrap();
+
+        // Apply a few empty blocks, then a block for the wrong parent, which must be rejected.
+        for _ in 0..3 {
+            let blk = state.validator.next_block();
+            let now = state.next_view();
+            state
+                .validate_and_apply(blk, &now, 0.0, TxnPrintInfo::new_no_time(0, 0))
+                .unwrap();
+        }
+        let blk = ElaboratedBlock::new(ValidatorState::default().commit());
+        let now = state.next_view();
+        state
+            .validate_and_apply(blk, &now, 0.0, TxnPrintInfo::new_no_time(0, 0))
+            .unwrap_err();
+        drop(state);
+
+        let ledger = SimLedger::load(&path).unwrap();
+        assert_eq!(ledger.len(), 5);
+        ledger.replay().unwrap();
+        assert_eq!(ledger.bisect(), None);
+
+        // Tamper with the recorded outcome of the second block and check that bisection finds it.
+        let mut events = ledger.events().to_vec();
+        if let SimEvent::Commit { state, .. } = &mut events[2] {
+            *state = ValidatorState::default().commit();
+        } else {
+            panic!("expected a commit event");
+        }
+        let tampered = SimLedger::from(events);
+        assert!(matches!(
+            tampered.replay(),
+            Err(SimError::StateMismatch { index: 2, .. })
+        ));
+        assert_eq!(tampered.bisect(), Some(2));
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Signed snapshots of the ledger state.
+//!
+//! A full node which has validated the chain up to some block can vouch for the resulting
+//! [ValidatorState] by signing its commitment with the node's staking key. A client which trusts
+//! that node can start from the snapshot, which includes the record Merkle frontier, the nullifier
+//! root, the verifier keys and the block height, and follow the events after
+//! [continuation_event_index](SignedSnapshot::continuation_event_index) instead of replaying the
+//! chain from genesis.
+
+use crate::stake_table::{StakingKey, StakingKeySignature, StakingPrivKey};
+use crate::state::{state_comm::LedgerStateCommitment, ValidatorState};
+use crate::util::canonical;
+use ark_serialize::*;
+use hotshot_types::traits::signature_key::SignatureKey;
+use jf_cap::MerkleTree;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, Snafu};
+
+/// Reasons a [SignedSnapshot] may fail verification.
+#[derive(Clone, Debug, Snafu, Serialize, Deserialize)]
+pub enum SnapshotError {
+    #[snafu(display("snapshot is signed by untrusted key {}", signer))]
+    UntrustedSigner { signer: StakingKey },
+    #[snafu(display("snapshot state does not match its commitment"))]
+    CommitmentMismatch,
+    #[snafu(display("invalid snapshot signature"))]
+    InvalidSignature,
+    #[snafu(display("snapshot record Merkle frontier does not match the record Merkle root"))]
+    InconsistentFrontier,
+}
+
+// The message signed by the node serving a snapshot.
+#[derive(CanonicalSerialize, CanonicalDeserialize)]
+struct SnapshotHeader {
+    commitment: LedgerStateCommitment,
+    continuation_event_index: u64,
+}
+
+impl SnapshotHeader {
+    fn bytes(&self) -> Vec<u8> {
+        canonical::serialize(self).unwrap()
+    }
+}
+
+/// A [ValidatorState] signed by the full node which computed it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignedSnapshot {
+    pub state: ValidatorState,
+    /// The commitment to `state`.
+    pub commitment: LedgerStateCommitment,
+    /// Event index to subscribe to to follow chain events built on top of this state.
+    pub continuation_event_index: u64,
+    pub signer: StakingKey,
+    pub signature: StakingKeySignature,
+}
+
+impl SignedSnapshot {
+    pub fn sign(
+        state: ValidatorState,
+        continuation_event_index: u64,
+        priv_key: &StakingPrivKey,
+    ) -> Self {
+        let commitment = state.commit();
+        let header = SnapshotHeader {
+            commitment,
+            continuation_event_index,
+        };
+        Self {
+            state,
+            commitment,
+            continuation_event_index,
+            signer: StakingKey::from(priv_key),
+            signature: StakingKey::sign(priv_key, &header.bytes()).into(),
+        }
+    }
+
+    /// Check that this snapshot was signed by one of `trusted` and is internally consistent.
+    ///
+    /// A snapshot which passes verification can be used in place of the state obtained by
+    /// replaying the chain up to block `state.block_height - 1`, provided the signer is honest. To
+    /// guard against a dishonest signer, compare [commitment](Self::commitment) with a state
+    /// commitment obtained from other sources.
+    pub fn verify(&self, trusted: &[StakingKey]) -> Result<(), SnapshotError> {
+        ensure!(
+            trusted.contains(&self.signer),
+            UntrustedSignerSnafu {
+                signer: self.signer.clone()
+            }
+        );
+        ensure!(
+            self.state.commit() == self.commitment,
+            CommitmentMismatchSnafu
+        );
+        let header = SnapshotHeader {
+            commitment: self.commitment,
+            continuation_event_index: self.continuation_event_index,
+        };
+        ensure!(
+            self.signer
+                .validate(self.signature.as_ref(), &header.bytes()),
+            InvalidSignatureSnafu
+        );
+        ensure!(
+            MerkleTree::restore_from_frontier(
+                self.state.record_merkle_commitment,
+                &self.state.record_merkle_frontier,
+            )
+            .is_some(),
+            InconsistentFrontierSnafu
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+
+    #[test]
+    fn test_snapshot_verification() {
+        let mut rng = ChaChaRng::from_seed([3; 32]);
+        let (signer, priv_key) = StakingKey::generate(&mut rng);
+        let (other, _) = StakingKey::generate(&mut rng);
+
+        let snapshot = SignedSnapshot::sign(ValidatorState::default(), 5, &priv_key);
+        snapshot.verify(&[signer.clone()]).unwrap();
+        assert!(matches!(
+            snapshot.verify(&[other]),
+            Err(SnapshotError::UntrustedSigner { .. })
+        ));
+
+        // Tampering with the state or the event index invalidates the snapshot.
+        let mut tampered = snapshot.clone();
+        tampered.state.block_height += 1;
+        assert!(matches!(
+            tampered.verify(&[signer.clone()]),
+            Err(SnapshotError::CommitmentMismatch)
+        ));
+        tampered.commitment = tampered.state.commit();
+        assert!(matches!(
+            tampered.verify(&[signer.clone()]),
+            Err(SnapshotError::InvalidSignature)
+        ));
+        let mut tampered = snapshot;
+        tampered.continuation_event_index += 1;
+        assert!(matches!(
+            tampered.verify(&[signer]),
+            Err(SnapshotError::InvalidSignature)
+        ));
+    }
+}
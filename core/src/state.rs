@@ -29,7 +29,7 @@ use crate::stake_table::{
 };
 
 use crate::state::state_comm::CommittableAmount;
-use crate::universal_params::{MERKLE_HEIGHT, VERIF_CRS};
+use crate::universal_params::{extend_verifier_keys, MERKLE_HEIGHT, VERIF_CRS};
 use arbitrary::{Arbitrary, Unstructured};
 use ark_serialize::*;
 use canonical::deserialize_canonical_bytes;
@@ -54,7 +54,7 @@ use std::hash::{Hash, Hasher};
 use std::io::Read;
 use std::iter::once;
 use std::num::NonZeroU64;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use typenum::U32;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -580,6 +580,58 @@ impl Clone for ValidationError {
     }
 }
 
+impl ValidationError {
+    /// Whether a transaction rejected with this error may succeed if it is resubmitted.
+    ///
+    /// Recoverable errors are caused by the transaction being built against a state which has since
+    /// changed (for example, stale nullifier proofs) or by the block it was included in, rather
+    /// than by the transaction itself. A transaction which fails with any other error will fail
+    /// the same way every time it is submitted, and should be rebuilt or abandoned.
+    pub fn is_recoverable(&self) -> bool {
+        use ValidationError::*;
+        matches!(
+            self,
+            BadNullifierProof {}
+                | MissingNullifierProof {}
+                | ConflictingNullifiers {}
+                | BadMerkleRoot {}
+                | InconsistentHelperProofs
+                | IncorrectParent
+                | InvalidTime
+        )
+    }
+
+    /// A short name for the kind of error, suitable for use as a metrics label.
+    pub fn kind(&self) -> &'static str {
+        use ValidationError::*;
+        match self {
+            NullifierAlreadyExists { .. } => "nullifier_already_exists",
+            BadNullifierProof {} => "bad_nullifier_proof",
+            MissingNullifierProof {} => "missing_nullifier_proof",
+            ConflictingNullifiers {} => "conflicting_nullifiers",
+            Failed {} => "failed",
+            BadMerkleLength {} => "bad_merkle_length",
+            BadMerkleLeaf {} => "bad_merkle_leaf",
+            BadMerkleRoot {} => "bad_merkle_root",
+            BadMerklePath {} => "bad_merkle_path",
+            CryptoError { .. } => "crypto_error",
+            UnsupportedTransferSize { .. } => "unsupported_transfer_size",
+            UnsupportedFreezeSize { .. } => "unsupported_freeze_size",
+            InconsistentHelperProofs => "inconsistent_helper_proofs",
+            UnexpectedGenesis => "unexpected_genesis",
+            IncorrectParent => "incorrect_parent",
+            InvalidTime => "invalid_time",
+            BadCollectRewardNote => "bad_collect_reward_note",
+            RewardAlreadyCollected { .. } => "reward_already_collected",
+            BadCollectedRewardProof {} => "bad_collected_reward_proof",
+            RewardAmountTooLarge => "reward_amount_too_large",
+            BadStakeTableProof {} => "bad_stake_table_proof",
+            BadStakeTableCommitmentsProof {} => "bad_stake_table_commitments_proof",
+            BadFeeCalculation {} => "bad_fee_calculation",
+        }
+    }
+}
+
 impl Committable for Block {
     fn commit(&self) -> commit::Commitment<Self> {
         commit::RawCommitmentBuilder::new("Block Comm")
@@ -906,20 +958,15 @@ impl NullifierHistory {
         //    nullifiers, because the new nullifiers are not actually in the set, which means they
         //    don't necessarily correspond to unique leaves, and therefore forgetting other
         //    nullifiers may inadvertently cause us to forget part of a path corresponding to a new
-        //    nullifier. Instead, we will create a new sparse representation of the current set by
-        //    starting with the current commitment and remembering paths only for the nullifiers we
-        //    care about. We can get the paths from `accum`.
+        //    nullifier. Instead, we take a multi-proof of the new nullifiers from `accum`, which is
+        //    exactly a sparse representation of the current set retaining only the paths we care
+        //    about.
         assert_eq!(accum.hash(), self.current);
-        let mut current = SetMerkleTree::sparse(self.current);
-        for n in &nulls {
-            current.remember(*n, accum.contains(*n).unwrap().1).unwrap();
-        }
+        let current = accum.multi_proof(&nulls).unwrap().into_tree();
 
         // Now that we have created a sparse snapshot of the current nullifiers set, we can insert
         // the new nullifiers into `accum` to derive the new commitment.
-        for n in &nulls {
-            accum.insert(*n).unwrap();
-        }
+        accum.insert_batch(nulls.iter().copied()).unwrap();
 
         Ok((current, accum.hash(), nulls))
     }
@@ -1159,6 +1206,9 @@ pub struct ChainVariables {
     pub chain_id: u16,
 
     /// Plonk verifier keys.
+    ///
+    /// These are the keys in effect at genesis. Use [ChainVariables::verif_crs_at] to get the keys
+    /// for a particular block, which take any [VerifierKeyUpgrade]s into account.
     pub verif_crs: ArcSer<VerifierKeySet>,
 
     /// VRF seed for checking rewards
@@ -1166,6 +1216,50 @@ pub struct ChainVariables {
 
     /// Committee size
     pub committee_size: u64,
+
+    /// New arities accepted from given block heights, in order of height.
+    ///
+    /// The schedule is part of the ledger state, so every node and client following the chain
+    /// agrees on the keys which verify each block. Use [ChainVariables::schedule_verif_crs] to add
+    /// to it.
+    pub verif_crs_upgrades: Vec<VerifierKeyUpgrade>,
+}
+
+/// New transaction arities which a chain accepts from a given block height.
+///
+/// This allows the chain to support new arities (for example, when a new verifying key is approved)
+/// without a new genesis. Only the arities are recorded in the ledger state; the keys for them are
+/// derived from the universal parameters.
+#[derive(
+    Clone, Debug, PartialEq, Eq, Serialize, Deserialize, CanonicalSerialize, CanonicalDeserialize,
+)]
+pub struct VerifierKeyUpgrade {
+    /// The first block which accepts the new arities.
+    pub height: u64,
+    /// New transfer arities, as `(inputs, outputs)`.
+    pub transfer_sizes: Vec<(usize, usize)>,
+    /// New freeze arities, as numbers of inputs.
+    pub freeze_sizes: Vec<usize>,
+}
+
+/// An error scheduling a [VerifierKeyUpgrade].
+#[derive(Debug, Snafu)]
+pub enum VerifierKeyUpgradeError {
+    #[snafu(display("an upgrade is already scheduled at block {}", height))]
+    DuplicateHeight { height: u64 },
+    #[snafu(display("failed to generate verifier keys: {}", source))]
+    GenerateKeys { source: TxnApiError },
+}
+
+// The verifier keys in effect from each upgrade of a chain on, keyed by the commitment to the chain
+// variables. Generating keys is expensive, and states are cloned and deserialized for every block,
+// so the keys are generated once per process instead of being stored in the state. Since the keys
+// are derived from the chain variables alone, this is purely a cache.
+type UpgradedVerifierKeys = Arc<Vec<Arc<VerifierKeySet>>>;
+
+lazy_static::lazy_static! {
+    static ref UPGRADED_VERIF_CRS: RwLock<HashMap<Commitment<ChainVariables>, UpgradedVerifierKeys>> =
+        Default::default();
 }
 
 #[tagged_blob("VRFSEED")]
@@ -1212,15 +1306,34 @@ impl Default for ChainVariables {
 
 impl Committable for ChainVariables {
     fn commit(&self) -> Commitment<Self> {
-        commit::RawCommitmentBuilder::new("ChainVariables")
+        let mut builder = commit::RawCommitmentBuilder::new("ChainVariables")
             .u64_field("protocol_version_major", self.protocol_version.0 as u64)
             .u64_field("protocol_version_minor", self.protocol_version.1 as u64)
             .u64_field("protocol_version_patch", self.protocol_version.2 as u64)
             .u64_field("chain_id", self.chain_id as u64)
             .var_size_bytes(&canonical::serialize(&self.verif_crs).unwrap())
             .fixed_size_bytes(self.vrf_seed.as_ref())
-            .u64_field("committee size", self.committee_size)
-            .finalize()
+            .u64_field("committee size", self.committee_size);
+        // Only include upgrades in the commitment if there are any, so that the commitments of
+        // chains without upgrades are unchanged.
+        if !self.verif_crs_upgrades.is_empty() {
+            builder = builder.u64_field("verif_crs_upgrades", self.verif_crs_upgrades.len() as u64);
+            for upgrade in &self.verif_crs_upgrades {
+                builder = builder
+                    .u64_field("height", upgrade.height)
+                    .u64_field("transfer_sizes", upgrade.transfer_sizes.len() as u64);
+                for (inputs, outputs) in &upgrade.transfer_sizes {
+                    builder = builder
+                        .u64_field("inputs", *inputs as u64)
+                        .u64_field("outputs", *outputs as u64);
+                }
+                builder = builder.u64_field("freeze_sizes", upgrade.freeze_sizes.len() as u64);
+                for inputs in &upgrade.freeze_sizes {
+                    builder = builder.u64_field("inputs", *inputs as u64);
+                }
+            }
+        }
+        builder.finalize()
     }
 }
 
@@ -1232,6 +1345,7 @@ impl<'a> Arbitrary<'a> for ChainVariables {
             verif_crs: VERIF_CRS.clone().into(),
             vrf_seed: u.arbitrary()?,
             committee_size: u.arbitrary()?,
+            verif_crs_upgrades: vec![],
         })
     }
 }
@@ -1266,7 +1380,76 @@ impl ChainVariables {
                 .finalize()
                 .into(),
             committee_size,
+            verif_crs_upgrades: vec![],
+        }
+    }
+
+    /// The verifier keys used to validate the block at `height`.
+    ///
+    /// Keys for upgraded arities are generated the first time they are needed.
+    pub fn verif_crs_at(&self, height: u64) -> Result<Arc<VerifierKeySet>, TxnApiError> {
+        match self
+            .verif_crs_upgrades
+            .iter()
+            .rposition(|upgrade| upgrade.height <= height)
+        {
+            Some(i) => Ok(self.upgraded_verif_crs()?[i].clone()),
+            None => Ok(self.verif_crs.clone().into()),
+        }
+    }
+
+    /// Accept new arities from `upgrade.height` on.
+    ///
+    /// The schedule is part of the chain variables, so upgrades are scheduled when building the
+    /// genesis block. Fails if an upgrade is already scheduled at the same height, or if keys
+    /// cannot be generated for the new arities, in which case the schedule is unchanged.
+    pub fn schedule_verif_crs(
+        &mut self,
+        upgrade: VerifierKeyUpgrade,
+    ) -> Result<(), VerifierKeyUpgradeError> {
+        let i = match self
+            .verif_crs_upgrades
+            .binary_search_by_key(&upgrade.height, |upgrade| upgrade.height)
+        {
+            Ok(_) => {
+                return Err(VerifierKeyUpgradeError::DuplicateHeight {
+                    height: upgrade.height,
+                })
+            }
+            Err(i) => i,
+        };
+        self.verif_crs_upgrades.insert(i, upgrade);
+        // Generate the keys now, so that an upgrade which can never be used is rejected here
+        // rather than when validating a block.
+        if let Err(source) = self.upgraded_verif_crs() {
+            self.verif_crs_upgrades.remove(i);
+            return Err(VerifierKeyUpgradeError::GenerateKeys { source });
+        }
+        Ok(())
+    }
+
+    // The keys in effect from each upgrade on, in the order of `verif_crs_upgrades`.
+    fn upgraded_verif_crs(&self) -> Result<UpgradedVerifierKeys, TxnApiError> {
+        let comm = self.commit();
+        if let Some(keys) = UPGRADED_VERIF_CRS.read().unwrap().get(&comm) {
+            return Ok(keys.clone());
+        }
+        let mut keys: Arc<VerifierKeySet> = self.verif_crs.clone().into();
+        let mut upgraded = vec![];
+        for upgrade in &self.verif_crs_upgrades {
+            keys = Arc::new(extend_verifier_keys(
+                &keys,
+                &upgrade.transfer_sizes,
+                &upgrade.freeze_sizes,
+            )?);
+            upgraded.push(keys.clone());
         }
+        let upgraded = Arc::new(upgraded);
+        UPGRADED_VERIF_CRS
+            .write()
+            .unwrap()
+            .insert(comm, upgraded.clone());
+        Ok(upgraded)
     }
 }
 
@@ -1337,7 +1520,13 @@ impl Default for ValidatorState {
 
 impl Committable for ValidatorState {
     fn commit(&self) -> Commitment<Self> {
-        let inputs = state_comm::LedgerCommitmentOpening {
+        self.opening().commit().into()
+    }
+}
+
+impl ValidatorState {
+    fn opening(&self) -> state_comm::LedgerCommitmentOpening {
+        state_comm::LedgerCommitmentOpening {
             chain: self.chain.commit(),
             prev_commit_time: self.prev_commit_time,
             block_height: self.block_height,
@@ -1362,8 +1551,7 @@ impl Committable for ValidatorState {
             )
             .commit(),
             collected_rewards: self.collected_rewards.commit(),
-        };
-        inputs.commit().into()
+        }
     }
 }
 
@@ -1417,6 +1605,63 @@ impl ValidatorState {
         Committable::commit(self).into()
     }
 
+    /// The names of the fields in which this state differs from `other`.
+    ///
+    /// This is empty if and only if the two states have the same commitment. Fields are compared by
+    /// their commitments, so this is cheap even for large states.
+    pub fn diff(&self, other: &Self) -> Vec<&'static str> {
+        let (a, b) = (self.opening(), other.opening());
+        [
+            ("chain", a.chain == b.chain),
+            (
+                "prev_commit_time",
+                *a.prev_commit_time == *b.prev_commit_time,
+            ),
+            ("block_height", a.block_height == b.block_height),
+            (
+                "transaction_count",
+                a.transaction_count == b.transaction_count,
+            ),
+            ("prev_state", a.prev_state == b.prev_state),
+            (
+                "record_merkle_commitment",
+                a.record_merkle_commitment == b.record_merkle_commitment,
+            ),
+            (
+                "record_merkle_frontier",
+                a.record_merkle_frontier == b.record_merkle_frontier,
+            ),
+            (
+                "past_record_merkle_roots",
+                a.past_record_merkle_roots == b.past_record_merkle_roots,
+            ),
+            ("past_nullifiers", a.past_nullifiers == b.past_nullifiers),
+            ("prev_block", a.prev_block == b.prev_block),
+            ("stake_table_root", a.stake_table_root == b.stake_table_root),
+            ("total_stake", a.total_stake == b.total_stake),
+            (
+                "historical_stake_tables",
+                a.historical_stake_tables == b.historical_stake_tables,
+            ),
+            (
+                "past_historial_stake_table_merkle_roots",
+                a.past_stc_merkle_roots == b.past_stc_merkle_roots,
+            ),
+            (
+                "historical_stake_tables_commitment",
+                a.historial_stake_tables_commitment == b.historial_stake_tables_commitment,
+            ),
+            (
+                "collected_rewards",
+                a.collected_rewards == b.collected_rewards,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, same)| !same)
+        .map(|(field, _)| field)
+        .collect()
+    }
+
     pub fn nullifiers_root(&self) -> set_hash::Hash {
         self.past_nullifiers.current_root()
     }
@@ -1525,27 +1770,28 @@ impl ValidatorState {
                 nulls.insert(n);
             }
 
+            let verif_crs = self
+                .chain
+                .verif_crs_at(self.block_height)
+                .map_err(|err| CryptoError { err: Ok(err) })?;
             let verif_keys = cap_txns
                 .iter()
                 .map(|txn| match txn {
-                    TransactionNote::Mint(_) => Ok(&self.chain.verif_crs.mint),
+                    TransactionNote::Mint(_) => Ok(&verif_crs.mint),
                     TransactionNote::Transfer(note) => {
                         let num_inputs = note.inputs_nullifiers.len();
                         let num_outputs = note.output_commitments.len();
-                        self.chain
-                            .verif_crs
-                            .xfr
-                            .key_for_size(num_inputs, num_outputs)
-                            .ok_or(UnsupportedTransferSize {
+                        verif_crs.xfr.key_for_size(num_inputs, num_outputs).ok_or(
+                            UnsupportedTransferSize {
                                 num_inputs,
                                 num_outputs,
-                            })
+                            },
+                        )
                     }
                     TransactionNote::Freeze(note) => {
                         let num_inputs = note.input_nullifiers.len();
                         let num_outputs = note.output_commitments.len();
-                        self.chain
-                            .verif_crs
+                        verif_crs
                             .freeze
                             .key_for_size(num_inputs, num_outputs)
                             .ok_or(UnsupportedFreezeSize { num_inputs })
@@ -1629,6 +1875,85 @@ impl ValidatorState {
         Ok((Block(txns), nullifiers_proofs, verified_rewards_proofs))
     }
 
+    /// Validate each transaction in a block on its own.
+    ///
+    /// [validate_block_check](Self::validate_block_check) fails the whole block on the first
+    /// invalid transaction, which does not tell the submitter of each transaction whether it was at
+    /// fault. This function instead returns a result for every transaction in `txns`, in order.
+    /// Each transaction is checked against this state as if it were alone in a block, and then
+    /// against the valid transactions before it in the block: a transaction which spends a
+    /// nullifier or collects a reward already claimed by an earlier valid transaction fails with
+    /// [ValidationError::NullifierAlreadyExists] or [ValidationError::RewardAlreadyCollected].
+    ///
+    /// The transactions which pass form a block which is valid for this state. Verifying proofs
+    /// one transaction at a time is slower than the batch verification in
+    /// [validate_block_check](Self::validate_block_check), so this is meant to explain a rejected
+    /// block rather than to replace block validation.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the block as a whole is invalid regardless of its transactions:
+    /// - [ValidationError::IncorrectParent]
+    /// - [ValidationError::InvalidTime]
+    /// - [ValidationError::InconsistentHelperProofs], if there is not one set of helper proofs per
+    ///   transaction
+    pub fn check_transactions(
+        &self,
+        now: &ConsensusTime,
+        parent_state: LedgerStateCommitment,
+        txns: &Block,
+        txns_helper_proofs: &[EspressoTxnHelperProofs],
+    ) -> Result<Vec<Result<(), ValidationError>>, ValidationError> {
+        if parent_state != self.commit() {
+            return Err(ValidationError::IncorrectParent);
+        }
+        if *now < self.prev_commit_time {
+            return Err(ValidationError::InvalidTime);
+        }
+        if txns.0.len() != txns_helper_proofs.len() {
+            return Err(ValidationError::InconsistentHelperProofs);
+        }
+
+        let mut nullifiers = HashSet::new();
+        let mut rewards = HashSet::new();
+        Ok(txns
+            .0
+            .iter()
+            .zip(txns_helper_proofs)
+            .map(|(txn, proofs)| {
+                // A genesis transaction is only valid alone in the genesis block, so it can't be
+                // checked in isolation.
+                if matches!(txn, EspressoTransaction::Genesis(_)) && txns.0.len() != 1 {
+                    return Err(ValidationError::UnexpectedGenesis);
+                }
+                self.validate_block_check(
+                    now,
+                    parent_state,
+                    Block(vec![txn.clone()]),
+                    vec![proofs.clone()],
+                )?;
+
+                let txn_nullifiers = txn.input_nullifiers();
+                if let Some(nullifier) = txn_nullifiers.iter().find(|n| nullifiers.contains(*n)) {
+                    return Err(ValidationError::NullifierAlreadyExists {
+                        nullifier: *nullifier,
+                    });
+                }
+                if let EspressoTransaction::Reward(reward_txn) = txn {
+                    let reward = CollectedRewards {
+                        staking_key: reward_txn.staking_key(),
+                        time: reward_txn.time(),
+                    };
+                    if !rewards.insert(reward.clone()) {
+                        return Err(ValidationError::RewardAlreadyCollected { reward });
+                    }
+                }
+                nullifiers.extend(txn_nullifiers);
+                Ok(())
+            })
+            .collect())
+    }
+
     /// Performs validation for a block, updating the ValidatorState.
     ///
     /// If successful, returns
@@ -2,6 +2,8 @@
 // Copyright (c) 2022 Espresso Systems (espressosys.com)
 // This file is part of the Espresso library.
 
+use crate::genesis::GenesisNote;
+use crate::sim_ledger::SimRecorder;
 use crate::stake_table::StakeTableCommitment;
 use crate::stake_table::{StakeTableMap, StakeTableSetMT};
 use crate::state::*;
@@ -27,6 +29,7 @@ use rand_chacha::rand_core::SeedableRng;
 use rand_chacha::ChaChaRng;
 use rayon::prelude::*;
 use std::collections::HashSet;
+use std::path::Path;
 use std::time::Instant;
 
 #[derive(Debug)]
@@ -54,6 +57,10 @@ pub struct MultiXfrTestState {
 
     pub outer_timer: Instant,
     pub inner_timer: Instant,
+
+    /// Journal of submitted transactions and applied blocks, for replay with
+    /// [SimLedger](crate::sim_ledger::SimLedger).
+    pub recorder: Option<SimRecorder>,
 }
 
 /// Transaction Information for println! only
@@ -193,6 +200,17 @@ impl MultiXfrTestState {
         *now = Instant::now();
     }
 
+    /// Record all subsequent transactions and blocks to a journal at `path`.
+    ///
+    /// The journal starts from the current state, and can be replayed with
+    /// [SimLedger::load](crate::sim_ledger::SimLedger::load).
+    pub fn record_to(&mut self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let mut recorder = SimRecorder::create(path)?;
+        recorder.init(&self.validator)?;
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
     pub fn next_view(&self) -> ConsensusTime {
         self.validator.prev_commit_time + 1
     }
@@ -320,6 +338,7 @@ impl MultiXfrTestState {
             ),
             outer_timer: timer,
             inner_timer: Instant::now(),
+            recorder: None,
         };
 
         let mut setup_block = ret.validator.next_block();
@@ -1099,6 +1118,9 @@ impl MultiXfrTestState {
             print_info.num_txs,
             ix
         );
+        if let Some(recorder) = &mut self.recorder {
+            recorder.submit(&txn).unwrap();
+        }
 
         let base_ix = self.record_merkle_tree.num_leaves()
             + blk
@@ -1132,12 +1154,17 @@ impl MultiXfrTestState {
     ) -> Result<(), ValidationError> {
         Self::update_timer(&mut self.inner_timer, |_| ());
 
-        self.validator.validate_block_check(
+        if let Err(err) = self.validator.validate_block_check(
             now,
             blk.parent_state,
             blk.block.clone(),
             blk.proofs.clone(),
-        )?;
+        ) {
+            if let Some(recorder) = &mut self.recorder {
+                recorder.reject(&blk, *now, &err).unwrap();
+            }
+            return Err(err);
+        }
         let new_state = self.validator.append(&blk, now).unwrap();
 
         for n in blk
@@ -1159,6 +1186,9 @@ impl MultiXfrTestState {
         }
 
         self.validator = new_state;
+        if let Some(recorder) = &mut self.recorder {
+            recorder.commit(&blk, *now, &self.validator).unwrap();
+        }
 
         let mut checking_time: f32 = 0.0;
         Self::update_timer(&mut self.inner_timer, |t| {
@@ -1230,10 +1260,39 @@ pub fn crypto_rng_from_seed(seed: [u8; 32]) -> ChaChaRng {
     ChaChaRng::from_seed(seed)
 }
 
+/// Build a distinct transaction for each `seed`.
+///
+/// The transaction is a genesis note creating a single native record, so it is the cheapest
+/// transaction to build, but it is not valid after genesis. It suits tests of components which do
+/// not validate transactions, like the mempool and mock services.
+pub fn genesis_txn(seed: u8) -> ElaboratedTransaction {
+    let mut rng = ChaChaRng::from_seed([seed; 32]);
+    let owner = UserKeyPair::generate(&mut rng).pub_key();
+    let ro = RecordOpening::new(
+        &mut rng,
+        1u64.into(),
+        AssetDefinition::native(),
+        owner,
+        FreezeFlag::Unfrozen,
+    );
+    let note = GenesisNote::new(
+        ChainVariables::default(),
+        std::sync::Arc::new(vec![ro]),
+        Default::default(),
+    );
+    ElaboratedTransaction {
+        txn: EspressoTransaction::Genesis(note),
+        proofs: EspressoTxnHelperProofs::Genesis,
+        memos: None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::reward::CollectedRewards;
     use crate::stake_table::{StakeTableMap, StakeTableSetMT};
+    use crate::StakingKey;
     use async_std::sync::Arc;
     use commit::Committable;
     use jf_cap::structs::{NoteType, Nullifier};
@@ -1474,6 +1533,118 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_verifier_key_upgrade() {
+        use crate::universal_params::{extend_verifier_keys, SUPPORTED_TRANSFER_SIZES, VERIF_CRS};
+
+        // Extending with an existing arity is a no-op, and new arities are added.
+        let extended = extend_verifier_keys(&VERIF_CRS, &[(1, 2), (2, 3), (2, 3)], &[2]).unwrap();
+        assert_eq!(
+            extended.xfr.iter().count(),
+            SUPPORTED_TRANSFER_SIZES.len() + 1
+        );
+        assert_eq!(
+            extended.freeze.iter().count(),
+            VERIF_CRS.freeze.iter().count()
+        );
+        assert!(extended.xfr.key_for_size(2, 3).is_some());
+
+        let mut chain = ChainVariables::new(0, VERIF_CRS.clone(), SORTITION_PARAMETER);
+        let original_commit = chain.commit();
+        let upgrade = VerifierKeyUpgrade {
+            height: 5,
+            transfer_sizes: vec![(2, 3)],
+            freeze_sizes: vec![],
+        };
+        chain.schedule_verif_crs(upgrade.clone()).unwrap();
+        assert_eq!(chain.verif_crs_upgrades, vec![upgrade.clone()]);
+        // The schedule is part of the ledger state.
+        assert_ne!(chain.commit(), original_commit);
+        assert_eq!(
+            bincode::deserialize::<ChainVariables>(&bincode::serialize(&chain).unwrap()).unwrap(),
+            chain
+        );
+        // Another chain with the same genesis parameters is not affected.
+        let other = ChainVariables::new(0, VERIF_CRS.clone(), SORTITION_PARAMETER);
+        assert_eq!(other.commit(), original_commit);
+        assert_eq!(
+            other.verif_crs_at(100).unwrap().xfr.iter().count(),
+            SUPPORTED_TRANSFER_SIZES.len()
+        );
+
+        // A second upgrade at the same height is rejected rather than replacing the first.
+        assert!(matches!(
+            chain.schedule_verif_crs(VerifierKeyUpgrade {
+                height: 5,
+                transfer_sizes: vec![],
+                freeze_sizes: vec![3],
+            }),
+            Err(VerifierKeyUpgradeError::DuplicateHeight { height: 5 })
+        ));
+        assert_eq!(chain.verif_crs_upgrades, vec![upgrade]);
+
+        let num_xfr_keys = |height| chain.verif_crs_at(height).unwrap().xfr.iter().count();
+        assert_eq!(num_xfr_keys(0), SUPPORTED_TRANSFER_SIZES.len());
+        assert_eq!(num_xfr_keys(4), SUPPORTED_TRANSFER_SIZES.len());
+        assert_eq!(num_xfr_keys(5), SUPPORTED_TRANSFER_SIZES.len() + 1);
+        assert_eq!(num_xfr_keys(100), SUPPORTED_TRANSFER_SIZES.len() + 1);
+    }
+
+    #[test]
+    fn test_validation_error_is_recoverable() {
+        let mut rng = ChaChaRng::from_seed([0x42u8; 32]);
+        let (staking_key, _) = StakingKey::generate(&mut rng);
+
+        // Errors caused by stale state or by the block the transaction was included in.
+        let recoverable = vec![
+            ValidationError::BadNullifierProof {},
+            ValidationError::MissingNullifierProof {},
+            ValidationError::ConflictingNullifiers {},
+            ValidationError::BadMerkleRoot {},
+            ValidationError::InconsistentHelperProofs,
+            ValidationError::IncorrectParent,
+            ValidationError::InvalidTime,
+        ];
+        for err in recoverable {
+            assert!(err.is_recoverable(), "{:?} should be recoverable", err);
+        }
+
+        // Errors which the transaction would hit again on every submission.
+        let fatal = vec![
+            ValidationError::NullifierAlreadyExists {
+                nullifier: Nullifier::random_for_test(&mut rng),
+            },
+            ValidationError::Failed {},
+            ValidationError::BadMerkleLength {},
+            ValidationError::BadMerkleLeaf {},
+            ValidationError::BadMerklePath {},
+            ValidationError::CryptoError {
+                err: Err("bad proof".into()),
+            },
+            ValidationError::UnsupportedTransferSize {
+                num_inputs: 5,
+                num_outputs: 5,
+            },
+            ValidationError::UnsupportedFreezeSize { num_inputs: 5 },
+            ValidationError::UnexpectedGenesis,
+            ValidationError::BadCollectRewardNote,
+            ValidationError::RewardAlreadyCollected {
+                reward: CollectedRewards {
+                    staking_key,
+                    time: ConsensusTime::genesis(),
+                },
+            },
+            ValidationError::BadCollectedRewardProof {},
+            ValidationError::RewardAmountTooLarge,
+            ValidationError::BadStakeTableProof {},
+            ValidationError::BadStakeTableCommitmentsProof {},
+            ValidationError::BadFeeCalculation {},
+        ];
+        for err in fatal {
+            assert!(!err.is_recoverable(), "{:?} should not be recoverable", err);
+        }
+    }
+
     #[test]
     fn test_record_history_commit_hash() {
         // Check that ValidatorStates with different record histories have different commits.
@@ -1485,14 +1656,21 @@ mod tests {
             StakeTableSetMT::new(MERKLE_HEIGHT).unwrap(),
         );
         let mut v2 = v1.clone();
+        assert!(v1.diff(&v2).is_empty());
 
         // Test validators with different history lengths.
         v1.past_record_merkle_roots.0.push_front(NodeValue::from(0));
         assert_ne!(v1.commit(), v2.commit());
+        assert_eq!(v1.diff(&v2), vec!["past_record_merkle_roots"]);
 
         // Test validators with the same length, but different histories.
         v2.past_record_merkle_roots.0.push_front(NodeValue::from(1));
         assert_ne!(v1.commit(), v2.commit());
+        v2.block_height += 1;
+        assert_eq!(
+            v1.diff(&v2),
+            vec!["block_height", "past_record_merkle_roots"]
+        );
     }
 
     // Test historical nullifier verification. Builds two transactions against the same state but
@@ -1564,6 +1742,77 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_check_transactions() {
+        let mut state = MultiXfrTestState::initialize(
+            [0x7bu8; 32],
+            2,
+            1,
+            (
+                MultiXfrRecordSpec {
+                    asset_def_ix: 1,
+                    owner_key_ix: 0,
+                    asset_amount: 1,
+                },
+                vec![MultiXfrRecordSpec {
+                    asset_def_ix: 1,
+                    owner_key_ix: 1,
+                    asset_amount: 1,
+                }],
+            ),
+        )
+        .unwrap();
+
+        // Two transactions spending the same record, forced into the same block.
+        let txns = state
+            .generate_transactions(
+                vec![
+                    (TestTxSpec::OneInput { rec: 0, key: 1 }, true),
+                    (TestTxSpec::OneInput { rec: 0, key: 1 }, false),
+                ],
+                TxnPrintInfo::new_no_time(0, 2),
+            )
+            .unwrap();
+        let mut blk = state.validator.next_block();
+        for tx in txns {
+            blk.block.0.push(tx.transaction.txn);
+            blk.proofs.push(tx.transaction.proofs);
+        }
+        let now = state.next_view();
+
+        // The block as a whole is rejected, but only the second transaction is at fault.
+        state
+            .validator
+            .validate_block_check(
+                &now,
+                blk.parent_state,
+                blk.block.clone(),
+                blk.proofs.clone(),
+            )
+            .unwrap_err();
+        let results = state
+            .validator
+            .check_transactions(&now, blk.parent_state, &blk.block, &blk.proofs)
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        results[0].as_ref().unwrap();
+        assert!(matches!(
+            results[1],
+            Err(ValidationError::NullifierAlreadyExists { .. })
+        ));
+
+        // Block-level errors are not attributed to transactions.
+        assert!(matches!(
+            state.validator.check_transactions(
+                &now,
+                blk.parent_state,
+                &blk.block,
+                &blk.proofs[..1]
+            ),
+            Err(ValidationError::InconsistentHelperProofs)
+        ));
+    }
+
     #[test]
     fn test_sliding_nullifiers_valid() {
         test_sliding_nullifiers(false);
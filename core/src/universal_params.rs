@@ -3,8 +3,10 @@
 // This file is part of the Espresso library.
 
 use async_std::sync::Arc;
-use jf_cap::proof::{freeze, mint, transfer};
-use key_set::{ProverKeySet, VerifierKeySet};
+use jf_cap::errors::TxnApiError;
+use jf_cap::proof::{freeze, mint, transfer, UniversalParam};
+use jf_cap::TransactionVerifyingKey;
+use key_set::{ProverKeySet, SizedKey, VerifierKeySet};
 use lazy_static::lazy_static;
 use reef::Ledger;
 
@@ -71,3 +73,67 @@ lazy_static! {
         })
     };
 }
+
+/// Add transfer and freeze keys of new arities to a verifier key set.
+///
+/// Arities which are already supported by `keys` are ignored. New arities are activated on a
+/// running chain with [crate::state::ChainVariables::schedule_verif_crs], which uses this to build
+/// the keys for each upgrade.
+pub fn extend_verifier_keys(
+    keys: &VerifierKeySet,
+    transfer_sizes: &[(usize, usize)],
+    freeze_sizes: &[usize],
+) -> Result<VerifierKeySet, TxnApiError> {
+    let mut xfr = keys.xfr.iter().cloned().collect::<Vec<_>>();
+    for &(inputs, outputs) in transfer_sizes {
+        if !xfr
+            .iter()
+            .any(|k| (k.num_inputs(), k.num_outputs()) == (inputs, outputs))
+        {
+            xfr.push(TransactionVerifyingKey::Transfer(
+                transfer::preprocess(&UNIVERSAL_PARAM, inputs, outputs, MERKLE_HEIGHT)?.1,
+            ));
+        }
+    }
+    let mut freeze = keys.freeze.iter().cloned().collect::<Vec<_>>();
+    for &inputs in freeze_sizes {
+        if !freeze.iter().any(|k| k.num_inputs() == inputs) {
+            freeze.push(TransactionVerifyingKey::Freeze(
+                freeze::preprocess(&UNIVERSAL_PARAM, inputs, MERKLE_HEIGHT)?.1,
+            ));
+        }
+    }
+    Ok(VerifierKeySet {
+        mint: keys.mint.clone(),
+        xfr: xfr.into_iter().collect(),
+        freeze: freeze.into_iter().collect(),
+    })
+}
+
+/// Generate proving keys for each of the arities supported by a verifier key set.
+pub fn prover_keys_for<'a>(
+    univ_param: &'a UniversalParam,
+    keys: &VerifierKeySet,
+) -> Result<ProverKeySet<'a>, TxnApiError> {
+    Ok(ProverKeySet {
+        mint: mint::preprocess(univ_param, MERKLE_HEIGHT)?.0,
+        xfr: keys
+            .xfr
+            .iter()
+            .map(|k| {
+                Ok(transfer::preprocess(
+                    univ_param,
+                    k.num_inputs(),
+                    k.num_outputs(),
+                    MERKLE_HEIGHT,
+                )?
+                .0)
+            })
+            .collect::<Result<_, TxnApiError>>()?,
+        freeze: keys
+            .freeze
+            .iter()
+            .map(|k| Ok(freeze::preprocess(univ_param, k.num_inputs(), MERKLE_HEIGHT)?.0))
+            .collect::<Result<_, TxnApiError>>()?,
+    })
+}
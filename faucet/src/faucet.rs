@@ -41,7 +41,7 @@ use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tide_disco::{App, RequestParams, StatusCode, Url};
 use tracing::{error, info, warn};
 
@@ -138,6 +138,17 @@ pub struct FaucetOptions {
     /// `num_records / num_grants`.
     #[arg(long, env = "ESPRESSO_FAUCET_NUM_WORKERS", default_value = "5")]
     pub num_workers: usize,
+
+    /// Minimum number of seconds between the last grant to a key and a new request from it.
+    ///
+    /// A value of 0 allows a key to request again as soon as its previous request has been
+    /// fulfilled.
+    #[arg(
+        long,
+        env = "ESPRESSO_FAUCET_REQUEST_COOLDOWN_SECS",
+        default_value = "0"
+    )]
+    pub request_cooldown_secs: u64,
 }
 
 impl FaucetOptions {
@@ -187,7 +198,12 @@ impl FaucetState {
         Ok(Self {
             keystore: Arc::new(Mutex::new(keystore)),
             status: Arc::new(RwLock::new(FaucetStatus::Initializing)),
-            queue: FaucetQueue::load(&opt.keystore_path(), opt.max_queue_len).await?,
+            queue: FaucetQueue::load(
+                &opt.keystore_path(),
+                opt.max_queue_len,
+                Duration::from_secs(opt.request_cooldown_secs),
+            )
+            .await?,
             grant_size: opt.grant_size.into(),
             num_grants: opt.num_grants,
             fee_size: opt.fee_size.into(),
@@ -223,19 +239,44 @@ impl FaucetState {
 /// queue. A new request being added to the queue corresponds to an entry `key -> Some(0)`, so the
 /// queue simply consists of the most recent `key -> Some(0)` entry for each key, in order,
 /// filtering out keys that have a more recent `key -> None` entry.
+///
+/// Alongside the queue, the index keeps a persistent ledger of every grant made, as a log of
+/// [GrantEntry]. The ledger is used to rate limit requests: a key whose last grant was less than
+/// `cooldown` ago cannot be added to the queue again.
 #[derive(Clone)]
 struct FaucetQueue {
     sender: mpmc::Sender<(UserPubKey, usize)>,
     receiver: mpmc::Receiver<(UserPubKey, usize)>,
     index: Arc<Mutex<FaucetQueueIndex>>,
     max_len: Option<usize>,
+    cooldown: Duration,
+}
+
+/// A record of a completed transfer from the faucet.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct GrantEntry {
+    key: UserPubKey,
+    /// Number of records transferred.
+    records: usize,
+    /// Seconds since the Unix epoch.
+    time: u64,
 }
 
 // A persistent ordered set.
 struct FaucetQueueIndex {
     index: HashMap<UserPubKey, usize>,
+    // Time of the most recent grant to each key, in seconds since the Unix epoch.
+    last_grant: HashMap<UserPubKey, u64>,
     store: AtomicStore,
     queue: AppendLog<BincodeLoadStore<(UserPubKey, Option<usize>)>>,
+    grants: AppendLog<BincodeLoadStore<GrantEntry>>,
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
 }
 
 impl FaucetQueueIndex {
@@ -259,8 +300,8 @@ impl FaucetQueueIndex {
                 error!("storage error adding {} to queue: {}", key, err);
                 err
             })?;
-        self.queue.commit_version().unwrap();
-        self.store.commit_version().unwrap();
+        self.queue.commit_version()?;
+        self.store.commit_version()?;
         // If successful, add it to our in-memory index.
         self.index.insert(key, 0);
         Ok(true)
@@ -278,6 +319,22 @@ impl FaucetQueueIndex {
         granted: usize,
         max_grants: usize,
     ) -> Result<bool, FaucetError> {
+        // Record the grant in the ledger. The transfer has already happened, so if this fails we
+        // log it but still update the queue, so the key is not granted twice.
+        let entry = GrantEntry {
+            key: key.clone(),
+            records: granted,
+            time: unix_time(),
+        };
+        if let Err(err) = self
+            .grants
+            .store_resource(&entry)
+            .and_then(|_| self.grants.commit_version())
+        {
+            error!("storage error recording grant to {}: {}", key, err);
+        }
+        self.last_grant.insert(key.clone(), entry.time);
+
         let grants_given = self.index[&key] + granted;
         if grants_given >= max_grants {
             // If this is the last grant to this key, remove it from the index.
@@ -291,8 +348,8 @@ impl FaucetQueueIndex {
                     error!("storage error updating {} in queue: {}", key, err);
                     err
                 })?;
-            self.queue.commit_version().unwrap();
-            self.store.commit_version().unwrap();
+            self.queue.commit_version()?;
+            self.store.commit_version()?;
             // If successful, update our in-memory index.
             self.index.insert(key, grants_given);
             Ok(true)
@@ -308,8 +365,8 @@ impl FaucetQueueIndex {
                 error!("storage error removing {} from queue: {}", key, err);
                 err
             })?;
-        self.queue.commit_version().unwrap();
-        self.store.commit_version().unwrap();
+        self.queue.commit_version()?;
+        self.store.commit_version()?;
         // Update our in-memory set.
         self.index.remove(key);
         Ok(())
@@ -319,15 +376,35 @@ impl FaucetQueueIndex {
     fn grants(&self, key: &UserPubKey) -> usize {
         self.index[key]
     }
+
+    /// How long `key` must wait before it can make another request.
+    fn cooldown_remaining(&self, key: &UserPubKey, cooldown: Duration) -> Option<Duration> {
+        let last = *self.last_grant.get(key)?;
+        let elapsed = Duration::from_secs(unix_time().saturating_sub(last));
+        cooldown.checked_sub(elapsed).filter(|d| !d.is_zero())
+    }
 }
 
 impl FaucetQueue {
-    async fn load(store: &Path, max_len: Option<usize>) -> Result<Self, FaucetError> {
+    async fn load(
+        store: &Path,
+        max_len: Option<usize>,
+        cooldown: Duration,
+    ) -> Result<Self, FaucetError> {
         // Load from storage.
         let mut loader = AtomicStoreLoader::load(store, "queue")?;
         let persistent_queue = AppendLog::load(&mut loader, Default::default(), "requests", 1024)?;
+        let grants: AppendLog<BincodeLoadStore<GrantEntry>> =
+            AppendLog::load(&mut loader, Default::default(), "grants", 1024)?;
         let store = AtomicStore::open(loader)?;
 
+        // Replay the grant ledger to find the most recent grant to each key.
+        let mut last_grant = HashMap::new();
+        for entry in grants.iter() {
+            let entry = entry?;
+            last_grant.insert(entry.key, entry.time);
+        }
+
         // Traverse the persisted queue entries backwards. This ensures that we encounter the most
         // recent value for each key first. If the most recent value for a given key is `Some(n)`,
         // it gets added to the index. If it is `None`, we just store `None` in `index` so that if
@@ -390,12 +467,15 @@ impl FaucetQueue {
         Ok(Self {
             index: Arc::new(Mutex::new(FaucetQueueIndex {
                 index,
+                last_grant,
                 queue: persistent_queue,
+                grants,
                 store,
             })),
             sender,
             receiver,
             max_len,
+            cooldown,
         })
     }
 
@@ -409,6 +489,16 @@ impl FaucetQueue {
                     return Err(FaucetError::QueueFull { max_len });
                 }
             }
+            if let Some(remaining) = index.cooldown_remaining(&key, self.cooldown) {
+                warn!(
+                    "rejecting {} because it was granted assets too recently",
+                    key
+                );
+                return Err(FaucetError::RateLimited {
+                    key,
+                    retry_after_secs: remaining.as_secs().max(1),
+                });
+            }
             if !index.insert(key.clone())? {
                 warn!("rejecting {} because it is already in the queue", key);
                 return Err(FaucetError::AlreadyInQueue { key });
@@ -427,11 +517,23 @@ impl FaucetQueue {
     }
 
     async fn grant(&mut self, request: UserPubKey, granted: usize, max_grants: usize) -> bool {
-        self.index
+        match self
+            .index
             .lock()
             .await
-            .grant(request, granted, max_grants)
-            .unwrap_or(false)
+            .grant(request.clone(), granted, max_grants)
+        {
+            Ok(more) => more,
+            Err(err) => {
+                // The transfer has already been made, so rather than retrying the request and
+                // risking granting it twice, drop it.
+                error!(
+                    "failed to update queue after granting to {}, dropping request: {}",
+                    request, err
+                );
+                false
+            }
+        }
     }
 
     async fn fail(&mut self, key: UserPubKey) {
@@ -928,6 +1030,49 @@ async fn main() -> Result<(), std::io::Error> {
     Ok(())
 }
 
+#[cfg(test)]
+mod queue_test {
+    use super::*;
+    use jf_cap::keys::UserKeyPair;
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+    use tempdir::TempDir;
+
+    #[async_std::test]
+    async fn test_rate_limit() {
+        let mut rng = ChaChaRng::from_seed([0x26; 32]);
+        let key = UserKeyPair::generate(&mut rng).pub_key();
+        let other = UserKeyPair::generate(&mut rng).pub_key();
+        let dir = TempDir::new("faucet_queue").unwrap();
+        let cooldown = Duration::from_secs(3600);
+
+        let mut queue = FaucetQueue::load(dir.path(), None, cooldown).await.unwrap();
+        queue.push(key.clone()).await.unwrap();
+        assert_eq!(queue.pop().await, Some((key.clone(), 0)));
+        assert!(!queue.grant(key.clone(), 2, 2).await);
+
+        // A second request within the cooldown is rejected, but other keys are not affected.
+        match queue.push(key.clone()).await {
+            Err(FaucetError::RateLimited {
+                key: limited,
+                retry_after_secs,
+            }) => {
+                assert_eq!(limited, key);
+                assert!(retry_after_secs > 0 && retry_after_secs <= cooldown.as_secs());
+            }
+            res => panic!("expected RateLimited, got {:?}", res),
+        }
+        queue.push(other).await.unwrap();
+
+        // The grant ledger is persistent, so restarting does not lift the limit.
+        drop(queue);
+        let queue = FaucetQueue::load(dir.path(), None, cooldown).await.unwrap();
+        assert!(matches!(
+            queue.push(key).await,
+            Err(FaucetError::RateLimited { .. })
+        ));
+    }
+}
+
 #[cfg(all(test, feature = "slow-tests"))]
 mod test {
     use super::*;
@@ -28,6 +28,16 @@ pub enum FaucetError {
     ))]
     AlreadyInQueue { key: UserPubKey },
 
+    #[snafu(display(
+        "key {} was granted assets recently, try again in {} seconds",
+        key,
+        retry_after_secs
+    ))]
+    RateLimited {
+        key: UserPubKey,
+        retry_after_secs: u64,
+    },
+
     #[snafu(display("error with persistent storage: {}", msg))]
     Persistence { msg: String },
 
@@ -46,6 +56,7 @@ impl tide_disco::Error for FaucetError {
             Self::Transfer { .. } => StatusCode::BadRequest,
             Self::Internal { status, .. } => *status,
             Self::AlreadyInQueue { .. } => StatusCode::TooManyRequests,
+            Self::RateLimited { .. } => StatusCode::TooManyRequests,
             Self::QueueFull { .. } => StatusCode::InternalServerError,
             Self::Persistence { .. } => StatusCode::InternalServerError,
             Self::Unavailable => StatusCode::ServiceUnavailable,
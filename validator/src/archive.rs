@@ -0,0 +1,84 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Snapshots of a node's persisted state.
+//!
+//! A node's store directory holds both its consensus state (the latest decided leaf) and its
+//! query service indexes (blocks, states, events and status). An archive is a copy of that
+//! directory, which can be imported into the store directory of a new node so that it starts from
+//! the archived state rather than from genesis.
+//!
+//! The stores are only guaranteed to be consistent when the node is not running, so archives
+//! should be exported from a stopped node.
+
+use snafu::{ResultExt, Snafu};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The subdirectory of a store holding the consensus state.
+const CONSENSUS_STATE_DIR: &str = "lw_validator";
+
+#[derive(Debug, Snafu)]
+pub enum ArchiveError {
+    #[snafu(display("{} does not contain a validator store", path.display()))]
+    NotAStore { path: PathBuf },
+
+    #[snafu(display("{} already exists and is not empty", path.display()))]
+    NotEmpty { path: PathBuf },
+
+    #[snafu(display("failed to copy {}: {}", path.display(), source))]
+    Copy { path: PathBuf, source: io::Error },
+}
+
+/// Copy the store at `store` into a new archive at `dest`.
+pub fn export_state(store: &Path, dest: &Path) -> Result<(), ArchiveError> {
+    check_store(store)?;
+    check_empty(dest)?;
+    copy_dir(store, dest)
+}
+
+/// Initialize the store at `store` from the archive at `src`.
+///
+/// If `force` is not set, this fails rather than overwrite an existing store.
+pub fn import_state(src: &Path, store: &Path, force: bool) -> Result<(), ArchiveError> {
+    check_store(src)?;
+    if force && store.exists() {
+        fs::remove_dir_all(store).context(CopySnafu { path: store })?;
+    } else {
+        check_empty(store)?;
+    }
+    copy_dir(src, store)
+}
+
+fn check_store(path: &Path) -> Result<(), ArchiveError> {
+    if path.join(CONSENSUS_STATE_DIR).is_dir() {
+        Ok(())
+    } else {
+        Err(ArchiveError::NotAStore { path: path.into() })
+    }
+}
+
+fn check_empty(path: &Path) -> Result<(), ArchiveError> {
+    match fs::read_dir(path) {
+        Ok(mut entries) if entries.next().is_some() => {
+            Err(ArchiveError::NotEmpty { path: path.into() })
+        }
+        _ => Ok(()),
+    }
+}
+
+fn copy_dir(src: &Path, dest: &Path) -> Result<(), ArchiveError> {
+    fs::create_dir_all(dest).context(CopySnafu { path: dest })?;
+    for entry in fs::read_dir(src).context(CopySnafu { path: src })? {
+        let entry = entry.context(CopySnafu { path: src })?;
+        let path = entry.path();
+        let target = dest.join(entry.file_name());
+        if path.is_dir() {
+            copy_dir(&path, &target)?;
+        } else {
+            fs::copy(&path, &target).context(CopySnafu { path: &path })?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,91 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+#![deny(warnings)]
+
+use clap::{Parser, Subcommand};
+use espresso_validator::{
+    archive::{export_state, import_state},
+    default_store_path,
+};
+use std::path::PathBuf;
+use std::process::exit;
+
+#[derive(Parser)]
+#[command(
+    name = "Espresso validator state",
+    about = "Exports and imports snapshots of a validator's persisted state."
+)]
+struct Options {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Copy a stopped node's state into a new archive.
+    Export {
+        #[command(flatten)]
+        store: StoreOpt,
+
+        /// Directory to create the archive in.
+        #[arg(long, short)]
+        output: PathBuf,
+    },
+
+    /// Initialize a node's state from an archive.
+    ///
+    /// The node will then start from the archived state the next time it is run without
+    /// `--reset-store-state`.
+    Import {
+        #[command(flatten)]
+        store: StoreOpt,
+
+        /// Archive created by `export`.
+        #[arg(long, short)]
+        input: PathBuf,
+
+        /// Replace the node's existing state.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(clap::Args)]
+struct StoreOpt {
+    /// Id of the node, used to locate its store if `--store-path` is not given.
+    #[arg(long, short, env = "ESPRESSO_VALIDATOR_ID")]
+    id: Option<usize>,
+
+    /// Path to the node's persistence files.
+    #[arg(long, short, env = "ESPRESSO_VALIDATOR_STORE_PATH")]
+    store_path: Option<PathBuf>,
+}
+
+impl StoreOpt {
+    fn path(&self) -> PathBuf {
+        match (&self.store_path, self.id) {
+            (Some(path), _) => path.clone(),
+            (None, Some(id)) => default_store_path(id),
+            (None, None) => {
+                eprintln!("one of --id or --store-path is required");
+                exit(1);
+            }
+        }
+    }
+}
+
+fn main() {
+    let res = match Options::parse().command {
+        Command::Export { store, output } => export_state(&store.path(), &output),
+        Command::Import {
+            store,
+            input,
+            force,
+        } => import_state(&input, &store.path(), force),
+    };
+    if let Err(err) = res {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
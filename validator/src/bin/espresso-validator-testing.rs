@@ -8,6 +8,7 @@ use async_std::{
     task::{sleep, spawn_blocking},
 };
 use clap::Parser;
+use commit::Committable;
 use espresso_core::StakingKey;
 use espresso_core::{
     genesis::GenesisNote,
@@ -16,11 +17,16 @@ use espresso_core::{
     testing::{MultiXfrRecordSpec, MultiXfrTestState, TestTxSpec, TxnPrintInfo},
     universal_params::VERIF_CRS,
 };
-use espresso_validator::{validator::*, *};
+use espresso_validator::{
+    progress::{ProgressEvent, ProgressReporter},
+    validator::*,
+    *,
+};
 use hotshot::types::SignatureKey;
 use hotshot::{traits::State, types::EventType};
 use rand::SeedableRng;
 use rand_chacha::ChaChaRng;
+use std::path::PathBuf;
 use std::time::Duration;
 use tracing::info;
 
@@ -37,6 +43,10 @@ struct Options {
     /// Number of successful transactions to submit.
     #[arg(long, short)]
     pub num_txns: u64,
+
+    /// Unix socket on which to report progress as JSON events.
+    #[arg(long)]
+    pub progress_socket: Option<PathBuf>,
 }
 
 fn genesis_for_test(node_opt: &NodeOpt) -> (GenesisNote, MultiXfrTestState) {
@@ -117,6 +127,7 @@ async fn generate_transactions(
     own_id: usize,
     mut hotshot: Consensus,
     mut state: MultiXfrTestState,
+    mut progress: ProgressReporter,
 ) {
     #[cfg(target_os = "linux")]
     let bytes_per_page = procfs::page_size().unwrap() as u64;
@@ -234,6 +245,12 @@ async fn generate_transactions(
                                 round + 1,
                                 leaf.state.commit()
                             );
+                            progress.report(&ProgressEvent::RoundCompleted {
+                                round: round + 1,
+                                commitment: leaf.state.commit().to_string(),
+                                block_hash: leaf.deltas.commit().to_string(),
+                                txn_count: leaf.state.transaction_count as u64,
+                            });
                             round += 1;
                             success = true;
                         }
@@ -265,6 +282,12 @@ async fn generate_transactions(
                             // genesis transaction.
                             let commit = leaf_chain.first().unwrap().state.commit();
                             println!("  - Round {} completed. Commitment: {}", round + 1, commit);
+                            progress.report(&ProgressEvent::RoundCompleted {
+                                round: round + 1,
+                                commitment: commit.to_string(),
+                                block_hash: leaf.deltas.commit().to_string(),
+                                txn_count: leaf.state.transaction_count as u64,
+                            });
                             final_commitment = Some(commit);
                             round = (leaf.state.transaction_count - 1) as u64;
                             break;
@@ -278,17 +301,12 @@ async fn generate_transactions(
     }
 
     info!("All rounds completed.");
-    // !!!!!!     WARNING !!!!!!!
-    // If the output below is changed, update the message for main() in
-    // src/bin/multi-machine-automation.rs as well
     if let Some(commitment) = final_commitment {
-        println!(
-            // THINK TWICE BEFORE CHANGING THIS
-            "Final commitment: {}",
-            commitment
-        );
+        println!("Final commitment: {}", commitment);
     }
-    // !!!!!! END WARNING !!!!!!!
+    progress.report(&ProgressEvent::Finished {
+        commitment: final_commitment.map(|commitment| commitment.to_string()),
+    });
 
     // Wait for other nodes to catch up.
     sleep(Duration::from_secs(10)).await;
@@ -298,8 +316,9 @@ async fn generate_transactions(
 async fn main() -> Result<(), std::io::Error> {
     let options = Options::parse();
     let id = options.node_opt.id;
+    let progress = ProgressReporter::connect(options.progress_socket.as_deref())?;
     let (genesis, state) = genesis_for_test(&options.node_opt);
     let hotshot = init(ChaChaRng::from_entropy(), genesis, options.node_opt).await?;
-    generate_transactions(options.num_txns, id, hotshot, state).await;
+    generate_transactions(options.num_txns, id, hotshot, state, progress).await;
     Ok(())
 }
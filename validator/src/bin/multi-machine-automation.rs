@@ -1,15 +1,26 @@
 // Copyright (c) 2022 Espresso Systems (espressosys.com)
 // This file is part of the Espresso library.
 
-use async_std::task::{sleep, spawn_blocking};
+use async_std::sync::Arc;
+use async_std::task::{sleep, spawn_blocking, JoinHandle};
 use clap::Parser;
 use escargot::CargoBuild;
 use espresso_esqs::full_node;
-use espresso_validator::{div_ceil, NodeOpt, QUORUM_THRESHOLD, STAKE_PER_NODE};
+use espresso_validator::{
+    archive::{export_state, import_state},
+    default_store_path, div_ceil, parse_duration,
+    progress::{read_events, ProgressEvent},
+    NodeOpt, QUORUM_THRESHOLD, STAKE_PER_NODE,
+};
+use std::collections::HashSet;
 use std::env;
 use std::io::{BufRead, BufReader};
-use std::process::{exit, Command, Stdio};
-use std::time::Duration;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{exit, Child, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tempdir::TempDir;
 
 #[derive(Parser)]
 #[command(
@@ -43,15 +54,190 @@ struct Options {
     /// If not provided, all nodes will keep running till `num_txns` rounds are completed.
     #[arg(long, requires("num-fail-nodes"))]
     fail_after_txn: Option<usize>,
+
+    /// Number of rounds after which `num_join_nodes` nodes join the network.
+    ///
+    /// The joining nodes are not started with the others. Once node 0 has completed this many
+    /// rounds and a node has left after `fail_after_txn` rounds, they are started from the
+    /// archived state of the node which left, and must catch up to the same final commitment as
+    /// the other nodes.
+    ///
+    /// Membership is fixed by the genesis stake table, so joining nodes are counted in
+    /// `num_nodes`.
+    #[arg(long, requires("num-txns"), requires("fail-after-txn"))]
+    join_after_txn: Option<u64>,
+
+    /// Number of nodes which join after `join_after_txn` rounds.
+    #[arg(long, default_value = "1")]
+    num_join_nodes: usize,
+
+    /// Time after which to relaunch the nodes which failed after `fail_after_txn` rounds.
+    ///
+    /// The failed nodes are restarted from their persisted state, and must catch up to the same
+    /// final commitment as the nodes which did not fail. If not provided, failed nodes are not
+    /// restarted.
+    #[arg(
+        long,
+        requires("num-txns"),
+        requires("fail-after-txn"),
+        value_parser = parse_duration
+    )]
+    restart_failed_after: Option<Duration>,
 }
 
 fn cargo_run(bin: impl AsRef<str>) -> Command {
-    CargoBuild::new()
-        .bin(bin.as_ref())
-        .current_release()
-        .run()
-        .expect("Failed to build.")
-        .command()
+    let build = CargoBuild::new().bin(bin.as_ref()).current_release();
+    // Build the nodes with the same test-only options, like `--fault-config`, as this binary.
+    #[cfg(feature = "testing")]
+    let build = build.features("testing");
+    build.run().expect("Failed to build.").command()
+}
+
+/// Build the command line for node `id`.
+///
+/// `store_path` overrides the store path given in the options, and if it is set the node loads
+/// its state from the store rather than resetting it.
+fn node_args(
+    options: &Options,
+    base_args: &[String],
+    id: usize,
+    num_txns: Option<u64>,
+    store_path: Option<&Path>,
+) -> Vec<String> {
+    let mut args = base_args.to_vec();
+    match store_path {
+        Some(path) => {
+            args.push("--store-path".into());
+            args.push(path.display().to_string());
+        }
+        None => {
+            if options.node_opt.reset_store_state {
+                args.push("--reset-store-state".into());
+            }
+            if let Some(path) = &options.node_opt.store_path {
+                args.push("--store-path".into());
+                args.push(path.display().to_string());
+            }
+        }
+    }
+    args.push("--id".into());
+    // Use `id` rather than `node_opt.id` since the latter is arbitrarily set as 0.
+    args.push(id.to_string());
+    args.push("--num-nodes".into());
+    args.push(options.node_opt.num_nodes.to_string());
+    #[cfg(feature = "testing")]
+    if let Some(path) = &options.node_opt.fault_config {
+        args.push("--fault-config".into());
+        args.push(path.display().to_string());
+    }
+    if let Some(num_txns) = num_txns {
+        args.push("--num-txns".into());
+        args.push(num_txns.to_string());
+    }
+    if let Some(full_node::Command::Esqs(opt)) = &options.node_opt.esqs {
+        args.push("esqs".into());
+        args.push("-p".into());
+        args.push(opt.port.to_string());
+        if let Some(path) = &opt.metastate.api_path {
+            args.push("--metastate-api-path".into());
+            args.push(path.display().to_string());
+        }
+        if let Some(path) = &opt.status.api_path {
+            args.push("--status-api-path".into());
+            args.push(path.display().to_string());
+        }
+    }
+    args
+}
+
+/// The directory holding the persisted state of node `id`.
+fn node_store_path(options: &Options, id: usize) -> PathBuf {
+    options
+        .node_opt
+        .store_path
+        .clone()
+        .unwrap_or_else(|| default_store_path(id))
+}
+
+/// A store directory for a node which is initialized from an archive.
+///
+/// This is separate from the store given in the options, which may be shared by several nodes.
+fn bootstrap_store_path(options: &Options, id: usize) -> PathBuf {
+    match &options.node_opt.store_path {
+        Some(path) => path.join(format!("node{}", id)),
+        None => default_store_path(id),
+    }
+}
+
+/// Collect output from a process as it runs.
+///
+/// If we don't do this eagerly, validators can block when their output pipes fill up causing
+/// deadlock.
+fn collect_output(id: usize, process: &mut Child, verbose: bool) {
+    let mut stdout = BufReader::new(process.stdout.take().unwrap());
+    spawn_blocking(move || {
+        let mut line = String::new();
+        loop {
+            if stdout
+                .read_line(&mut line)
+                .unwrap_or_else(|_| panic!("Failed to read stdout for node {}", id))
+                == 0
+            {
+                break;
+            }
+            if verbose {
+                print!("[{}] {}", id, line);
+            }
+            line.clear();
+        }
+    });
+}
+
+/// Collect the progress events reported by a node on `listener`.
+///
+/// If `progress` is given, it is updated with the number of rounds the node has completed.
+fn collect_progress(
+    listener: UnixListener,
+    progress: Option<Arc<AtomicU64>>,
+) -> JoinHandle<Vec<ProgressEvent>> {
+    spawn_blocking(move || {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(_) => return vec![],
+        };
+        read_events(stream)
+            .inspect(|event| {
+                if let (Some(progress), ProgressEvent::RoundCompleted { round, .. }) =
+                    (&progress, event)
+                {
+                    progress.fetch_max(*round, Ordering::SeqCst);
+                }
+            })
+            .collect()
+    })
+}
+
+/// A running validator process.
+struct Node {
+    id: usize,
+    process: Child,
+    /// The socket on which the node reports progress, and the events it has reported.
+    progress: Option<(PathBuf, JoinHandle<Vec<ProgressEvent>>)>,
+}
+
+impl Node {
+    /// The events reported by the node, which must have exited.
+    async fn events(self) -> Vec<ProgressEvent> {
+        match self.progress {
+            Some((socket, events)) => {
+                // If the node exited without ever connecting, the collector is still waiting for
+                // a connection. Connect ourselves so that it sees an empty stream.
+                UnixStream::connect(&socket).ok();
+                events.await
+            }
+            None => vec![],
+        }
+    }
 }
 
 #[async_std::main]
@@ -85,17 +271,7 @@ async fn main() {
     env::remove_var("ESPRESSO_VALIDATOR_MAX_TRANSACTIONS");
     env::remove_var("ESPRESSO_VALIDATOR_NONBOOTSTRAP_PORT");
 
-    let mut args = vec![];
-    if options.node_opt.reset_store_state {
-        args.push("--reset-store-state");
-    }
-    let store_path;
-    if let Some(path) = &options.node_opt.store_path {
-        store_path = path.display().to_string();
-        args.push("--store-path");
-        args.push(&store_path);
-    }
-    let cdn;
+    let mut args: Vec<String> = vec![];
     if let Some(url) = &options.node_opt.cdn {
         if url.host_str() != Some("localhost") {
             panic!(
@@ -103,71 +279,93 @@ async fn main() {
                 (note that a scheme is required for URL parsing, e.g. tcp://localhost:80)"
             );
         }
-        cdn = url.to_string();
-        args.push("--cdn");
-        args.push(&cdn);
+        args.push("--cdn".into());
+        args.push(url.to_string());
     }
     if options.node_opt.libp2p {
-        args.push("--libp2p");
+        args.push("--libp2p".into());
     }
-    let min_propose_time = format!("{}ms", options.node_opt.min_propose_time.as_millis());
-    args.push("--min-propose-time");
-    args.push(&min_propose_time);
-    let max_propose_time = format!("{}ms", options.node_opt.max_propose_time.as_millis());
-    args.push("--max-propose-time");
-    args.push(&max_propose_time);
-    let next_view_timeout = format!("{}ms", options.node_opt.next_view_timeout.as_millis());
-    args.push("--next-view-timeout");
-    args.push(&next_view_timeout);
-    let timeout_ratio = options.node_opt.timeout_ratio.to_string();
-    args.push("--timeout-ratio");
-    args.push(&timeout_ratio);
-    let round_start_delay = format!("{}ms", options.node_opt.round_start_delay.as_millis());
-    args.push("--round-start-delay");
-    args.push(&round_start_delay);
-    let start_delay = format!("{}ms", options.node_opt.start_delay.as_millis());
-    args.push("--start-delay");
-    args.push(&start_delay);
-    let max_transactions = options.node_opt.max_transactions.to_string();
-    args.push("--max-transactions");
-    args.push(&max_transactions);
-    let num_nodes_str = options.node_opt.num_nodes.to_string();
-    let num_nodes = num_nodes_str.parse::<usize>().unwrap();
-    let faucet_pub_keys = options
-        .node_opt
-        .faucet_pub_key
-        .iter()
-        .map(|k| k.to_string())
-        .collect::<Vec<_>>();
-    for pub_key in &faucet_pub_keys {
-        args.push("--faucet-pub-key");
-        args.push(pub_key);
+    args.push("--min-propose-time".into());
+    args.push(format!(
+        "{}ms",
+        options.node_opt.min_propose_time.as_millis()
+    ));
+    args.push("--max-propose-time".into());
+    args.push(format!(
+        "{}ms",
+        options.node_opt.max_propose_time.as_millis()
+    ));
+    args.push("--next-view-timeout".into());
+    args.push(format!(
+        "{}ms",
+        options.node_opt.next_view_timeout.as_millis()
+    ));
+    args.push("--timeout-ratio".into());
+    args.push(options.node_opt.timeout_ratio.to_string());
+    args.push("--round-start-delay".into());
+    args.push(format!(
+        "{}ms",
+        options.node_opt.round_start_delay.as_millis()
+    ));
+    args.push("--start-delay".into());
+    args.push(format!("{}ms", options.node_opt.start_delay.as_millis()));
+    args.push("--max-transactions".into());
+    args.push(options.node_opt.max_transactions.to_string());
+    let num_nodes = options.node_opt.num_nodes;
+    for pub_key in &options.node_opt.faucet_pub_key {
+        args.push("--faucet-pub-key".into());
+        args.push(pub_key.to_string());
     }
 
-    let (num_txn_str, exe) = match options.num_txns {
-        Some(num_txns) => (num_txns.to_string(), "espresso-validator-testing"),
-        None => ("".to_string(), "espresso-validator"),
+    let exe = match options.num_txns {
+        Some(_) => "espresso-validator-testing",
+        None => "espresso-validator",
     };
-    let (num_fail_nodes, fail_after_txn_str) = match options.num_fail_nodes {
+    let (num_fail_nodes, fail_after_txn) = match options.num_fail_nodes {
         Some(num_fail_nodes) => {
             assert!(num_fail_nodes <= num_nodes);
             if num_fail_nodes == 0 {
-                (0, "".to_string())
+                (0, None)
             } else {
-                let fail_after_txn_str = options
+                let fail_after_txn = options
                     .fail_after_txn
-                    .expect("`fail-after-txn` isn't specified when `num-failed-nodes` is nonzero")
-                    .to_string();
-                (num_fail_nodes, fail_after_txn_str)
+                    .expect("`fail-after-txn` isn't specified when `num-failed-nodes` is nonzero");
+                (num_fail_nodes, Some(fail_after_txn as u64))
+            }
+        }
+        None => (0, None),
+    };
+    let threshold = div_ceil!(QUORUM_THRESHOLD, STAKE_PER_NODE) as usize;
+
+    // Nodes which leave after `fail_after_txn` rounds are the last `num_fail_nodes` nodes. Nodes
+    // which join late are the `num_join_nodes` nodes before them.
+    let first_fail_id = num_nodes - num_fail_nodes;
+    let num_join_nodes = match options.join_after_txn {
+        Some(join_after_txn) => {
+            if num_fail_nodes == 0 || Some(join_after_txn) < fail_after_txn {
+                eprintln!(
+                    "joining nodes bootstrap from the state of a node which has left, so \
+                    `--join-after-txn` requires `--num-fail-nodes` to be nonzero and \
+                    `--fail-after-txn` to be at most `--join-after-txn`"
+                );
+                exit(1);
+            }
+            if first_fail_id < options.num_join_nodes + threshold {
+                eprintln!("not enough nodes to reach quorum before the joining nodes join");
+                exit(1);
             }
+            options.num_join_nodes
         }
-        None => (0, "".to_string()),
+        None => 0,
     };
+    let first_join_id = first_fail_id - num_join_nodes;
 
     // Start a CDN server if one is required.
     let cdn = options.node_opt.cdn.as_ref().map(|url| {
         let port = url.port_or_known_default().unwrap().to_string();
-        let num_nodes = options.node_opt.num_nodes.to_string();
+        // The CDN waits for the nodes which are started initially to connect before starting the
+        // network.
+        let num_nodes = (num_nodes - num_join_nodes).to_string();
         let mut cdn_args = vec!["-p", &port, "-n", &num_nodes];
         if !options.node_opt.libp2p {
             // If we're not using libp2p (we're just using the CDN for networking) we don't need a
@@ -206,154 +404,183 @@ async fn main() {
         process
     });
 
-    // Start the consensus for each node.
-    let first_fail_id = num_nodes - num_fail_nodes;
-    let mut processes: Vec<_> = (0..num_nodes)
-        .map(|id| {
-            let mut this_args = args.clone();
-            let id_str = id.to_string();
-            this_args.push("--id");
-            // Use `id_str` rather than `node_opt.id` since the latter is arbitrarily set as 0.
-            this_args.push(&id_str);
-            this_args.push("--num-nodes");
-            this_args.push(&num_nodes_str);
-            if id >= first_fail_id {
-                this_args.push("--num-txns");
-                this_args.push(&fail_after_txn_str);
-            } else if !num_txn_str.is_empty() {
-                this_args.push("--num-txns");
-                this_args.push(&num_txn_str);
-            }
-            let mut esqs_args = vec![];
-            if let Some(full_node::Command::Esqs(opt)) = &options.node_opt.esqs {
-                esqs_args = vec!["esqs".to_string(), "-p".to_string(), opt.port.to_string()];
-                if let Some(path) = &opt.metastate.api_path {
-                    esqs_args.push("--metastate-api-path".to_string());
-                    esqs_args.push(path.display().to_string());
-                }
-                if let Some(path) = &opt.status.api_path {
-                    esqs_args.push("--status-api-path".to_string());
-                    esqs_args.push(path.display().to_string());
-                }
-            }
-            for arg in &esqs_args {
-                this_args.push(arg);
-            }
-            if options.verbose {
-                println!("{} {}", exe, this_args.join(" "));
-            }
-            (
-                id,
-                cargo_run(exe)
-                    .args(this_args)
-                    .stdout(Stdio::piped())
-                    .spawn()
-                    .unwrap_or_else(|_| panic!("Failed to start the validator for node {}", id)),
-            )
-        })
-        .collect();
-
-    // Collect output from each process as they run. If we don't do this eagerly, validators can
-    // block when their output pipes fill up causing deadlock.
-    let mut outputs = processes
-        .iter_mut()
-        .map(|(id, p)| {
-            let mut stdout = BufReader::new(p.stdout.take().unwrap());
-            let id = *id;
-            let verbose = options.verbose;
-            spawn_blocking(move || {
-                let mut lines = Vec::new();
-                let mut line = String::new();
-                loop {
-                    if stdout
-                        .read_line(&mut line)
-                        .unwrap_or_else(|_| panic!("Failed to read stdout for node {}", id))
-                        == 0
-                    {
-                        break;
-                    }
-                    if verbose {
-                        print!("[{}] {}", id, line);
-                    }
-                    lines.push(std::mem::take(&mut line));
-                }
-                lines
-            })
-        })
-        .collect::<Vec<_>>();
+    // Nodes generating transactions report their progress on a socket in this directory. Node 0's
+    // progress is used to decide when the joining nodes join.
+    let socket_dir = TempDir::new("espresso-progress").expect("Failed to create socket directory");
+    let progress = Arc::new(AtomicU64::new(0));
+    let mut num_spawned = 0;
+    let mut spawn_node = |id: usize, num_txns: Option<u64>, store_path: Option<&Path>| {
+        let mut node_cmd = node_args(&options, &args, id, num_txns, store_path);
+        let node_progress = if options.num_txns.is_some() {
+            // Restarted nodes need a new socket, so name sockets by spawn order as well as id.
+            let socket = socket_dir
+                .path()
+                .join(format!("node{}-{}.sock", id, num_spawned));
+            let listener = UnixListener::bind(&socket).expect("Failed to bind progress socket");
+            node_cmd.push("--progress-socket".into());
+            node_cmd.push(socket.display().to_string());
+            let progress = if id == 0 {
+                Some(progress.clone())
+            } else {
+                None
+            };
+            Some((socket, collect_progress(listener, progress)))
+        } else {
+            None
+        };
+        num_spawned += 1;
+        if options.verbose {
+            println!("{} {}", exe, node_cmd.join(" "));
+        }
+        let mut process = cargo_run(exe)
+            .args(node_cmd)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap_or_else(|_| panic!("Failed to start the validator for node {}", id));
+        collect_output(id, &mut process, options.verbose);
+        Node {
+            id,
+            process,
+            progress: node_progress,
+        }
+    };
+
+    // Start the consensus for each node, except those which join later.
+    let mut nodes = vec![];
+    for id in (0..num_nodes).filter(|id| !(first_join_id..first_fail_id).contains(id)) {
+        let num_txns = if id >= first_fail_id {
+            fail_after_txn
+        } else {
+            options.num_txns
+        };
+        nodes.push(spawn_node(id, num_txns, None));
+    }
 
     // Check each process.
     let mut commitment = None;
     let mut succeeded_nodes = 0;
     let mut finished_nodes = 0;
-    let threshold = div_ceil!(QUORUM_THRESHOLD, STAKE_PER_NODE) as usize;
-    let expect_failure = num_fail_nodes as usize > num_nodes - threshold;
+    let mut left_nodes = 0;
+    let mut joined = num_join_nodes == 0;
+    // Failed nodes waiting to be restarted, with the time at which to restart them.
+    let mut pending_restarts = vec![];
+    let mut restarted = HashSet::new();
+    let mut recovered_nodes = 0;
+    // Each restarted node finishes a second time.
+    let mut expected_finishes = num_nodes;
+    // If the failed nodes are restarted, consensus can recover even if it stalls while they are
+    // down.
+    let expect_failure =
+        options.restart_failed_after.is_none() && num_fail_nodes as usize > num_nodes - threshold;
     println!(
         "Waiting for validators to finish ({}/{}/{})",
         num_nodes, num_fail_nodes, threshold
     );
-    while succeeded_nodes < threshold && finished_nodes < num_nodes {
+    while (succeeded_nodes < threshold
+        || restarted.len() + pending_restarts.len() > recovered_nodes)
+        && finished_nodes < expected_finishes
+    {
         // If the consensus is expected to fail, not all processes will complete.
         if expect_failure && (finished_nodes >= num_fail_nodes as usize) {
             break;
         }
         // Pause before checking the exit status.
         sleep(Duration::from_secs(10)).await;
-        for ((id, mut p), output) in core::mem::take(&mut processes)
-            .into_iter()
-            .zip(core::mem::take(&mut outputs))
-        {
-            match p.try_wait() {
+        for mut node in core::mem::take(&mut nodes) {
+            let id = node.id;
+            match node.process.try_wait() {
                 Ok(Some(_)) => {
                     // Check whether the commitments are the same.
                     if options.num_txns.is_some() {
-                        let lines = output.await;
-                        if id < first_fail_id as usize {
-                            for line in lines {
-                                if line.starts_with("Final commitment:") {
-                                    let strs: Vec<&str> = line.split(' ').collect();
-                                    let final_commitment = strs.last().unwrap_or_else(|| {
-                                        panic!("Failed to parse commitment for node {}", id)
-                                    });
+                        let events = node.events().await;
+                        if id < first_fail_id as usize || restarted.contains(&id) {
+                            for event in events {
+                                if let ProgressEvent::Finished {
+                                    commitment: Some(final_commitment),
+                                } = event
+                                {
                                     println!(
                                         "Validator {} finished with commitment {}",
                                         id, final_commitment
                                     );
-                                    if let Some(comm) = commitment.clone() {
-                                        assert_eq!(comm, final_commitment.to_string());
+                                    if let Some(comm) = &commitment {
+                                        assert_eq!(*comm, final_commitment);
                                     } else {
-                                        commitment = Some(final_commitment.to_string());
+                                        commitment = Some(final_commitment);
                                     }
                                     succeeded_nodes += 1;
+                                    if restarted.contains(&id) {
+                                        recovered_nodes += 1;
+                                    }
                                 }
                             }
                         } else {
                             println!("Validator {} finished", id);
+                            left_nodes += 1;
+                            if let Some(delay) = options.restart_failed_after {
+                                pending_restarts.push((id, Instant::now() + delay));
+                                expected_finishes += 1;
+                            }
                         }
                     }
                     finished_nodes += 1;
                 }
                 Ok(None) => {
-                    // Add back unfinished process and output.
-                    processes.push((id, p));
-                    outputs.push(output);
+                    // Add back unfinished process.
+                    nodes.push(node);
                 }
                 Err(e) => {
                     println!("Error attempting to wait for validator {}: {}", id, e);
-                    // Add back unfinished process and output.
-                    processes.push((id, p));
-                    outputs.push(output);
+                    // Add back unfinished process.
+                    nodes.push(node);
                 }
             }
         }
+
+        // Once a node has left and the network has made enough progress, start the joining nodes
+        // from the state of the node which left. We wait for the node to exit so that its store is
+        // consistent.
+        if !joined
+            && left_nodes > 0
+            && progress.load(Ordering::SeqCst) >= options.join_after_txn.unwrap()
+        {
+            let archive = TempDir::new("espresso-archive").expect("Failed to create archive");
+            export_state(&node_store_path(&options, first_fail_id), archive.path())
+                .expect("Failed to export state");
+            for id in first_join_id..first_fail_id {
+                let store_path = bootstrap_store_path(&options, id);
+                import_state(archive.path(), &store_path, true).expect("Failed to import state");
+                println!("Validator {} joining", id);
+                nodes.push(spawn_node(id, options.num_txns, Some(&store_path)));
+            }
+            joined = true;
+        }
+
+        // Relaunch failed nodes from their own stores once their downtime has passed.
+        let now = Instant::now();
+        let (due, waiting): (Vec<_>, Vec<_>) = pending_restarts
+            .into_iter()
+            .partition(|(_, at): &(usize, Instant)| *at <= now);
+        pending_restarts = waiting;
+        for (id, _) in due {
+            println!("Validator {} restarting", id);
+            nodes.push(spawn_node(
+                id,
+                options.num_txns,
+                Some(&node_store_path(&options, id)),
+            ));
+            restarted.insert(id);
+        }
     }
 
     // Kill processes that are still running.
-    for (id, mut p) in processes {
-        p.kill()
-            .unwrap_or_else(|_| panic!("Failed to kill node {}", id));
-        p.wait()
-            .unwrap_or_else(|_| panic!("Failed to wait for node {} to exit", id));
+    for mut node in nodes {
+        node.process
+            .kill()
+            .unwrap_or_else(|_| panic!("Failed to kill node {}", node.id));
+        node.process
+            .wait()
+            .unwrap_or_else(|_| panic!("Failed to wait for node {} to exit", node.id));
     }
     if let Some(mut p) = cdn {
         p.kill().expect("Failed to kill CDN");
@@ -362,6 +589,8 @@ async fn main() {
 
     // Check whether the number of succeeded nodes meets the threshold.
     assert!(succeeded_nodes >= threshold);
+    // Check that every restarted node caught up to the same commitment as the healthy nodes.
+    assert_eq!(recovered_nodes, restarted.len() + pending_restarts.len());
     println!("Consensus completed for all nodes")
 }
 
@@ -378,6 +607,7 @@ mod test {
         fail_after_txn: u64,
         expect_success: bool,
         libp2p: bool,
+        extra_args: &[&str],
     ) {
         println!(
             "Testing {} txns with {}/{} nodes failed after txn {}",
@@ -433,6 +663,7 @@ mod test {
         if libp2p {
             args.push("--libp2p");
         }
+        args.extend(extra_args);
         let now = Instant::now();
         let status = cargo_run("multi-machine-automation")
             .args(args)
@@ -449,9 +680,9 @@ mod test {
     // This test is disabled until the libp2p networking implementation is fixed.
     #[async_std::test]
     async fn test_automation_libp2p() {
-        automate(7, 5, 1, 3, true, true).await;
-        automate(7, 5, 3, 1, false, true).await;
-        automate(11, 2, 0, 0, true, true).await;
+        automate(7, 5, 1, 3, true, true, &[]).await;
+        automate(7, 5, 3, 1, false, true, &[]).await;
+        automate(11, 2, 0, 0, true, true, &[]).await;
 
         // Disabling the following test cases to avoid exceeding the time limit.
         // automate(5, 0, 0, true).await;
@@ -461,8 +692,41 @@ mod test {
 
     #[async_std::test]
     async fn test_automation_cdn() {
-        automate(7, 5, 1, 3, true, false).await;
-        automate(7, 5, 3, 1, false, false).await;
-        automate(11, 2, 0, 0, true, false).await;
+        automate(7, 5, 1, 3, true, false, &[]).await;
+        automate(7, 5, 3, 1, false, false, &[]).await;
+        automate(11, 2, 0, 0, true, false, &[]).await;
+    }
+
+    #[async_std::test]
+    async fn test_automation_join() {
+        // One node leaves after 2 transactions, and another joins from its state after 3.
+        automate(7, 5, 1, 2, true, false, &["--join-after-txn", "3"]).await;
+    }
+
+    #[async_std::test]
+    async fn test_automation_restart() {
+        // Enough nodes fail to stall consensus, but they recover from their stores.
+        automate(7, 5, 3, 2, true, false, &["--restart-failed-after", "10s"]).await;
+    }
+
+    #[cfg(feature = "testing")]
+    #[async_std::test]
+    async fn test_automation_faults() {
+        // Drop everything node 6 sends for a few rounds and make every link a bit lossy and slow.
+        // The remaining nodes still form a quorum, so consensus must make progress.
+        let dir = TempDir::new("test_automation_faults").unwrap();
+        let path = dir.path().join("faults.json");
+        let rules = serde_json::json!([
+            {
+                "from_round": 1,
+                "to_round": 3,
+                "links": (0..6).map(|i| (6, i)).collect::<Vec<_>>(),
+                "partition": true,
+            },
+            { "drop_rate": 0.05, "delay_ms": 50 },
+        ]);
+        std::fs::write(&path, rules.to_string()).unwrap();
+        let path = path.display().to_string();
+        automate(7, 5, 0, 0, true, false, &["--fault-config", &path]).await;
     }
 }
@@ -0,0 +1,263 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Network fault injection, for testing liveness and safety under adverse network conditions.
+//!
+//! This module is only built with the `testing` feature, so production validators cannot be
+//! configured to drop or delay consensus messages.
+
+use crate::network::HybridNetwork;
+use crate::node_impl::SignatureKey;
+use async_std::{sync::Arc, task::sleep};
+use async_trait::async_trait;
+use espresso_core::{state::ValidatorState, StakingKey};
+use hotshot::{
+    traits::{NetworkError, NetworkingImplementation},
+    types::Message,
+};
+use hotshot_types::traits::network::NetworkChange;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// A fault to inject into the messages between some nodes for a range of rounds.
+///
+/// Fault configurations are JSON lists of rules, like
+/// ```json
+/// [
+///     { "from_round": 2, "to_round": 5, "links": [[0, 1], [1, 0]], "partition": true },
+///     { "from_round": 0, "drop_rate": 0.1, "delay_ms": 200 }
+/// ]
+/// ```
+/// A round is a committed block: the rule applies while the latest block height seen by the
+/// receiving node is in `from_round..to_round`. A node which is cut off from a quorum stops seeing
+/// new blocks, so `to_round` cannot be relied on to heal a partition of the links _to_ such a node.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct FaultRule {
+    #[serde(default)]
+    pub from_round: u64,
+    /// The first round in which the rule no longer applies. If not given, the rule applies
+    /// forever.
+    #[serde(default)]
+    pub to_round: Option<u64>,
+    /// `(sender, receiver)` pairs of node IDs whose messages are affected. If empty, messages
+    /// between all nodes are affected.
+    #[serde(default)]
+    pub links: Vec<(usize, usize)>,
+    /// Fraction of messages to drop, between 0 and 1.
+    #[serde(default)]
+    pub drop_rate: f64,
+    /// Time by which to delay each message.
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// Drop all messages.
+    #[serde(default)]
+    pub partition: bool,
+}
+
+impl FaultRule {
+    fn applies(&self, round: u64, sender: usize, receiver: usize) -> bool {
+        round >= self.from_round
+            && self.to_round.map_or(true, |to| round < to)
+            && (self.links.is_empty() || self.links.contains(&(sender, receiver)))
+    }
+}
+
+/// Load fault rules from a JSON file.
+pub fn load_fault_rules(path: &Path) -> io::Result<Vec<FaultRule>> {
+    serde_json::from_slice(&fs::read(path)?)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Applies [FaultRule]s to the messages received by a node.
+#[derive(Debug)]
+pub struct FaultInjector {
+    node_id: usize,
+    known_nodes: Vec<SignatureKey>,
+    rules: Vec<FaultRule>,
+    round: AtomicU64,
+}
+
+impl FaultInjector {
+    pub fn new(node_id: usize, known_nodes: Vec<StakingKey>, rules: Vec<FaultRule>) -> Self {
+        Self {
+            node_id,
+            known_nodes: known_nodes.into_iter().map(SignatureKey::from).collect(),
+            rules,
+            round: AtomicU64::new(0),
+        }
+    }
+
+    /// Record that the node has reached `round`.
+    pub fn set_round(&self, round: u64) {
+        self.round.fetch_max(round, Ordering::SeqCst);
+    }
+
+    /// How long to delay a message from `sender`, or [None] if it should be dropped.
+    fn fate(&self, sender: &SignatureKey) -> Option<Duration> {
+        let sender = match self.known_nodes.iter().position(|key| key == sender) {
+            Some(sender) if sender != self.node_id => sender,
+            // Messages from ourselves or from unknown nodes are not affected.
+            _ => return Some(Duration::ZERO),
+        };
+        let round = self.round.load(Ordering::SeqCst);
+        let mut delay = Duration::ZERO;
+        for rule in &self.rules {
+            if !rule.applies(round, sender, self.node_id) {
+                continue;
+            }
+            if rule.partition || rand::thread_rng().gen_bool(rule.drop_rate.clamp(0.0, 1.0)) {
+                return None;
+            }
+            delay = delay.max(Duration::from_millis(rule.delay_ms));
+        }
+        Some(delay)
+    }
+
+    async fn filter(
+        &self,
+        messages: Vec<Message<ValidatorState, SignatureKey>>,
+    ) -> Vec<Message<ValidatorState, SignatureKey>> {
+        let mut delay = Duration::ZERO;
+        let messages = messages
+            .into_iter()
+            .filter(|message| match self.fate(&message.sender) {
+                Some(d) => {
+                    delay = delay.max(d);
+                    true
+                }
+                None => false,
+            })
+            .collect();
+        if !delay.is_zero() {
+            sleep(delay).await;
+        }
+        messages
+    }
+
+    async fn admit(&self, message: &Message<ValidatorState, SignatureKey>) -> bool {
+        match self.fate(&message.sender) {
+            Some(delay) => {
+                if !delay.is_zero() {
+                    sleep(delay).await;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// A [HybridNetwork] which can inject faults into the messages it receives, for testing.
+///
+/// Faults are applied by the receiver, so each node must be given the rules for the links to it.
+#[derive(Clone, Debug)]
+pub struct FaultyNetwork {
+    inner: HybridNetwork,
+    faults: Option<Arc<FaultInjector>>,
+}
+
+impl FaultyNetwork {
+    pub fn new(inner: HybridNetwork, faults: Option<Arc<FaultInjector>>) -> Self {
+        Self { inner, faults }
+    }
+}
+
+#[async_trait]
+impl NetworkingImplementation<Message<ValidatorState, SignatureKey>, SignatureKey>
+    for FaultyNetwork
+{
+    async fn ready(&self) -> bool {
+        self.inner.ready().await
+    }
+
+    async fn broadcast_message(
+        &self,
+        message: Message<ValidatorState, SignatureKey>,
+    ) -> Result<(), NetworkError> {
+        self.inner.broadcast_message(message).await
+    }
+
+    async fn message_node(
+        &self,
+        message: Message<ValidatorState, SignatureKey>,
+        recipient: SignatureKey,
+    ) -> Result<(), NetworkError> {
+        self.inner.message_node(message, recipient).await
+    }
+
+    async fn broadcast_queue(
+        &self,
+    ) -> Result<Vec<Message<ValidatorState, SignatureKey>>, NetworkError> {
+        let messages = self.inner.broadcast_queue().await?;
+        Ok(match &self.faults {
+            Some(faults) => faults.filter(messages).await,
+            None => messages,
+        })
+    }
+
+    async fn next_broadcast(&self) -> Result<Message<ValidatorState, SignatureKey>, NetworkError> {
+        loop {
+            let message = self.inner.next_broadcast().await?;
+            match &self.faults {
+                Some(faults) if !faults.admit(&message).await => continue,
+                _ => return Ok(message),
+            }
+        }
+    }
+
+    async fn direct_queue(
+        &self,
+    ) -> Result<Vec<Message<ValidatorState, SignatureKey>>, NetworkError> {
+        let messages = self.inner.direct_queue().await?;
+        Ok(match &self.faults {
+            Some(faults) => faults.filter(messages).await,
+            None => messages,
+        })
+    }
+
+    async fn next_direct(&self) -> Result<Message<ValidatorState, SignatureKey>, NetworkError> {
+        loop {
+            let message = self.inner.next_direct().await?;
+            match &self.faults {
+                Some(faults) if !faults.admit(&message).await => continue,
+                _ => return Ok(message),
+            }
+        }
+    }
+
+    async fn known_nodes(&self) -> Vec<SignatureKey> {
+        self.inner.known_nodes().await
+    }
+
+    async fn network_changes(&self) -> Result<Vec<NetworkChange<SignatureKey>>, NetworkError> {
+        self.inner.network_changes().await
+    }
+
+    async fn shut_down(&self) -> () {
+        self.inner.shut_down().await
+    }
+
+    async fn put_record(
+        &self,
+        key: impl Serialize + Send + Sync + 'static,
+        value: impl Serialize + Send + Sync + 'static,
+    ) -> Result<(), NetworkError> {
+        self.inner.put_record(key, value).await
+    }
+
+    async fn get_record<V: (for<'a> Deserialize<'a>)>(
+        &self,
+        key: impl Serialize + Send + Sync + 'static,
+    ) -> Result<V, NetworkError> {
+        self.inner.get_record(key).await
+    }
+
+    async fn notify_of_subsequent_leader(&self, pk: SignatureKey, cancelled: Arc<AtomicBool>) {
+        self.inner.notify_of_subsequent_leader(pk, cancelled).await
+    }
+}
@@ -7,13 +7,14 @@
 use ark_serialize::*;
 use ark_std::rand::{CryptoRng, RngCore};
 use async_std::sync::{Arc, RwLock};
-use async_std::task::spawn;
+use async_std::task::{sleep, spawn};
 use clap::Parser;
 use cld::ClDuration;
 use dirs::data_local_dir;
 use espresso_core::kv_merkle_tree::KVMerkleTree;
+use espresso_core::mempool::{BlockPolicy, FeeSchedule, MempoolConfig};
 use espresso_core::reward::{
-    eligibility, CollectRewardNote, CollectedRewards, CollectedRewardsSet,
+    eligibility, insert_collected_rewards, CollectRewardNote, CollectedRewards, CollectedRewardsSet,
 };
 use espresso_core::stake_table::StakingKey;
 use espresso_core::state::{
@@ -24,13 +25,14 @@ use espresso_core::{
     stake_table::{StakeTableHash, StakingPrivKey},
     state::{
         ChainVariables, ElaboratedBlock, ElaboratedTransaction, LWPersistence, ValidatorState,
+        VerifierKeyUpgrade,
     },
     universal_params::VERIF_CRS,
 };
 use espresso_esqs::full_node::{self};
 use espresso_esqs::full_node_data_source::QueryData;
 use espresso_validator_api::data_source::ValidatorDataSource;
-use futures::{select, Future, FutureExt};
+use futures::{select, Future, FutureExt, StreamExt};
 use hotshot::types::{ed25519::Ed25519Priv, EventType};
 use hotshot::{
     traits::{
@@ -53,6 +55,7 @@ use node_impl::{SignatureKey, ValidatorNodeImpl};
 use rand_chacha::{rand_core::SeedableRng as _, ChaChaRng};
 use snafu::Snafu;
 use static_assertions::const_assert;
+use std::cmp::max;
 use std::collections::BTreeMap;
 use std::env;
 use std::fmt::{self, Display, Formatter};
@@ -65,8 +68,13 @@ use std::time::Duration;
 use tracing::{debug, event, Level};
 use url::Url;
 
+pub mod archive;
+#[cfg(feature = "testing")]
+mod faults;
+pub mod metrics;
 mod network;
 pub mod node_impl;
+pub mod progress;
 #[cfg(any(test, feature = "testing"))]
 pub mod testing;
 pub mod validator;
@@ -394,6 +402,84 @@ pub struct NodeOpt {
     )]
     pub max_transactions: NonZeroUsize,
 
+    /// Maximum number of pending transactions held in the mempool.
+    ///
+    /// When the mempool is full, a new transaction is only accepted if it pays a higher fee than
+    /// the lowest-fee pending transaction, which it replaces.
+    #[arg(
+        long,
+        env = "ESPRESSO_VALIDATOR_MEMPOOL_CAPACITY",
+        default_value = "10000"
+    )]
+    pub mempool_capacity: usize,
+
+    /// Maximum number of pending transactions from a single sender.
+    #[arg(
+        long,
+        env = "ESPRESSO_VALIDATOR_MEMPOOL_MAX_PER_SENDER",
+        default_value = "100"
+    )]
+    pub mempool_max_per_sender: usize,
+
+    /// How long a transaction may wait in the mempool before it is dropped.
+    #[arg(
+        long,
+        env = "ESPRESSO_VALIDATOR_MEMPOOL_EXPIRATION",
+        default_value = "10m",
+        value_parser = parse_duration
+    )]
+    pub mempool_expiration: Duration,
+
+    /// How long a transaction forwarded to consensus may go uncommitted before it is proposed
+    /// again.
+    #[arg(
+        long,
+        env = "ESPRESSO_VALIDATOR_MEMPOOL_RESUBMIT_AFTER",
+        default_value = "1m",
+        value_parser = parse_duration
+    )]
+    pub mempool_resubmit_after: Duration,
+
+    /// Minimum fee per unit of transaction weight required for admission to the mempool.
+    ///
+    /// The weight of a transaction grows with its number of inputs and outputs. Transactions paying
+    /// less than this times their weight are rejected. Reward transactions have no weight.
+    #[arg(
+        long,
+        env = "ESPRESSO_VALIDATOR_MIN_FEE_PER_WEIGHT",
+        default_value = "0"
+    )]
+    pub min_fee_per_weight: u128,
+
+    /// Forward pending transactions to consensus once this many are waiting.
+    ///
+    /// Together with `flush-bytes` and `flush-delay`, this controls how transactions are batched
+    /// before they are proposed: a batch is forwarded as soon as any of the three limits is
+    /// reached. The default of 1 forwards every transaction as soon as it is received.
+    #[arg(
+        long,
+        env = "ESPRESSO_VALIDATOR_FLUSH_TRANSACTIONS",
+        default_value = "1"
+    )]
+    pub flush_transactions: NonZeroUsize,
+
+    /// Forward pending transactions to consensus once this many bytes are waiting.
+    #[arg(
+        long,
+        env = "ESPRESSO_VALIDATOR_FLUSH_BYTES",
+        default_value = "1048576"
+    )]
+    pub flush_bytes: usize,
+
+    /// Forward pending transactions to consensus once the oldest has waited this long.
+    #[arg(
+        long,
+        env = "ESPRESSO_VALIDATOR_FLUSH_DELAY",
+        default_value = "1s",
+        value_parser = parse_duration
+    )]
+    pub flush_delay: Duration,
+
     /// Unique identifier for this instance of Espresso.
     #[arg(long, env = "ESPRESSO_VALIDATOR_CHAIN_ID", default_value = "0")]
     pub chain_id: u16,
@@ -412,6 +498,35 @@ pub struct NodeOpt {
     #[arg(long, env = "ESPRESSO_VALIDATOR_REWARDS_PUB_KEY")]
     pub rewards_pub_key: Option<UserPubKey>,
 
+    /// Port on which to serve Prometheus metrics at `/metrics`.
+    ///
+    /// If not provided, metrics are not served.
+    #[arg(long, env = "ESPRESSO_VALIDATOR_METRICS_PORT")]
+    pub metrics_port: Option<u16>,
+
+    /// JSON file of network faults to inject into the messages received by this node.
+    ///
+    /// This is for testing liveness and safety under adverse network conditions, and is only
+    /// available with the `testing` feature. See [faults::FaultRule] for the format.
+    #[cfg(feature = "testing")]
+    #[arg(long, env = "ESPRESSO_VALIDATOR_FAULT_CONFIG")]
+    pub fault_config: Option<PathBuf>,
+
+    /// Accept new transaction arities from a given block height.
+    ///
+    /// The format is `HEIGHT:SIZES`, where `SIZES` is a comma-separated list of transfer arities
+    /// `INPUTSxOUTPUTS` and freeze arities `fINPUTS`, for example `1000:2x3,3x3,f4`. This option may
+    /// be passed multiple times to schedule several upgrades, at different heights. The upgrades
+    /// are part of the genesis block, so every node on the chain must be started with the same
+    /// upgrades, just as with any other genesis option.
+    #[arg(
+        long,
+        env = "ESPRESSO_VALIDATOR_VERIF_KEY_UPGRADES",
+        value_delimiter = ';',
+        value_parser = parse_verif_key_upgrade
+    )]
+    pub verif_key_upgrade: Vec<VerifierKeyUpgrade>,
+
     /// Whether to color log output with ANSI color codes.
     #[arg(long, env = "ESPRESSO_COLORED_LOGS")]
     pub colored_logs: bool,
@@ -434,6 +549,47 @@ pub fn parse_duration(s: &str) -> Result<Duration, ParseDurationError> {
         })
 }
 
+#[derive(Clone, Debug, Snafu)]
+#[snafu(display("invalid verifier key upgrade {}: {}", upgrade, reason))]
+pub struct ParseVerifKeyUpgradeError {
+    upgrade: String,
+    reason: String,
+}
+
+/// Parse a [VerifierKeyUpgrade] from a string of the form `HEIGHT:SIZES`.
+///
+/// See [NodeOpt::verif_key_upgrade] for the format.
+pub fn parse_verif_key_upgrade(s: &str) -> Result<VerifierKeyUpgrade, ParseVerifKeyUpgradeError> {
+    let err = |reason: &str| ParseVerifKeyUpgradeError {
+        upgrade: s.to_string(),
+        reason: reason.to_string(),
+    };
+    let (height, sizes) = s
+        .split_once(':')
+        .ok_or_else(|| err("expected HEIGHT:SIZES"))?;
+    let mut upgrade = VerifierKeyUpgrade {
+        height: height.parse().map_err(|_| err("invalid height"))?,
+        transfer_sizes: vec![],
+        freeze_sizes: vec![],
+    };
+    for size in sizes.split(',') {
+        if let Some(inputs) = size.strip_prefix('f') {
+            upgrade
+                .freeze_sizes
+                .push(inputs.parse().map_err(|_| err("invalid freeze arity"))?);
+        } else {
+            let (inputs, outputs) = size
+                .split_once('x')
+                .ok_or_else(|| err("expected INPUTSxOUTPUTS or fINPUTS"))?;
+            upgrade.transfer_sizes.push((
+                inputs.parse().map_err(|_| err("invalid transfer arity"))?,
+                outputs.parse().map_err(|_| err("invalid transfer arity"))?,
+            ));
+        }
+    }
+    Ok(upgrade)
+}
+
 impl NodeOpt {
     pub fn new(id: usize, num_nodes: usize) -> Self {
         Self::parse_from(vec![
@@ -466,6 +622,18 @@ impl NodeOpt {
         if self.next_view_timeout <= self.round_start_delay {
             return Err("next view timeout must be greater than round start delay".into());
         }
+        let mut heights = self
+            .verif_key_upgrade
+            .iter()
+            .map(|upgrade| upgrade.height)
+            .collect::<Vec<_>>();
+        heights.sort_unstable();
+        if let Some(pair) = heights.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(format!(
+                "more than one verifier key upgrade at block {}",
+                pair[0]
+            ));
+        }
         Ok(())
     }
 }
@@ -511,7 +679,7 @@ pub fn project_path() -> PathBuf {
 }
 
 /// Returns the default directory to store persistence files.
-fn default_store_path(node_id: usize) -> PathBuf {
+pub fn default_store_path(node_id: usize) -> PathBuf {
     let mut data_dir = data_local_dir()
         .unwrap_or_else(|| env::current_dir().unwrap_or_else(|_| PathBuf::from("./")));
     data_dir.push("espresso");
@@ -536,6 +704,9 @@ fn get_secret_key_seed(seed: Option<SecretKeySeed>) -> [u8; 32] {
         .into()
 }
 
+#[cfg(feature = "testing")]
+type Network = faults::FaultyNetwork;
+#[cfg(not(feature = "testing"))]
 type Network = network::HybridNetwork;
 type Storage = MemoryStorage<ValidatorState>;
 pub type Consensus = HotShotHandle<ValidatorNodeImpl<Network, Storage>>;
@@ -565,10 +736,22 @@ pub fn genesis(node_opt: &NodeOpt) -> GenesisNote {
         })
         .collect();
 
+    let mut chain = ChainVariables::new(node_opt.chain_id, VERIF_CRS.clone(), COMMITTEE_SIZE);
+    for upgrade in &node_opt.verif_key_upgrade {
+        event!(
+            Level::INFO,
+            "scheduling verifier key upgrade at block {}",
+            upgrade.height
+        );
+        if let Err(err) = chain.schedule_verif_crs(upgrade.clone()) {
+            panic!("invalid verifier key upgrade: {}", err);
+        }
+    }
+
     // generate keys
     let known_nodes = gen_keys(node_opt.secret_key_seed, node_opt.num_nodes);
     GenesisNote::new(
-        ChainVariables::new(node_opt.chain_id, VERIF_CRS.clone(), COMMITTEE_SIZE),
+        chain,
         Arc::new(faucet_records),
         initialize_stake_table(
             known_nodes
@@ -595,12 +778,7 @@ async fn init_hotshot(
     priv_key: StakingPrivKey,
     networking: Network,
     genesis: GenesisNote,
-) -> (
-    Consensus,
-    KVMerkleProof<StakeTableHash>,
-    Amount,
-    CollectedRewardsSet,
-) {
+) -> (Consensus, KVMerkleProof<StakeTableHash>, Amount, u64) {
     // Create the initial hotshot
     let stake_distribution = known_nodes
         .iter()
@@ -636,41 +814,39 @@ async fn init_hotshot(
 
     let storage = get_store_dir(node_opt);
     let storage_path = Path::new(&storage);
-    let (lw_persistence, collected_rewards_set) = if node_opt.reset_store_state {
+    let lw_persistence = if node_opt.reset_store_state {
         debug!("Initializing new session");
-        (
-            LWPersistence::new(storage_path, "validator").unwrap(),
-            CollectedRewardsSet::EmptySubtree,
-        )
+        LWPersistence::new(storage_path, "validator").unwrap()
     } else {
         debug!("Restoring from persisted session");
-        let lw_persistence = LWPersistence::load(storage_path, "validator").unwrap();
-        if lw_persistence.load_latest_leaf().is_ok() {
-            panic!("unimplemented") // TODO not storing CollectedRewardSet yet + catchup not implemented
-        } else {
-            (lw_persistence, CollectedRewardsSet::EmptySubtree)
-        }
+        LWPersistence::load(storage_path, "validator").unwrap()
     };
-    let stake_table_list = genesis.stake_table.clone();
-    let genesis_vrf_seed = genesis.chain.vrf_seed;
-    let genesis = ElaboratedBlock::genesis(genesis);
-    let (initializer, stake_proof, stake_amount) = match lw_persistence.load_latest_leaf() {
-        Ok(_state) => {
-            panic!("");
-        }
-        Err(_) => {
-            let initializer = HotShotInitializer::from_genesis(genesis).unwrap();
-            let mut stake_table = KVMerkleTree::<StakeTableHash>::default();
-            for (key, amount) in stake_table_list.iter() {
-                stake_table.insert(key.clone(), *amount);
-            }
-
-            let (amount, stake_proof) = stake_table
-                .lookup(StakingKey::from_private(&priv_key))
-                .unwrap();
+    // Stake cannot be transferred yet, so the stake table is always the genesis stake table, even
+    // when we are restoring a later state.
+    let mut stake_table = KVMerkleTree::<StakeTableHash>::default();
+    for (key, amount) in genesis.stake_table.iter() {
+        stake_table.insert(key.clone(), *amount);
+    }
+    let (stake_amount, stake_proof) = stake_table
+        .lookup(StakingKey::from_private(&priv_key))
+        .unwrap();
+    let stake_amount = stake_amount.unwrap();
 
-            (initializer, stake_proof, amount.unwrap())
+    let genesis_vrf_seed = genesis.chain.vrf_seed;
+    let (initializer, block_height) = match lw_persistence.load_latest_leaf() {
+        Ok(leaf) => {
+            debug!(
+                "Restoring consensus from block {} (state {})",
+                leaf.state.block_height,
+                leaf.state.commit()
+            );
+            let block_height = leaf.state.block_height;
+            (HotShotInitializer::from_reload(leaf), block_height)
         }
+        Err(_) => (
+            HotShotInitializer::from_genesis(ElaboratedBlock::genesis(genesis)).unwrap(),
+            0,
+        ),
     };
 
     let hotshot = HotShot::init(
@@ -688,7 +864,7 @@ async fn init_hotshot(
     lw_persistence.launch(hotshot.clone().into_stream());
 
     debug!("Hotshot online!");
-    (hotshot, stake_proof, stake_amount, collected_rewards_set)
+    (hotshot, stake_proof, stake_amount, block_height)
 }
 
 pub async fn run_consensus<F: Send + Future>(mut consensus: Consensus, kill: F) {
@@ -737,6 +913,8 @@ pub fn gen_keys(seed: Option<SecretKeySeed>, num_nodes: usize) -> Vec<StakingPri
         })
         .collect()
 }
+
+/// Start a validator, along with the full node data source it keeps up to date.
 #[allow(clippy::too_many_arguments)]
 pub async fn init_validator<R: CryptoRng + RngCore + Send + 'static>(
     rng: R,
@@ -744,7 +922,7 @@ pub async fn init_validator<R: CryptoRng + RngCore + Send + 'static>(
     priv_key: StakingPrivKey,
     pub_keys: Vec<StakingKey>,
     genesis: GenesisNote,
-) -> Consensus {
+) -> (Consensus, Arc<RwLock<QueryData>>) {
     debug!("Current node: {}", node_opt.id);
 
     let num_bootstrap = node_opt.bootstrap_nodes.len();
@@ -799,11 +977,13 @@ pub async fn init_validator<R: CryptoRng + RngCore + Send + 'static>(
     };
 
     let own_network = match node_opt.cdn.clone() {
-        Some(cdn) if !node_opt.libp2p => Network::new_cdn(pub_keys.clone(), cdn, node_opt.id)
-            .await
-            .unwrap(),
+        Some(cdn) if !node_opt.libp2p => {
+            network::HybridNetwork::new_cdn(pub_keys.clone(), cdn, node_opt.id)
+                .await
+                .unwrap()
+        }
         _ => {
-            let network = Network::new_p2p(
+            let network = network::HybridNetwork::new_p2p(
                 pub_keys[node_opt.id].clone(),
                 to_connect_addrs,
                 node_type,
@@ -818,7 +998,7 @@ pub async fn init_validator<R: CryptoRng + RngCore + Send + 'static>(
                 // If there is a centralized server, use it as a barrier, so we don't proceed beyond
                 // this point until all nodes have reached this point and connected to the server.
                 // We will still use the libp2p network for consensus itself.
-                Network::new_cdn(pub_keys.clone(), cdn, node_opt.id)
+                network::HybridNetwork::new_cdn(pub_keys.clone(), cdn, node_opt.id)
                     .await
                     .unwrap();
             }
@@ -827,12 +1007,26 @@ pub async fn init_validator<R: CryptoRng + RngCore + Send + 'static>(
         }
     };
 
+    #[cfg(feature = "testing")]
+    let faults = node_opt.fault_config.as_ref().map(|path| {
+        let rules = faults::load_fault_rules(path).unwrap_or_else(|err| {
+            panic!("failed to load fault config {}: {}", path.display(), err)
+        });
+        Arc::new(faults::FaultInjector::new(
+            node_opt.id,
+            pub_keys.clone(),
+            rules,
+        ))
+    });
+    #[cfg(feature = "testing")]
+    let own_network = Network::new(own_network, faults.clone());
+
     let known_nodes = pub_keys.clone();
 
     debug!("All nodes connected to network");
 
     // Initialize the state and hotshot
-    let (hotshot, stake_proof, stake_amount, collected_rewards) = init_hotshot(
+    let (hotshot, stake_proof, stake_amount, block_height) = init_hotshot(
         node_opt,
         known_nodes,
         priv_key.clone(),
@@ -841,8 +1035,29 @@ pub async fn init_validator<R: CryptoRng + RngCore + Send + 'static>(
     )
     .await;
 
+    #[cfg(feature = "testing")]
+    if let Some(faults) = faults {
+        // Fault rules are scheduled by round, so keep the injector up to date with the height of
+        // the chain.
+        let mut events = hotshot.clone().into_stream();
+        spawn(async move {
+            while let Some(event) = events.next().await {
+                if let EventType::Decide { leaf_chain } = event {
+                    if let Some(leaf) = leaf_chain.first() {
+                        faults.set_round(leaf.state.block_height);
+                    }
+                }
+            }
+        });
+    }
+
+    let data_source = open_data_source(node_opt, hotshot.clone(), priv_key.clone());
+
     if let Some(rewards_pub_key) = node_opt.rewards_pub_key.clone() {
         tracing::info!("spawning reward daemon: {:?}", rewards_pub_key);
+        // Only the root of the collected rewards set is part of the consensus state, so when we
+        // restore from the store we rebuild the set from the stored blocks.
+        let collected_rewards = data_source.read().await.collected_rewards(block_height);
         spawn(collect_reward_daemon(
             rng,
             stake_proof,
@@ -854,19 +1069,60 @@ pub async fn init_validator<R: CryptoRng + RngCore + Send + 'static>(
         ));
     }
 
-    hotshot
+    (hotshot, data_source)
 }
 
-pub fn open_data_source(node_opt: &NodeOpt, consensus: Consensus) -> Arc<RwLock<QueryData>> {
+fn open_data_source(
+    node_opt: &NodeOpt,
+    consensus: Consensus,
+    priv_key: StakingPrivKey,
+) -> Arc<RwLock<QueryData>> {
     let storage = get_store_dir(node_opt);
-    Arc::new(RwLock::new(if node_opt.reset_store_state {
+    let data_source = if node_opt.reset_store_state {
         QueryData::new(&storage, Box::new(consensus), node_opt.location.clone()).unwrap()
     } else {
         QueryData::load(&storage, Box::new(consensus), node_opt.location.clone()).unwrap()
-    }))
+    };
+    let mempool_config = MempoolConfig {
+        capacity: node_opt.mempool_capacity,
+        max_per_sender: node_opt.mempool_max_per_sender,
+        expiration: node_opt.mempool_expiration,
+        resubmit_after: node_opt.mempool_resubmit_after,
+        fee_schedule: FeeSchedule {
+            min_fee_per_weight: node_opt.min_fee_per_weight,
+        },
+    };
+    let block_policy = BlockPolicy {
+        max_transactions: node_opt.flush_transactions.get(),
+        max_bytes: node_opt.flush_bytes,
+        max_delay: node_opt.flush_delay,
+    };
+    let data_source = Arc::new(RwLock::new(
+        data_source
+            .with_mempool_config(mempool_config)
+            .with_block_policy(block_policy)
+            .with_snapshot_key(priv_key),
+    ));
+    // Batches that never fill up, transactions which could not be forwarded to consensus, and
+    // transactions which were forwarded but never committed are flushed on a timer.
+    let interval = if node_opt.flush_transactions.get() > 1 {
+        max(node_opt.flush_delay / 4, Duration::from_millis(10))
+    } else {
+        Duration::from_secs(1)
+    };
+    spawn(flush_mempool_daemon(data_source.clone(), interval));
+    data_source
+}
+
+async fn flush_mempool_daemon(data_source: Arc<RwLock<QueryData>>, interval: Duration) {
+    loop {
+        sleep(interval).await;
+        if let Err(err) = data_source.write().await.flush_mempool().await {
+            tracing::error!("failed to flush mempool: {}", err);
+        }
+    }
 }
 
-#[allow(dead_code)] // FIXME use this function in main
 async fn collect_reward_daemon<R: CryptoRng + RngCore + Send>(
     mut rng: R,
     stake_proof: KVMerkleProof<StakeTableHash>,
@@ -886,9 +1142,12 @@ async fn collect_reward_daemon<R: CryptoRng + RngCore + Send>(
             for leaf in leaf_chain.iter().rev() {
                 tracing::debug!("event received {:?}", leaf);
                 let validator_state = &leaf.state;
-                let blk = &leaf.deltas;
                 let view_number = leaf.justify_qc.view_number;
 
+                // Keep the collected rewards set in sync with the state, so that we can prove a
+                // reward uncollected relative to the latest collected rewards root.
+                insert_collected_rewards(&mut collected_rewards, &leaf.deltas.block.0);
+
                 // 0. check if I'm elected
 
                 if let Some(vrf_proof) = eligibility::prove_eligibility(
@@ -933,19 +1192,7 @@ async fn collect_reward_daemon<R: CryptoRng + RngCore + Send>(
                         .await
                         .expect("Failed to submit reward transaction");
 
-                    // 3. update collected_reward_set
-                    for txn in blk.block.0.iter() {
-                        if let EspressoTransaction::Reward(note) = txn {
-                            let staking_key = note.staking_key();
-                            let collected_reward = CollectedRewards {
-                                staking_key,
-                                time: view_number,
-                            };
-                            collected_rewards.insert(collected_reward, ());
-                        }
-                    }
-
-                    // 4. Check block if contain stake transfer transaction and update stake proof
+                    // 3. Check block if contain stake transfer transaction and update stake proof
                     // TODO we haven't implemented stake transfer yet
                 }
             }
@@ -0,0 +1,257 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Prometheus metrics for a validator node.
+//!
+//! Metrics are collected from the node's HotShot event stream and, for full nodes, from the
+//! mempool, transaction checks and nullifier proof cache of the query service, and are served in
+//! the Prometheus text format at `/metrics`.
+//!
+//! Each node only knows its own view of the chain, so lag between peers is not reported directly.
+//! Instead, every node exports its `espresso_block_height`, and the lag of a node can be computed
+//! when querying, e.g. `max(espresso_block_height) - espresso_block_height`.
+
+use crate::Consensus;
+use async_std::sync::{Arc, Mutex, RwLock};
+use async_std::task::{spawn, JoinHandle};
+use espresso_esqs::full_node_data_source::QueryData;
+use espresso_validator_api::data_source::ValidatorDataSource;
+use futures::StreamExt;
+use hotshot::types::EventType;
+use hotshot_types::traits::signature_key::EncodedPublicKey;
+use std::fmt::Write;
+use std::time::Instant;
+use tide::listener::Listener;
+
+/// Upper bounds, in seconds, of the block latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 9] = [0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    // Cumulative counts for each bucket in [LATENCY_BUCKETS].
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&mut self.buckets) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct MetricsState {
+    blocks_proposed: u64,
+    blocks_committed: u64,
+    transactions_committed: u64,
+    view_timeouts: u64,
+    consensus_errors: u64,
+    block_height: u64,
+    last_commit: Option<Instant>,
+    block_latency: Histogram,
+}
+
+/// Metrics for a single validator.
+#[derive(Default)]
+pub struct Metrics {
+    state: Mutex<MetricsState>,
+    data_source: Option<Arc<RwLock<QueryData>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also report mempool and nullifier proof cache metrics from the query service of a full node.
+    pub fn with_data_source(mut self, data_source: Arc<RwLock<QueryData>>) -> Self {
+        self.data_source = Some(data_source);
+        self
+    }
+
+    /// Update the metrics from the events of `consensus`.
+    ///
+    /// `own_key` identifies blocks proposed by this node.
+    pub fn collect(self: &Arc<Self>, consensus: Consensus, own_key: EncodedPublicKey) {
+        let metrics = self.clone();
+        spawn(async move {
+            let mut events = consensus.into_stream();
+            while let Some(event) = events.next().await {
+                let mut state = metrics.state.lock().await;
+                match event {
+                    EventType::Decide { leaf_chain } => {
+                        for leaf in leaf_chain.iter() {
+                            state.blocks_committed += 1;
+                            state.transactions_committed += leaf.deltas.block.0.len() as u64;
+                            if leaf.proposer_id == own_key {
+                                state.blocks_proposed += 1;
+                            }
+                            state.block_height = state.block_height.max(leaf.state.block_height);
+                        }
+                        // Latency is the time since the previous decide, shared by all of the blocks
+                        // decided together.
+                        let now = Instant::now();
+                        if let Some(last) = state.last_commit.replace(now) {
+                            let latency = now.duration_since(last).as_secs_f64();
+                            for _ in 0..leaf_chain.len() {
+                                state.block_latency.observe(latency);
+                            }
+                        }
+                    }
+                    EventType::NextLeaderViewTimeout { .. } => {
+                        state.view_timeouts += 1;
+                    }
+                    EventType::Error { .. } => {
+                        state.consensus_errors += 1;
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    /// Render the metrics in the Prometheus text format.
+    pub async fn render(&self) -> String {
+        let mut out = String::new();
+        {
+            let state = self.state.lock().await;
+            counter(
+                &mut out,
+                "espresso_blocks_proposed_total",
+                "Committed blocks proposed by this node.",
+                state.blocks_proposed,
+            );
+            counter(
+                &mut out,
+                "espresso_blocks_committed_total",
+                "Blocks committed by consensus.",
+                state.blocks_committed,
+            );
+            counter(
+                &mut out,
+                "espresso_transactions_validated_total",
+                "Transactions in committed blocks.",
+                state.transactions_committed,
+            );
+            counter(
+                &mut out,
+                "espresso_view_timeouts_total",
+                "Consensus views which timed out.",
+                state.view_timeouts,
+            );
+            counter(
+                &mut out,
+                "espresso_consensus_errors_total",
+                "Errors reported by consensus.",
+                state.consensus_errors,
+            );
+            gauge(
+                &mut out,
+                "espresso_block_height",
+                "Height of the latest committed block.",
+                state.block_height,
+            );
+
+            let name = "espresso_block_latency_seconds";
+            writeln!(out, "# HELP {} Time between committed blocks.", name).unwrap();
+            writeln!(out, "# TYPE {} histogram", name).unwrap();
+            let latency = &state.block_latency;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&latency.buckets) {
+                writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count).unwrap();
+            }
+            writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, latency.count).unwrap();
+            writeln!(out, "{}_sum {}", name, latency.sum).unwrap();
+            writeln!(out, "{}_count {}", name, latency.count).unwrap();
+        }
+
+        if let Some(data_source) = &self.data_source {
+            let data_source = data_source.read().await;
+            let mempool = data_source.mempool();
+            gauge(
+                &mut out,
+                "espresso_mempool_depth",
+                "Transactions waiting in the mempool.",
+                mempool.len() as u64,
+            );
+            let name = "espresso_transactions_rejected_total";
+            writeln!(
+                out,
+                "# HELP {} Transactions rejected by the mempool, by error type.",
+                name
+            )
+            .unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            for (kind, count) in mempool.rejected() {
+                writeln!(out, "{}{{error=\"{}\"}} {}", name, kind, count).unwrap();
+            }
+
+            let name = "espresso_transactions_checked_total";
+            writeln!(
+                out,
+                "# HELP {} Mempool transactions checked before proposal, by outcome.",
+                name
+            )
+            .unwrap();
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            for (outcome, count) in data_source.validation_outcomes() {
+                writeln!(out, "{}{{outcome=\"{}\"}} {}", name, outcome, count).unwrap();
+            }
+
+            let (hits, misses) = data_source.nullifier_cache_stats();
+            counter(
+                &mut out,
+                "espresso_nullifier_proof_cache_hits_total",
+                "Nullifier proofs served from the cache.",
+                hits,
+            );
+            counter(
+                &mut out,
+                "espresso_nullifier_proof_cache_misses_total",
+                "Nullifier proofs built from the nullifier set.",
+                misses,
+            );
+        }
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    metric(out, name, "counter", help, value)
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    metric(out, name, "gauge", help, value)
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+    writeln!(out, "{} {}", name, value).unwrap();
+}
+
+/// Serve `metrics` at `/metrics` on `port`.
+///
+/// Fails if the port cannot be bound. Otherwise, returns a handle to the server task, which
+/// resolves to the error if the server fails later.
+pub async fn serve(
+    metrics: Arc<Metrics>,
+    port: u16,
+) -> std::io::Result<JoinHandle<std::io::Result<()>>> {
+    let mut app = tide::with_state(metrics);
+    app.at("/metrics")
+        .get(|req: tide::Request<Arc<Metrics>>| async move {
+            Ok(tide::Response::builder(200)
+                .content_type("text/plain; version=0.0.4")
+                .body(req.state().render().await)
+                .build())
+        });
+    let mut listener = app.bind(format!("0.0.0.0:{}", port)).await?;
+    Ok(spawn(async move { listener.accept().await }))
+}
@@ -0,0 +1,70 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+//! Machine-readable progress reports from validator processes.
+//!
+//! A validator started with a progress socket connects to it and writes a [ProgressEvent] as a
+//! line of JSON each time it makes progress. This lets tools which drive validator processes, like
+//! the multi-machine automation, follow them without parsing their human-readable output.
+
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    /// A round completed with a block containing the round's transaction being committed.
+    RoundCompleted {
+        round: u64,
+        commitment: String,
+        block_hash: String,
+        txn_count: u64,
+    },
+    /// The validator completed all of its rounds.
+    Finished { commitment: Option<String> },
+}
+
+/// Writes progress events to a progress socket.
+#[derive(Debug, Default)]
+pub struct ProgressReporter {
+    stream: Option<UnixStream>,
+}
+
+impl ProgressReporter {
+    /// Connect to the progress socket at `path`.
+    ///
+    /// If `path` is [None], events are discarded.
+    pub fn connect(path: Option<&Path>) -> io::Result<Self> {
+        Ok(Self {
+            stream: path.map(UnixStream::connect).transpose()?,
+        })
+    }
+
+    pub fn report(&mut self, event: &ProgressEvent) {
+        if let Some(stream) = &mut self.stream {
+            let mut line = serde_json::to_vec(event).unwrap();
+            line.push(b'\n');
+            if let Err(err) = stream.write_all(&line) {
+                tracing::warn!("failed to report progress {:?}: {}", event, err);
+            }
+        }
+    }
+}
+
+/// Read the events written by a [ProgressReporter] until it disconnects.
+///
+/// Lines which are not valid events are skipped.
+pub fn read_events(stream: impl Read) -> impl Iterator<Item = ProgressEvent> {
+    BufReader::new(stream)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| match serde_json::from_str(&line) {
+            Ok(event) => Some(event),
+            Err(err) => {
+                tracing::warn!("invalid progress event {}: {}", line, err);
+                None
+            }
+        })
+}
@@ -2,7 +2,7 @@
 // This file is part of the Espresso library.
 
 use crate::{
-    gen_keys, genesis, init_validator, open_data_source, parse_duration, run_consensus, NodeOpt,
+    gen_keys, genesis, init_validator, parse_duration, run_consensus, NodeOpt,
     MINIMUM_BOOTSTRAP_NODES, MINIMUM_NODES,
 };
 use address_book::{error::AddressBookError, store::FileStore};
@@ -32,6 +32,7 @@ use std::time::{Duration, Instant};
 use surf_disco::Url;
 use tempdir::TempDir;
 
+mod restart;
 mod rewards;
 
 pub struct UnencryptedKeystoreLoader {
@@ -119,7 +120,8 @@ pub struct TestNetwork {
     pub address_book_api: Url,
     pub nodes: Vec<TestNode>,
     address_book: Option<AddressBook>,
-    _store: TempDir,
+    config: TestNetworkConfig,
+    store: TempDir,
 }
 
 impl TestNetwork {
@@ -127,6 +129,17 @@ impl TestNetwork {
         Self::kill_impl(take(&mut self.nodes), take(&mut self.address_book)).await
     }
 
+    /// Kill every validator and start them again from their persisted state.
+    ///
+    /// The restarted validators serve the query and submit APIs at new URLs. The address book is
+    /// not restarted.
+    pub async fn restart(&mut self, rng: &mut ChaChaRng) {
+        Self::kill_impl(take(&mut self.nodes), None).await;
+        self.nodes = start_nodes(rng, &self.config, &self.store).await;
+        self.query_api = self.nodes[0].esqs.as_ref().unwrap().url();
+        self.submit_api = self.query_api.clone();
+    }
+
     async fn kill_impl(nodes: Vec<TestNode>, address_book: Option<AddressBook>) {
         join_all(nodes.into_iter().map(|node| node.kill())).await;
         if let Some(address_book) = address_book {
@@ -144,6 +157,13 @@ impl Drop for TestNetwork {
     }
 }
 
+/// The parameters which identify a [TestNetwork], so that it can be restarted.
+struct TestNetworkConfig {
+    seed: [u8; 32],
+    faucet_pub_key: UserPubKey,
+    rewards_pub_key: Option<UserPubKey>,
+}
+
 /// Create a minimal network of validators for testing.
 ///
 /// This function will start the minimal number of validators needed to run consensus. One of the
@@ -156,6 +176,37 @@ pub async fn minimal_test_network(
 ) -> TestNetwork {
     let mut seed = [0; 32];
     rng.fill_bytes(&mut seed);
+    let config = TestNetworkConfig {
+        seed,
+        faucet_pub_key,
+        rewards_pub_key,
+    };
+    let store = TempDir::new("minimal_test_network_store").unwrap();
+    let nodes = start_nodes(rng, &config, &store).await;
+
+    let address_book = AddressBook::init().await;
+    let address_book_api = address_book.url();
+
+    TestNetwork {
+        query_api: nodes[0].esqs.as_ref().unwrap().url(),
+        submit_api: nodes[0].esqs.as_ref().unwrap().url(),
+        address_book_api,
+        nodes,
+        address_book: Some(address_book),
+        config,
+        store,
+    }
+}
+
+/// Start the validators of a minimal test network, persisting their state in `store`.
+///
+/// Validators whose state is already in `store` resume from it.
+async fn start_nodes(
+    rng: &mut ChaChaRng,
+    config: &TestNetworkConfig,
+    store: &TempDir,
+) -> Vec<TestNode> {
+    let seed = config.seed;
     let bootstrap_ports = (0..MINIMUM_BOOTSTRAP_NODES)
         .into_iter()
         .map(|_| pick_unused_port().unwrap());
@@ -173,15 +224,14 @@ pub async fn minimal_test_network(
         .collect::<Vec<_>>();
     println!("generated public keys in {:?}", start.elapsed());
 
-    let store = TempDir::new("minimal_test_network_store").unwrap();
     let mut nodes_futures = vec![];
     for (i, key) in keys.iter().enumerate() {
         let bootstrap_nodes = bootstrap_nodes.clone();
         let pub_keys = pub_keys.clone();
         let mut store_path = store.path().to_owned();
         let priv_key = key.clone();
-        let faucet_pub_key = faucet_pub_key.clone();
-        let rewards_pub_key = rewards_pub_key.clone();
+        let faucet_pub_key = config.faucet_pub_key.clone();
+        let rewards_pub_key = config.rewards_pub_key.clone();
 
         store_path.push(i.to_string());
         let new_rng = ChaChaRng::from_rng(&mut *rng).unwrap();
@@ -221,8 +271,8 @@ pub async fn minimal_test_network(
                 ..NodeOpt::new(i, MINIMUM_NODES)
             };
             let genesis = genesis(&node_opt);
-            let consensus = init_validator(new_rng, &node_opt, priv_key, pub_keys, genesis).await;
-            let data_source = open_data_source(&node_opt, consensus.clone());
+            let (consensus, data_source) =
+                init_validator(new_rng, &node_opt, priv_key, pub_keys, genesis).await;
 
             // If applicable, run a query service.
             let esqs = if i == 0 {
@@ -246,19 +296,7 @@ pub async fn minimal_test_network(
         };
         nodes_futures.push(future);
     }
-    let nodes = join_all(nodes_futures).await;
-
-    let address_book = AddressBook::init().await;
-    let address_book_api = address_book.url();
-
-    TestNetwork {
-        query_api: nodes[0].esqs.as_ref().unwrap().url(),
-        submit_api: nodes[0].esqs.as_ref().unwrap().url(),
-        address_book_api,
-        nodes,
-        address_book: Some(address_book),
-        _store: store,
-    }
+    join_all(nodes_futures).await
 }
 
 pub async fn retry<Fut: Future<Output = bool>>(f: impl Fn() -> Fut) {
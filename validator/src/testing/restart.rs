@@ -0,0 +1,206 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the Espresso library.
+
+#[cfg(all(test, feature = "slow-tests"))]
+mod test {
+    use crate::testing::TempDir;
+    use crate::testing::UnencryptedKeystoreLoader;
+    use crate::testing::{minimal_test_network, retry, TestNetwork};
+    use espresso_client::{network::NetworkBackend, EspressoKeystore};
+    use espresso_core::{state::LedgerStateCommitment, universal_params::UNIVERSAL_PARAM};
+    use espresso_esqs::ApiError;
+    use jf_cap::{keys::UserKeyPair, structs::AssetCode};
+    use rand_chacha::{rand_core::SeedableRng, ChaChaRng};
+    use surf_disco::Client;
+    use tracing_test::traced_test;
+
+    async fn faucet_keystore(
+        network: &TestNetwork,
+        faucet_key_pair: &UserKeyPair,
+        loader: &mut UnencryptedKeystoreLoader,
+    ) -> EspressoKeystore<'static, NetworkBackend<'static>, ()> {
+        let mut keystore = EspressoKeystore::new(
+            NetworkBackend::new(
+                &UNIVERSAL_PARAM,
+                network.query_api.clone(),
+                network.address_book_api.clone(),
+                network.submit_api.clone(),
+            )
+            .await
+            .unwrap(),
+            loader,
+        )
+        .await
+        .unwrap();
+        keystore
+            .add_account(faucet_key_pair.clone(), "faucet".into(), Default::default())
+            .await
+            .unwrap();
+        keystore
+            .await_sending_key_scan(&faucet_key_pair.address())
+            .await
+            .unwrap();
+        keystore
+    }
+
+    async fn latest_state(network: &TestNetwork) -> (u64, LedgerStateCommitment) {
+        let client = Client::<ApiError>::new(network.query_api.clone());
+        assert!(client.connect(None).await);
+        let block_id: u64 = client.get("status/latest_block_id").send().await.unwrap();
+        let comm = client
+            .get(&format!("availability/getstatecomm/{}", block_id))
+            .send()
+            .await
+            .unwrap();
+        (block_id, comm)
+    }
+
+    #[cfg(feature = "slow-tests")]
+    #[async_std::test]
+    #[traced_test]
+    async fn test_restart_from_store() {
+        let mut rng = ChaChaRng::from_seed([2; 32]);
+        let faucet_key_pair = UserKeyPair::generate(&mut rng);
+        let mut network = minimal_test_network(&mut rng, faucet_key_pair.pub_key(), None).await;
+
+        // Commit a block before the restart.
+        let mut loader = UnencryptedKeystoreLoader {
+            dir: TempDir::new("restart_test").unwrap(),
+        };
+        let mut keystore = faucet_keystore(&network, &faucet_key_pair, &mut loader).await;
+        let receipt = keystore
+            .transfer(
+                None,
+                &AssetCode::native(),
+                &[(faucet_key_pair.pub_key(), 100)],
+                1,
+            )
+            .await
+            .unwrap();
+        keystore.await_transaction(&receipt).await.unwrap();
+        let balance = keystore.balance(&AssetCode::native()).await;
+        let (block_id, comm) = latest_state(&network).await;
+        drop(keystore);
+
+        // Restart every node from its store. The nodes resume from the latest persisted leaf
+        // instead of genesis, so the query service still has the old blocks and the ledger keeps
+        // growing from where it left off.
+        network.restart(&mut rng).await;
+        let (new_block_id, _) = latest_state(&network).await;
+        assert!(new_block_id >= block_id);
+        let client = Client::<ApiError>::new(network.query_api.clone());
+        let restored_comm: LedgerStateCommitment = client
+            .get(&format!("availability/getstatecomm/{}", block_id))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(restored_comm, comm);
+
+        // A fresh keystore following the restarted network sees the same balance, and can still
+        // get transactions committed.
+        let mut loader = UnencryptedKeystoreLoader {
+            dir: TempDir::new("restart_test").unwrap(),
+        };
+        let mut keystore = faucet_keystore(&network, &faucet_key_pair, &mut loader).await;
+        assert_eq!(keystore.balance(&AssetCode::native()).await, balance);
+        let receipt = keystore
+            .transfer(
+                None,
+                &AssetCode::native(),
+                &[(faucet_key_pair.pub_key(), 100)],
+                1,
+            )
+            .await
+            .unwrap();
+        keystore.await_transaction(&receipt).await.unwrap();
+        let (final_block_id, _) = latest_state(&network).await;
+        assert!(final_block_id > block_id);
+    }
+
+    #[cfg(feature = "slow-tests")]
+    #[async_std::test]
+    #[traced_test]
+    async fn test_restart_then_claim_reward() {
+        let mut rng = ChaChaRng::from_seed([3; 32]);
+        let faucet_key_pair = UserKeyPair::generate(&mut rng);
+        let rewards_key_pair = UserKeyPair::generate(&mut rng);
+        let mut network = minimal_test_network(
+            &mut rng,
+            faucet_key_pair.pub_key(),
+            Some(rewards_key_pair.pub_key()),
+        )
+        .await;
+
+        // Collect some rewards before the restart, so that the collected rewards set is not empty.
+        let mut loader = UnencryptedKeystoreLoader {
+            dir: TempDir::new("restart_reward_test").unwrap(),
+        };
+        let mut keystore = faucet_keystore(&network, &faucet_key_pair, &mut loader).await;
+        keystore
+            .add_account(
+                rewards_key_pair.clone(),
+                "rewards".into(),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        let receipt = keystore
+            .transfer(
+                None,
+                &AssetCode::native(),
+                &[(faucet_key_pair.pub_key(), 100)],
+                1,
+            )
+            .await
+            .unwrap();
+        keystore.await_transaction(&receipt).await.unwrap();
+        retry(|| async {
+            keystore
+                .balance_breakdown(&rewards_key_pair.address(), &AssetCode::native())
+                .await
+                > 0.into()
+        })
+        .await;
+        drop(keystore);
+
+        // After the restart, the validators rebuild the collected rewards set from their stores,
+        // so the rewards they claim for new blocks are still accepted.
+        network.restart(&mut rng).await;
+        let mut loader = UnencryptedKeystoreLoader {
+            dir: TempDir::new("restart_reward_test").unwrap(),
+        };
+        let mut keystore = faucet_keystore(&network, &faucet_key_pair, &mut loader).await;
+        keystore
+            .add_account(
+                rewards_key_pair.clone(),
+                "rewards".into(),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        keystore
+            .await_sending_key_scan(&rewards_key_pair.address())
+            .await
+            .unwrap();
+        let restored_rewards = keystore
+            .balance_breakdown(&rewards_key_pair.address(), &AssetCode::native())
+            .await;
+        let receipt = keystore
+            .transfer(
+                None,
+                &AssetCode::native(),
+                &[(faucet_key_pair.pub_key(), 100)],
+                1,
+            )
+            .await
+            .unwrap();
+        keystore.await_transaction(&receipt).await.unwrap();
+        retry(|| async {
+            keystore
+                .balance_breakdown(&rewards_key_pair.address(), &AssetCode::native())
+                .await
+                > restored_rewards
+        })
+        .await;
+    }
+}
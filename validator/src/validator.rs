@@ -3,6 +3,7 @@
 
 #![deny(warnings)]
 
+use crate::metrics::{self, Metrics};
 use crate::*;
 use espresso_core::StakingKey;
 use espresso_esqs::full_node::EsQS;
@@ -30,9 +31,25 @@ pub async fn init<R: CryptoRng + RngCore + Send + 'static>(
     let known_nodes = keys
         .into_iter()
         .map(|sk| StakingKey::from_private(&sk))
-        .collect();
-    let hotshot = init_validator(rng, &node_opt, priv_key, known_nodes, genesis).await;
-    let data_source = open_data_source(&node_opt, hotshot.clone());
+        .collect::<Vec<_>>();
+    let own_key = SignatureKey::from(known_nodes[node_opt.id].clone()).to_bytes();
+    let (hotshot, data_source) =
+        init_validator(rng, &node_opt, priv_key, known_nodes, genesis).await;
+
+    if let Some(port) = node_opt.metrics_port {
+        let mut metrics = Metrics::new();
+        if node_opt.esqs.is_some() {
+            metrics = metrics.with_data_source(data_source.clone());
+        }
+        let metrics = Arc::new(metrics);
+        metrics.collect(hotshot.clone(), own_key);
+        let server = metrics::serve(metrics, port).await?;
+        spawn(async move {
+            if let Err(err) = server.await {
+                tracing::error!("metrics server failed: {}", err);
+            }
+        });
+    }
 
     // Start an EsQS server if requested.
     if let Some(esqs) = &node_opt.esqs {